@@ -8,8 +8,9 @@ use std::{collections::HashMap, fmt::Display};
 use std::{fmt, mem};
 
 use bril_rs::{Argument, Code, EffectOps, Function, Instruction, Position, Program, Type};
+use petgraph::algo::dominators::{self, Dominators};
 use petgraph::stable_graph::StableDiGraph;
-use petgraph::visit::Visitable;
+use petgraph::visit::{EdgeRef, IntoEdgeReferences, Visitable};
 use petgraph::{
     graph::NodeIndex,
     visit::{DfsPostOrder, Walker},
@@ -23,6 +24,7 @@ pub(crate) type NodeSet = <StableDiGraph<BasicBlock, Branch> as Visitable>::Map;
 #[cfg(test)]
 mod tests;
 
+pub(crate) mod simplify;
 pub(crate) mod structured;
 pub(crate) mod to_structured;
 
@@ -141,6 +143,7 @@ impl FromStr for BlockName {
 /// part of the RVSDG conversion process. The `Identifier` type stores both
 /// kinds of name.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Identifier {
     Name(String),
     Num(usize),
@@ -194,6 +197,16 @@ impl BasicBlock {
         }
     }
 
+    /// The label of the block, as it appears when the CFG is printed.
+    pub fn label(&self) -> String {
+        self.name.to_string()
+    }
+
+    /// The instructions in the block, not including the branch at its end.
+    pub fn instrs(&self) -> &[Instruction] {
+        &self.instrs
+    }
+
     fn to_code(&self) -> Vec<Code> {
         let mut instrs = Vec::new();
         instrs.push(Code::Label {
@@ -237,7 +250,8 @@ pub struct Cfg {
     pub(crate) exit: NodeIndex,
     /// The name of the function.
     pub(crate) name: String,
-    return_ty: Option<Type>,
+    /// The return type of the function, if it has one.
+    pub(crate) return_ty: Option<Type>,
 }
 
 impl Cfg {
@@ -246,7 +260,146 @@ impl Cfg {
     }
 }
 
+/// Read-only access to the structure of the CFG, e.g. for working out why a
+/// function's control flow needed restructuring.
 impl Cfg {
+    /// The name of the function.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The entry block.
+    pub fn entry(&self) -> NodeIndex {
+        self.entry
+    }
+
+    /// The exit block, which every return jumps to.
+    pub fn exit(&self) -> NodeIndex {
+        self.exit
+    }
+
+    /// Every block in the CFG.
+    pub fn blocks(&self) -> impl Iterator<Item = (NodeIndex, &BasicBlock)> + '_ {
+        self.graph
+            .node_indices()
+            .map(|node| (node, &self.graph[node]))
+    }
+
+    /// The block with the given label (see [BasicBlock::label]), if any.
+    pub fn block(&self, label: &str) -> Option<NodeIndex> {
+        self.blocks()
+            .find(|(_, block)| block.label() == label)
+            .map(|(node, _)| node)
+    }
+
+    /// Every edge in the CFG, as a pair of the source and target blocks. A
+    /// conditional branch has one edge per target.
+    pub fn edges(&self) -> impl Iterator<Item = (NodeIndex, NodeIndex)> + '_ {
+        self.graph
+            .edge_references()
+            .map(|edge| (edge.source(), edge.target()))
+    }
+
+    /// The dominator tree of the CFG, rooted at the entry block.
+    pub fn dominators(&self) -> Dominators<NodeIndex> {
+        dominators::simple_fast(&self.graph, self.entry)
+    }
+
+    /// The edges whose target dominates their source. These close natural
+    /// loops; a cycle with no back edge (one that can be entered in more than
+    /// one place) is irreducible.
+    pub fn back_edges(&self) -> Vec<(NodeIndex, NodeIndex)> {
+        let dom = self.dominators();
+        self.edges()
+            .filter(|(src, dst)| {
+                dom.dominators(*src)
+                    .is_some_and(|mut doms| doms.any(|node| node == *dst))
+            })
+            .collect()
+    }
+}
+
+impl Cfg {
+    /// Convert the CFG back into a Bril function.
+    ///
+    /// Blocks are laid out in reverse postorder, each ending in an explicit
+    /// `jmp`, `br`, or `ret`. Only CFGs branching on boolean variables are
+    /// supported: `AssignCond` annotations and multi-way branches will panic.
+    pub(crate) fn to_bril(&self) -> Function {
+        let mut order = DfsPostOrder::new(&self.graph, self.entry)
+            .iter(&self.graph)
+            .collect::<Vec<_>>();
+        order.reverse();
+
+        let mut instrs = Vec::new();
+        for node in order {
+            if node == self.exit {
+                continue;
+            }
+            instrs.extend(self.graph[node].to_code());
+            instrs.push(Code::Instruction(self.terminator(node)));
+        }
+
+        Function {
+            args: self.args.clone(),
+            instrs,
+            name: self.name.clone(),
+            pos: None,
+            return_type: self.return_ty.clone(),
+        }
+    }
+
+    fn terminator(&self, node: NodeIndex) -> Instruction {
+        let block = &self.graph[node];
+        let effect = |op, args, labels| Instruction::Effect {
+            args,
+            funcs: vec![],
+            labels,
+            op,
+            pos: None,
+        };
+        let label = |node: NodeIndex| self.graph[node].name.to_string();
+        let edges = self.graph.edges(node).collect::<Vec<_>>();
+        match edges.as_slice() {
+            [edge] if edge.target() == self.exit => {
+                let args = block
+                    .footer
+                    .iter()
+                    .map(|ann| match ann {
+                        Annotation::AssignRet { src } => src.to_string(),
+                        Annotation::AssignCond { .. } => {
+                            panic!("cannot convert AssignCond in block {} to bril", block.name)
+                        }
+                    })
+                    .collect();
+                effect(EffectOps::Return, args, vec![])
+            }
+            [edge] => effect(EffectOps::Jump, vec![], vec![label(edge.target())]),
+            [e1, e2] => match (&e1.weight().op, &e2.weight().op) {
+                (BranchOp::Cond { arg, val }, BranchOp::Cond { .. }) => {
+                    let (then, els) = if *val == CondVal::from(true) {
+                        (e1, e2)
+                    } else {
+                        (e2, e1)
+                    };
+                    effect(
+                        EffectOps::Branch,
+                        vec![arg.to_string()],
+                        vec![label(then.target()), label(els.target())],
+                    )
+                }
+                _ => panic!(
+                    "mix of conditional and unconditional branches in block {}",
+                    block.name
+                ),
+            },
+            _ => panic!(
+                "unsupported branches out of block {}: {edges:?}",
+                block.name
+            ),
+        }
+    }
+
     fn reverse_postorder(self: &Cfg) -> HashMap<BlockName, usize> {
         let mut reverse_postorder = HashMap::<BlockName, usize>::new();
         let mut post_counter = 0;
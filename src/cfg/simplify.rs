@@ -0,0 +1,104 @@
+//! Cleaning up a CFG before it is restructured.
+//!
+//! Bril programs produced by other tools often contain copies of variables
+//! and blocks that do nothing but jump somewhere else. Neither changes what
+//! the program computes, but every extra block adds edges that restructuring
+//! has to untangle before the CFG can be converted to an RVSDG.
+use std::collections::HashMap;
+
+use bril_rs::{Instruction, ValueOps};
+use petgraph::{stable_graph::NodeIndex, visit::EdgeRef, Direction};
+
+use super::{BranchOp, Cfg};
+
+/// Propagate copies within each block of `cfg`, then splice out the empty
+/// blocks that unconditionally jump to another block.
+///
+/// Copies are only propagated to later instructions in the same block, and
+/// the copies themselves are left in place (aside from copies of a variable
+/// to itself), since other blocks may read them.
+pub(crate) fn simplify_cfg(cfg: &mut Cfg) {
+    for block in cfg.graph.node_weights_mut() {
+        propagate_copies(&mut block.instrs);
+    }
+    while let Some(block) = cfg.graph.node_indices().find(|node| spliceable(cfg, *node)) {
+        let succ = cfg.graph.neighbors(block).next().unwrap();
+        let incoming = Vec::from_iter(
+            cfg.graph
+                .edges_directed(block, Direction::Incoming)
+                .map(|edge| (edge.source(), edge.weight().clone())),
+        );
+        for (pred, branch) in incoming {
+            cfg.graph.add_edge(pred, succ, branch);
+        }
+        cfg.graph.remove_node(block);
+    }
+}
+
+/// Replace each read of a variable that was copied from another one earlier in
+/// `instrs` (and that neither has been assigned since) with a read of the
+/// original, and drop the copies of a variable to itself.
+fn propagate_copies(instrs: &mut Vec<Instruction>) {
+    // The variable that each copied variable currently holds the value of.
+    let mut copies = HashMap::<String, String>::new();
+    for instr in instrs.iter_mut() {
+        if let Instruction::Value { args, .. } | Instruction::Effect { args, .. } = instr {
+            for arg in args.iter_mut() {
+                if let Some(src) = copies.get(arg) {
+                    *arg = src.clone();
+                }
+            }
+        }
+        let dest = match &*instr {
+            Instruction::Constant { dest, .. } | Instruction::Value { dest, .. } => dest,
+            Instruction::Effect { .. } => continue,
+        };
+        copies.remove(dest);
+        copies.retain(|_, src| src != dest);
+        if let Instruction::Value {
+            op: ValueOps::Id,
+            args,
+            dest,
+            ..
+        } = &*instr
+        {
+            if args[0] != *dest {
+                copies.insert(dest.clone(), args[0].clone());
+            }
+        }
+    }
+    instrs.retain(|instr| {
+        !matches!(
+            instr,
+            Instruction::Value { op: ValueOps::Id, args, dest, .. } if args[0] == *dest
+        )
+    });
+}
+
+/// Whether `block` is an empty block (other than the entry or exit) that
+/// unconditionally jumps to another block, and that can be spliced out
+/// without giving one of its predecessors two edges to the same block (which
+/// would make a conditional branch that goes the same way either way).
+fn spliceable(cfg: &Cfg, block: NodeIndex) -> bool {
+    if block == cfg.entry || block == cfg.exit {
+        return false;
+    }
+    let weight = &cfg.graph[block];
+    if !weight.instrs.is_empty() || !weight.footer.is_empty() {
+        return false;
+    }
+    let mut edges = cfg.graph.edges(block);
+    let (Some(edge), None) = (edges.next(), edges.next()) else {
+        return false;
+    };
+    let succ = edge.target();
+    edge.weight().op == BranchOp::Jmp
+        && succ != block
+        && cfg
+            .graph
+            .neighbors_directed(block, Direction::Incoming)
+            .all(|pred| {
+                cfg.graph.edges_connecting(pred, block).count() == 1
+                    && !cfg.graph.contains_edge(pred, succ)
+            })
+}
@@ -158,3 +158,41 @@ fn unstructured_causes_error() {
         Err(EggCCError::UnstructuredControlFlow)
     ))
 }
+
+#[test]
+fn unstructured_dominators() {
+    // The program from `rvsdg_unstructured`: .B and .C form a cycle that can
+    // be entered at either block.
+    const PROGRAM: &str = r#"@main(): int {
+        x: int = const 4;
+        a_cond: bool = lt x x;
+        br a_cond .B .C;
+      .B:
+        a: int = const 1;
+        b_cond: bool = lt x a;
+        x: int = add x a;
+        br b_cond .C .D;
+      .C:
+        jmp .B;
+      .D:
+        ret x;
+      }"#;
+    let cfg = &program_to_cfg(&parse_from_string(PROGRAM)).functions[0];
+    let block = |label: &str| cfg.block(label).unwrap();
+    let (entry, b, c, d) = (cfg.entry(), block("B"), block("C"), block("D"));
+    assert_eq!(cfg.blocks().count(), 5);
+    assert_eq!(cfg.block("entry___"), Some(entry));
+
+    let dom = cfg.dominators();
+    assert_eq!(dom.immediate_dominator(entry), None);
+    assert_eq!(dom.immediate_dominator(b), Some(entry));
+    assert_eq!(dom.immediate_dominator(c), Some(entry));
+    assert_eq!(dom.immediate_dominator(d), Some(b));
+    assert_eq!(dom.immediate_dominator(cfg.exit()), Some(d));
+
+    // Neither block of the cycle dominates the other, so there is no back
+    // edge: the cycle is irreducible.
+    assert!(cfg.edges().any(|edge| edge == (b, c)));
+    assert!(cfg.edges().any(|edge| edge == (c, b)));
+    assert!(cfg.back_edges().is_empty());
+}
@@ -5,6 +5,7 @@ use crate::{
         structured::{StructuredBlock, StructuredFunction},
         BasicBlock, BlockName,
     },
+    rvsdg::BitOp,
     EggCCError, Optimizer,
 };
 use bril_rs::{Argument, Code, EffectOps, Instruction, Literal, Program, Type, ValueOps};
@@ -721,3 +722,53 @@ pub(crate) fn egglog_op_to_bril(op: Symbol) -> ValueOps {
     let with_quotes = "\"".to_owned() + &op.to_string() + "\"";
     serde_json::from_str(&with_quotes).unwrap()
 }
+
+/// The egglog constructor for the unsigned version of the comparison `op`
+/// (see [Expr::UnsignedCmp](crate::rvsdg::Expr::UnsignedCmp)).
+pub(crate) fn unsigned_cmp_to_egglog(op: ValueOps) -> &'static str {
+    match op {
+        ValueOps::Lt => "ult",
+        ValueOps::Le => "ule",
+        ValueOps::Gt => "ugt",
+        ValueOps::Ge => "uge",
+        op => panic!("{op} is not an ordered comparison"),
+    }
+}
+
+/// The inverse of [unsigned_cmp_to_egglog], or `None` if `op` isn't the
+/// constructor for an unsigned comparison.
+pub(crate) fn egglog_to_unsigned_cmp(op: &str) -> Option<ValueOps> {
+    match op {
+        "ult" => Some(ValueOps::Lt),
+        "ule" => Some(ValueOps::Le),
+        "ugt" => Some(ValueOps::Gt),
+        "uge" => Some(ValueOps::Ge),
+        _ => None,
+    }
+}
+
+/// The egglog constructor for the bitwise operation `op` (see
+/// [Expr::Bitwise](crate::rvsdg::Expr::Bitwise)). These are named apart from
+/// the boolean `and` and `or`.
+pub(crate) fn bitop_to_egglog(op: BitOp) -> &'static str {
+    match op {
+        BitOp::Shl => "shl",
+        BitOp::Shr => "shr",
+        BitOp::And => "bitand",
+        BitOp::Or => "bitor",
+        BitOp::Xor => "bitxor",
+    }
+}
+
+/// The inverse of [bitop_to_egglog], or `None` if `op` isn't the constructor
+/// for a bitwise operation.
+pub(crate) fn egglog_to_bitop(op: &str) -> Option<BitOp> {
+    match op {
+        "shl" => Some(BitOp::Shl),
+        "shr" => Some(BitOp::Shr),
+        "bitand" => Some(BitOp::And),
+        "bitor" => Some(BitOp::Or),
+        "bitxor" => Some(BitOp::Xor),
+        _ => None,
+    }
+}
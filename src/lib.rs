@@ -1,17 +1,21 @@
 use bril2json::parse_abstract_program_from_read;
-use bril_rs::Program;
+use bril_rs::{
+    Argument, Code, ConstOps, EffectOps, Function, Instruction, Literal, Program, Type, ValueOps,
+};
 
+use cfg::program_to_cfg;
 use cfg::structured::StructuredProgram;
 use cfg::to_structured::cfg_to_structured;
-use cfg::{program_to_cfg, CfgProgram};
 use egglog::ast::Expr;
 use egglog::EGraph;
-use rvsdg::{RvsdgError, RvsdgProgram};
+use rvsdg::RvsdgProgram;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::io::Write;
 use std::path::PathBuf;
 use std::process::Stdio;
+use std::time::{Duration, Instant};
+use util::ListDisplay;
 
 use thiserror::Error;
 
@@ -21,6 +25,14 @@ pub(crate) mod peg;
 pub(crate) mod rvsdg;
 pub mod util;
 
+pub use cfg::{BasicBlock, Cfg, CfgProgram, Identifier};
+pub use rvsdg::builder::RvsdgBuilder;
+pub use rvsdg::extract::{CostModel, CostReport, DefaultCostModel, StateDepthCostModel};
+pub use rvsdg::{
+    compile_to_rvsdg, entry_function, program_to_egglog, BitOp, FunctionSignature, Operand,
+    OptLevel, RvsdgError, RvsdgFunction,
+};
+
 #[derive(Debug, Error)]
 pub enum EggCCError {
     #[error("Egglog error: {0}")]
@@ -35,6 +47,170 @@ pub enum EggCCError {
     RvsdgError(RvsdgError),
     #[error("Uninitialized variable {0} used in function {1}")]
     UninitializedVariable(String, String),
+    #[error("Invalid custom rules: {0}")]
+    InvalidRules(egglog::Error),
+    #[error("Cannot interpret run type {0} because it doesn't produce a bril program")]
+    Uninterpretable(util::RunType),
+    #[error("Interpreter error: {0}")]
+    Interp(String),
+    #[error("Couldn't write {}: {1}", .0.display())]
+    Write(PathBuf, std::io::Error),
+}
+
+/// The outcome of [Optimizer::interp_with_budget].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InterpResult {
+    /// The program finished, printing this to stdout.
+    Terminated(String),
+    /// The program was still running when its step budget ran out.
+    Diverged,
+}
+
+/// The type of a constant with the literal `lit`.
+fn literal_type(lit: &Literal) -> Type {
+    match lit {
+        Literal::Int(_) => Type::Int,
+        Literal::Bool(_) => Type::Bool,
+        Literal::Float(_) => Type::Float,
+        Literal::Char(_) => Type::Char,
+    }
+}
+
+/// `program`, instrumented to stop once it has taken more than `steps` steps
+/// (see [Optimizer::interp_with_budget]). A program that runs out of steps
+/// prints `marker` and then fails to allocate memory, which stops brilirs with
+/// an error however deep in the call stack it is.
+///
+/// The steps left are kept on the heap: the new `main` allocates them and
+/// calls the old one, and every function takes them as an extra argument.
+fn with_step_budget(program: &Program, steps: usize, marker: i64) -> Program {
+    let mut prefix = "fuel".to_string();
+    while program.functions.iter().any(|func| {
+        let vars = func.instrs.iter().filter_map(|code| match code {
+            Code::Label { label, .. } => Some(label),
+            Code::Instruction(
+                Instruction::Constant { dest, .. } | Instruction::Value { dest, .. },
+            ) => Some(dest),
+            Code::Instruction(Instruction::Effect { .. }) => None,
+        });
+        std::iter::once(&func.name)
+            .chain(func.args.iter().map(|arg| &arg.name))
+            .chain(vars)
+            .any(|name| name.starts_with(&prefix))
+    }) {
+        prefix.push('_');
+    }
+    let fuel = prefix;
+    let tick = format!("{fuel}_tick");
+    let inner = format!("{fuel}_main");
+    let call_tick = Code::Instruction(Instruction::Effect {
+        args: vec![fuel.clone()],
+        funcs: vec![tick.clone()],
+        labels: vec![],
+        op: EffectOps::Call,
+        pos: None,
+    });
+
+    let mut res = program.clone();
+    for func in &mut res.functions {
+        if func.name == "main" {
+            func.name = inner.clone();
+        }
+        func.args.push(Argument {
+            name: fuel.clone(),
+            arg_type: Type::Pointer(Box::new(Type::Int)),
+        });
+        let mut instrs = vec![call_tick.clone()];
+        let mut code = std::mem::take(&mut func.instrs).into_iter().peekable();
+        while let Some(mut instr) = code.next() {
+            if let Code::Instruction(
+                Instruction::Value {
+                    op: ValueOps::Call,
+                    args,
+                    funcs,
+                    ..
+                }
+                | Instruction::Effect {
+                    op: EffectOps::Call,
+                    args,
+                    funcs,
+                    ..
+                },
+            ) = &mut instr
+            {
+                for callee in funcs.iter_mut().filter(|callee| *callee == "main") {
+                    *callee = inner.clone();
+                }
+                args.push(fuel.clone());
+            }
+            let is_label = matches!(instr, Code::Label { .. });
+            instrs.push(instr);
+            if is_label {
+                // Phis have to stay at the start of the block, and a call
+                // leaves the label that they read unchanged.
+                while let Some(phi) = code.next_if(|code| {
+                    matches!(
+                        code,
+                        Code::Instruction(Instruction::Value {
+                            op: ValueOps::Phi,
+                            ..
+                        })
+                    )
+                }) {
+                    instrs.push(phi);
+                }
+                instrs.push(call_tick.clone());
+            }
+        }
+        func.instrs = instrs;
+    }
+
+    let main = program.functions.iter().find(|func| func.name == "main");
+    let params = main.map_or(vec![], |main| main.args.clone());
+    let call = format!(
+        "call @{inner} {} {fuel}",
+        ListDisplay(params.iter().map(|arg| &arg.name), " ")
+    );
+    let call = match main.and_then(|main| main.return_type.as_ref()) {
+        Some(ty) => format!("{fuel}_result: {ty} = {call}"),
+        None => call,
+    };
+    let params = if params.is_empty() {
+        String::new()
+    } else {
+        let params = params
+            .iter()
+            .map(|arg| format!("{}: {}", arg.name, arg.arg_type));
+        format!("({})", ListDisplay(params, ", "))
+    };
+    let helpers = format!(
+        "@{tick}(fuel: ptr<int>) {{
+           left: int = load fuel;
+           one: int = const 1;
+           left: int = sub left one;
+           store fuel left;
+           zero: int = const 0;
+           more: bool = ge left zero;
+           br more .done .exhausted;
+         .exhausted:
+           marker: int = const {marker};
+           print marker;
+           size: int = const -1;
+           halt: ptr<int> = alloc size;
+         .done:
+         }}
+         @main{params} {{
+           {fuel}_steps: int = const {steps};
+           {fuel}_one: int = const 1;
+           {fuel}: ptr<int> = alloc {fuel}_one;
+           store {fuel} {fuel}_steps;
+           {call};
+           free {fuel};
+         }}",
+    );
+    let helpers = Optimizer::parse_bril(&helpers).expect("instrumentation should parse");
+    res.functions.extend(helpers.functions);
+    res
 }
 
 #[allow(dead_code)]
@@ -87,9 +263,82 @@ impl RunType {
     }
 }
 
+/// Bounds on how much saturation a [Run](util::Run) does. Once a bound is reached,
+/// saturation stops and the program is extracted from the partially saturated
+/// egraph, which is still correct but may be less optimized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SaturationLimit {
+    /// Run at most this many iterations, even if more were asked for.
+    pub max_iters: Option<usize>,
+    /// Stop once the egraph holds at least this many tuples.
+    pub max_nodes: Option<usize>,
+}
+
+impl SaturationLimit {
+    /// Run `schedule`, which should perform a single iteration of `run`
+    /// commands (one per line), up to `iters` times, until this limit is
+    /// reached, or until egglog reports that an iteration changed nothing.
+    /// Returns the number of iterations that were run.
+    pub(crate) fn saturate(
+        &self,
+        egraph: &mut EGraph,
+        iters: usize,
+        schedule: &str,
+    ) -> Result<usize, EggCCError> {
+        let iters = self.max_iters.map_or(iters, |max| iters.min(max));
+        for iter in 0..iters {
+            if self.max_nodes.is_some_and(|max| egraph.num_tuples() >= max) {
+                return Ok(iter);
+            }
+            let mut updated = false;
+            for command in schedule.lines().filter(|line| !line.trim().is_empty()) {
+                egraph
+                    .parse_and_run_program(command)
+                    .map_err(EggCCError::EggLog)?;
+                updated |= egraph
+                    .get_run_report()
+                    .as_ref()
+                    .map_or(true, |report| report.updated);
+            }
+            if !updated {
+                return Ok(iter + 1);
+            }
+        }
+        Ok(iters)
+    }
+}
+
+/// What the optimizer reports back besides the optimized program. The
+/// `Option`al fields are debugging output that is only produced on request:
+/// they are `None` unless they were asked for, in which case the optimizer
+/// fills them in.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Captures {
+    /// How many iterations of saturation were run.
+    pub(crate) iterations: usize,
+    /// An equivalent standalone egglog program.
+    pub(crate) egglog_program: Option<String>,
+    /// The saturated egraph, serialized to JSON.
+    pub(crate) egraph_json: Option<String>,
+}
+
+/// How long each phase of a [Run](util::Run) took. Phases a run type doesn't have
+/// (e.g. saturation, for conversions) are zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunTimings {
+    /// Converting the Bril program into the representation that gets
+    /// optimized, including encoding it for egglog.
+    pub lowering: Duration,
+    /// Running egglog's rules.
+    pub saturation: Duration,
+    /// Extracting the optimized program back out of the egraph.
+    pub extraction: Duration,
+}
+
 pub struct Optimizer {
     pub num_iters: usize,
     pub var_counter: usize,
+    pub limit: SaturationLimit,
 }
 
 impl Default for Optimizer {
@@ -97,6 +346,7 @@ impl Default for Optimizer {
         Self {
             num_iters: 3,
             var_counter: 0,
+            limit: SaturationLimit::default(),
         }
     }
 }
@@ -156,6 +406,167 @@ impl Optimizer {
         String::from_utf8(optimized_out).unwrap()
     }
 
+    /// Run the rust interpreter on `program`, returning what it printed
+    /// along with the interpreter's error, if it failed.
+    fn try_interp(program: &Program, args: &[String]) -> (String, Result<(), String>) {
+        let mut stdout = Vec::new();
+        let res = brilirs::run_input(
+            std::io::BufReader::new(program.to_string().as_bytes()),
+            std::io::BufWriter::new(&mut stdout),
+            args,
+            false,
+            std::io::stderr(),
+            false,
+            true,
+            None,
+        )
+        .map_err(|err| err.to_string());
+        (String::from_utf8(stdout).unwrap(), res)
+    }
+
+    /// Like [Optimizer::interp], but giving up once the program has taken
+    /// more than `steps` steps, so that programs which loop forever don't hang
+    /// the caller. A step is entering a function or a labelled block, so every
+    /// call and loop iteration counts. Errors from the interpreter are
+    /// returned rather than panicking.
+    pub fn interp_with_budget(
+        program: &Program,
+        args: Vec<String>,
+        steps: usize,
+    ) -> Result<InterpResult, EggCCError> {
+        let run = |marker| Self::try_interp(&with_step_budget(program, steps, marker), &args);
+        let (stdout, res) = run(0);
+        let Err(err) = res else {
+            return Ok(InterpResult::Terminated(stdout));
+        };
+        // Running out of steps also fails, right after printing the marker.
+        // The runs are deterministic, so they only differ if a different
+        // marker gets printed.
+        if run(1).0 != stdout {
+            Ok(InterpResult::Diverged)
+        } else {
+            Err(EggCCError::Interp(err))
+        }
+    }
+
+    /// Run the `main` function of `program` on `args` with the rust
+    /// interpreter, returning what it prints and what it returns (if
+    /// anything).
+    ///
+    /// brilirs only takes arguments as strings and ignores what `main`
+    /// returns, so `main` is renamed and called from a new `main` that passes
+    /// `args` as constants and prints the result.
+    pub fn interp_main(
+        program: &Program,
+        args: &[Literal],
+    ) -> Result<(String, Option<Literal>), EggCCError> {
+        let Some(main) = program.functions.iter().find(|func| func.name == "main") else {
+            return Err(EggCCError::ConversionError("no main function".into()));
+        };
+        let params = Vec::from_iter(main.args.iter().map(|arg| &arg.arg_type));
+        let types = Vec::from_iter(args.iter().map(literal_type));
+        if params.len() != types.len() || params.iter().zip(&types).any(|(p, t)| **p != *t) {
+            return Err(EggCCError::ConversionError(format!(
+                "main takes {params:?}, but was given {args:?}"
+            )));
+        }
+        if let Some(ty @ Type::Pointer(_)) = &main.return_type {
+            return Err(EggCCError::ConversionError(format!(
+                "can't observe a main function returning {ty}"
+            )));
+        }
+        let return_type = main.return_type.clone();
+
+        let mut inner = "main".to_string();
+        while program.functions.iter().any(|func| func.name == inner) {
+            inner.push('_');
+        }
+        let mut wrapped = program.clone();
+        for func in &mut wrapped.functions {
+            if func.name == "main" {
+                func.name = inner.clone();
+            }
+            for code in &mut func.instrs {
+                if let Code::Instruction(
+                    Instruction::Value { funcs, .. } | Instruction::Effect { funcs, .. },
+                ) = code
+                {
+                    for callee in funcs.iter_mut().filter(|callee| *callee == "main") {
+                        *callee = inner.clone();
+                    }
+                }
+            }
+        }
+
+        let mut instrs = Vec::new();
+        let mut arg_names = Vec::new();
+        for (i, (lit, ty)) in args.iter().zip(types).enumerate() {
+            let dest = format!("arg{i}");
+            instrs.push(Code::Instruction(Instruction::Constant {
+                dest: dest.clone(),
+                op: ConstOps::Const,
+                pos: None,
+                const_type: ty,
+                value: lit.clone(),
+            }));
+            arg_names.push(dest);
+        }
+        let call = match &return_type {
+            Some(ty) => Instruction::Value {
+                args: arg_names,
+                dest: "result".into(),
+                funcs: vec![inner],
+                labels: vec![],
+                op: ValueOps::Call,
+                pos: None,
+                op_type: ty.clone(),
+            },
+            None => Instruction::Effect {
+                args: arg_names,
+                funcs: vec![inner],
+                labels: vec![],
+                op: EffectOps::Call,
+                pos: None,
+            },
+        };
+        instrs.push(Code::Instruction(call));
+        if return_type.is_some() {
+            instrs.push(Code::Instruction(Instruction::Effect {
+                args: vec!["result".into()],
+                funcs: vec![],
+                labels: vec![],
+                op: EffectOps::Print,
+                pos: None,
+            }));
+        }
+        wrapped.functions.push(Function {
+            args: vec![],
+            instrs,
+            name: "main".into(),
+            pos: None,
+            return_type: None,
+        });
+
+        let mut stdout = Self::interp(&wrapped, vec![], None);
+        let Some(ty) = return_type else {
+            return Ok((stdout, None));
+        };
+        // The result is printed last, on a line of its own.
+        let printed = stdout.strip_suffix('\n').expect("missing result");
+        let start = printed.rfind('\n').map_or(0, |i| i + 1);
+        let result = &printed[start..];
+        let result = match ty {
+            Type::Int => result.parse().ok().map(Literal::Int),
+            Type::Bool => result.parse().ok().map(Literal::Bool),
+            Type::Float => result.parse().ok().map(Literal::Float),
+            Type::Char => result.parse().ok().map(Literal::Char),
+            Type::Pointer(_) => unreachable!("rejected above"),
+        };
+        let result = result.expect("unexpected result from the interpreter");
+        stdout.truncate(start);
+        Ok((stdout, Some(result)))
+    }
+
     pub fn parse_and_optimize(&mut self, program: &str) -> Result<Program, EggCCError> {
         let parsed = Self::parse_bril(program)?;
         let res = self.optimize(&parsed)?;
@@ -227,6 +638,11 @@ impl Optimizer {
         self
     }
 
+    pub fn with_limit(mut self, limit: SaturationLimit) -> Self {
+        self.limit = limit;
+        self
+    }
+
     pub fn structured_to_optimizer(&mut self, structured: &StructuredProgram) -> String {
         let egg_fns: HashMap<String, Expr> = structured
             .functions
@@ -246,16 +662,50 @@ impl Optimizer {
         &mut self,
         bril_program: &Program,
     ) -> Result<StructuredProgram, EggCCError> {
+        self.optimized_structured_timed(
+            bril_program,
+            &mut RunTimings::default(),
+            &mut Captures::default(),
+        )
+    }
+
+    /// Like [Optimizer::optimized_structured], adding the time spent in each
+    /// phase to `timings`. Loading the egglog program's schema and rules counts
+    /// as saturation. The iterations are run one at a time, so that they stop
+    /// early once the limit is reached or nothing changes.
+    ///
+    /// If `captures.egglog_program` is given, the egglog program that was run
+    /// (including its iterations) is written to it.
+    pub(crate) fn optimized_structured_timed(
+        &mut self,
+        bril_program: &Program,
+        timings: &mut RunTimings,
+        captures: &mut Captures,
+    ) -> Result<StructuredProgram, EggCCError> {
+        let start = Instant::now();
         let structured = Self::program_to_structured(bril_program)?;
 
         let egglog_code = self.structured_to_optimizer(&structured);
+        timings.lowering += start.elapsed();
 
+        let start = Instant::now();
         let mut egraph = EGraph::default();
         egraph
             .parse_and_run_program(&egglog_code)
             .map_err(EggCCError::EggLog)?
             .into_iter()
             .for_each(|output| log::info!("{}", output));
+        let iters_run = self
+            .limit
+            .saturate(&mut egraph, self.num_iters, "(run 1)")?;
+        captures.iterations += iters_run;
+        timings.saturation += start.elapsed();
+        if let Some(program) = &mut captures.egglog_program {
+            program.push_str(&egglog_code);
+            program.push_str(&"(run 1)\n".repeat(iters_run));
+        }
+
+        let start = Instant::now();
 
         let egg_fns: HashMap<String, Expr> = structured
             .functions
@@ -278,6 +728,7 @@ impl Optimizer {
 
             result.push(structured_func);
         }
+        timings.extraction += start.elapsed();
         Ok(StructuredProgram { functions: result })
     }
 
@@ -285,9 +736,9 @@ impl Optimizer {
         Ok(self.optimized_structured(bril_program)?.to_program())
     }
 
+    /// The egglog program for optimizing `program`, without any `run`
+    /// commands: [Optimizer::optimized_structured_timed] runs the iterations.
     pub fn make_optimizer_for(&mut self, program: &str) -> String {
-        //let schedule = "(run 3)";
-        let schedule = format!("(run {})", self.num_iters);
         format!(
             "
         (datatype Type
@@ -359,7 +810,6 @@ impl Optimizer {
         (rewrite (sub ty (Int ty a) (Int ty b)) (Int ty (- a b)))
 
         {program}
-        {schedule}
         "
         )
     }
@@ -22,7 +22,14 @@ struct Args {
     #[clap(long)]
     profile_out: Option<PathBuf>,
 
-    /// The bril program to optimize
+    /// Write the saturated egraph to this file as JSON,
+    /// for run modes that optimize an rvsdg
+    #[clap(long)]
+    dump_egraph: Option<PathBuf>,
+
+    /// The bril program to optimize, in the text format or
+    /// (with a `.json` extension) the JSON format.
+    /// Use `-` to read the text format from stdin.
     file: PathBuf,
     /// The arguments to the bril program
     /// (only used when interpreting)
@@ -32,28 +39,37 @@ struct Args {
 fn main() {
     let args = Args::parse();
 
+    let test = if args.file.as_os_str() == "-" {
+        TestProgram::Stdin
+    } else if args.file.extension().is_some_and(|ext| ext == "json") {
+        TestProgram::BrilJson(args.file.clone())
+    } else {
+        TestProgram::File(args.file.clone())
+    };
+    // Read the program once, since stdin can't be read twice.
+    let prog_with_args = test.read_program();
+
     if let Some(debug_dir) = args.debug_dir {
-        if let Result::Err(error) = visualize(TestProgram::File(args.file.clone()), debug_dir) {
+        let test = TestProgram::Prog(prog_with_args.clone());
+        if let Result::Err(error) = visualize(test, debug_dir) {
             eprintln!("{}", error);
             return;
         }
     }
 
-    if args.interp && !args.run_mode.produces_bril() {
-        eprintln!(
-            "Cannot interpret run type {} because it doesn't produce a bril program.",
-            args.run_mode
-        );
-        return;
-    }
-
     let run = Run {
-        prog_with_args: TestProgram::File(args.file.clone()).read_program(),
-        test_type: args.run_mode,
         interp: args.interp,
+        dump_egraph: args.dump_egraph,
+        ..Run::new(prog_with_args, args.run_mode)
     };
 
-    let result = run.run();
+    let result = match run.run() {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("{}", error);
+            return;
+        }
+    };
 
     if result.result_interpreted.is_some() {
         println!("{}", result.result_interpreted.unwrap());
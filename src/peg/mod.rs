@@ -15,6 +15,7 @@ pub(crate) mod simulate;
 
 use crate::rvsdg::{Expr, Id, Operand, RvsdgBody, RvsdgFunction, RvsdgProgram};
 use std::collections::HashMap;
+use thiserror::Error;
 
 #[cfg(test)]
 mod tests;
@@ -41,6 +42,19 @@ pub(crate) enum PegBody {
     Edge(Id),
 }
 
+/// Errors from converting RVSDGs to PEGs.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub(crate) enum PegError {
+    /// PEGs have a single result, so functions with more than one (like the
+    /// ones lowered from lambdas) can't be converted.
+    #[error("PEGs for functions with {0} results are not supported")]
+    MultipleResults(usize),
+    /// PEGs have no notion of first-class functions, so lambda and apply
+    /// nodes can't be converted.
+    #[error("PEGs for first-class functions are not supported")]
+    FirstClassFunctions,
+}
+
 /// A function, expressed using PEGs.
 #[derive(Debug, PartialEq)]
 pub(crate) struct PegFunction {
@@ -54,19 +68,30 @@ pub(crate) struct PegFunction {
 
 impl PegFunction {
     #[allow(dead_code)]
-    pub fn new(rvsdg: &RvsdgFunction) -> PegFunction {
+    pub fn new(rvsdg: &RvsdgFunction) -> Result<PegFunction, PegError> {
+        if rvsdg
+            .nodes
+            .iter()
+            .any(|body| matches!(body, RvsdgBody::Lambda { .. } | RvsdgBody::Apply { .. }))
+        {
+            return Err(PegError::FirstClassFunctions);
+        }
         let mut nodes = Vec::new();
         let mut builder = PegBuilder {
             rvsdgs: &rvsdg.nodes,
             pegs: &mut nodes,
             memoize: &mut HashMap::new(),
         };
-        let result = rvsdg.result.map(|op| builder.get_pegs(op, &[]));
-        PegFunction {
-            n_args: rvsdg.n_args,
+        let result = match rvsdg.results.as_slice() {
+            [] => None,
+            [op] => Some(builder.get_pegs(*op, &[])),
+            results => return Err(PegError::MultipleResults(results.len())),
+        };
+        Ok(PegFunction {
+            n_args: rvsdg.args.len(),
             nodes,
             result,
-        }
+        })
     }
 }
 
@@ -127,15 +152,41 @@ impl PegBuilder<'_> {
                                 xs.iter().map(|x| self.get_pegs(*x, scope)).collect(),
                                 ty.clone(),
                             ),
-                            Expr::Call(f, xs, num_outputs, ty) => Expr::Call(
+                            Expr::EffectfulOp(op, xs, ty) => Expr::EffectfulOp(
+                                *op,
+                                xs.iter().map(|x| self.get_pegs(*x, scope)).collect(),
+                                ty.clone(),
+                            ),
+                            Expr::Effect(op, xs) => Expr::Effect(
+                                *op,
+                                xs.iter().map(|x| self.get_pegs(*x, scope)).collect(),
+                            ),
+                            Expr::Speculation(op, xs) => Expr::Speculation(
+                                *op,
+                                xs.iter().map(|x| self.get_pegs(*x, scope)).collect(),
+                            ),
+                            Expr::Call(f, xs, num_outputs, ty, pure) => Expr::Call(
                                 f.clone(),
                                 xs.iter().map(|x| self.get_pegs(*x, scope)).collect(),
                                 *num_outputs,
                                 ty.clone(),
+                                *pure,
                             ),
                             Expr::Print(xs) => {
                                 Expr::Print(xs.iter().map(|x| self.get_pegs(*x, scope)).collect())
                             }
+                            Expr::Select(xs, ty) => Expr::Select(
+                                xs.iter().map(|x| self.get_pegs(*x, scope)).collect(),
+                                ty.clone(),
+                            ),
+                            Expr::UnsignedCmp(op, xs) => Expr::UnsignedCmp(
+                                *op,
+                                xs.iter().map(|x| self.get_pegs(*x, scope)).collect(),
+                            ),
+                            Expr::Bitwise(op, xs) => Expr::Bitwise(
+                                *op,
+                                xs.iter().map(|x| self.get_pegs(*x, scope)).collect(),
+                            ),
                             Expr::Const(o, t, l) => Expr::Const(*o, t.clone(), l.clone()),
                         };
                         assert_eq!(0, selected);
@@ -239,6 +290,9 @@ impl PegBuilder<'_> {
 
                         edges_start + selected
                     }
+                    RvsdgBody::Lambda { .. } | RvsdgBody::Apply { .. } => {
+                        unreachable!("rejected by PegFunction::new")
+                    }
                 }
             }
         }
@@ -249,8 +303,13 @@ pub struct PegProgram {
     pub(crate) functions: Vec<PegFunction>,
 }
 
-pub(crate) fn rvsdg_to_peg(RvsdgProgram { functions }: &RvsdgProgram) -> PegProgram {
-    PegProgram {
-        functions: functions.iter().map(PegFunction::new).collect(),
-    }
+pub(crate) fn rvsdg_to_peg(
+    RvsdgProgram { functions }: &RvsdgProgram,
+) -> Result<PegProgram, PegError> {
+    Ok(PegProgram {
+        functions: functions
+            .iter()
+            .map(PegFunction::new)
+            .collect::<Result<_, _>>()?,
+    })
 }
@@ -1,5 +1,6 @@
 //! Render a PEG into Dot format;
 
+use crate::conversions::{bitop_to_egglog, unsigned_cmp_to_egglog};
 use crate::peg::{PegBody, PegFunction, PegProgram};
 use crate::rvsdg::Expr;
 use bril_rs::ConstOps;
@@ -34,11 +35,15 @@ impl PegFunction {
             let node = match node {
                 PegBody::Arg(arg) => format!("arg {arg}"),
                 PegBody::BasicOp(expr) => match expr {
-                    Expr::Op(f, xs, _ty) => {
+                    Expr::Op(f, xs, _ty) | Expr::EffectfulOp(f, xs, _ty) => {
                         js = xs.to_vec();
                         format!("{f}")
                     }
-                    Expr::Call(f, xs, _, _) => {
+                    Expr::Effect(f, xs) | Expr::Speculation(f, xs) => {
+                        js = xs.to_vec();
+                        format!("{f}")
+                    }
+                    Expr::Call(f, xs, ..) => {
                         js = xs.to_vec();
                         format!("{f}")
                     }
@@ -46,6 +51,18 @@ impl PegFunction {
                         js = xs.to_vec();
                         "PRINT".into()
                     }
+                    Expr::Select(xs, _) => {
+                        js = xs.to_vec();
+                        "select".into()
+                    }
+                    Expr::UnsignedCmp(f, xs) => {
+                        js = xs.to_vec();
+                        unsigned_cmp_to_egglog(*f).into()
+                    }
+                    Expr::Bitwise(f, xs) => {
+                        js = xs.to_vec();
+                        bitop_to_egglog(*f).into()
+                    }
                     Expr::Const(ConstOps::Const, _, literal) => {
                         format!("{literal}")
                     }
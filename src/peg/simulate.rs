@@ -44,8 +44,35 @@ impl PegBody {
                         op => todo!("implement {op}"),
                     }
                 }
+                Expr::EffectfulOp(..) | Expr::Effect(..) | Expr::Speculation(..) => {
+                    panic!("can't simulate effectful operations")
+                }
                 Expr::Call(..) => panic!("can't simulate inter-function calls"),
                 Expr::Print(..) => panic!("can't simulate print"),
+                Expr::UnsignedCmp(op, xs) => {
+                    let [x, y] =
+                        [xs[0], xs[1]].map(|x| int(nodes[x].simulate(args, nodes, indices)) as u64);
+                    match op {
+                        ValueOps::Lt => Literal::Bool(x < y),
+                        ValueOps::Le => Literal::Bool(x <= y),
+                        ValueOps::Gt => Literal::Bool(x > y),
+                        ValueOps::Ge => Literal::Bool(x >= y),
+                        op => panic!("{op} is not an ordered comparison"),
+                    }
+                }
+                Expr::Bitwise(op, xs) => {
+                    let [x, y] =
+                        [xs[0], xs[1]].map(|x| int(nodes[x].simulate(args, nodes, indices)));
+                    Literal::Int(op.eval(x, y))
+                }
+                Expr::Select(xs, _) => {
+                    let c = nodes[xs[0]].simulate(args, nodes, indices);
+                    if bool(c) {
+                        nodes[xs[1]].simulate(args, nodes, indices)
+                    } else {
+                        nodes[xs[2]].simulate(args, nodes, indices)
+                    }
+                }
                 Expr::Const(ConstOps::Const, literal, _) => literal.clone(),
             },
             PegBody::Arg(arg) => args[*arg].clone(),
@@ -1,7 +1,8 @@
 use crate::cfg::program_to_cfg;
-use crate::peg::{PegBody, PegFunction};
+use crate::peg::{PegBody, PegError, PegFunction};
+use crate::rvsdg::builder::RvsdgBuilder;
 use crate::rvsdg::cfg_to_rvsdg;
-use crate::rvsdg::{Expr, Id};
+use crate::rvsdg::{Expr, Id, Operand};
 use crate::util::parse_from_string;
 use bril_rs::{ConstOps, Literal, Type, ValueOps};
 use std::fs::File;
@@ -99,7 +100,7 @@ fn peg_expr() {
     let prog = parse_from_string(PROGRAM);
     let cfg = program_to_cfg(&prog);
     let rvsdg = cfg_to_rvsdg(&cfg).unwrap();
-    let peg = PegFunction::new(&rvsdg.functions[0]);
+    let peg = PegFunction::new(&rvsdg.functions[0]).unwrap();
 
     let mut expected = PegTest::default();
     let one = expected.lit_int(1);
@@ -159,7 +160,7 @@ fn peg_basic_odd_branch() {
     let prog = parse_from_string(PROGRAM);
     let cfg = program_to_cfg(&prog);
     let rvsdg = cfg_to_rvsdg(&cfg).unwrap();
-    let have = PegFunction::new(&rvsdg.functions[0]);
+    let have = PegFunction::new(&rvsdg.functions[0]).unwrap();
 
     let want: Vec<_> = (0..10)
         .map(|i| want.simulate(&[Literal::Int(i)]).unwrap())
@@ -207,7 +208,28 @@ fn peg_unstructured() {
     let prog = parse_from_string(PROGRAM);
     let cfg = program_to_cfg(&prog);
     let rvsdg = cfg_to_rvsdg(&cfg).unwrap();
-    let have = PegFunction::new(&rvsdg.functions[0]);
+    let have = PegFunction::new(&rvsdg.functions[0]).unwrap();
 
     assert_eq!(want.simulate(&[]).unwrap(), have.simulate(&[]).unwrap());
 }
+
+#[test]
+fn peg_multiple_results() {
+    let mut f = RvsdgBuilder::default();
+    let sum = f.add(Operand::Arg(0), Operand::Arg(1), Type::Int);
+    let rvsdg = f.into_pure_function(&[Type::Int, Type::Int], &[Operand::Arg(0), sum]);
+    assert_eq!(PegFunction::new(&rvsdg), Err(PegError::MultipleResults(2)));
+}
+
+#[test]
+fn peg_first_class_functions() {
+    let mut body = RvsdgBuilder::default();
+    let one = body.lit_int(1);
+    let inc = body.add(Operand::Arg(0), one, Type::Int);
+    let mut f = RvsdgBuilder::default();
+    let lambda = f.lambda(1, body, &[inc, Operand::Arg(1)]);
+    let apply = f.apply(lambda, &[Operand::Arg(0), Operand::Arg(1)]);
+    let (result, state) = (Operand::Project(0, apply), Operand::Project(1, apply));
+    let rvsdg = f.into_function(&[Type::Int], &[result], state);
+    assert_eq!(PegFunction::new(&rvsdg), Err(PegError::FirstClassFunctions));
+}
@@ -0,0 +1,342 @@
+//! A builder for constructing [RvsdgFunction]s by hand, e.g. for tests or
+//! for analyses that don't start from a Bril program.
+use bril_rs::{ConstOps, EffectOps, Literal, Type, ValueOps};
+
+use crate::cfg::Identifier;
+
+use super::{BitOp, Expr, Id, Operand, RvsdgBody, RvsdgFunction};
+
+/// Utility struct for building an RVSDG.
+///
+/// Every method adds a single node and returns a reference to it. Nodes with
+/// more than one output (like `div`, which also outputs a state edge) return
+/// their [Id] instead, so that outputs can be picked out with
+/// [Operand::Project]. The arguments of the function (and of gamma and theta
+/// regions) are referred to with [Operand::Arg], with the state edge last.
+///
+/// ```
+/// use bril_rs::Type;
+/// use eggcc::{Operand, RvsdgBuilder};
+///
+/// // @main(x: int): int { one: int = const 1; res: int = add x one; ret res; }
+/// let mut builder = RvsdgBuilder::default();
+/// let one = builder.lit_int(1);
+/// let res = builder.add(Operand::Arg(0), one, Type::Int);
+/// let func = builder.into_pure_function(&[Type::Int], &[res]);
+/// assert_eq!(func.stats().nodes, 2);
+/// ```
+#[derive(Default)]
+pub struct RvsdgBuilder {
+    nodes: Vec<RvsdgBody>,
+}
+
+impl RvsdgBuilder {
+    /// Finish building a function named `main`. "pure" functions are ones
+    /// whose state edges 'pass through'.
+    pub fn into_pure_function(self, args: &[Type], outputs: &[Operand]) -> RvsdgFunction {
+        self.into_function(args, outputs, Operand::Arg(args.len()))
+    }
+
+    /// Finish building a function named `main` with the given argument types,
+    /// results, and final state edge.
+    pub fn into_function(
+        self,
+        args: &[Type],
+        results: &[Operand],
+        state: Operand,
+    ) -> RvsdgFunction {
+        RvsdgFunction {
+            name: "main".into(),
+            args: args.to_vec(),
+            return_type: RvsdgFunction::infer_return_type(&self.nodes, args, results),
+            nodes: self.nodes,
+            results: results.to_vec(),
+            state,
+        }
+    }
+
+    pub fn lit_int(&mut self, i: i64) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Const(
+            ConstOps::Const,
+            Literal::Int(i),
+            Type::Int,
+        )))
+    }
+
+    pub fn lit_bool(&mut self, b: bool) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Const(
+            ConstOps::Const,
+            Literal::Bool(b),
+            Type::Bool,
+        )))
+    }
+
+    pub fn lit_float(&mut self, f: f64) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Const(
+            ConstOps::Const,
+            Literal::Float(f),
+            Type::Float,
+        )))
+    }
+
+    pub fn lit_char(&mut self, c: char) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Const(
+            ConstOps::Const,
+            Literal::Char(c),
+            Type::Char,
+        )))
+    }
+
+    pub fn void_function(&mut self, func: impl Into<Identifier>, args: &[Operand]) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Call(
+            func.into(),
+            args.to_vec(),
+            1,
+            None,
+            false,
+        )))
+    }
+
+    /// Call `func`, which returns a `ty`. Like [RvsdgBuilder::div], the call
+    /// threads the state edge; project output 1 for the new state.
+    pub fn call(&mut self, func: impl Into<Identifier>, args: &[Operand], ty: Type) -> Id {
+        let res = self.nodes.len();
+        self.nodes.push(RvsdgBody::BasicOp(Expr::Call(
+            func.into(),
+            args.to_vec(),
+            2,
+            Some(ty),
+            false,
+        )));
+        res
+    }
+
+    pub fn cmp(&mut self, op: ValueOps, l: Operand, r: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Op(op, vec![l, r], Type::Bool)))
+    }
+
+    /// Compare `l` and `r` with `op` as unsigned integers, rather than as the
+    /// signed integers that [RvsdgBuilder::cmp] compares.
+    pub fn unsigned_cmp(&mut self, op: ValueOps, l: Operand, r: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::UnsignedCmp(op, vec![l, r])))
+    }
+
+    pub fn bitwise(&mut self, op: BitOp, l: Operand, r: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Bitwise(op, vec![l, r])))
+    }
+
+    pub fn shl(&mut self, x: Operand, amount: Operand) -> Operand {
+        self.bitwise(BitOp::Shl, x, amount)
+    }
+
+    pub fn shr(&mut self, x: Operand, amount: Operand) -> Operand {
+        self.bitwise(BitOp::Shr, x, amount)
+    }
+
+    pub fn bitand(&mut self, l: Operand, r: Operand) -> Operand {
+        self.bitwise(BitOp::And, l, r)
+    }
+
+    pub fn bitor(&mut self, l: Operand, r: Operand) -> Operand {
+        self.bitwise(BitOp::Or, l, r)
+    }
+
+    pub fn bitxor(&mut self, l: Operand, r: Operand) -> Operand {
+        self.bitwise(BitOp::Xor, l, r)
+    }
+
+    pub fn eq(&mut self, l: Operand, r: Operand) -> Operand {
+        self.cmp(ValueOps::Eq, l, r)
+    }
+
+    pub fn lt(&mut self, l: Operand, r: Operand) -> Operand {
+        self.cmp(ValueOps::Lt, l, r)
+    }
+
+    pub fn gt(&mut self, l: Operand, r: Operand) -> Operand {
+        self.cmp(ValueOps::Gt, l, r)
+    }
+
+    pub fn le(&mut self, l: Operand, r: Operand) -> Operand {
+        self.cmp(ValueOps::Le, l, r)
+    }
+
+    pub fn ge(&mut self, l: Operand, r: Operand) -> Operand {
+        self.cmp(ValueOps::Ge, l, r)
+    }
+
+    pub fn and(&mut self, l: Operand, r: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Op(
+            ValueOps::And,
+            vec![l, r],
+            Type::Bool,
+        )))
+    }
+
+    pub fn or(&mut self, l: Operand, r: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Op(
+            ValueOps::Or,
+            vec![l, r],
+            Type::Bool,
+        )))
+    }
+
+    pub fn not(&mut self, x: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Op(
+            ValueOps::Not,
+            vec![x],
+            Type::Bool,
+        )))
+    }
+
+    pub fn add(&mut self, l: Operand, r: Operand, ty: Type) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Op(ValueOps::Add, vec![l, r], ty)))
+    }
+
+    pub fn sub(&mut self, l: Operand, r: Operand, ty: Type) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Op(ValueOps::Sub, vec![l, r], ty)))
+    }
+
+    pub fn mul(&mut self, l: Operand, r: Operand, ty: Type) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Op(ValueOps::Mul, vec![l, r], ty)))
+    }
+
+    /// Division threads the state edge; project output 1 for the new state.
+    pub fn div(&mut self, l: Operand, r: Operand, state: Operand, ty: Type) -> Id {
+        let res = self.nodes.len();
+        self.nodes.push(RvsdgBody::BasicOp(Expr::EffectfulOp(
+            ValueOps::Div,
+            vec![l, r, state],
+            ty,
+        )));
+        res
+    }
+
+    pub fn fadd(&mut self, l: Operand, r: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Op(
+            ValueOps::Fadd,
+            vec![l, r],
+            Type::Float,
+        )))
+    }
+
+    pub fn ptradd(&mut self, ptr: Operand, offset: Operand, ty: Type) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Op(
+            ValueOps::PtrAdd,
+            vec![ptr, offset],
+            ty,
+        )))
+    }
+
+    pub fn alloc(&mut self, size: Operand, state: Operand, ty: Type) -> Id {
+        let res = self.nodes.len();
+        self.nodes.push(RvsdgBody::BasicOp(Expr::EffectfulOp(
+            ValueOps::Alloc,
+            vec![size, state],
+            ty,
+        )));
+        res
+    }
+
+    pub fn load(&mut self, ptr: Operand, state: Operand, ty: Type) -> Id {
+        let res = self.nodes.len();
+        self.nodes.push(RvsdgBody::BasicOp(Expr::EffectfulOp(
+            ValueOps::Load,
+            vec![ptr, state],
+            ty,
+        )));
+        res
+    }
+
+    pub fn store(&mut self, ptr: Operand, val: Operand, state: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Effect(
+            EffectOps::Store,
+            vec![ptr, val, state],
+        )))
+    }
+
+    pub fn free(&mut self, ptr: Operand, state: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Effect(
+            EffectOps::Free,
+            vec![ptr, state],
+        )))
+    }
+
+    pub fn speculate(&mut self, state: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Speculation(
+            EffectOps::Speculate,
+            vec![state],
+        )))
+    }
+
+    /// A `guard` on `cond`, which can only be converted to Bril if `cond` is
+    /// the literal `true`.
+    pub fn guard(&mut self, cond: Operand, state: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Speculation(
+            EffectOps::Guard,
+            vec![cond, state],
+        )))
+    }
+
+    pub fn commit(&mut self, state: Operand) -> Operand {
+        self.make_node(RvsdgBody::BasicOp(Expr::Speculation(
+            EffectOps::Commit,
+            vec![state],
+        )))
+    }
+
+    pub fn print(&mut self, x: Operand, state: Operand) -> Operand {
+        self.print_all(&[x], state)
+    }
+
+    /// Print all of `xs` on one line, like `print` with several arguments.
+    pub fn print_all(&mut self, xs: &[Operand], state: Operand) -> Operand {
+        let mut ops = xs.to_vec();
+        ops.push(state);
+        self.make_node(RvsdgBody::BasicOp(Expr::Print(ops)))
+    }
+
+    pub fn gamma(&mut self, pred: Operand, inputs: &[Operand], outputs: &[&[Operand]]) -> Id {
+        let res = self.nodes.len();
+        self.nodes.push(RvsdgBody::Gamma {
+            pred,
+            inputs: inputs.to_vec(),
+            outputs: outputs.iter().map(|outs| outs.to_vec()).collect(),
+        });
+        res
+    }
+
+    pub fn theta(&mut self, pred: Operand, inputs: &[Operand], outputs: &[Operand]) -> Id {
+        let res = self.nodes.len();
+        self.nodes.push(RvsdgBody::Theta {
+            pred,
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+        });
+        res
+    }
+
+    /// Lambdas have their own heap of nodes, built up in `body`.
+    pub fn lambda(&mut self, n_args: usize, body: RvsdgBuilder, outputs: &[Operand]) -> Operand {
+        self.make_node(RvsdgBody::Lambda {
+            n_args,
+            nodes: body.nodes,
+            outputs: outputs.to_vec(),
+        })
+    }
+
+    pub fn apply(&mut self, func: Operand, args: &[Operand]) -> Id {
+        let res = self.nodes.len();
+        self.nodes.push(RvsdgBody::Apply {
+            func,
+            args: args.to_vec(),
+        });
+        res
+    }
+
+    fn make_node(&mut self, body: RvsdgBody) -> Operand {
+        let res = Operand::Project(0, self.nodes.len());
+        self.nodes.push(body);
+        res
+    }
+}
@@ -0,0 +1,104 @@
+//! Which functions in a program call which others.
+//!
+//! Interprocedural passes (like
+//! [inline_calls](super::optimize::inline_calls)) use this to avoid chasing
+//! recursive calls around a cycle forever.
+use petgraph::{
+    algo::tarjan_scc,
+    graph::{DiGraph, NodeIndex},
+};
+
+use super::{Expr, RvsdgBody, RvsdgFunction};
+
+/// The directed graph of calls between the functions of a program. Functions
+/// are referred to by their index in the program.
+pub struct CallGraph {
+    /// Node `i` is the function at index `i`, and there is an edge for every
+    /// function that it calls (at most one per callee).
+    graph: DiGraph<(), ()>,
+    /// The index into `sccs` of the component each function is in.
+    component: Vec<usize>,
+    /// The strongly-connected components of `graph`.
+    sccs: Vec<Vec<usize>>,
+}
+
+/// Build the call graph for the functions in `program`. Calls inside of
+/// lambdas count as calls from the function containing the lambda, and calls
+/// to functions outside of `program` are ignored.
+pub(crate) fn call_graph(program: &[RvsdgFunction]) -> CallGraph {
+    fn callees(nodes: &[RvsdgBody], program: &[RvsdgFunction], out: &mut Vec<usize>) {
+        for body in nodes {
+            match body {
+                RvsdgBody::BasicOp(Expr::Call(callee, ..)) => out.extend(
+                    program
+                        .iter()
+                        .position(|func| callee.to_string() == func.name),
+                ),
+                RvsdgBody::Lambda { nodes, .. } => callees(nodes, program, out),
+                _ => {}
+            }
+        }
+    }
+
+    let mut graph = DiGraph::new();
+    for _ in program {
+        graph.add_node(());
+    }
+    for (caller, func) in program.iter().enumerate() {
+        let mut calls = Vec::new();
+        callees(&func.nodes, program, &mut calls);
+        calls.sort_unstable();
+        calls.dedup();
+        for callee in calls {
+            graph.add_edge(NodeIndex::new(caller), NodeIndex::new(callee), ());
+        }
+    }
+
+    let sccs = Vec::from_iter(tarjan_scc(&graph).into_iter().map(|scc| {
+        let mut scc = Vec::from_iter(scc.into_iter().map(NodeIndex::index));
+        scc.sort_unstable();
+        scc
+    }));
+    let mut component = vec![0; program.len()];
+    for (i, scc) in sccs.iter().enumerate() {
+        for func in scc {
+            component[*func] = i;
+        }
+    }
+    CallGraph {
+        graph,
+        component,
+        sccs,
+    }
+}
+
+impl CallGraph {
+    /// Whether `caller` contains a call to `callee`.
+    pub fn calls(&self, caller: usize, callee: usize) -> bool {
+        self.graph
+            .contains_edge(NodeIndex::new(caller), NodeIndex::new(callee))
+    }
+
+    /// The strongly-connected components of the graph, in reverse topological
+    /// order: a function only calls functions in its own component or in
+    /// earlier ones. Each component is sorted.
+    pub fn sccs(&self) -> &[Vec<usize>] {
+        &self.sccs
+    }
+
+    /// The strongly-connected component containing `func`.
+    pub fn scc_of(&self, func: usize) -> &[usize] {
+        &self.sccs[self.component[func]]
+    }
+
+    /// Whether `func` can (directly or indirectly) call itself.
+    pub fn is_recursive(&self, func: usize) -> bool {
+        self.scc_of(func).len() > 1 || self.calls(func, func)
+    }
+
+    /// Whether `caller` and `callee` are part of the same recursive cycle, so
+    /// that a call from one to the other can end up calling the first again.
+    pub fn in_cycle(&self, caller: usize, callee: usize) -> bool {
+        self.component[caller] == self.component[callee] && self.is_recursive(caller)
+    }
+}
@@ -0,0 +1,234 @@
+//! Explaining why two [RvsdgFunction]s aren't structurally equal.
+//!
+//! [RvsdgFunction::structurally_eq] only says whether two functions match;
+//! [diff] walks them the same way and reports where they first diverge, so
+//! that a failing comparison in a test says what went wrong.
+use std::fmt;
+
+use super::{Expr, Id, Operand, RvsdgBody, RvsdgFunction};
+
+/// The first place where two functions differ, found by [diff].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RvsdgDiff {
+    /// The steps from the outputs of the functions to where they differ, e.g.
+    /// `["state operand", "gamma branch 1, output 0"]`.
+    pub(crate) path: Vec<String>,
+    /// What the first function has there.
+    pub(crate) left: String,
+    /// What the second function has there.
+    pub(crate) right: String,
+}
+
+impl fmt::Display for RvsdgDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} vs {}",
+            self.path.join(" → "),
+            self.left,
+            self.right
+        )
+    }
+}
+
+/// The first structural difference between `f1` and `f2`, or `None` if they
+/// are structurally equal (in the sense of [RvsdgFunction::structurally_eq]).
+/// The state edge is compared first, then each result in turn, and nodes are
+/// compared before their operands.
+pub(crate) fn diff(f1: &RvsdgFunction, f2: &RvsdgFunction) -> Option<RvsdgDiff> {
+    let diff = if f1.args != f2.args {
+        let diff = mismatch(format!("{:?}", f1.args), format!("{:?}", f2.args));
+        Some(step("arguments".into(), diff))
+    } else if f1.return_type != f2.return_type {
+        let diff = mismatch(
+            format!("{:?}", f1.return_type),
+            format!("{:?}", f2.return_type),
+        );
+        Some(step("return type".into(), diff))
+    } else {
+        let (n1, n2) = (&f1.nodes[..], &f2.nodes[..]);
+        operands_diff(&f1.state, &f2.state, n1, n2)
+            .map(|diff| step("state operand".into(), diff))
+            .or_else(|| all_diff(&f1.results, &f2.results, n1, n2, "result"))
+    };
+    diff.map(|mut diff| {
+        // Steps are added on the way back out, so they are innermost first.
+        diff.path.reverse();
+        diff
+    })
+}
+
+fn mismatch(left: String, right: String) -> RvsdgDiff {
+    RvsdgDiff {
+        path: vec![],
+        left,
+        right,
+    }
+}
+
+fn step(label: String, mut diff: RvsdgDiff) -> RvsdgDiff {
+    diff.path.push(label);
+    diff
+}
+
+fn operands_diff(
+    o1: &Operand,
+    o2: &Operand,
+    f1: &[RvsdgBody],
+    f2: &[RvsdgBody],
+) -> Option<RvsdgDiff> {
+    match (o1.canonical(), o2.canonical()) {
+        (Operand::Arg(x), Operand::Arg(y)) if x == y => None,
+        (Operand::Id(l), Operand::Id(r)) => ids_diff(l, r, f1, f2),
+        (Operand::Project(p1, l), Operand::Project(p2, r)) if p1 == p2 => ids_diff(l, r, f1, f2),
+        (o1, o2) => Some(mismatch(describe(o1, f1), describe(o2, f2))),
+    }
+}
+
+/// The first difference between the operands in `ops1` and `ops2`, which are
+/// labelled `{what} {i}` in the path.
+fn all_diff(
+    ops1: &[Operand],
+    ops2: &[Operand],
+    f1: &[RvsdgBody],
+    f2: &[RvsdgBody],
+    what: &str,
+) -> Option<RvsdgDiff> {
+    if ops1.len() != ops2.len() {
+        let count = |ops: &[Operand]| format!("{} operands", ops.len());
+        return Some(mismatch(count(ops1), count(ops2)));
+    }
+    ops1.iter().zip(ops2).enumerate().find_map(|(i, (l, r))| {
+        operands_diff(l, r, f1, f2).map(|diff| step(format!("{what} {i}"), diff))
+    })
+}
+
+fn ids_diff(i1: Id, i2: Id, f1: &[RvsdgBody], f2: &[RvsdgBody]) -> Option<RvsdgDiff> {
+    match (&f1[i1], &f2[i2]) {
+        (RvsdgBody::BasicOp(e1), RvsdgBody::BasicOp(e2)) if same_node(e1, e2) => {
+            all_diff(e1.operands(), e2.operands(), f1, f2, "operand")
+        }
+        (
+            RvsdgBody::Gamma {
+                pred: p1,
+                inputs: is1,
+                outputs: os1,
+            },
+            RvsdgBody::Gamma {
+                pred: p2,
+                inputs: is2,
+                outputs: os2,
+            },
+        ) if os1.len() == os2.len() => operands_diff(p1, p2, f1, f2)
+            .map(|diff| step("gamma predicate".into(), diff))
+            .or_else(|| all_diff(is1, is2, f1, f2, "gamma input"))
+            .or_else(|| {
+                os1.iter()
+                    .zip(os2)
+                    .enumerate()
+                    .find_map(|(branch, (l, r))| {
+                        all_diff(l, r, f1, f2, &format!("gamma branch {branch}, output"))
+                    })
+            }),
+        (
+            RvsdgBody::Theta {
+                pred: p1,
+                inputs: is1,
+                outputs: os1,
+            },
+            RvsdgBody::Theta {
+                pred: p2,
+                inputs: is2,
+                outputs: os2,
+            },
+        ) => operands_diff(p1, p2, f1, f2)
+            .map(|diff| step("theta predicate".into(), diff))
+            .or_else(|| all_diff(is1, is2, f1, f2, "theta input"))
+            .or_else(|| all_diff(os1, os2, f1, f2, "theta output")),
+        (
+            RvsdgBody::Lambda {
+                n_args: n1,
+                nodes: b1,
+                outputs: os1,
+            },
+            RvsdgBody::Lambda {
+                n_args: n2,
+                nodes: b2,
+                outputs: os2,
+            },
+        ) if n1 == n2 => all_diff(os1, os2, b1, b2, "lambda output"),
+        (
+            RvsdgBody::Apply {
+                func: func1,
+                args: as1,
+            },
+            RvsdgBody::Apply {
+                func: func2,
+                args: as2,
+            },
+        ) => operands_diff(func1, func2, f1, f2)
+            .map(|diff| step("applied function".into(), diff))
+            .or_else(|| all_diff(as1, as2, f1, f2, "apply argument")),
+        (l, r) => Some(mismatch(describe_body(l), describe_body(r))),
+    }
+}
+
+/// Whether two basic operations agree on everything but their operands,
+/// including the number of them.
+fn same_node(e1: &Expr<Operand>, e2: &Expr<Operand>) -> bool {
+    let same = match (e1, e2) {
+        (Expr::Op(op1, _, ty1), Expr::Op(op2, _, ty2))
+        | (Expr::EffectfulOp(op1, _, ty1), Expr::EffectfulOp(op2, _, ty2)) => {
+            op1 == op2 && ty1 == ty2
+        }
+        (Expr::UnsignedCmp(op1, _), Expr::UnsignedCmp(op2, _)) => op1 == op2,
+        (Expr::Bitwise(op1, _), Expr::Bitwise(op2, _)) => op1 == op2,
+        (Expr::Effect(op1, _), Expr::Effect(op2, _))
+        | (Expr::Speculation(op1, _), Expr::Speculation(op2, _)) => op1 == op2,
+        (Expr::Call(func1, _, n1, ty1, pure1), Expr::Call(func2, _, n2, ty2, pure2)) => {
+            func1 == func2 && n1 == n2 && ty1 == ty2 && pure1 == pure2
+        }
+        (Expr::Const(c1, lit1, ty1), Expr::Const(c2, lit2, ty2)) => {
+            c1 == c2 && lit1 == lit2 && ty1 == ty2
+        }
+        (Expr::Select(_, ty1), Expr::Select(_, ty2)) => ty1 == ty2,
+        (Expr::Print(_), Expr::Print(_)) => true,
+        _ => false,
+    };
+    same && e1.operands().len() == e2.operands().len()
+}
+
+fn describe(op: Operand, nodes: &[RvsdgBody]) -> String {
+    match op {
+        Operand::Arg(arg) => format!("Arg({arg})"),
+        Operand::Id(id) => describe_body(&nodes[id]),
+        Operand::Project(output, id) => format!("output {output} of {}", describe_body(&nodes[id])),
+    }
+}
+
+fn describe_body(body: &RvsdgBody) -> String {
+    match body {
+        RvsdgBody::BasicOp(expr) => {
+            let n_operands = expr.operands().len();
+            let expr = match expr {
+                Expr::Op(op, _, ty) => format!("Op({op:?}, {ty})"),
+                Expr::UnsignedCmp(op, _) => format!("UnsignedCmp({op:?})"),
+                Expr::Bitwise(op, _) => format!("Bitwise({op:?})"),
+                Expr::EffectfulOp(op, _, ty) => format!("EffectfulOp({op:?}, {ty})"),
+                Expr::Effect(op, _) => format!("Effect({op:?})"),
+                Expr::Speculation(op, _) => format!("Speculation({op:?})"),
+                Expr::Call(func, _, _, Some(ty), true) => format!("PureCall(@{func}, {ty})"),
+                Expr::Call(func, _, _, Some(ty), _) => format!("Call(@{func}, {ty})"),
+                Expr::Call(func, _, _, None, _) => format!("Call(@{func})"),
+                Expr::Const(_, lit, ty) => format!("Const({lit}, {ty})"),
+                Expr::Select(_, ty) => format!("Select({ty})"),
+                Expr::Print(_) => "Print".into(),
+            };
+            format!("{expr} with {n_operands} operands")
+        }
+        RvsdgBody::Gamma { outputs, .. } => format!("gamma with {} branches", outputs.len()),
+        RvsdgBody::Theta { .. } => "theta".into(),
+        RvsdgBody::Lambda { n_args, .. } => format!("lambda with {n_args} arguments"),
+        RvsdgBody::Apply { .. } => "apply".into(),
+    }
+}
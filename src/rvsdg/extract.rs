@@ -0,0 +1,257 @@
+//! Extracting [RvsdgFunction]s back out of an egraph.
+//!
+//! egglog's extractor picks the cheapest term in each e-class, where the cost
+//! of a term is the sum of the costs of its constructors. A [CostModel] picks
+//! the costs of the constructors for [Expr](super::Expr)s, which are attached
+//! to their declarations when the schema is loaded into a fresh egraph (see
+//! [new_rvsdg_egraph_with_costs]).
+//!
+//! A [CostModel] also picks the cost of each hop along the state edge. Since
+//! a term's cost includes the costs of all its subterms, a value pays this
+//! once for every effectful operation on the state chain below it, so raising
+//! it (see [StateDepthCostModel]) makes extraction prefer equivalent graphs
+//! with shallower state chains.
+use std::collections::{BTreeMap, HashSet};
+
+use egglog::{ast::Command, EGraph};
+
+use crate::{
+    conversions::{bitop_to_egglog, unsigned_cmp_to_egglog},
+    EggCCError,
+};
+
+use super::{EgglogFunctionResult, Expr, Operand, RvsdgBody, RvsdgFunction};
+
+/// Assigns a cost to each of the operations that an RVSDG [Expr](super::Expr)
+/// can be built from. Operations are named by their egglog constructor, which
+/// is the name of the Bril opcode (`"add"`, `"mul"`, ...), `"select"`, an
+/// unsigned comparison (`"ult"`, ...), a bitwise operation (`"shl"`,
+/// `"bitand"`, ...), or `"Const"`, `"Call"`, or `"PureCall"`.
+///
+/// Costs must be positive: egglog does not extract zero-cost cycles well.
+pub trait CostModel: Send + Sync {
+    fn cost(&self, op: &str) -> usize;
+
+    /// The cost of an effectful operation taking its incoming state edge, on
+    /// top of the cost of the operation itself. Defaults to egglog's default
+    /// cost for a constructor.
+    fn state_edge_cost(&self) -> usize {
+        1
+    }
+}
+
+/// The cost model used unless another one is asked for. Constants are nearly
+/// free, multiplication and division are more expensive than addition, and
+/// memory operations and calls are the most expensive of all.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultCostModel;
+
+impl CostModel for DefaultCostModel {
+    fn cost(&self, op: &str) -> usize {
+        match op {
+            "Const" => 1,
+            "add" | "sub" | "eq" | "lt" | "gt" | "le" | "ge" | "not" | "and" | "or" | "ptradd"
+            | "shl" | "shr" | "bitand" | "bitor" | "bitxor" | "ult" | "ugt" | "ule" | "uge" => 10,
+            "fadd" | "fsub" | "feq" | "flt" | "fgt" | "fle" | "fge" => 20,
+            "mul" | "fmul" => 30,
+            "div" | "fdiv" => 50,
+            "alloc" | "load" | "store" | "free" | "print" | "Call" | "PureCall" => 100,
+            _ => 10,
+        }
+    }
+}
+
+/// A [CostModel] that penalizes each hop along the state edge by `hop_cost`,
+/// and otherwise costs operations like `model`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StateDepthCostModel<M> {
+    pub model: M,
+    pub hop_cost: usize,
+}
+
+impl<M: CostModel> CostModel for StateDepthCostModel<M> {
+    fn cost(&self, op: &str) -> usize {
+        self.model.cost(op)
+    }
+
+    fn state_edge_cost(&self) -> usize {
+        self.hop_cost
+    }
+}
+
+/// An egraph with the RVSDG schema and the rulesets in `optimizations.egg`
+/// installed, where the constructors for `Expr`s have the costs given by
+/// `model`. No rules are run.
+pub fn new_rvsdg_egraph_with_costs(model: &dyn CostModel) -> Result<EGraph, EggCCError> {
+    let mut egraph = EGraph::default();
+    let commands = schema_with_costs(&mut egraph, model)?;
+    egraph.run_program(commands).map_err(EggCCError::EggLog)?;
+
+    let optimizations = std::fs::read_to_string("src/rvsdg/optimizations.egg").unwrap();
+    egraph
+        .parse_and_run_program(&optimizations)
+        .map_err(EggCCError::EggLog)?;
+    Ok(egraph)
+}
+
+/// The text of the commands that [new_rvsdg_egraph_with_costs] runs, for
+/// reproducing an optimization outside of eggcc.
+pub(crate) fn rvsdg_egglog_prelude(model: &dyn CostModel) -> Result<String, EggCCError> {
+    let commands = schema_with_costs(&mut EGraph::default(), model)?;
+    let mut prelude = String::from_iter(commands.iter().map(|command| format!("{command}\n")));
+    prelude.push_str(&std::fs::read_to_string("src/rvsdg/optimizations.egg").unwrap());
+    Ok(prelude)
+}
+
+/// Parse the RVSDG schema, setting the costs of the constructors for `Expr`s
+/// and of `Effectful` according to `model`.
+fn schema_with_costs(
+    egraph: &mut EGraph,
+    model: &dyn CostModel,
+) -> Result<Vec<Command>, EggCCError> {
+    let schema = std::fs::read_to_string("src/rvsdg/schema.egg").unwrap();
+    let mut commands = egraph.parse_program(&schema).map_err(EggCCError::EggLog)?;
+    for command in &mut commands {
+        if let Command::Function(decl) = command {
+            if decl.schema.output.as_str() == "Expr" {
+                decl.cost = Some(model.cost(decl.name.as_str()));
+            } else if decl.name.as_str() == "Effectful" {
+                decl.cost = Some(model.state_edge_cost());
+            }
+        }
+    }
+    Ok(commands)
+}
+
+/// Extract the cheapest function equivalent to `func` from `egraph`, which
+/// must already contain `func`'s outputs (e.g. from evaluating the
+/// expressions in `func.to_egglog_expr()`). Costs are whatever the egraph was
+/// created with.
+pub fn extract_cheapest(
+    egraph: &mut EGraph,
+    func: &RvsdgFunction,
+) -> Result<RvsdgFunction, EggCCError> {
+    let mut termdag = Default::default();
+    let mut extract = |expr: &egglog::ast::Expr| -> Result<egglog::ast::Expr, EggCCError> {
+        let (sort, value) = egraph
+            .eval_expr(expr, None, true)
+            .map_err(EggCCError::EggLog)?;
+        let (_cost, term) = egraph.extract(value, &mut termdag, &sort);
+        Ok(termdag.term_to_expr(&term))
+    };
+    let extracted = match func.to_egglog_expr() {
+        EgglogFunctionResult::StateOnly(state) => EgglogFunctionResult::StateOnly(extract(&state)?),
+        EgglogFunctionResult::StateAndValue { state, value } => {
+            EgglogFunctionResult::StateAndValue {
+                state: extract(&state)?,
+                value: extract(&value)?,
+            }
+        }
+        EgglogFunctionResult::StateAndValues { state, values } => {
+            EgglogFunctionResult::StateAndValues {
+                state: extract(&state)?,
+                values: values
+                    .iter()
+                    .map(&mut extract)
+                    .collect::<Result<_, EggCCError>>()?,
+            }
+        }
+    };
+    RvsdgFunction::egglog_expr_to_function(&extracted, &func.name, &func.args)
+        .map_err(|err| EggCCError::ConversionError(err.to_string()))
+}
+
+/// A breakdown of what a function costs under a [CostModel], as computed by
+/// [cost_report].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CostReport {
+    /// The total cost of the function.
+    pub total: usize,
+    /// How much each kind of operation contributes to `total`, keyed by the
+    /// name of its egglog constructor (see [CostModel]). The state edges that
+    /// effectful operations take are listed under `"Effectful"`.
+    pub by_op: BTreeMap<String, usize>,
+}
+
+/// Like [extract_cheapest], also reporting the cost of the extracted function
+/// under `model`, which should be the model `egraph` was created with.
+pub fn extract_with_report(
+    egraph: &mut EGraph,
+    func: &RvsdgFunction,
+    model: &dyn CostModel,
+) -> Result<(RvsdgFunction, CostReport), EggCCError> {
+    let extracted = extract_cheapest(egraph, func)?;
+    let report = cost_report(&extracted, model);
+    Ok((extracted, report))
+}
+
+/// The cost of every operation that `func`'s results and state depend on,
+/// including those in the regions of gammas, thetas, and lambdas, under
+/// `model`.
+///
+/// Each node is counted once, however many times it is used. egglog instead
+/// counts a shared subterm once per use, so the cost it extracts by can be
+/// higher than the total reported here.
+pub fn cost_report(func: &RvsdgFunction, model: &dyn CostModel) -> CostReport {
+    fn add_region(
+        nodes: &[RvsdgBody],
+        outputs: &[Operand],
+        model: &dyn CostModel,
+        report: &mut CostReport,
+    ) {
+        let mut seen = HashSet::new();
+        let mut stack = outputs.to_vec();
+        while let Some(op) = stack.pop() {
+            let (Operand::Id(id) | Operand::Project(_, id)) = op else {
+                continue;
+            };
+            if !seen.insert(id) {
+                continue;
+            }
+            let body = &nodes[id];
+            body.for_each_operand(|op| stack.push(*op));
+            match body {
+                RvsdgBody::BasicOp(expr) => {
+                    let constructor = match expr {
+                        Expr::Op(op, ..) | Expr::EffectfulOp(op, ..) => op.to_string(),
+                        Expr::Effect(op, _) | Expr::Speculation(op, _) => op.to_string(),
+                        Expr::Call(.., false) => "Call".into(),
+                        Expr::Call(.., true) => "PureCall".into(),
+                        Expr::Const(..) => "Const".into(),
+                        Expr::Select(..) => "select".into(),
+                        Expr::UnsignedCmp(op, _) => unsigned_cmp_to_egglog(*op).into(),
+                        Expr::Bitwise(op, _) => bitop_to_egglog(*op).into(),
+                        Expr::Print(_) => "print".into(),
+                    };
+                    let cost = model.cost(&constructor);
+                    add(report, constructor, cost);
+                    if !matches!(
+                        expr,
+                        Expr::Op(..)
+                            | Expr::UnsignedCmp(..)
+                            | Expr::Bitwise(..)
+                            | Expr::Const(..)
+                            | Expr::Select(..)
+                            | Expr::Call(.., true)
+                    ) {
+                        add(report, "Effectful".into(), model.state_edge_cost());
+                    }
+                }
+                RvsdgBody::Lambda { nodes, outputs, .. } => {
+                    add_region(nodes, outputs, model, report)
+                }
+                RvsdgBody::Gamma { .. } | RvsdgBody::Theta { .. } | RvsdgBody::Apply { .. } => {}
+            }
+        }
+    }
+
+    fn add(report: &mut CostReport, constructor: String, cost: usize) {
+        report.total += cost;
+        *report.by_op.entry(constructor).or_default() += cost;
+    }
+
+    let mut report = CostReport::default();
+    let outputs = Vec::from_iter(func.results.iter().chain([&func.state]).copied());
+    add_region(&func.nodes, &outputs, model, &mut report);
+    report
+}
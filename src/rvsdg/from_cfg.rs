@@ -6,15 +6,19 @@
 //! points) are replaced with references to either arguments to the enclosing
 //! region or outputs from some region. To detect the start of loop regions, we
 //! look for back-edges dominated by the current node. To detect the start of
-//! branch regions, we look for nodes with more than one successor.
+//! branch regions, we look for nodes with more than one successor. A chain of
+//! branches that only compute the condition for the next branch (e.g. a
+//! `switch` compiled to comparisons) becomes a single gamma with one branch per
+//! case.
 
 use bril_rs::{ConstOps, EffectOps, Instruction, Literal, Position, Type, ValueOps};
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use petgraph::algo::dominators;
-use petgraph::visit::EdgeRef;
+use petgraph::visit::{Dfs, EdgeRef};
 use petgraph::Direction;
 use petgraph::{algo::dominators::Dominators, stable_graph::NodeIndex};
 
+use crate::cfg::simplify::simplify_cfg;
 use crate::cfg::{ret_id, Annotation, BranchOp, Cfg, CondVal, Identifier};
 use crate::rvsdg::Result;
 
@@ -25,10 +29,47 @@ use super::{
     Expr, Id, Operand, RvsdgBody, RvsdgError,
 };
 
+/// Check that every reachable path to the exit of `cfg` returns a value if and
+/// only if the function has a return type. This runs before restructuring,
+/// which adds paths to the exit of its own.
+fn check_returns(cfg: &Cfg) -> Result {
+    let mut reachable = Dfs::new(&cfg.graph, cfg.entry);
+    let mut seen = HashSet::new();
+    while let Some(block) = reachable.next(&cfg.graph) {
+        seen.insert(block);
+    }
+    for edge in cfg.graph.edges_directed(cfg.exit, Direction::Incoming) {
+        if !seen.contains(&edge.source()) {
+            continue;
+        }
+        let block = &cfg.graph[edge.source()];
+        let returns_value = block
+            .footer
+            .iter()
+            .any(|ann| matches!(ann, Annotation::AssignRet { .. }));
+        if returns_value == cfg.has_return_value() {
+            continue;
+        }
+        let (func, block, pos) = (
+            cfg.name.clone(),
+            block.name.to_string(),
+            edge.weight().pos.clone(),
+        );
+        return Err(if returns_value {
+            RvsdgError::UnexpectedReturnValue { func, block, pos }
+        } else {
+            RvsdgError::MissingReturnValue { func, block, pos }
+        });
+    }
+    Ok(())
+}
+
 pub(crate) fn cfg_func_to_rvsdg(
     cfg: &mut Cfg,
     function_types: &FunctionTypes,
 ) -> Result<RvsdgFunction> {
+    check_returns(cfg)?;
+    simplify_cfg(cfg);
     cfg.restructure();
     let analysis = live_variables(cfg);
     let dom = dominators::simple_fast(&cfg.graph, cfg.entry);
@@ -58,23 +99,30 @@ pub(crate) fn cfg_func_to_rvsdg(
         }
         cur = next;
     }
-    let result = if builder.cfg.has_return_value() {
+    let results = if builder.cfg.has_return_value() {
         let ret_var = builder.analysis.intern.intern(ret_id());
-        Some(get_op(
+        vec![get_op(
             ret_var,
             &None,
             &builder.store,
             &builder.analysis.intern,
-        )?)
+        )?]
     } else {
-        None
+        vec![]
     };
-    let n_args = builder.cfg.args.len();
+    let args = builder
+        .cfg
+        .args
+        .iter()
+        .map(|arg| arg.arg_type.clone())
+        .collect();
     let state = builder.store[&state_var];
     Ok(RvsdgFunction {
-        n_args,
+        name: builder.cfg.name.clone(),
+        args,
+        return_type: builder.cfg.return_ty.clone(),
         nodes: builder.expr,
-        result,
+        results,
         state,
     })
 }
@@ -126,13 +174,14 @@ impl<'a> RvsdgBuilder<'a> {
 
         // First, we need to record the live operands going into the loop. These
         // are the loop inputs.
-        let live_vars = self.analysis.var_state(block).unwrap();
+        let live_in = Vec::from_iter(self.analysis.var_state(block).unwrap().live_in.iter());
+        self.bind_placeholders(&live_in);
 
-        let mut input_vars = Vec::with_capacity(live_vars.live_in.len());
+        let mut input_vars = Vec::with_capacity(live_in.len());
         let mut inputs = Vec::new();
         let pos = self.cfg.graph[block].pos.clone();
         let mut arg = 0;
-        for input in live_vars.live_in.iter() {
+        for input in live_in {
             let Some(op) = self.store.get(&input).copied() else { continue; };
             input_vars.push(input);
             inputs.push(op);
@@ -142,9 +191,13 @@ impl<'a> RvsdgBuilder<'a> {
 
         // Now we "run" the loop until we reach the end:
         let tail = if let Some(tail) = loop_tail {
-            let mut next = self.try_branch(block)?.unwrap();
+            let mut next = self
+                .try_branch(block)?
+                .ok_or_else(|| self.unstructured(block))?;
             while next != tail {
-                next = self.try_loop(next)?.unwrap();
+                next = self
+                    .try_loop(next)?
+                    .ok_or_else(|| self.unstructured(next))?;
             }
             tail
         } else {
@@ -193,10 +246,11 @@ impl<'a> RvsdgBuilder<'a> {
                 arg,
                 val: CondVal { val, of },
             } => {
-                assert_eq!(
-                    of, 2,
-                    "loop predicate has more than two options (restructuring should avoid this)"
-                );
+                // Restructuring should avoid loop predicates with more than
+                // two options.
+                if of != 2 {
+                    return Err(self.unstructured(tail));
+                }
                 let var = self.analysis.intern.intern(arg);
                 let op = get_op(var, &None, &self.store, &self.analysis.intern)?;
                 if val == 0 {
@@ -230,6 +284,73 @@ impl<'a> RvsdgBuilder<'a> {
             .find(|succ| succ != &block))
     }
 
+    /// Bind the variables in `vars` that aren't bound yet to placeholders.
+    ///
+    /// A variable can be live into a loop or a branch before it is assigned
+    /// if it is only read on paths that assign it first. For example, the
+    /// predicate that restructuring adds to pick which exit a loop took (say,
+    /// a `ret` from the middle of the body rather than the loop's normal
+    /// exit) is only assigned on the way out of the loop. The region still
+    /// has to carry some value for it along every path, so it starts out as a
+    /// constant that is never read. Variables of unknown or pointer type
+    /// (Bril has no pointer constants) are left unbound.
+    fn bind_placeholders(&mut self, vars: &[VarId]) {
+        for var in vars {
+            if self.store.contains_key(var) {
+                continue;
+            }
+            let Some(ty) = self.var_type(*var) else {
+                continue;
+            };
+            let lit = match ty {
+                Type::Int => Literal::Int(0),
+                Type::Bool => Literal::Bool(false),
+                Type::Float => Literal::Float(0.0),
+                Type::Char => Literal::Char(' '),
+                Type::Pointer(_) => continue,
+            };
+            let id = get_id(
+                &mut self.expr,
+                RvsdgBody::BasicOp(Expr::Const(ConstOps::Const, lit, ty)),
+            );
+            self.store.insert(*var, Operand::Id(id));
+        }
+    }
+
+    /// The type of `var`, from the first assignment to it found in the CFG.
+    fn var_type(&self, var: VarId) -> Option<Type> {
+        let name = self.analysis.intern.get_var(var);
+        self.cfg.graph.node_weights().find_map(|block| {
+            let assigned = block.instrs.iter().find_map(|instr| match instr {
+                Instruction::Constant {
+                    dest,
+                    const_type: ty,
+                    ..
+                }
+                | Instruction::Value {
+                    dest, op_type: ty, ..
+                } if Identifier::from(dest) == *name => Some(ty.clone()),
+                _ => None,
+            });
+            assigned.or_else(|| {
+                block.footer.iter().find_map(|ann| match ann {
+                    Annotation::AssignCond { dst, .. } if dst == name => Some(Type::Int),
+                    _ => None,
+                })
+            })
+        })
+    }
+
+    /// The error for control flow out of `block` that restructuring should
+    /// have ruled out.
+    fn unstructured(&self, block: NodeIndex) -> RvsdgError {
+        let block = &self.cfg.graph[block];
+        RvsdgError::UnstructuredControlFlow {
+            block: block.name.to_string(),
+            pos: block.pos.clone(),
+        }
+    }
+
     fn try_branch(&mut self, block: NodeIndex) -> Result<Option<NodeIndex>> {
         self.translate_block(block)?;
         if self
@@ -246,41 +367,64 @@ impl<'a> RvsdgBuilder<'a> {
                 .neighbors_directed(block, Direction::Outgoing)
                 .next());
         }
-        let placeholder = Identifier::Num(!0);
-        let mut pred = placeholder.clone();
-        let mut succs = Vec::from_iter(self.cfg.graph.edges_directed(block, Direction::Outgoing).map(|e| {
-            if let BranchOp::Cond { arg, val: CondVal { val, of:_ }} = &e.weight().op {
-                if pred == placeholder {
-                    pred = arg.clone();
+        let (pred, succs, live_in) = match self.flatten_branches(block) {
+            Some(tree) => self.translate_branch_tree(block, &tree)?,
+            None => {
+                let placeholder = Identifier::Num(!0);
+                let mut pred = placeholder.clone();
+                let mut succs = Vec::new();
+                for e in self.cfg.graph.edges_directed(block, Direction::Outgoing) {
+                    let BranchOp::Cond { arg, val: CondVal { val, of: _ } } = &e.weight().op else {
+                        // An invalid mix of conditional and non-conditional branches.
+                        return Err(self.unstructured(block));
+                    };
+                    if pred == placeholder {
+                        pred = arg.clone();
+                    }
+                    succs.push((*val, e.target()));
                 }
-                (*val, e.target())
-            } else {
-                panic!("Invalid mix of conditional and non-conditional branches in block {block:?}")
+                succs.sort_by_key(|(val, _)| *val);
+                // Branches should be contiguous.
+                if succs
+                    .iter()
+                    .enumerate()
+                    .any(|(i, (val, _))| i != *val as usize)
+                {
+                    return Err(self.unstructured(block));
+                }
+                let pred_var = self.analysis.intern.intern(pred);
+                let pred = get_op(
+                    pred_var,
+                    &self.cfg.graph[block].pos,
+                    &self.store,
+                    &self.analysis.intern,
+                )?;
+                let live_in =
+                    Vec::from_iter(self.analysis.var_state(block).unwrap().live_in.iter());
+                (
+                    pred,
+                    Vec::from_iter(succs.into_iter().map(|(_, succ)| succ)),
+                    live_in,
+                )
             }
-        }));
-        succs.sort_by_key(|(val, _)| *val);
-        // Branches should be contiguous.
-        succs
-            .iter()
-            .enumerate()
-            .for_each(|(i, (val, _))| assert_eq!(i, *val as usize));
+        };
 
         let mut inputs = Vec::<Operand>::new();
         let mut outputs = Vec::<Vec<Operand>>::new();
-        let live_vars = self.analysis.var_state(block).unwrap();
 
         // Not all live variables have necessarily been bound yet.
         // `input_vars` and `output_vars` store the variables that are bound.
-        let mut input_vars = Vec::with_capacity(live_vars.live_in.len());
+        self.bind_placeholders(&live_in);
+        let mut input_vars = Vec::with_capacity(live_in.len());
         let mut output_vars = Vec::new();
-        for var in live_vars.live_in.iter() {
+        for var in live_in {
             let Some(op) = self.store.get(&var).copied() else { continue; };
             inputs.push(op);
             input_vars.push(var);
         }
 
         let mut next = None;
-        for (_, succ) in succs {
+        for succ in succs {
             // First, make sure that all inputs are correctly bound to inputs to the block.
             for (i, var) in input_vars.iter().copied().enumerate() {
                 self.store.insert(var, Operand::Arg(i));
@@ -288,7 +432,9 @@ impl<'a> RvsdgBuilder<'a> {
             // Loop until we reach a join point.
             let mut curr = succ;
             loop {
-                curr = self.try_loop(curr)?.unwrap();
+                curr = self
+                    .try_loop(curr)?
+                    .ok_or_else(|| self.unstructured(curr))?;
                 if self
                     .cfg
                     .graph
@@ -313,20 +459,16 @@ impl<'a> RvsdgBuilder<'a> {
             }
             outputs.push(output_vec);
             if let Some(next) = next {
-                assert_eq!(next, curr);
+                // All branches should meet at the same join point.
+                if next != curr {
+                    return Err(self.unstructured(block));
+                }
             } else {
                 next = Some(curr);
             }
         }
 
         let next = next.unwrap();
-        let pred_var = self.analysis.intern.intern(pred);
-        let pred = get_op(
-            pred_var,
-            &self.cfg.graph[block].pos,
-            &self.store,
-            &self.analysis.intern,
-        )?;
         let gamma_node = get_id(
             &mut self.expr,
             RvsdgBody::Gamma {
@@ -343,6 +485,195 @@ impl<'a> RvsdgBuilder<'a> {
         Ok(Some(next))
     }
 
+    /// The condition and the targets (for `false` and `true`) of `block`, if
+    /// it ends in a `br` on a boolean variable from the source program.
+    fn two_way_branch(&self, block: NodeIndex) -> Option<(Identifier, [NodeIndex; 2])> {
+        let mut cond = None;
+        let mut targets = [None, None];
+        for e in self.cfg.graph.edges_directed(block, Direction::Outgoing) {
+            let BranchOp::Cond {
+                arg: arg @ Identifier::Name(_),
+                val: CondVal { val, of: 2 },
+            } = &e.weight().op
+            else {
+                return None;
+            };
+            if cond.get_or_insert_with(|| arg.clone()) != arg {
+                return None;
+            }
+            let target = targets.get_mut(*val as usize)?;
+            if target.replace(e.target()).is_some() {
+                return None;
+            }
+        }
+        Some((cond?, [targets[0]?, targets[1]?]))
+    }
+
+    /// Whether `block` can be run before the branch leading to it: it is only
+    /// reachable from that branch, and it just computes pure values.
+    fn is_hoistable(&self, block: NodeIndex) -> bool {
+        let weight = &self.cfg.graph[block];
+        self.cfg
+            .graph
+            .neighbors_directed(block, Direction::Incoming)
+            .count()
+            == 1
+            && weight.footer.is_empty()
+            && weight.instrs.iter().all(|instr| match instr {
+                Instruction::Constant { .. } => true,
+                Instruction::Value { op, .. } => !matches!(
+                    op,
+                    ValueOps::Call | ValueOps::Div | ValueOps::Alloc | ValueOps::Load
+                ),
+                Instruction::Effect { op, .. } => *op == EffectOps::Nop,
+            })
+    }
+
+    fn branch_tree(
+        &self,
+        block: NodeIndex,
+        cond: Identifier,
+        targets: [NodeIndex; 2],
+    ) -> BranchTree {
+        let sides = targets.map(|target| match self.two_way_branch(target) {
+            Some((cond, targets)) if self.is_hoistable(target) => {
+                self.branch_tree(target, cond, targets)
+            }
+            _ => BranchTree::Leaf(target),
+        });
+        BranchTree::Branch(block, cond, Box::new(sides))
+    }
+
+    /// Recognize a chain of `br`s out of `block` that only compute the
+    /// condition for the next `br` (like a `switch` compiled to comparisons),
+    /// so that all of the blocks at the end of the chain can become the
+    /// branches of a single gamma.
+    ///
+    /// The blocks in the middle of the chain are hoisted to run before the
+    /// gamma, so none of the variables they assign can be live in a branch
+    /// that didn't go through them. Their branches also have to meet up at the
+    /// same join point as the rest of the chain.
+    fn flatten_branches(&mut self, block: NodeIndex) -> Option<BranchTree> {
+        let (cond, targets) = self.two_way_branch(block)?;
+        let tree = self.branch_tree(block, cond, targets);
+        let hoisted = tree.hoisted();
+        if hoisted.is_empty() {
+            return None;
+        }
+        let leaves = tree.leaves();
+        let has_one_pred = |block: NodeIndex| {
+            self.cfg
+                .graph
+                .neighbors_directed(block, Direction::Incoming)
+                .count()
+                == 1
+        };
+        if !leaves.iter().all(|leaf| has_one_pred(*leaf)) {
+            return None;
+        }
+        for (block, inner) in hoisted {
+            let has_own_join = self.cfg.graph.node_indices().any(|node| {
+                self.dom.immediate_dominator(node) == Some(block) && !has_one_pred(node)
+            });
+            if has_own_join {
+                return None;
+            }
+            let defs =
+                Vec::from_iter(self.cfg.graph[block].instrs.iter().filter_map(
+                    |instr| match instr {
+                        Instruction::Constant { dest, .. } | Instruction::Value { dest, .. } => {
+                            Some(dest.clone())
+                        }
+                        Instruction::Effect { .. } => None,
+                    },
+                ));
+            for def in defs {
+                let var = self.analysis.intern.intern(def);
+                let clobbered = leaves
+                    .iter()
+                    .filter(|leaf| !inner.contains(leaf))
+                    .any(|leaf| {
+                        self.analysis
+                            .var_state(*leaf)
+                            .unwrap()
+                            .live_in
+                            .contains(var)
+                    });
+                if clobbered {
+                    return None;
+                }
+            }
+        }
+        Some(tree)
+    }
+
+    /// Translate the hoisted blocks of `tree`, returning the predicate
+    /// choosing between its leaves, the leaves themselves, and the variables
+    /// that may be live on entry to the leaves.
+    fn translate_branch_tree(
+        &mut self,
+        block: NodeIndex,
+        tree: &BranchTree,
+    ) -> Result<(Operand, Vec<NodeIndex>, Vec<VarId>)> {
+        let mut leaves = Vec::new();
+        let pred = self.translate_branch_subtree(tree, false, &mut leaves)?;
+        // Hoisted blocks can assign variables that the leaves use.
+        let mut live_in = Vec::from_iter(self.analysis.var_state(block).unwrap().live_in.iter());
+        for leaf in &leaves {
+            for var in self.analysis.var_state(*leaf).unwrap().live_in.iter() {
+                if !live_in.contains(&var) {
+                    live_in.push(var);
+                }
+            }
+        }
+        Ok((pred, leaves, live_in))
+    }
+
+    /// The index of the leaf of `tree` that is taken, as a (nested) select
+    /// between constants. Leaves are numbered from the `false` side.
+    fn translate_branch_subtree(
+        &mut self,
+        tree: &BranchTree,
+        hoist: bool,
+        leaves: &mut Vec<NodeIndex>,
+    ) -> Result<Operand> {
+        match tree {
+            BranchTree::Leaf(block) => {
+                let index = i64::try_from(leaves.len()).unwrap();
+                leaves.push(*block);
+                let id = get_id(
+                    &mut self.expr,
+                    RvsdgBody::BasicOp(Expr::Const(
+                        ConstOps::Const,
+                        Literal::Int(index),
+                        Type::Int,
+                    )),
+                );
+                Ok(Operand::Id(id))
+            }
+            BranchTree::Branch(block, cond, sides) => {
+                if hoist {
+                    self.translate_block(*block)?;
+                }
+                // Later blocks in the chain may reassign the condition.
+                let cond_var = self.analysis.intern.intern(cond.clone());
+                let cond = get_op(
+                    cond_var,
+                    &self.cfg.graph[*block].pos,
+                    &self.store,
+                    &self.analysis.intern,
+                )?;
+                let els = self.translate_branch_subtree(&sides[0], true, leaves)?;
+                let then = self.translate_branch_subtree(&sides[1], true, leaves)?;
+                let id = get_id(
+                    &mut self.expr,
+                    RvsdgBody::BasicOp(Expr::Select(vec![cond, then, els], Type::Int)),
+                );
+                Ok(Operand::Id(id))
+            }
+        }
+    }
+
     fn translate_block(&mut self, block: NodeIndex) -> Result<()> {
         let block = &self.cfg.graph[block];
 
@@ -391,12 +722,6 @@ impl<'a> RvsdgBuilder<'a> {
                     pos,
                     op_type,
                 } => match op {
-                    ValueOps::Alloc | ValueOps::Load | ValueOps::PtrAdd => {
-                        return Err(RvsdgError::UnsupportedOperation {
-                            op: *op,
-                            pos: pos.clone(),
-                        });
-                    }
                     ValueOps::Id => {
                         let dest_var = self.analysis.intern.intern(dest);
                         let src_var = self.analysis.intern.intern(&args[0]);
@@ -409,10 +734,30 @@ impl<'a> RvsdgBuilder<'a> {
                         self.store.insert(dest_var, arg_id);
                     }
                     ValueOps::Call => {
+                        if !self.function_types.contains_key(&funcs[0]) {
+                            return Err(RvsdgError::UnknownFunction {
+                                func: funcs[0].clone(),
+                                pos: pos.clone(),
+                            });
+                        }
                         let dest_var = self.analysis.intern.intern(dest);
                         let mut ops = convert_args(args, &mut self.analysis, &mut self.store, pos)?;
                         ops.push(self.store[&self.analysis.state_var]);
-                        let expr = Expr::Call((&funcs[0]).into(), ops, 2, Some(op_type.clone()));
+                        let ty = Some(op_type.clone());
+                        let expr = Expr::Call((&funcs[0]).into(), ops, 2, ty, false);
+                        let expr_id = get_id(&mut self.expr, RvsdgBody::BasicOp(expr));
+                        self.store.insert(dest_var, Operand::Id(expr_id));
+                        self.store
+                            .insert(self.analysis.state_var, Operand::Project(1, expr_id));
+                    }
+                    ValueOps::Div | ValueOps::Alloc | ValueOps::Load => {
+                        // Division traps on a zero divisor and memory
+                        // operations depend on prior stores, so these have to
+                        // stay ordered with respect to the rest of the effects.
+                        let dest_var = self.analysis.intern.intern(dest);
+                        let mut ops = convert_args(args, &mut self.analysis, &mut self.store, pos)?;
+                        ops.push(self.store[&self.analysis.state_var]);
+                        let expr = Expr::EffectfulOp(*op, ops, op_type.clone());
                         let expr_id = get_id(&mut self.expr, RvsdgBody::BasicOp(expr));
                         self.store.insert(dest_var, Operand::Id(expr_id));
                         self.store
@@ -426,6 +771,8 @@ impl<'a> RvsdgBuilder<'a> {
                         self.store.insert(dest_var, Operand::Id(expr_id));
                     }
                 },
+                // `nop`s (e.g. left behind by other tools) don't touch any
+                // values or the state edge, so they are dropped.
                 Instruction::Effect {
                     op: EffectOps::Nop, ..
                 } => {}
@@ -436,20 +783,25 @@ impl<'a> RvsdgBuilder<'a> {
                     pos,
                     ..
                 } => {
+                    let Some(ty) = self.function_types.get(&funcs[0]).cloned() else {
+                        return Err(RvsdgError::UnknownFunction {
+                            func: funcs[0].clone(),
+                            pos: pos.clone(),
+                        });
+                    };
                     let mut ops = convert_args(args, &mut self.analysis, &mut self.store, pos)?;
                     ops.push(self.store[&self.analysis.state_var]);
-                    let expr = Expr::Call(
-                        (&funcs[0]).into(),
-                        ops,
-                        1,
-                        self.function_types
-                            .get(&funcs[0])
-                            .unwrap_or_else(|| panic!("unknown function {}", funcs[0]))
-                            .clone(),
-                    );
+                    // A call whose result is discarded still has the same
+                    // outputs as one whose result is used: the value (if the
+                    // callee returns one) is just never projected out.
+                    let n_outputs = if ty.is_some() { 2 } else { 1 };
+                    let expr = Expr::Call((&funcs[0]).into(), ops, n_outputs, ty, false);
                     let expr_id = get_id(&mut self.expr, RvsdgBody::BasicOp(expr));
-                    self.store
-                        .insert(self.analysis.state_var, Operand::Id(expr_id));
+                    let state = match n_outputs {
+                        2 => Operand::Project(1, expr_id),
+                        _ => Operand::Id(expr_id),
+                    };
+                    self.store.insert(self.analysis.state_var, state);
                     debug_assert_eq!(funcs.len(), 1);
                 }
                 Instruction::Effect {
@@ -465,6 +817,40 @@ impl<'a> RvsdgBuilder<'a> {
                     self.store
                         .insert(self.analysis.state_var, Operand::Id(expr_id));
                 }
+                Instruction::Effect {
+                    op: op @ (EffectOps::Store | EffectOps::Free),
+                    args,
+                    pos,
+                    ..
+                } => {
+                    let mut ops = convert_args(args, &mut self.analysis, &mut self.store, pos)?;
+                    ops.push(self.store[&self.analysis.state_var]);
+                    let expr = Expr::Effect(*op, ops);
+                    let expr_id = get_id(&mut self.expr, RvsdgBody::BasicOp(expr));
+                    self.store
+                        .insert(self.analysis.state_var, Operand::Id(expr_id));
+                }
+                // Speculative regions aren't rolled back, so only guards that
+                // can't fail, and so never jump to their label, are supported.
+                Instruction::Effect {
+                    op: op @ (EffectOps::Speculate | EffectOps::Commit | EffectOps::Guard),
+                    args,
+                    pos,
+                    ..
+                } => {
+                    let mut ops = convert_args(args, &mut self.analysis, &mut self.store, pos)?;
+                    if *op == EffectOps::Guard && !ops[0].is_literal_true(&self.expr) {
+                        return Err(RvsdgError::FallibleGuard {
+                            func: self.cfg.name.clone(),
+                            pos: pos.clone(),
+                        });
+                    }
+                    ops.push(self.store[&self.analysis.state_var]);
+                    let expr = Expr::Speculation(*op, ops);
+                    let expr_id = get_id(&mut self.expr, RvsdgBody::BasicOp(expr));
+                    self.store
+                        .insert(self.analysis.state_var, Operand::Id(expr_id));
+                }
                 Instruction::Effect { op, pos, .. } => {
                     // Two notes here:
                     // * Control flow like Return and Jmp _are_ supported, but
@@ -510,6 +896,40 @@ impl<'a> RvsdgBuilder<'a> {
     }
 }
 
+/// A tree of two-way branches, where every inner node is a block ending in a
+/// `br` and the leaves are the blocks where the chain of branches ends.
+enum BranchTree {
+    Leaf(NodeIndex),
+    /// A block, its condition, and the trees for its `false` and `true`
+    /// targets.
+    Branch(NodeIndex, Identifier, Box<[BranchTree; 2]>),
+}
+
+impl BranchTree {
+    fn leaves(&self) -> Vec<NodeIndex> {
+        match self {
+            BranchTree::Leaf(block) => vec![*block],
+            BranchTree::Branch(_, _, sides) => sides.iter().flat_map(BranchTree::leaves).collect(),
+        }
+    }
+
+    /// The blocks below the root that end in a `br`, along with the leaves
+    /// below each of them.
+    fn hoisted(&self) -> Vec<(NodeIndex, Vec<NodeIndex>)> {
+        let BranchTree::Branch(_, _, sides) = self else {
+            return vec![];
+        };
+        let mut res = Vec::new();
+        for side in sides.iter() {
+            if let BranchTree::Branch(block, _, _) = side {
+                res.push((*block, side.leaves()));
+            }
+            res.extend(side.hoisted());
+        }
+        res
+    }
+}
+
 fn get_id(exprs: &mut Vec<RvsdgBody>, body: RvsdgBody) -> Id {
     let id = exprs.len();
     exprs.push(body);
@@ -68,6 +68,9 @@ pub(crate) fn live_variables(cfg: &Cfg) -> LiveVariableAnalysis {
                     state.gen.remove(var);
                 }
                 Annotation::AssignRet { src } => {
+                    let ret = names.intern(ret_id());
+                    state.kills.insert(ret);
+                    state.gen.remove(ret);
                     state.gen.insert(names.intern(src.clone()));
                 }
             }
@@ -88,7 +91,7 @@ pub(crate) fn live_variables(cfg: &Cfg) -> LiveVariableAnalysis {
                     for arg in args {
                         state.gen.insert(names.intern(arg));
                     }
-                    if let ValueOps::Call = op {
+                    if let ValueOps::Call | ValueOps::Div | ValueOps::Alloc | ValueOps::Load = op {
                         state.gen.insert(names.intern(state_id()));
                     }
                 }
@@ -97,7 +100,9 @@ pub(crate) fn live_variables(cfg: &Cfg) -> LiveVariableAnalysis {
                         state.gen.insert(names.intern(arg));
                     }
 
-                    if let EffectOps::Print | EffectOps::Call = op {
+                    if let EffectOps::Print | EffectOps::Call | EffectOps::Store | EffectOps::Free =
+                        op
+                    {
                         state.gen.insert(names.intern(state_id()));
                     }
                 }
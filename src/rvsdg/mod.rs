@@ -29,25 +29,49 @@
 //!
 //! In addition to those papers, the Jamey Sharp's
 //! [optir](https://github.com/jameysharp/optir) project is a major inspiration.
+pub(crate) mod builder;
+pub(crate) mod call_graph;
+pub(crate) mod diff;
+pub(crate) mod extract;
 pub(crate) mod from_cfg;
 pub(crate) mod live_variables;
+pub(crate) mod optimize;
+pub(crate) mod random;
 pub(crate) mod restructure;
+pub(crate) mod rvsdg2dot;
 pub(crate) mod rvsdg2svg;
+pub(crate) mod stats;
+pub(crate) mod to_cfg;
+pub(crate) mod validate;
 
-use std::fmt;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    mem::discriminant,
+    time::Instant,
+};
 
-use bril_rs::{ConstOps, Literal, Type, ValueOps};
-use egglog::EGraph;
+use bril_rs::{ConstOps, EffectOps, Literal, Program, Type, ValueOps};
+use egglog::{ast::Command, EGraph, SerializeConfig};
 use ordered_float::OrderedFloat;
 use thiserror::Error;
 
 use crate::{
-    cfg::{CfgProgram, Identifier},
-    conversions::egglog_op_to_bril,
-    EggCCError,
+    cfg::{program_to_cfg, to_cfg, CfgProgram, Identifier},
+    conversions::{
+        bitop_to_egglog, egglog_op_to_bril, egglog_to_bitop, egglog_to_unsigned_cmp,
+        unsigned_cmp_to_egglog,
+    },
+    Captures, EggCCError, Optimizer, RunTimings, SaturationLimit,
 };
 
-use self::from_cfg::cfg_func_to_rvsdg;
+use self::{
+    extract::{new_rvsdg_egraph_with_costs, rvsdg_egglog_prelude, CostModel, DefaultCostModel},
+    from_cfg::{cfg_func_to_rvsdg, FunctionTypes},
+    to_cfg::rvsdg_to_cfg,
+    validate::ValidationError,
+};
 
 #[cfg(test)]
 mod tests;
@@ -55,6 +79,10 @@ mod tests;
 /// Errors from the rvsdg module.
 #[derive(Debug, Error)]
 pub enum RvsdgError {
+    /// The source text isn't a valid Bril program.
+    #[error("Parse error: {0}")]
+    Parse(String),
+
     #[error("Unsupported operation: {op:?}, {pos:?}")]
     UnsupportedOperation {
         op: bril_rs::ValueOps,
@@ -73,10 +101,67 @@ pub enum RvsdgError {
         pos: Option<bril_rs::Position>,
     },
 
+    #[error("Call to unknown function @{func}, {pos:?}")]
+    UnknownFunction {
+        func: String,
+        pos: Option<bril_rs::Position>,
+    },
+
     // NB: We should  be able to suppor these patterns, but it might be better
     // to desugar them away as part of the CFG parsing step.
     #[error("Multiple branches from loop tail to head ({pos:?})")]
     UnsupportedLoopTail { pos: Option<bril_rs::Position> },
+
+    /// `block` returns without a value from a function that returns one.
+    #[error("Function @{func} returns a value, but .{block} returns without one, {pos:?}")]
+    MissingReturnValue {
+        func: String,
+        block: String,
+        pos: Option<bril_rs::Position>,
+    },
+
+    /// `block` returns a value from a function that doesn't return one.
+    #[error("Function @{func} doesn't return a value, but .{block} returns one, {pos:?}")]
+    UnexpectedReturnValue {
+        func: String,
+        block: String,
+        pos: Option<bril_rs::Position>,
+    },
+
+    /// Control flow leaving `block` doesn't have the shape that restructuring
+    /// is supposed to produce.
+    #[error("Unstructured control flow out of block .{block}, {pos:?}")]
+    UnstructuredControlFlow {
+        block: String,
+        pos: Option<bril_rs::Position>,
+    },
+
+    /// No function is named after any of the entry points in `names`.
+    #[error("No entry function named any of {names:?}")]
+    MissingEntryFunction { names: Vec<String> },
+
+    /// More than one function is named after an entry point.
+    #[error("Multiple entry functions: {funcs:?}")]
+    AmbiguousEntryFunction { funcs: Vec<String> },
+
+    /// Converting back into Bril: `func` has more than one result, but Bril
+    /// functions return at most one value.
+    #[error("Function @{func} has {results} results, but Bril functions return at most one")]
+    MultipleResults { func: String, results: usize },
+
+    /// Converting back into Bril: `func` uses a lambda or an apply node, but
+    /// Bril has no first-class functions.
+    #[error("Function @{func} uses first-class functions, which Bril doesn't have")]
+    FirstClassFunction { func: String },
+
+    /// A `guard` in `func` whose condition isn't the literal `true`. Rolling
+    /// back a speculative region isn't modeled, so only guards that can't
+    /// fail are supported.
+    #[error("Function @{func} has a guard that may fail, {pos:?}")]
+    FallibleGuard {
+        func: String,
+        pos: Option<bril_rs::Position>,
+    },
 }
 
 pub(crate) type Result<T = ()> = std::result::Result<T, RvsdgError>;
@@ -87,12 +172,77 @@ pub(crate) enum Annotation {
     AssignRet { src: Identifier },
 }
 
-pub(crate) type Id = usize;
+pub type Id = usize;
 
-#[derive(Debug, PartialEq)]
+/// The operations of an [Expr::Bitwise], on 64-bit two's complement integers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BitOp {
+    /// Shift left by the second operand, shifting in zeros. Shifting by 64 or
+    /// more gives 0, and shifting by a negative amount does nothing.
+    Shl,
+    /// Shift right by the second operand, shifting in copies of the sign bit.
+    /// Shifting by 64 or more gives 0 or -1, and shifting by a negative
+    /// amount does nothing.
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+impl BitOp {
+    /// The result of this operation on `x` and `y`.
+    pub(crate) fn eval(self, x: i64, y: i64) -> i64 {
+        match self {
+            BitOp::Shl | BitOp::Shr if y < 0 => x,
+            BitOp::Shl => u32::try_from(y)
+                .ok()
+                .and_then(|y| x.checked_shl(y))
+                .unwrap_or(0),
+            BitOp::Shr => x >> y.min(63),
+            BitOp::And => x & y,
+            BitOp::Or => x | y,
+            BitOp::Xor => x ^ y,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum Expr<Op> {
-    /// A primitive operation.
+    /// A primitive operation. Comparisons are signed, as in Bril.
     Op(ValueOps, Vec<Op>, Type),
+    /// An ordered integer comparison (`lt`, `le`, `gt`, or `ge`) that treats
+    /// its operands as unsigned. Bril has no such comparisons, so these never
+    /// come from a Bril program, but they are kept apart from the signed ones
+    /// so that optimizations don't conflate the two.
+    UnsignedCmp(ValueOps, Vec<Op>),
+    /// A shift or bitwise operation on two integers. Bril has none of these
+    /// either, so they only come from optimizations (e.g. strength reduction)
+    /// and are emulated with arithmetic when converting back to Bril.
+    Bitwise(BitOp, Vec<Op>),
+    /// A primitive operation that may trap (e.g. division by zero) or that
+    /// touches memory (`alloc` and `load`). Like `Call`, the last operand is
+    /// the incoming state edge, and the node has two outputs: the result of
+    /// the operation and the outgoing state edge.
+    ///
+    /// Threading state through these operations keeps them from being
+    /// eliminated or reordered outside of a branch that guards them.
+    EffectfulOp(ValueOps, Vec<Op>, Type),
+    /// A primitive effect with no result (`store` and `free`). As with
+    /// `Print`, the last operand is the incoming state edge and the only
+    /// output is the outgoing state edge.
+    Effect(EffectOps, Vec<Op>),
+    /// An instruction from Bril's speculation extension: `speculate` and
+    /// `commit`, whose only operand is the state edge, or `guard`, whose
+    /// operands are `[cond, state]`. As with `Effect`, the only output is the
+    /// outgoing state edge.
+    ///
+    /// Rollback is not modeled: `speculate` and `commit` just mark where a
+    /// speculative region starts and ends, and a `guard` is only allowed if
+    /// its condition is the literal `true`, so that it never jumps to its
+    /// label (see [RvsdgError::FallibleGuard]).
+    Speculation(EffectOps, Vec<Op>),
     /// A function call. The last parameter is the number of outputs to the
     /// function. Functions always have an "extra" output that is used for the
     /// 'state edge' flowing out of the function.
@@ -100,17 +250,64 @@ pub(crate) enum Expr<Op> {
     /// Essentially all of the code here does not use this value at all. The
     /// exception is the SVG rendering code, which relies on this value to
     /// determine how many output ports to add to a function call.
-    Call(Identifier, Vec<Op>, usize, Option<Type>),
+    ///
+    /// The flag marks a call to a function known to be pure (see
+    /// [mark_pure_calls](optimize::mark_pure_calls)). Such a call takes no
+    /// state edge and has no state output: its only output is the result, so
+    /// it can be reordered or deduplicated like any other pure operation.
+    Call(Identifier, Vec<Op>, usize, Option<Type>, bool),
     /// A literal constant.
     Const(ConstOps, Literal, Type),
+    /// `pred ? then : else`, with operands `[pred, then, else]`. Bril has no
+    /// select instruction: these are introduced by
+    /// [gammas_to_selects](optimize::gammas_to_selects) and become branches
+    /// again when converting back to a CFG.
+    Select(Vec<Op>, Type),
     /// Following bril, we treat 'print' as a built-in primitive, rather than
     /// just another function. For the purposes of RVSDG translation, however,
     /// print is treated the same as any other function that has no ouptputs.
+    ///
+    /// As in Bril, every operand but the state edge is printed, in order and
+    /// separated by spaces, on a single line.
     Print(Vec<Op>),
 }
 
+impl<Op> Expr<Op> {
+    /// The operands to this expression, including any state edge.
+    pub(crate) fn operands(&self) -> &[Op] {
+        match self {
+            Expr::Op(_, ops, _)
+            | Expr::UnsignedCmp(_, ops)
+            | Expr::Bitwise(_, ops)
+            | Expr::EffectfulOp(_, ops, _)
+            | Expr::Effect(_, ops)
+            | Expr::Speculation(_, ops)
+            | Expr::Call(_, ops, ..)
+            | Expr::Select(ops, _)
+            | Expr::Print(ops) => ops,
+            Expr::Const(..) => &[],
+        }
+    }
+
+    pub(crate) fn operands_mut(&mut self) -> &mut [Op] {
+        match self {
+            Expr::Op(_, ops, _)
+            | Expr::UnsignedCmp(_, ops)
+            | Expr::Bitwise(_, ops)
+            | Expr::EffectfulOp(_, ops, _)
+            | Expr::Effect(_, ops)
+            | Expr::Speculation(_, ops)
+            | Expr::Call(_, ops, ..)
+            | Expr::Select(ops, _)
+            | Expr::Print(ops) => ops,
+            Expr::Const(..) => &mut [],
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
-pub(crate) enum Operand {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Operand {
     /// A reference to an argument in the enclosing region.
     Arg(usize),
     /// Another node in the RVSDG.
@@ -119,7 +316,8 @@ pub(crate) enum Operand {
     Project(usize, Id),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum RvsdgBody {
     BasicOp(Expr<Operand>),
 
@@ -139,26 +337,118 @@ pub(crate) enum RvsdgBody {
         inputs: Vec<Operand>,
         outputs: Vec<Operand>,
     },
+
+    /// A function as a first-class value. Like an [RvsdgFunction], a lambda
+    /// takes `n_args + 1` arguments, the last of which is the state edge, and
+    /// its last output is the outgoing state edge.
+    ///
+    /// Lambdas are closed: they have no inputs, and their outputs point into
+    /// their own backing heap of nodes rather than the enclosing one.
+    Lambda {
+        n_args: usize,
+        nodes: Vec<RvsdgBody>,
+        outputs: Vec<Operand>,
+    },
+
+    /// Apply a lambda to some arguments, the last of which is the state edge.
+    /// The outputs correspond to the outputs of the lambda.
+    Apply {
+        func: Operand,
+        args: Vec<Operand>,
+    },
+}
+
+impl RvsdgBody {
+    /// Call `f` on every operand of this node that points into the same heap
+    /// as the node itself. This includes the outputs of gamma and theta
+    /// regions, but not the outputs of a lambda, which has its own heap.
+    pub(crate) fn for_each_operand(&self, mut f: impl FnMut(&Operand)) {
+        match self {
+            RvsdgBody::BasicOp(expr) => expr.operands().iter().for_each(f),
+            RvsdgBody::Gamma {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                f(pred);
+                inputs.iter().chain(outputs.iter().flatten()).for_each(f);
+            }
+            RvsdgBody::Theta {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                f(pred);
+                inputs.iter().chain(outputs).for_each(f);
+            }
+            RvsdgBody::Lambda { .. } => {}
+            RvsdgBody::Apply { func, args } => {
+                f(func);
+                args.iter().for_each(f);
+            }
+        }
+    }
+
+    /// Like [RvsdgBody::for_each_operand], but allows the operands to be
+    /// modified.
+    pub(crate) fn for_each_operand_mut(&mut self, mut f: impl FnMut(&mut Operand)) {
+        match self {
+            RvsdgBody::BasicOp(expr) => expr.operands_mut().iter_mut().for_each(f),
+            RvsdgBody::Gamma {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                f(pred);
+                inputs
+                    .iter_mut()
+                    .chain(outputs.iter_mut().flatten())
+                    .for_each(f);
+            }
+            RvsdgBody::Theta {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                f(pred);
+                inputs.iter_mut().chain(outputs).for_each(f);
+            }
+            RvsdgBody::Lambda { .. } => {}
+            RvsdgBody::Apply { func, args } => {
+                f(func);
+                args.iter_mut().for_each(f);
+            }
+        }
+    }
 }
 
 /// Represents a single function as an RVSDG.
-/// The function has arguments, a result, and nodes.
+/// The function has arguments, results, and nodes.
 /// The nodes are stored in a vector, and variants of RvsdgBody refer
 /// to nodes by their index in the vector.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RvsdgFunction {
-    /// The number of input arguments to the function.
+    /// The name of the function.
+    pub(crate) name: String,
+    /// The types of the input arguments to the function.
     ///
-    /// Functions all take `n_args + 1` arguments, where the last argument is a
-    /// "state edge" used to preserve ordering constraints to (potentially)
-    /// impure function calls.
-    pub(crate) n_args: usize,
+    /// Functions all take `args.len() + 1` arguments, where the last argument
+    /// is a "state edge" used to preserve ordering constraints to
+    /// (potentially) impure function calls.
+    pub(crate) args: Vec<Type>,
+    /// The type of the function's (first) result, or `None` if it returns
+    /// nothing. Functions lowered from Bril take this from their declaration;
+    /// elsewhere it is inferred from the results with
+    /// [RvsdgFunction::infer_return_type].
+    pub(crate) return_type: Option<Type>,
     /// The backing heap for Rvsdg node ids within this function.
     pub(crate) nodes: Vec<RvsdgBody>,
-    /// The (optional) result pointing into this function.
+    /// The results pointing into this function. Functions lowered from Bril
+    /// have at most one result.
     ///
     /// NB: until effects are supported, the only way to ensure a computation is
     /// marked as used is to populate a result of some kind.
-    pub(crate) result: Option<Operand>,
+    pub(crate) results: Vec<Operand>,
 
     /// The output port corersponding to the state edge of the function.
     pub(crate) state: Operand,
@@ -167,8 +457,10 @@ pub struct RvsdgFunction {
 impl fmt::Debug for RvsdgFunction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("RvsdgFunction")
-            .field("n_args", &self.n_args)
-            .field("result", &self.result)
+            .field("name", &self.name)
+            .field("args", &self.args)
+            .field("return_type", &self.return_type)
+            .field("results", &self.results)
             .field("state", &self.state);
         let mut map = f.debug_map();
         for (i, node) in self.nodes.iter().enumerate() {
@@ -179,15 +471,629 @@ impl fmt::Debug for RvsdgFunction {
     }
 }
 
+/// The argument and return types of a function, as in a Bril function
+/// declaration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionSignature {
+    /// The types of the arguments, not counting the state edge.
+    pub args: Vec<Type>,
+    /// The type of the result, or `None` if the function returns nothing.
+    pub return_type: Option<Type>,
+}
+
+impl RvsdgFunction {
+    /// The argument and return types of this function.
+    pub fn signature(&self) -> FunctionSignature {
+        FunctionSignature {
+            args: self.args.clone(),
+            return_type: self.return_type.clone(),
+        }
+    }
+
+    /// The type of the first of `results`, in a function with the given
+    /// argument types and heap, or `None` if there are no results (or the type
+    /// can't be determined, e.g. for the outputs of lambdas).
+    pub(crate) fn infer_return_type(
+        nodes: &[RvsdgBody],
+        args: &[Type],
+        results: &[Operand],
+    ) -> Option<Type> {
+        let args: Vec<_> = args.iter().cloned().map(Some).collect();
+        operand_type(nodes, &args, *results.first()?)
+    }
+
+    /// Whether `self` and `other` have the same signature and compute the same
+    /// thing in the same way, up to the order in which their nodes are laid out
+    /// and their names. Nodes are
+    /// compared by a DFS from the results and state edges, so dead nodes are
+    /// ignored.
+    ///
+    /// `Operand::Project(0, x)` and `Operand::Id(x)` are treated as the same
+    /// operand.
+    pub fn structurally_eq(&self, other: &RvsdgFunction) -> bool {
+        self.structurally_eq_impl(other, EqMode::default())
+    }
+
+    /// Like [RvsdgFunction::structurally_eq], but also treating the operands
+    /// of commutative operations (`add`, `mul`, `and`, `or`, and `eq`) as
+    /// unordered, so that e.g. `add(a, b)` matches `add(b, a)`.
+    pub fn structurally_eq_commutative(&self, other: &RvsdgFunction) -> bool {
+        let mode = EqMode {
+            commutative: true,
+            ..EqMode::default()
+        };
+        self.structurally_eq_impl(other, mode)
+    }
+
+    /// Like [RvsdgFunction::structurally_eq], but ignoring the types that
+    /// operations, calls, selects, and constants are annotated with, e.g. to
+    /// compare functions before and after a type-inference pass. The literals
+    /// of constants must still match.
+    pub fn structurally_eq_ignoring_types(&self, other: &RvsdgFunction) -> bool {
+        let mode = EqMode {
+            ignore_types: true,
+            ..EqMode::default()
+        };
+        self.structurally_eq_impl(other, mode)
+    }
+
+    /// The nodes of this function in topological order: every node comes after
+    /// the nodes producing its operands, including the outputs of the regions
+    /// it contains, so the body of a gamma or theta comes before the gamma or
+    /// theta itself. Dead nodes are included too. The bodies of lambdas have
+    /// their own heap and aren't visited.
+    pub(crate) fn nodes_topo(&self) -> impl Iterator<Item = (Id, &RvsdgBody)> + '_ {
+        let mut visited = vec![false; self.nodes.len()];
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let roots = self.results.iter().chain([&self.state]);
+        let roots = roots
+            .filter_map(|op| match op {
+                Operand::Arg(_) => None,
+                Operand::Id(id) | Operand::Project(_, id) => Some(*id),
+            })
+            .chain(0..self.nodes.len());
+        for root in roots {
+            // A node is pushed once to visit its operands, and again (marked
+            // `true`) to be emitted once they have all been emitted.
+            let mut stack = vec![(root, false)];
+            while let Some((id, expanded)) = stack.pop() {
+                if expanded {
+                    order.push(id);
+                    continue;
+                }
+                if visited[id] {
+                    continue;
+                }
+                visited[id] = true;
+                stack.push((id, true));
+                self.nodes[id].for_each_operand(|op| match op {
+                    Operand::Id(id) | Operand::Project(_, id) if !visited[*id] => {
+                        stack.push((*id, false))
+                    }
+                    _ => {}
+                });
+            }
+        }
+        order.into_iter().map(move |id| (id, &self.nodes[id]))
+    }
+
+    fn structurally_eq_impl(&self, other: &RvsdgFunction, mode: EqMode) -> bool {
+        self.args == other.args
+            && self.return_type == other.return_type
+            && ops_equal(&self.state, &other.state, &self.nodes, &other.nodes, mode)
+            && all_equal(
+                &self.results,
+                &other.results,
+                &self.nodes,
+                &other.nodes,
+                mode,
+            )
+    }
+}
+
+/// Whether the operands of `op` can be compared in either order.
+fn is_commutative(op: &ValueOps) -> bool {
+    matches!(
+        op,
+        ValueOps::Add | ValueOps::Mul | ValueOps::And | ValueOps::Or | ValueOps::Eq
+    )
+}
+
+fn types_equal<T: PartialEq>(ty1: &T, ty2: &T, mode: EqMode) -> bool {
+    mode.ignore_types || ty1 == ty2
+}
+
+fn ops_equal(o1: &Operand, o2: &Operand, f1: &[RvsdgBody], f2: &[RvsdgBody], mode: EqMode) -> bool {
+    match (o1.canonical(), o2.canonical()) {
+        (Operand::Arg(x), Operand::Arg(y)) => x == y,
+        (Operand::Id(l), Operand::Id(r)) => ids_equal(l, r, f1, f2, mode),
+        (Operand::Project(p1, l), Operand::Project(p2, r)) => {
+            p1 == p2 && ids_equal(l, r, f1, f2, mode)
+        }
+        (Operand::Arg(_), Operand::Id(_))
+        | (Operand::Arg(_), Operand::Project(_, _))
+        | (Operand::Id(_), Operand::Arg(_))
+        | (Operand::Id(_), Operand::Project(_, _))
+        | (Operand::Project(_, _), Operand::Arg(_))
+        | (Operand::Project(_, _), Operand::Id(_)) => false,
+    }
+}
+
+fn all_equal(
+    ops1: &[Operand],
+    ops2: &[Operand],
+    f1: &[RvsdgBody],
+    f2: &[RvsdgBody],
+    mode: EqMode,
+) -> bool {
+    ops1.len() == ops2.len()
+        && ops1
+            .iter()
+            .zip(ops2.iter())
+            .all(|(l, r)| ops_equal(l, r, f1, f2, mode))
+}
+
+fn ids_equal(i1: Id, i2: Id, f1: &[RvsdgBody], f2: &[RvsdgBody], mode: EqMode) -> bool {
+    match (&f1[i1], &f2[i2]) {
+        (RvsdgBody::BasicOp(l), RvsdgBody::BasicOp(r)) => match (l, r) {
+            (Expr::Op(vo1, as1, ty1), Expr::Op(vo2, as2, ty2))
+                if mode.commutative && is_commutative(vo1) && as1.len() == 2 && as2.len() == 2 =>
+            {
+                let swapped = [as2[1], as2[0]];
+                vo1 == vo2
+                    && types_equal(ty1, ty2, mode)
+                    && (all_equal(as1, as2, f1, f2, mode) || all_equal(as1, &swapped, f1, f2, mode))
+            }
+            (Expr::Op(vo1, as1, ty1), Expr::Op(vo2, as2, ty2))
+            | (Expr::EffectfulOp(vo1, as1, ty1), Expr::EffectfulOp(vo2, as2, ty2)) => {
+                vo1 == vo2 && all_equal(as1, as2, f1, f2, mode) && types_equal(ty1, ty2, mode)
+            }
+            (Expr::Call(func1, as1, n1, ty1, pure1), Expr::Call(func2, as2, n2, ty2, pure2)) => {
+                func1 == func2
+                    && n1 == n2
+                    && pure1 == pure2
+                    && all_equal(as1, as2, f1, f2, mode)
+                    && types_equal(ty1, ty2, mode)
+            }
+            (Expr::Const(c1, lit1, ty1), Expr::Const(c2, lit2, ty2)) => {
+                c1 == c2 && lit1 == lit2 && types_equal(ty1, ty2, mode)
+            }
+            (Expr::Effect(e1, as1), Expr::Effect(e2, as2)) => {
+                e1 == e2 && all_equal(as1, as2, f1, f2, mode)
+            }
+            (Expr::Print(as1), Expr::Print(as2)) => all_equal(as1, as2, f1, f2, mode),
+            (Expr::Select(as1, ty1), Expr::Select(as2, ty2)) => {
+                all_equal(as1, as2, f1, f2, mode) && types_equal(ty1, ty2, mode)
+            }
+            (Expr::UnsignedCmp(op1, as1), Expr::UnsignedCmp(op2, as2)) => {
+                op1 == op2 && all_equal(as1, as2, f1, f2, mode)
+            }
+            (Expr::Bitwise(op1, as1), Expr::Bitwise(op2, as2)) => {
+                op1 == op2 && all_equal(as1, as2, f1, f2, mode)
+            }
+            (Expr::Speculation(op1, as1), Expr::Speculation(op2, as2)) => {
+                op1 == op2 && all_equal(as1, as2, f1, f2, mode)
+            }
+            // Different kinds of operation. Every kind is listed, so that a new
+            // one has to be given its own arm above.
+            (
+                Expr::Op(..)
+                | Expr::UnsignedCmp(..)
+                | Expr::Bitwise(..)
+                | Expr::EffectfulOp(..)
+                | Expr::Effect(..)
+                | Expr::Speculation(..)
+                | Expr::Call(..)
+                | Expr::Const(..)
+                | Expr::Select(..)
+                | Expr::Print(..),
+                _,
+            ) => false,
+        },
+        (
+            RvsdgBody::Theta {
+                pred: p1,
+                inputs: is1,
+                outputs: os1,
+            },
+            RvsdgBody::Theta {
+                pred: p2,
+                inputs: is2,
+                outputs: os2,
+            },
+        ) => {
+            ops_equal(p1, p2, f1, f2, mode)
+                && all_equal(is1, is2, f1, f2, mode)
+                && all_equal(os1, os2, f1, f2, mode)
+        }
+        (
+            RvsdgBody::Gamma {
+                pred: p1,
+                inputs: is1,
+                outputs: os1,
+            },
+            RvsdgBody::Gamma {
+                pred: p2,
+                inputs: is2,
+                outputs: os2,
+            },
+        ) => {
+            if !ops_equal(p1, p2, f1, f2, mode) || !all_equal(is1, is2, f1, f2, mode) {
+                return false;
+            }
+            os1.len() == os2.len()
+                && os1
+                    .iter()
+                    .zip(os2.iter())
+                    .all(|(l, r)| all_equal(l, r, f1, f2, mode))
+        }
+        (
+            RvsdgBody::Lambda {
+                n_args: n1,
+                nodes: b1,
+                outputs: os1,
+            },
+            RvsdgBody::Lambda {
+                n_args: n2,
+                nodes: b2,
+                outputs: os2,
+            },
+        ) => n1 == n2 && all_equal(os1, os2, b1, b2, mode),
+        (
+            RvsdgBody::Apply {
+                func: func1,
+                args: as1,
+            },
+            RvsdgBody::Apply {
+                func: func2,
+                args: as2,
+            },
+        ) => ops_equal(func1, func2, f1, f2, mode) && all_equal(as1, as2, f1, f2, mode),
+        (RvsdgBody::BasicOp(_), RvsdgBody::Gamma { .. })
+        | (RvsdgBody::BasicOp(_), RvsdgBody::Theta { .. })
+        | (RvsdgBody::BasicOp(_), RvsdgBody::Lambda { .. })
+        | (RvsdgBody::BasicOp(_), RvsdgBody::Apply { .. })
+        | (RvsdgBody::Gamma { .. }, RvsdgBody::BasicOp(_))
+        | (RvsdgBody::Gamma { .. }, RvsdgBody::Theta { .. })
+        | (RvsdgBody::Gamma { .. }, RvsdgBody::Lambda { .. })
+        | (RvsdgBody::Gamma { .. }, RvsdgBody::Apply { .. })
+        | (RvsdgBody::Theta { .. }, RvsdgBody::BasicOp(_))
+        | (RvsdgBody::Theta { .. }, RvsdgBody::Gamma { .. })
+        | (RvsdgBody::Theta { .. }, RvsdgBody::Lambda { .. })
+        | (RvsdgBody::Theta { .. }, RvsdgBody::Apply { .. })
+        | (RvsdgBody::Lambda { .. }, RvsdgBody::BasicOp(_))
+        | (RvsdgBody::Lambda { .. }, RvsdgBody::Gamma { .. })
+        | (RvsdgBody::Lambda { .. }, RvsdgBody::Theta { .. })
+        | (RvsdgBody::Lambda { .. }, RvsdgBody::Apply { .. })
+        | (RvsdgBody::Apply { .. }, RvsdgBody::BasicOp(_))
+        | (RvsdgBody::Apply { .. }, RvsdgBody::Gamma { .. })
+        | (RvsdgBody::Apply { .. }, RvsdgBody::Theta { .. })
+        | (RvsdgBody::Apply { .. }, RvsdgBody::Lambda { .. }) => false,
+    }
+}
+
+/// Whether `o1` and `o2`, which are both in `nodes`, compute the same thing in
+/// the same way, in the sense of [RvsdgFunction::structurally_eq].
+pub(crate) fn operands_structurally_eq(nodes: &[RvsdgBody], o1: &Operand, o2: &Operand) -> bool {
+    ops_equal(o1, o2, nodes, nodes, EqMode::default())
+}
+
+/// The type of `op` in a region whose arguments have the types `args`, where
+/// `None` (or a missing entry) stands for the state edge or an unknown type,
+/// as does a dangling `op`, which [validate](validate::validate) reports.
+/// The outputs of gammas and thetas are typed by looking through to the
+/// operands they come from.
+fn operand_type(nodes: &[RvsdgBody], args: &[Option<Type>], op: Operand) -> Option<Type> {
+    let (output, id) = match op {
+        Operand::Arg(arg) => return args.get(arg).cloned().flatten(),
+        Operand::Id(id) => (0, id),
+        Operand::Project(output, id) => (output, id),
+    };
+    match nodes.get(id)? {
+        // Any other output of a basic op is a state edge.
+        RvsdgBody::BasicOp(_) if output != 0 => None,
+        RvsdgBody::BasicOp(
+            Expr::Op(_, _, ty)
+            | Expr::EffectfulOp(_, _, ty)
+            | Expr::Select(_, ty)
+            | Expr::Const(_, _, ty),
+        ) => Some(ty.clone()),
+        RvsdgBody::BasicOp(Expr::UnsignedCmp(..)) => Some(Type::Bool),
+        RvsdgBody::BasicOp(Expr::Bitwise(..)) => Some(Type::Int),
+        RvsdgBody::BasicOp(Expr::Call(_, _, _, ty, _)) => ty.clone(),
+        RvsdgBody::BasicOp(_) => None,
+        RvsdgBody::Gamma {
+            inputs, outputs, ..
+        } => {
+            let inner: Vec<_> = inputs
+                .iter()
+                .map(|input| operand_type(nodes, args, *input))
+                .collect();
+            outputs
+                .iter()
+                .find_map(|branch| operand_type(nodes, &inner, *branch.get(output)?))
+        }
+        // Each output of a theta has the type of the corresponding input.
+        RvsdgBody::Theta { inputs, .. } => operand_type(nodes, args, *inputs.get(output)?),
+        RvsdgBody::Lambda { .. } | RvsdgBody::Apply { .. } => None,
+    }
+}
+
+/// Functions are equal when they are [structurally
+/// equal](RvsdgFunction::structurally_eq), so their names are ignored.
+impl PartialEq for RvsdgFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.structurally_eq(other)
+    }
+}
+
+impl Eq for RvsdgFunction {}
+
+/// Consistent with [RvsdgFunction::structurally_eq]: the name, the order of
+/// the nodes, and dead nodes don't affect the hash, and neither does writing
+/// `Operand::Project(0, x)` rather than `Operand::Id(x)`.
+impl Hash for RvsdgFunction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for ty in &self.args {
+            ty.to_string().hash(state);
+        }
+        self.return_type
+            .as_ref()
+            .map(ToString::to_string)
+            .hash(state);
+        let mut hashes = StructuralHashes::new(&self.nodes);
+        hashes.operand(&self.state).hash(state);
+        hashes.all(&self.results).hash(state);
+    }
+}
+
+/// Hashes of the nodes in a heap that only depend on what the nodes compute,
+/// memoized so that shared nodes are only hashed once.
+struct StructuralHashes<'a> {
+    nodes: &'a [RvsdgBody],
+    memo: Vec<Option<u64>>,
+}
+
+impl<'a> StructuralHashes<'a> {
+    fn new(nodes: &'a [RvsdgBody]) -> Self {
+        StructuralHashes {
+            nodes,
+            memo: vec![None; nodes.len()],
+        }
+    }
+
+    fn operand(&mut self, op: &Operand) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match op.canonical() {
+            Operand::Arg(arg) => (0u8, arg).hash(&mut hasher),
+            Operand::Id(id) => (1u8, self.node(id)).hash(&mut hasher),
+            Operand::Project(output, id) => (2u8, output, self.node(id)).hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    fn all(&mut self, ops: &[Operand]) -> Vec<u64> {
+        ops.iter().map(|op| self.operand(op)).collect()
+    }
+
+    fn node(&mut self, id: Id) -> u64 {
+        if let Some(hash) = self.memo[id] {
+            return hash;
+        }
+        let mut hasher = DefaultHasher::new();
+        let nodes = self.nodes;
+        let body = &nodes[id];
+        discriminant(body).hash(&mut hasher);
+        match body {
+            RvsdgBody::BasicOp(expr) => {
+                discriminant(expr).hash(&mut hasher);
+                match expr {
+                    Expr::Op(op, _, ty) | Expr::EffectfulOp(op, _, ty) => {
+                        (op.to_string(), ty.to_string()).hash(&mut hasher)
+                    }
+                    Expr::UnsignedCmp(op, _) => op.to_string().hash(&mut hasher),
+                    Expr::Bitwise(op, _) => op.hash(&mut hasher),
+                    Expr::Effect(op, _) | Expr::Speculation(op, _) => {
+                        op.to_string().hash(&mut hasher)
+                    }
+                    Expr::Call(func, _, n_outputs, ty, pure) => {
+                        func.to_string().hash(&mut hasher);
+                        (n_outputs, ty.as_ref().map(Type::to_string), pure).hash(&mut hasher);
+                    }
+                    Expr::Const(op, lit, ty) => {
+                        (op.to_string(), ty.to_string()).hash(&mut hasher);
+                        match lit {
+                            Literal::Int(n) => n.hash(&mut hasher),
+                            Literal::Bool(b) => b.hash(&mut hasher),
+                            // 0.0 and -0.0 are equal, so they hash the same.
+                            Literal::Float(f) if *f == 0.0 => 0u64.hash(&mut hasher),
+                            Literal::Float(f) => f.to_bits().hash(&mut hasher),
+                            Literal::Char(c) => c.hash(&mut hasher),
+                        }
+                    }
+                    Expr::Select(_, ty) => ty.to_string().hash(&mut hasher),
+                    Expr::Print(_) => {}
+                }
+                self.all(expr.operands()).hash(&mut hasher);
+            }
+            RvsdgBody::Gamma {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                self.operand(pred).hash(&mut hasher);
+                self.all(inputs).hash(&mut hasher);
+                for outputs in outputs {
+                    self.all(outputs).hash(&mut hasher);
+                }
+            }
+            RvsdgBody::Theta {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                self.operand(pred).hash(&mut hasher);
+                self.all(inputs).hash(&mut hasher);
+                self.all(outputs).hash(&mut hasher);
+            }
+            RvsdgBody::Lambda {
+                n_args,
+                nodes,
+                outputs,
+            } => {
+                n_args.hash(&mut hasher);
+                StructuralHashes::new(nodes).all(outputs).hash(&mut hasher);
+            }
+            RvsdgBody::Apply { func, args } => {
+                self.operand(func).hash(&mut hasher);
+                self.all(args).hash(&mut hasher);
+            }
+        }
+        let hash = hasher.finish();
+        self.memo[id] = Some(hash);
+        hash
+    }
+}
+
+/// Which differences [RvsdgFunction::structurally_eq] and its variants
+/// overlook.
+#[derive(Clone, Copy, Default)]
+struct EqMode {
+    /// Compare the operands of commutative operations in either order.
+    commutative: bool,
+    /// Don't compare the types that nodes are annotated with.
+    ignore_types: bool,
+}
+
+impl Operand {
+    /// `Operand::Id(x)` and `Operand::Project(0, x)` both refer to the first
+    /// output of `x`; normalize the latter to the former.
+    pub(crate) fn canonical(&self) -> Operand {
+        match *self {
+            Operand::Project(0, x) => Operand::Id(x),
+            op => op,
+        }
+    }
+
+    /// Whether this operand is the literal `true`, looking it up in `nodes`.
+    pub(crate) fn is_literal_true(&self, nodes: &[RvsdgBody]) -> bool {
+        matches!(self.canonical(), Operand::Id(id) if matches!(
+            nodes[id],
+            RvsdgBody::BasicOp(Expr::Const(_, Literal::Bool(true), _))
+        ))
+    }
+}
+
 /// A Bril program represented as an Rvsdg.
 /// For now, it's simply a vector of [RvsdgFunction]s.
 /// In the future, we may want functions to be represented within
 /// the RVSDG.
+#[derive(PartialEq, Eq, Hash)]
 pub struct RvsdgProgram {
     pub(crate) functions: Vec<RvsdgFunction>,
 }
 
+/// An [RvsdgProgram] along with an index from function names to positions in
+/// `functions`, so that calls (which only name their callee) can be resolved
+/// to the function they call.
+pub struct ProgramRvsdg {
+    pub(crate) functions: Vec<RvsdgFunction>,
+    by_name: HashMap<String, usize>,
+    /// The return type of each function, which calls to it are lowered with.
+    types: FunctionTypes,
+}
+
+impl From<RvsdgProgram> for ProgramRvsdg {
+    fn from(program: RvsdgProgram) -> Self {
+        let by_name = program
+            .functions
+            .iter()
+            .enumerate()
+            .map(|(i, func)| (func.name.clone(), i))
+            .collect();
+        let types = program
+            .functions
+            .iter()
+            .map(|func| (func.name.clone(), func.return_type.clone()))
+            .collect();
+        ProgramRvsdg {
+            functions: program.functions,
+            by_name,
+            types,
+        }
+    }
+}
+
+impl ProgramRvsdg {
+    /// The position of the function called `name` in `functions`.
+    pub(crate) fn index_of(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    /// The position in `functions` of the function that `expr` calls, or
+    /// `None` if `expr` isn't a call to a function in this program.
+    pub(crate) fn resolve_call<Op>(&self, expr: &Expr<Op>) -> Option<usize> {
+        match expr {
+            Expr::Call(Identifier::Name(name), ..) => self.index_of(name),
+            _ => None,
+        }
+    }
+
+    /// The function that `expr` calls, if it is a call to a function in this
+    /// program.
+    pub(crate) fn callee<Op>(&self, expr: &Expr<Op>) -> Option<&RvsdgFunction> {
+        self.resolve_call(expr).map(|i| &self.functions[i])
+    }
+
+    /// Replace the function called `name` with the one defined in
+    /// `new_source` (the text of a Bril program defining just that function),
+    /// lowering only the new definition. Every other function is left as is.
+    ///
+    /// Calls are lowered by name, so the rest of the program stays valid as
+    /// long as the function's signature is unchanged. Returns whether the
+    /// signature (its argument types or return type) changed, in which case
+    /// the functions calling it should be re-lowered too.
+    pub(crate) fn update_function(
+        &mut self,
+        name: &Identifier,
+        new_source: &str,
+    ) -> std::result::Result<bool, EggCCError> {
+        let name = name.to_string();
+        let Some(index) = self.index_of(&name) else {
+            return Err(EggCCError::RvsdgError(RvsdgError::UnknownFunction {
+                func: name,
+                pos: None,
+            }));
+        };
+        let program = Optimizer::parse_bril(new_source)?;
+        let [func] = program.functions.as_slice() else {
+            return Err(EggCCError::Parse(format!(
+                "expected a single definition of @{name}, got {} functions",
+                program.functions.len()
+            )));
+        };
+        if func.name != name {
+            return Err(EggCCError::Parse(format!(
+                "expected a definition of @{name}, got @{}",
+                func.name
+            )));
+        }
+
+        let old_return = self.types.insert(name, func.return_type.clone());
+        let mut cfg = to_cfg(func);
+        let lowered = cfg_func_to_rvsdg(&mut cfg, &self.types).map_err(EggCCError::RvsdgError)?;
+        let changed = lowered.args != self.functions[index].args
+            || old_return != Some(func.return_type.clone());
+        self.functions[index] = lowered;
+        Ok(changed)
+    }
+}
+
 pub(crate) fn cfg_to_rvsdg(cfg: &CfgProgram) -> std::result::Result<RvsdgProgram, EggCCError> {
+    lower_cfg_program(cfg).map_err(EggCCError::RvsdgError)
+}
+
+fn lower_cfg_program(cfg: &CfgProgram) -> Result<RvsdgProgram> {
     // Rvsdg translation also restructured the cfg
     // so make a copy for that.
     let mut cfg_restructured = cfg.clone();
@@ -195,11 +1101,67 @@ pub(crate) fn cfg_to_rvsdg(cfg: &CfgProgram) -> std::result::Result<RvsdgProgram
 
     let mut functions = vec![];
     for func in cfg_restructured.functions.iter_mut() {
-        functions.push(cfg_func_to_rvsdg(func, &func_types).map_err(EggCCError::RvsdgError)?);
+        functions.push(cfg_func_to_rvsdg(func, &func_types)?);
     }
     Ok(RvsdgProgram { functions })
 }
 
+/// Parse the Bril program `src` and lower each of its functions to an RVSDG,
+/// in the order they appear in the program.
+///
+/// ```
+/// use bril_rs::Type;
+/// use eggcc::{compile_to_rvsdg, FunctionSignature};
+///
+/// const PROGRAM: &str = r#"
+/// @inc(x: int): int {
+///     one: int = const 1;
+///     res: int = add x one;
+///     ret res;
+/// }
+/// "#;
+/// let functions = compile_to_rvsdg(PROGRAM).unwrap();
+/// assert_eq!(functions.len(), 1);
+/// assert_eq!(
+///     functions[0].signature(),
+///     FunctionSignature {
+///         args: vec![Type::Int],
+///         return_type: Some(Type::Int),
+///     }
+/// );
+/// assert_eq!(functions[0].stats().nodes, 2);
+/// ```
+pub fn compile_to_rvsdg(src: &str) -> Result<Vec<RvsdgFunction>> {
+    let program = Optimizer::parse_bril(src).map_err(|err| RvsdgError::Parse(err.to_string()))?;
+    Ok(lower_cfg_program(&program_to_cfg(&program))?.functions)
+}
+
+/// The index of the function in `program` that runs first, i.e. the one
+/// named after one of the entry points in `names` (typically just `main`).
+/// Entry functions can be defined anywhere in the program, so this shouldn't
+/// be assumed to be the first function. It is an error for no function, or
+/// for more than one, to have one of `names`.
+pub fn entry_function(program: &[RvsdgFunction], names: &[Identifier]) -> Result<usize> {
+    let mut entries = program
+        .iter()
+        .enumerate()
+        .filter(|(_, func)| names.contains(&Identifier::from(&func.name)));
+    match (entries.next(), entries.next()) {
+        (Some((index, _)), None) => Ok(index),
+        (None, _) => Err(RvsdgError::MissingEntryFunction {
+            names: Vec::from_iter(names.iter().map(Identifier::to_string)),
+        }),
+        (Some((_, first)), Some((_, second))) => Err(RvsdgError::AmbiguousEntryFunction {
+            funcs: Vec::from_iter(
+                [first, second]
+                    .into_iter()
+                    .chain(entries.map(|(_, func)| func))
+                    .map(|func| func.name.clone()),
+            ),
+        }),
+    }
+}
+
 /// The result of a function, as an egglog expression.
 pub enum EgglogFunctionResult {
     /// The result of a function with no return values (namely, the "state edge"
@@ -211,6 +1173,324 @@ pub enum EgglogFunctionResult {
         /// The function return value.
         value: egglog::ast::Expr,
     },
+    StateAndValues {
+        /// The outgoing state edge for the function.
+        state: egglog::ast::Expr,
+        /// The function return values, in order.
+        values: Vec<egglog::ast::Expr>,
+    },
+}
+
+impl EgglogFunctionResult {
+    /// The state edge, followed by the values of the function (if any).
+    pub fn exprs(&self) -> Vec<&egglog::ast::Expr> {
+        match self {
+            EgglogFunctionResult::StateOnly(state) => vec![state],
+            EgglogFunctionResult::StateAndValue { state, value } => vec![state, value],
+            EgglogFunctionResult::StateAndValues { state, values } => {
+                std::iter::once(state).chain(values).collect()
+            }
+        }
+    }
+
+    /// The inverse of [EgglogFunctionResult::exprs]: the state edge, followed
+    /// by the values of the function.
+    pub fn from_exprs(mut exprs: Vec<egglog::ast::Expr>) -> EgglogFunctionResult {
+        assert!(!exprs.is_empty(), "functions always have a state edge");
+        let state = exprs.remove(0);
+        match exprs.len() {
+            0 => EgglogFunctionResult::StateOnly(state),
+            1 => EgglogFunctionResult::StateAndValue {
+                state,
+                value: exprs.remove(0),
+            },
+            _ => EgglogFunctionResult::StateAndValues {
+                state,
+                values: exprs,
+            },
+        }
+    }
+}
+
+impl RvsdgProgram {
+    /// Add every function to an egraph, run the constant-folding rules for up
+    /// to `iters` iterations, and extract the cheapest equivalent expression
+    /// for each function's outputs. Outputs are listed in the order given by
+    /// [EgglogFunctionResult::exprs].
+    pub fn optimize_egglog(
+        &self,
+        iters: usize,
+    ) -> std::result::Result<Vec<Vec<egglog::ast::Expr>>, EggCCError> {
+        self.optimize_egglog_with(iters, &[CONST_FOLD_RULESET], &DefaultCostModel)
+    }
+
+    /// Like [RvsdgProgram::optimize_egglog], but running each of `rulesets`
+    /// (in order, every iteration) and extracting the expressions that are
+    /// cheapest according to `model`.
+    pub fn optimize_egglog_with(
+        &self,
+        iters: usize,
+        rulesets: &[&str],
+        model: &dyn CostModel,
+    ) -> std::result::Result<Vec<Vec<egglog::ast::Expr>>, EggCCError> {
+        self.optimize_egglog_timed(
+            iters,
+            Rules {
+                rulesets,
+                custom: &[],
+            },
+            model,
+            SaturationLimit::default(),
+            &mut RunTimings::default(),
+            &mut Captures::default(),
+        )
+    }
+
+    /// Like [RvsdgProgram::optimize_egglog_with], saturating with `rules`,
+    /// stopping saturation early once `limit` is reached, and adding the time
+    /// spent in each phase to `timings`. Encoding the program and adding it
+    /// to the egraph counts as lowering.
+    ///
+    /// If `captures.egglog_program` is given, an equivalent standalone egglog
+    /// program is written to it: the schema and rules, a `let` for each
+    /// function output (named `{function}-{index}`) and for each node shared
+    /// between them (see [RvsdgFunction::to_egglog_lets]), the iterations that
+    /// were run, and an `extract` for each output. If `captures.egraph_json` is
+    /// given, the saturated egraph is serialized to it (see
+    /// [egraph_to_json]), with the function outputs as its roots.
+    pub(crate) fn optimize_egglog_timed(
+        &self,
+        iters: usize,
+        rules: Rules,
+        model: &dyn CostModel,
+        limit: SaturationLimit,
+        timings: &mut RunTimings,
+        captures: &mut Captures,
+    ) -> std::result::Result<Vec<Vec<egglog::ast::Expr>>, EggCCError> {
+        let start = Instant::now();
+        let mut egraph = new_rvsdg_egraph_with_costs(model)?;
+        let custom_rulesets = add_custom_rules(&mut egraph, rules.custom)?;
+        let mut lets = String::new();
+        let mut names = Vec::with_capacity(self.functions.len());
+        for func in &self.functions {
+            let (bindings, res) = func.to_egglog_lets(&func.name);
+            for (name, expr) in &bindings {
+                lets.push_str(&format!("(let {name} {expr})\n"));
+            }
+            let outputs = res.exprs().into_iter().enumerate().map(|(i, expr)| {
+                let name = format!("{}-{i}", func.name);
+                lets.push_str(&format!("(let {name} {expr})\n"));
+                name
+            });
+            names.push(Vec::from_iter(outputs));
+        }
+        egraph
+            .parse_and_run_program(&lets)
+            .map_err(EggCCError::EggLog)?;
+        timings.lowering += start.elapsed();
+
+        let start = Instant::now();
+        let rulesets = rules
+            .rulesets
+            .iter()
+            .copied()
+            .chain(custom_rulesets.iter().map(String::as_str));
+        let schedule = ruleset_schedule(rulesets);
+        let iters_run = limit.saturate(&mut egraph, iters, &schedule)?;
+        captures.iterations += iters_run;
+        timings.saturation += start.elapsed();
+
+        if let Some(program) = &mut captures.egglog_program {
+            program.push_str(&rvsdg_egglog_prelude(model)?);
+            for rules in rules.custom {
+                program.push_str(rules);
+                program.push('\n');
+            }
+            program.push_str(&lets);
+            program.push_str(&schedule.repeat(iters_run));
+            for name in names.iter().flatten() {
+                program.push_str(&format!("(extract {name})\n"));
+            }
+        }
+
+        let start = Instant::now();
+        let mut termdag = Default::default();
+        let mut optimized = Vec::with_capacity(names.len());
+        let mut roots = vec![];
+        for outputs in &names {
+            let mut exprs = vec![];
+            for name in outputs {
+                let (sort, value) = egraph
+                    .eval_expr(&egglog::ast::Expr::Var(name.as_str().into()), None, true)
+                    .map_err(EggCCError::EggLog)?;
+                let (_cost, term) = egraph.extract(value, &mut termdag, &sort);
+                exprs.push(termdag.term_to_expr(&term));
+                roots.push(value);
+            }
+            optimized.push(exprs);
+        }
+        timings.extraction += start.elapsed();
+
+        if let Some(json) = &mut captures.egraph_json {
+            *json = egraph_to_json(&egraph, &roots);
+        }
+        Ok(optimized)
+    }
+
+    /// Mark the calls to the functions named in `pure` as pure, throughout the
+    /// program (see [mark_pure_calls](optimize::mark_pure_calls)).
+    pub(crate) fn mark_pure_calls(&mut self, pure: &[String]) {
+        for func in &mut self.functions {
+            optimize::mark_pure_calls(func, pure);
+        }
+    }
+
+    /// Convert the program back into Bril as is, without optimizing it.
+    pub(crate) fn to_bril(&self) -> Result<Program> {
+        let functions = self
+            .functions
+            .iter()
+            .map(|func| Ok(rvsdg_to_cfg(func)?.to_bril()))
+            .collect::<Result<_>>()?;
+        Ok(Program {
+            functions,
+            imports: vec![],
+        })
+    }
+
+    /// Like [RvsdgProgram::optimize_egglog_timed], converting the extracted
+    /// expressions back into a Bril program. Converting back counts as
+    /// extraction.
+    pub(crate) fn optimize_to_bril_timed(
+        &self,
+        iters: usize,
+        rules: Rules,
+        model: &dyn CostModel,
+        limit: SaturationLimit,
+        timings: &mut RunTimings,
+        captures: &mut Captures,
+    ) -> std::result::Result<Program, EggCCError> {
+        let optimized =
+            self.optimize_egglog_timed(iters, rules, model, limit, timings, captures)?;
+        let start = Instant::now();
+        let mut functions = Vec::with_capacity(optimized.len());
+        for (func, exprs) in self.functions.iter().zip(optimized) {
+            let res = EgglogFunctionResult::from_exprs(exprs);
+            let func = RvsdgFunction::egglog_expr_to_function(&res, &func.name, &func.args)
+                .map_err(|err| EggCCError::ConversionError(err.to_string()))?;
+            let cfg = rvsdg_to_cfg(&func).map_err(EggCCError::RvsdgError)?;
+            functions.push(cfg.to_bril());
+        }
+        timings.extraction += start.elapsed();
+        Ok(Program {
+            functions,
+            imports: vec![],
+        })
+    }
+}
+
+/// Serialize `egraph` to the JSON format that egraph visualization tools
+/// read, with the e-classes of `roots` as its root e-classes. The format
+/// doesn't record how big each e-class is, so we also add an `eclass_sizes`
+/// field mapping each e-class to its number of e-nodes.
+fn egraph_to_json(egraph: &EGraph, roots: &[egglog::Value]) -> String {
+    let serialized = egraph.serialize(SerializeConfig::default());
+    let mut json = serde_json::to_value(&serialized).unwrap();
+    let mut sizes = serde_json::Map::new();
+    for node in json["nodes"].as_object().unwrap().values() {
+        let eclass = node["eclass"].as_str().unwrap().to_string();
+        let size = sizes.entry(eclass).or_insert(0.into());
+        *size = (size.as_u64().unwrap() + 1).into();
+    }
+    json["eclass_sizes"] = sizes.into();
+    // egglog names the e-class of a value by its sort and id.
+    json["root_eclasses"] = Vec::from_iter(
+        roots
+            .iter()
+            .map(|value| format!("{}-{}", value.tag, value.bits)),
+    )
+    .into();
+    json.to_string()
+}
+
+/// The names of the `let`s that functions are bound to by
+/// [program_to_egglog], by function name.
+type Lambdas = HashMap<String, String>;
+
+/// What [RvsdgFunction]s are encoded in egglog with references to, rather
+/// than spelling them out in full.
+struct Bindings<'a> {
+    /// The functions bound to a lambda by [program_to_egglog].
+    lambdas: &'a Lambdas,
+    /// The nodes bound by [RvsdgFunction::to_egglog_lets]. Ids are only
+    /// meaningful in the heap they come from, so lambda bodies are encoded
+    /// without these.
+    nodes: HashMap<Id, String>,
+}
+
+impl<'a> Bindings<'a> {
+    fn new(lambdas: &'a Lambdas) -> Bindings<'a> {
+        Bindings {
+            lambdas,
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// The encoding of the node `id` in `nodes`: a reference to its binding if
+    /// there is one, and the node itself otherwise.
+    fn body(&self, nodes: &[RvsdgBody], id: Id) -> egglog::ast::Expr {
+        match self.nodes.get(&id) {
+            Some(name) => egglog::ast::Expr::Var(name.as_str().into()),
+            None => RvsdgFunction::body_to_egglog_expr(nodes, self, &nodes[id]),
+        }
+    }
+}
+
+/// Count the uses of each node reachable from `op`, adding the nodes to
+/// `order` after all of their operands the first time they are reached.
+fn count_uses(nodes: &[RvsdgBody], op: &Operand, uses: &mut [usize], order: &mut Vec<Id>) {
+    let (Operand::Id(id) | Operand::Project(_, id)) = *op else {
+        return;
+    };
+    uses[id] += 1;
+    if uses[id] == 1 {
+        nodes[id].for_each_operand(|op| count_uses(nodes, op, uses, order));
+        order.push(id);
+    }
+}
+
+/// Encode all of `program` as a single egglog program, so that rules can see
+/// across function boundaries. Each function becomes a `Lambda` whose outputs
+/// are its results followed by its state edge (the same order as the outputs
+/// of a call), bound by a `let` named `lambda-{name}`. The program has to be
+/// run after the RVSDG schema, e.g. in an egraph from [new_rvsdg_egraph].
+///
+/// Calls become `Apply`s of the callee's binding. Functions are bound callees
+/// first, so calls between mutually recursive functions (or to functions
+/// outside of `program`) can't refer to a binding and are encoded as plain
+/// `Call`s instead.
+pub fn program_to_egglog(program: &[RvsdgFunction]) -> String {
+    use egglog::ast::{Expr::*, Literal::Int};
+    let mut lambdas = Lambdas::new();
+    let mut res = String::new();
+    for scc in call_graph::call_graph(program).sccs() {
+        for &i in scc {
+            let func = &program[i];
+            let bindings = Bindings::new(&lambdas);
+            let outputs = func.results.iter().chain([&func.state]).map(|output| {
+                RvsdgFunction::operand_to_egglog_expr(&func.nodes, &bindings, output)
+            });
+            let n_args = Lit(Int(i64::try_from(func.args.len() + 1).unwrap()));
+            let lambda = Call(
+                "Lambda".into(),
+                vec![n_args, Call("vec-of".into(), outputs.collect())],
+            );
+            let name = format!("lambda-{}", func.name);
+            res.push_str(&format!("(let {name} {lambda})\n"));
+            lambdas.insert(func.name.clone(), name);
+        }
+    }
+    res
 }
 
 impl RvsdgFunction {
@@ -225,14 +1505,22 @@ impl RvsdgFunction {
         }
     }
 
-    fn expr_to_egglog_expr(&self, expr: &Expr<Operand>) -> egglog::ast::Expr {
+    fn expr_to_egglog_expr(
+        nodes: &[RvsdgBody],
+        bindings: &Bindings,
+        expr: &Expr<Operand>,
+    ) -> egglog::ast::Expr {
         use egglog::ast::{Expr::*, Literal::*};
-        let f = |operands: &Vec<Operand>, ty: Option<Type>| {
+        let f = |operands: &[Operand], ty: Option<Type>| {
             let mut res = Vec::with_capacity(operands.len() + ty.is_some() as usize);
             if let Some(ty) = ty {
                 res.push(Self::expr_from_ty(&ty));
             }
-            res.extend(operands.iter().map(|op| self.operand_to_egglog_expr(op)));
+            res.extend(
+                operands
+                    .iter()
+                    .map(|op| Self::operand_to_egglog_expr(nodes, bindings, op)),
+            );
             res
         };
 
@@ -240,11 +1528,48 @@ impl RvsdgFunction {
             Expr::Op(op, operands, ty) => {
                 Call(op.to_string().into(), f(operands, Some(ty.clone())))
             }
+            // The state edge is encoded separately, see `body_to_egglog_expr`.
+            Expr::EffectfulOp(op, operands, ty) => Call(
+                op.to_string().into(),
+                f(&operands[..operands.len() - 1], Some(ty.clone())),
+            ),
+            Expr::Effect(op, operands) | Expr::Speculation(op, operands) => Call(
+                op.to_string().into(),
+                f(&operands[..operands.len() - 1], None),
+            ),
             // TODO I'm pretty sure this conversion isn't right
-            Expr::Call(ident, operands, _, ty) => {
+            Expr::Call(ident, operands, _, ty, false) => {
                 Call(ident.to_string().into(), f(operands, ty.clone()))
             }
-            Expr::Print(operands) => Call("PRINT".into(), f(operands, None)),
+            // Pure calls are named apart, since they have no state edge.
+            Expr::Call(ident, operands, _, ty, true) => {
+                let ty = ty.as_ref().expect("pure calls return a value");
+                Call(
+                    "PureCall".into(),
+                    vec![
+                        Self::expr_from_ty(ty),
+                        Lit(String(ident.to_string().into())),
+                        Call("vec-of".into(), f(operands, None)),
+                    ],
+                )
+            }
+            // Prints are variadic, so their arguments are collected into a
+            // vector. As with effects, the state edge is encoded separately.
+            Expr::Print(operands) => Call(
+                "print".into(),
+                vec![Call(
+                    "vec-of".into(),
+                    f(&operands[..operands.len() - 1], None),
+                )],
+            ),
+            Expr::Select(operands, ty) => Call("select".into(), f(operands, Some(ty.clone()))),
+            Expr::UnsignedCmp(op, operands) => Call(
+                unsigned_cmp_to_egglog(*op).into(),
+                f(operands, Some(Type::Bool)),
+            ),
+            Expr::Bitwise(op, operands) => {
+                Call(bitop_to_egglog(*op).into(), f(operands, Some(Type::Int)))
+            }
             Expr::Const(ConstOps::Const, lit, ty) => {
                 let lit = match (ty, lit) {
                     (Type::Int, Literal::Int(n)) => Call("Num".into(), vec![Lit(Int(*n))]),
@@ -272,24 +1597,61 @@ impl RvsdgFunction {
         }
     }
 
-    fn body_to_egglog_expr(&self, body: &RvsdgBody) -> egglog::ast::Expr {
-        use egglog::ast::Expr::*;
+    fn body_to_egglog_expr(
+        nodes: &[RvsdgBody],
+        bindings: &Bindings,
+        body: &RvsdgBody,
+    ) -> egglog::ast::Expr {
+        use egglog::ast::{Expr::*, Literal::*};
         match body {
-            RvsdgBody::BasicOp(expr) => Call("PureOp".into(), vec![self.expr_to_egglog_expr(expr)]),
+            RvsdgBody::BasicOp(
+                expr @ (Expr::EffectfulOp(_, operands, _)
+                | Expr::Effect(_, operands)
+                | Expr::Speculation(_, operands)
+                | Expr::Print(operands)),
+            ) => {
+                let state = operands.last().expect("missing state edge");
+                Call(
+                    "Effectful".into(),
+                    vec![
+                        Self::expr_to_egglog_expr(nodes, bindings, expr),
+                        Self::operand_to_egglog_expr(nodes, bindings, state),
+                    ],
+                )
+            }
+            // Calls to functions that have been bound to a lambda (see
+            // [program_to_egglog]) apply that lambda instead.
+            RvsdgBody::BasicOp(Expr::Call(callee, operands, _, _, false))
+                if bindings.lambdas.contains_key(&callee.to_string()) =>
+            {
+                let func = Call(
+                    "Node".into(),
+                    vec![Var(bindings.lambdas[&callee.to_string()].as_str().into())],
+                );
+                let args = operands
+                    .iter()
+                    .map(|arg| Self::operand_to_egglog_expr(nodes, bindings, arg));
+                let args = Call("vec-of".into(), args.collect());
+                Call("Apply".into(), vec![func, args])
+            }
+            RvsdgBody::BasicOp(expr) => Call(
+                "PureOp".into(),
+                vec![Self::expr_to_egglog_expr(nodes, bindings, expr)],
+            ),
             RvsdgBody::Gamma {
                 pred,
                 inputs,
                 outputs,
             } => {
-                let pred = self.operand_to_egglog_expr(pred);
+                let pred = Self::operand_to_egglog_expr(nodes, bindings, pred);
                 let inputs = inputs
                     .iter()
-                    .map(|input| self.operand_to_egglog_expr(input));
+                    .map(|input| Self::operand_to_egglog_expr(nodes, bindings, input));
                 let inputs = Call("vec-of".into(), inputs.collect());
                 let outputs = outputs.iter().map(|region| {
                     let region = region
                         .iter()
-                        .map(|output| self.operand_to_egglog_expr(output));
+                        .map(|output| Self::operand_to_egglog_expr(nodes, bindings, output));
                     Call("VO".into(), vec![Call("vec-of".into(), region.collect())])
                 });
                 let outputs = Call("vec-of".into(), outputs.collect());
@@ -300,30 +1662,52 @@ impl RvsdgFunction {
                 inputs,
                 outputs,
             } => {
-                let pred = self.operand_to_egglog_expr(pred);
+                let pred = Self::operand_to_egglog_expr(nodes, bindings, pred);
                 let inputs = inputs
                     .iter()
-                    .map(|input| self.operand_to_egglog_expr(input));
+                    .map(|input| Self::operand_to_egglog_expr(nodes, bindings, input));
                 let inputs = Call("vec-of".into(), inputs.collect());
                 let outputs = outputs
                     .iter()
-                    .map(|output| self.operand_to_egglog_expr(output));
+                    .map(|output| Self::operand_to_egglog_expr(nodes, bindings, output));
                 let outputs = Call("vec-of".into(), outputs.collect());
                 Call("Theta".into(), vec![pred, inputs, outputs])
             }
+            RvsdgBody::Lambda {
+                n_args,
+                nodes: body,
+                outputs,
+            } => {
+                let n_args = Lit(Int(i64::try_from(*n_args).unwrap()));
+                let bindings = Bindings::new(bindings.lambdas);
+                let outputs = outputs
+                    .iter()
+                    .map(|output| Self::operand_to_egglog_expr(body, &bindings, output));
+                let outputs = Call("vec-of".into(), outputs.collect());
+                Call("Lambda".into(), vec![n_args, outputs])
+            }
+            RvsdgBody::Apply { func, args } => {
+                let func = Self::operand_to_egglog_expr(nodes, bindings, func);
+                let args = args
+                    .iter()
+                    .map(|arg| Self::operand_to_egglog_expr(nodes, bindings, arg));
+                let args = Call("vec-of".into(), args.collect());
+                Call("Apply".into(), vec![func, args])
+            }
         }
     }
 
-    fn operand_to_egglog_expr(&self, op: &Operand) -> egglog::ast::Expr {
+    fn operand_to_egglog_expr(
+        nodes: &[RvsdgBody],
+        bindings: &Bindings,
+        op: &Operand,
+    ) -> egglog::ast::Expr {
         use egglog::ast::{Expr::*, Literal::*};
         match op {
             Operand::Arg(p) => Call("Arg".into(), vec![Lit(Int(i64::try_from(*p).unwrap()))]),
-            Operand::Id(id) => Call(
-                "Node".into(),
-                vec![self.body_to_egglog_expr(&self.nodes[*id])],
-            ),
+            Operand::Id(id) => Call("Node".into(), vec![bindings.body(nodes, *id)]),
             Operand::Project(i, id) => {
-                let body = self.body_to_egglog_expr(&self.nodes[*id]);
+                let body = bindings.body(nodes, *id);
                 Call(
                     "Project".into(),
                     vec![Lit(Int(i64::try_from(*i).unwrap())), body],
@@ -333,12 +1717,52 @@ impl RvsdgFunction {
     }
 
     pub fn to_egglog_expr(&self) -> EgglogFunctionResult {
-        let state = self.operand_to_egglog_expr(&self.state);
-        if let Some(result) = &self.result {
-            let value = self.operand_to_egglog_expr(result);
-            EgglogFunctionResult::StateAndValue { state, value }
-        } else {
-            EgglogFunctionResult::StateOnly(state)
+        self.to_egglog_expr_with(&Bindings::new(&Lambdas::new()))
+    }
+
+    /// Like [RvsdgFunction::to_egglog_expr], but with each node that is used
+    /// more than once bound by a `let` (named `{prefix}-n{id}`) and referred to
+    /// by name, so that the encoding grows with the number of nodes rather than
+    /// the number of paths through them. The bindings are returned in the order
+    /// they have to be run, and the outputs refer to them.
+    pub(crate) fn to_egglog_lets(
+        &self,
+        prefix: &str,
+    ) -> (Vec<(String, egglog::ast::Expr)>, EgglogFunctionResult) {
+        let mut uses = vec![0; self.nodes.len()];
+        let mut order = vec![];
+        for output in self.results.iter().chain([&self.state]) {
+            count_uses(&self.nodes, output, &mut uses, &mut order);
+        }
+        let lambdas = Lambdas::new();
+        let mut bindings = Bindings::new(&lambdas);
+        let mut lets = vec![];
+        // Operands are visited before the nodes that use them, so every
+        // binding only refers to earlier ones.
+        for id in order.into_iter().filter(|id| uses[*id] > 1) {
+            let expr = Self::body_to_egglog_expr(&self.nodes, &bindings, &self.nodes[id]);
+            let name = format!("{prefix}-n{id}");
+            lets.push((name.clone(), expr));
+            bindings.nodes.insert(id, name);
+        }
+        (lets, self.to_egglog_expr_with(&bindings))
+    }
+
+    fn to_egglog_expr_with(&self, bindings: &Bindings) -> EgglogFunctionResult {
+        let state = Self::operand_to_egglog_expr(&self.nodes, bindings, &self.state);
+        match self.results.as_slice() {
+            [] => EgglogFunctionResult::StateOnly(state),
+            [result] => {
+                let value = Self::operand_to_egglog_expr(&self.nodes, bindings, result);
+                EgglogFunctionResult::StateAndValue { state, value }
+            }
+            results => {
+                let values = results
+                    .iter()
+                    .map(|result| Self::operand_to_egglog_expr(&self.nodes, bindings, result))
+                    .collect();
+                EgglogFunctionResult::StateAndValues { state, values }
+            }
         }
     }
 
@@ -359,10 +1783,32 @@ impl RvsdgFunction {
     }
 
     fn egglog_expr_to_body(body: &egglog::ast::Expr, bodies: &mut Vec<RvsdgBody>) -> Id {
-        use egglog::ast::Expr::*;
+        use egglog::ast::{Expr::*, Literal::*};
         if let Call(func, args) = body {
             let body = match (func.as_str(), &args.as_slice()) {
                 ("PureOp", [expr]) => RvsdgBody::BasicOp(Self::egglog_expr_to_expr(expr, bodies)),
+                ("Effectful", [expr, state]) => {
+                    let state = Self::egglog_expr_to_operand(state, bodies);
+                    match Self::egglog_expr_to_expr(expr, bodies) {
+                        Expr::Op(op, mut operands, ty) => {
+                            operands.push(state);
+                            RvsdgBody::BasicOp(Expr::EffectfulOp(op, operands, ty))
+                        }
+                        Expr::Effect(op, mut operands) => {
+                            operands.push(state);
+                            RvsdgBody::BasicOp(Expr::Effect(op, operands))
+                        }
+                        Expr::Speculation(op, mut operands) => {
+                            operands.push(state);
+                            RvsdgBody::BasicOp(Expr::Speculation(op, operands))
+                        }
+                        Expr::Print(mut operands) => {
+                            operands.push(state);
+                            RvsdgBody::BasicOp(Expr::Print(operands))
+                        }
+                        _ => panic!("expect an effectful operation, got {expr}"),
+                    }
+                }
                 ("Gamma", [pred, inputs, outputs]) => {
                     let pred = Self::egglog_expr_to_operand(pred, bodies);
                     let inputs = vec_map(inputs, |e| Self::egglog_expr_to_operand(e, bodies));
@@ -392,6 +1838,21 @@ impl RvsdgFunction {
                         outputs,
                     }
                 }
+                ("Lambda", [Lit(Int(n_args)), outputs]) => {
+                    // Lambdas get their own heap of nodes.
+                    let mut nodes = vec![];
+                    let outputs = vec_map(outputs, |e| Self::egglog_expr_to_operand(e, &mut nodes));
+                    RvsdgBody::Lambda {
+                        n_args: *n_args as usize,
+                        nodes,
+                        outputs,
+                    }
+                }
+                ("Apply", [func, args]) => {
+                    let func = Self::egglog_expr_to_operand(func, bodies);
+                    let args = vec_map(args, |e| Self::egglog_expr_to_operand(e, bodies));
+                    RvsdgBody::Apply { func, args }
+                }
                 _ => panic!("expect an operand, got {body}"),
             };
             bodies.push(body);
@@ -413,14 +1874,69 @@ impl RvsdgFunction {
                         args,
                         1,
                         Some(Self::egglog_expr_to_ty(ty)),
+                        false,
+                    )
+                }
+                ("PureCall", [ty, egglog::ast::Expr::Lit(Literal::String(ident)), args]) => {
+                    let args = vec_map(args, |e| Self::egglog_expr_to_operand(e, bodies));
+                    Expr::Call(
+                        Identifier::Name(ident.to_string()),
+                        args,
+                        1,
+                        Some(Self::egglog_expr_to_ty(ty)),
+                        true,
                     )
                 }
+                ("store", [ptr, val]) => Expr::Effect(
+                    EffectOps::Store,
+                    vec![
+                        Self::egglog_expr_to_operand(ptr, bodies),
+                        Self::egglog_expr_to_operand(val, bodies),
+                    ],
+                ),
+                ("free", [ptr]) => Expr::Effect(
+                    EffectOps::Free,
+                    vec![Self::egglog_expr_to_operand(ptr, bodies)],
+                ),
+                ("speculate", []) => Expr::Speculation(EffectOps::Speculate, vec![]),
+                ("commit", []) => Expr::Speculation(EffectOps::Commit, vec![]),
+                ("guard", [cond]) => Expr::Speculation(
+                    EffectOps::Guard,
+                    vec![Self::egglog_expr_to_operand(cond, bodies)],
+                ),
+                ("print", [args]) => {
+                    Expr::Print(vec_map(args, |e| Self::egglog_expr_to_operand(e, bodies)))
+                }
+                ("select", [ty, pred, then, els]) => Expr::Select(
+                    vec![
+                        Self::egglog_expr_to_operand(pred, bodies),
+                        Self::egglog_expr_to_operand(then, bodies),
+                        Self::egglog_expr_to_operand(els, bodies),
+                    ],
+                    Self::egglog_expr_to_ty(ty),
+                ),
                 ("Const", [ty, _const_op, lit]) => Expr::Const(
                     // todo remove the const op from the encoding because it is always ConstOps::Const
                     ConstOps::Const,
                     Self::egglog_expr_to_literal(lit),
                     Self::egglog_expr_to_ty(ty),
                 ),
+                (op @ ("ult" | "ule" | "ugt" | "uge"), [_ty, opr1, opr2]) => Expr::UnsignedCmp(
+                    egglog_to_unsigned_cmp(op).unwrap(),
+                    vec![
+                        Self::egglog_expr_to_operand(opr1, bodies),
+                        Self::egglog_expr_to_operand(opr2, bodies),
+                    ],
+                ),
+                (op @ ("shl" | "shr" | "bitand" | "bitor" | "bitxor"), [_ty, opr1, opr2]) => {
+                    Expr::Bitwise(
+                        egglog_to_bitop(op).unwrap(),
+                        vec![
+                            Self::egglog_expr_to_operand(opr1, bodies),
+                            Self::egglog_expr_to_operand(opr2, bodies),
+                        ],
+                    )
+                }
                 (binop, [ty, opr1, opr2]) => {
                     let opr1 = Self::egglog_expr_to_operand(opr1, bodies);
                     let opr2 = Self::egglog_expr_to_operand(opr2, bodies);
@@ -430,6 +1946,14 @@ impl RvsdgFunction {
                         Self::egglog_expr_to_ty(ty),
                     )
                 }
+                (unop, [ty, opr]) => {
+                    let opr = Self::egglog_expr_to_operand(opr, bodies);
+                    Expr::Op(
+                        egglog_op_to_bril(unop.into()),
+                        vec![opr],
+                        Self::egglog_expr_to_ty(ty),
+                    )
+                }
                 _ => panic!("expect an operand, got {expr}"),
             }
         } else {
@@ -458,10 +1982,15 @@ impl RvsdgFunction {
         if let Call(func, args) = lit {
             match (func.as_str(), &args.as_slice()) {
                 ("Num", [Lit(Int(n))]) => Literal::Int(*n),
+                ("Bool", [Lit(Int(b))]) => Literal::Bool(*b != 0),
                 ("Float", [Lit(F64(n))]) => Literal::Float(f64::from(*n)),
                 ("Char", [Lit(String(s))]) => {
-                    assert_eq!(s.as_str().len(), 1);
-                    Literal::Char(s.as_str().chars().next().unwrap())
+                    // Chars may take more than one byte to encode.
+                    let mut chars = s.as_str().chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => Literal::Char(c),
+                        _ => panic!("expect a single char, got {lit}"),
+                    }
                 }
                 _ => panic!("expect a list, got {lit}"),
             }
@@ -470,23 +1999,44 @@ impl RvsdgFunction {
         }
     }
 
-    pub fn egglog_expr_to_function(res: &EgglogFunctionResult, n_args: usize) -> RvsdgFunction {
+    /// Decode the outputs of a function named `name` with arguments of type
+    /// `args` from egglog, the inverse of [RvsdgFunction::to_egglog_expr].
+    ///
+    /// Nothing about the encoding keeps the `Arg`s in an expression in range
+    /// for the region they are used in (e.g. for hand-written egglog), so the
+    /// decoded function is checked with [validate](validate::validate).
+    pub fn egglog_expr_to_function(
+        res: &EgglogFunctionResult,
+        name: &str,
+        args: &[Type],
+    ) -> std::result::Result<RvsdgFunction, ValidationError> {
         let mut nodes = vec![];
-        let (result, state) = match res {
+        let (results, state) = match res {
             EgglogFunctionResult::StateOnly(state) => {
-                (None, Self::egglog_expr_to_operand(state, &mut nodes))
+                (vec![], Self::egglog_expr_to_operand(state, &mut nodes))
             }
             EgglogFunctionResult::StateAndValue { state, value } => (
-                Some(Self::egglog_expr_to_operand(value, &mut nodes)),
+                vec![Self::egglog_expr_to_operand(value, &mut nodes)],
+                Self::egglog_expr_to_operand(state, &mut nodes),
+            ),
+            EgglogFunctionResult::StateAndValues { state, values } => (
+                values
+                    .iter()
+                    .map(|value| Self::egglog_expr_to_operand(value, &mut nodes))
+                    .collect(),
                 Self::egglog_expr_to_operand(state, &mut nodes),
             ),
         };
-        RvsdgFunction {
-            n_args,
+        let func = RvsdgFunction {
+            name: name.to_string(),
+            args: args.to_vec(),
+            return_type: RvsdgFunction::infer_return_type(&nodes, args, &results),
             nodes,
-            result,
+            results,
             state,
-        }
+        };
+        validate::validate(&func)?;
+        Ok(func)
     }
 }
 
@@ -519,9 +2069,181 @@ fn vec_map<T>(inputs: &egglog::ast::Expr, mut f: impl FnMut(&egglog::ast::Expr)
     results
 }
 
+/// The ruleset in `optimizations.egg` for rewriting terms into the form that
+/// the other rulesets match. [ruleset_schedule] runs it before them.
+pub(crate) const NORMALIZE_RULESET: &str = "normalize";
+
+/// One iteration of running [NORMALIZE_RULESET] and then each of `rulesets`,
+/// in order, as egglog commands.
+pub(crate) fn ruleset_schedule<'a>(rulesets: impl IntoIterator<Item = &'a str>) -> String {
+    String::from_iter(
+        std::iter::once(NORMALIZE_RULESET)
+            .chain(rulesets)
+            .map(|ruleset| format!("(run {ruleset} 1)\n")),
+    )
+}
+
+/// The ruleset in `optimizations.egg` for folding arithmetic on constants.
+pub(crate) const CONST_FOLD_RULESET: &str = "const-fold";
+
+/// The ruleset in `optimizations.egg` for selecting the taken branch of gammas
+/// with constant predicates.
+pub(crate) const GAMMA_SIMPLIFY_RULESET: &str = "gamma-simplify";
+
+/// The ruleset in `optimizations.egg` for simplifying `and`, `or`, and `not`
+/// applied to constants or to each other.
+pub(crate) const BOOL_SIMPLIFY_RULESET: &str = "bool-simplify";
+
+/// The ruleset in `optimizations.egg` for simplifying comparisons of a value
+/// with itself and negated comparisons.
+pub(crate) const CMP_SIMPLIFY_RULESET: &str = "cmp-simplify";
+
+/// The ruleset in `optimizations.egg` for replacing multiplications by
+/// constants with cheaper operations. This can introduce shifts, which have
+/// no Bril counterpart.
+pub(crate) const STRENGTH_REDUCE_RULESET: &str = "strength-reduce";
+
+/// The ruleset in `optimizations.egg` for identities like `x + 0 = x` and
+/// `x - x = 0`.
+pub(crate) const ALGEBRAIC_SIMPLIFY_RULESET: &str = "algebraic-simplify";
+
+/// The ruleset in `optimizations.egg` for commuting additions. This can grow
+/// the egraph considerably, so it is only run at the highest opt level.
+pub(crate) const COMMUTE_RULESET: &str = "commute";
+
+/// The ruleset in `optimizations.egg` for reassociating chains of additions
+/// and multiplications, so that e.g. `(a + 1) + 2` folds to `a + 3`. Like
+/// [COMMUTE_RULESET], this is only run at the highest opt level.
+pub(crate) const REASSOCIATE_RULESET: &str = "reassociate";
+
+/// How hard a [Run](crate::util::Run) should try to optimize the program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    /// Don't run any rewrites.
+    None,
+    /// Fold constants and apply cheap algebraic identities.
+    #[default]
+    Basic,
+    /// Run every rewrite we have, including ones (like commutativity and
+    /// associativity) that can make the egraph much larger.
+    Aggressive,
+}
+
+impl fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptLevel::None => write!(f, "opt-none"),
+            OptLevel::Basic => write!(f, "opt-basic"),
+            OptLevel::Aggressive => write!(f, "opt-aggressive"),
+        }
+    }
+}
+
+impl OptLevel {
+    /// The RVSDG rulesets to saturate with at this level.
+    pub(crate) fn rulesets(&self) -> &'static [&'static str] {
+        match self {
+            OptLevel::None => &[],
+            OptLevel::Basic => &[
+                CONST_FOLD_RULESET,
+                ALGEBRAIC_SIMPLIFY_RULESET,
+                CMP_SIMPLIFY_RULESET,
+            ],
+            OptLevel::Aggressive => &[
+                CONST_FOLD_RULESET,
+                ALGEBRAIC_SIMPLIFY_RULESET,
+                CMP_SIMPLIFY_RULESET,
+                GAMMA_SIMPLIFY_RULESET,
+                BOOL_SIMPLIFY_RULESET,
+                STRENGTH_REDUCE_RULESET,
+                COMMUTE_RULESET,
+                REASSOCIATE_RULESET,
+            ],
+        }
+    }
+}
+
+/// The rules the RVSDG optimizer saturates with.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Rules<'a> {
+    /// The built-in rulesets to run, in order, every iteration.
+    pub(crate) rulesets: &'a [&'a str],
+    /// Extra egglog commands to run once the schema is loaded, such as
+    /// definitions of rulesets and the rules in them (see
+    /// [add_custom_rules]). The rulesets they define are run every
+    /// iteration, after the built-in ones.
+    pub(crate) custom: &'a [String],
+}
+
+/// An egraph with the RVSDG schema and the rulesets in `optimizations.egg`
+/// installed, using the [DefaultCostModel] for extraction. No rules are run.
 pub fn new_rvsdg_egraph() -> EGraph {
-    let mut egraph = EGraph::default();
-    let schema = std::fs::read_to_string("src/rvsdg/schema.egg").unwrap();
-    egraph.parse_and_run_program(schema.as_str()).unwrap();
+    new_rvsdg_egraph_with_costs(&DefaultCostModel).unwrap()
+}
+
+/// Run each of `rules` (a sequence of egglog commands, e.g. declaring a
+/// ruleset and adding rewrites to it) in `egraph`, returning the names of the
+/// rulesets they declare. Rules that fail to parse or typecheck against the
+/// RVSDG schema are reported as [EggCCError::InvalidRules].
+pub(crate) fn add_custom_rules(
+    egraph: &mut EGraph,
+    rules: &[String],
+) -> std::result::Result<Vec<String>, EggCCError> {
+    let mut rulesets = vec![];
+    for rules in rules {
+        let commands = egraph
+            .parse_program(rules)
+            .map_err(EggCCError::InvalidRules)?;
+        rulesets.extend(commands.iter().filter_map(|command| match command {
+            Command::AddRuleset(name) => Some(name.to_string()),
+            _ => None,
+        }));
+        egraph
+            .run_program(commands)
+            .map_err(EggCCError::InvalidRules)?;
+    }
+    Ok(rulesets)
+}
+
+/// How many iterations of the rulesets [assert_egraph_equivalent] runs.
+const EQUIVALENCE_ITERS: usize = 10;
+
+/// Whether the rewrite rules show `original` and `optimized` to be equivalent.
+/// Both functions are added to a fresh [new_rvsdg_egraph], every ruleset is
+/// run, and each output (the state edge, then the results) of one function is
+/// checked to be in the same eclass as the corresponding output of the other.
+///
+/// Unlike [RvsdgFunction::structurally_eq], this sees through rewrites (e.g.
+/// `1 + 2` is equivalent to `3`). It is incomplete, though: functions that the
+/// rules can't relate are reported as different even if they aren't.
+pub(crate) fn assert_egraph_equivalent(
+    original: &RvsdgFunction,
+    optimized: &RvsdgFunction,
+) -> bool {
+    let (original_lets, original) = original.to_egglog_lets("original");
+    let (optimized_lets, optimized) = optimized.to_egglog_lets("optimized");
+    let (original, optimized) = (original.exprs(), optimized.exprs());
+    if original.len() != optimized.len() {
+        return false;
+    }
+    let mut program = String::new();
+    for (name, expr) in original_lets.iter().chain(&optimized_lets) {
+        program.push_str(&format!("(let {name} {expr})\n"));
+    }
+    for (i, (l, r)) in original.iter().zip(&optimized).enumerate() {
+        program.push_str(&format!(
+            "(let original-{i} {l})\n(let optimized-{i} {r})\n"
+        ));
+    }
+    let schedule = ruleset_schedule(OptLevel::Aggressive.rulesets().iter().copied());
+    program.push_str(&schedule.repeat(EQUIVALENCE_ITERS));
+    let mut egraph = new_rvsdg_egraph();
     egraph
+        .parse_and_run_program(&program)
+        .expect("failed to run the rewrite rules");
+    (0..original.len()).all(|i| {
+        egraph
+            .parse_and_run_program(&format!("(check (= original-{i} optimized-{i}))"))
+            .is_ok()
+    })
 }
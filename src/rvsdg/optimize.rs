@@ -0,0 +1,2773 @@
+//! Optimizations implemented directly over [RvsdgFunction]s, without going
+//! through egglog.
+use std::{
+    collections::{HashMap, HashSet},
+    iter::once,
+    mem,
+};
+
+use bril_rs::{ConstOps, EffectOps, Literal, Type, ValueOps};
+
+use super::{
+    call_graph::call_graph, operands_structurally_eq, Expr, Id, Operand, RvsdgBody, RvsdgFunction,
+};
+
+/// Remove the nodes in `func` that are not reachable from its results or its
+/// state edge, renumbering the remaining nodes (and every operand pointing at
+/// them) to keep `nodes` contiguous. The bodies of lambdas are pruned as well.
+pub(crate) fn prune_dead_nodes(func: &mut RvsdgFunction) {
+    prune(
+        &mut func.nodes,
+        func.results.iter_mut().chain(once(&mut func.state)),
+    );
+}
+
+fn node_of(op: &Operand) -> Option<Id> {
+    match op {
+        Operand::Arg(_) => None,
+        Operand::Id(id) | Operand::Project(_, id) => Some(*id),
+    }
+}
+
+/// Point `op` at what it was replaced by, as given by `replacement` for each
+/// output of a node, following replacements until reaching an operand that
+/// wasn't replaced.
+fn resolve_replaced(op: &mut Operand, replacement: impl Fn(usize, Id) -> Option<Operand>) {
+    while let Some(new_op) = match *op {
+        Operand::Arg(_) => None,
+        Operand::Id(id) => replacement(0, id),
+        Operand::Project(output, id) => replacement(output, id),
+    } {
+        *op = new_op;
+    }
+}
+
+fn prune<'a>(nodes: &mut Vec<RvsdgBody>, roots: impl Iterator<Item = &'a mut Operand>) {
+    let roots = Vec::from_iter(roots);
+    let mut live = vec![false; nodes.len()];
+    let mut stack = Vec::from_iter(roots.iter().filter_map(|op| node_of(op)));
+    while let Some(id) = stack.pop() {
+        if live[id] {
+            continue;
+        }
+        live[id] = true;
+        nodes[id].for_each_operand(|op| stack.extend(node_of(op)));
+    }
+
+    let mut renamed = vec![None; nodes.len()];
+    let live_ids = live.iter().enumerate().filter(|(_, live)| **live);
+    for (next, (id, _)) in live_ids.enumerate() {
+        renamed[id] = Some(next);
+    }
+    let rename = |op: &mut Operand| match op {
+        Operand::Arg(_) => {}
+        Operand::Id(id) | Operand::Project(_, id) => {
+            *id = renamed[*id].expect("live node points to a dead node")
+        }
+    };
+
+    for (id, mut body) in mem::take(nodes).into_iter().enumerate() {
+        if !live[id] {
+            continue;
+        }
+        body.for_each_operand_mut(rename);
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = &mut body
+        {
+            prune(lambda_nodes, outputs.iter_mut());
+        }
+        nodes.push(body);
+    }
+    roots.into_iter().for_each(rename);
+}
+
+/// Number the nodes of `func` in a canonical order: a post-order depth-first
+/// traversal from its results and then its state edge, visiting the operands
+/// of each node in order. The numbering only depends on the structure of the
+/// function, not on the order its nodes happened to be added in, which keeps
+/// visualizations stable. Like [prune_dead_nodes], this drops unreachable
+/// nodes, and lambda bodies are renumbered as well.
+pub(crate) fn renumber_nodes(func: &mut RvsdgFunction) {
+    renumber(
+        &mut func.nodes,
+        func.results.iter_mut().chain(once(&mut func.state)),
+    );
+}
+
+pub(crate) fn renumber<'a>(
+    nodes: &mut Vec<RvsdgBody>,
+    roots: impl Iterator<Item = &'a mut Operand>,
+) {
+    let roots = Vec::from_iter(roots);
+    let mut renamed = vec![None; nodes.len()];
+    let mut order = Vec::with_capacity(nodes.len());
+    // Nodes to visit, and whether their operands have been visited already.
+    let mut stack = Vec::from_iter(
+        roots
+            .iter()
+            .rev()
+            .filter_map(|op| node_of(op))
+            .map(|id| (id, false)),
+    );
+    while let Some((id, visited)) = stack.pop() {
+        if renamed[id].is_some() {
+            continue;
+        }
+        if visited {
+            renamed[id] = Some(order.len());
+            order.push(id);
+            continue;
+        }
+        stack.push((id, true));
+        let mut operands = vec![];
+        nodes[id].for_each_operand(|op| operands.extend(node_of(op)));
+        stack.extend(operands.into_iter().rev().map(|id| (id, false)));
+    }
+
+    let rename = |op: &mut Operand| match op {
+        Operand::Arg(_) => {}
+        Operand::Id(id) | Operand::Project(_, id) => {
+            *id = renamed[*id].expect("reachable node points to an unreachable node")
+        }
+    };
+    let mut old = Vec::from_iter(mem::take(nodes).into_iter().map(Some));
+    for id in order {
+        let mut body = old[id].take().unwrap();
+        body.for_each_operand_mut(rename);
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = &mut body
+        {
+            renumber(lambda_nodes, outputs.iter_mut());
+        }
+        nodes.push(body);
+    }
+    roots.into_iter().for_each(rename);
+}
+
+/// Remove the arguments of `func` that it never reads, renumbering the
+/// `Operand::Arg`s that refer to the remaining ones. The state edge is always
+/// kept. Returns the indices of the removed arguments, in increasing order, so
+/// that calls to `func` can stop passing them.
+pub(crate) fn drop_unused_args(func: &mut RvsdgFunction) -> Vec<usize> {
+    let roots = || func.results.iter().copied().chain(once(func.state));
+    let used = region_args(&func.nodes, roots());
+    let removed = Vec::from_iter((0..func.args.len()).filter(|arg| !used.contains(arg)));
+    if removed.is_empty() {
+        return removed;
+    }
+
+    // Only the function's own region refers to its arguments: nested regions
+    // have arguments of their own.
+    let renamed = Vec::from_iter(
+        (0..=func.args.len()).map(|arg| arg - removed.iter().filter(|r| **r < arg).count()),
+    );
+    let rename = |op: &mut Operand| {
+        if let Operand::Arg(arg) = op {
+            *arg = renamed[*arg];
+        }
+    };
+    for id in outer_region(&func.nodes, roots()) {
+        region_operands_mut(&mut func.nodes[id])
+            .into_iter()
+            .for_each(rename);
+    }
+    func.results.iter_mut().for_each(rename);
+    rename(&mut func.state);
+
+    let mut arg = 0;
+    func.args.retain(|_| {
+        arg += 1;
+        !removed.contains(&(arg - 1))
+    });
+    removed
+}
+
+/// Refer to the output of every single-output basic operation in `func` as
+/// `Operand::Id(x)` rather than `Operand::Project(0, x)`, so that operands
+/// naming the same output are equal (and hash the same). Operands of nodes
+/// with several outputs are left alone.
+pub(crate) fn normalize_operands(func: &mut RvsdgFunction) {
+    normalize_nodes(
+        &mut func.nodes,
+        func.results.iter_mut().chain(once(&mut func.state)),
+    );
+}
+
+fn normalize_nodes<'a>(nodes: &mut [RvsdgBody], roots: impl Iterator<Item = &'a mut Operand>) {
+    let single_output = Vec::from_iter(nodes.iter().map(|body| {
+        matches!(
+            body,
+            RvsdgBody::BasicOp(
+                Expr::Op(..)
+                    | Expr::UnsignedCmp(..)
+                    | Expr::Bitwise(..)
+                    | Expr::Select(..)
+                    | Expr::Const(..)
+                    | Expr::Effect(..)
+                    | Expr::Speculation(..)
+                    | Expr::Print(..)
+                    | Expr::Call(_, _, 1, ..)
+            )
+        )
+    }));
+    let normalize = |op: &mut Operand| match *op {
+        Operand::Project(0, id) if single_output[id] => *op = Operand::Id(id),
+        _ => {}
+    };
+    for body in nodes.iter_mut() {
+        body.for_each_operand_mut(normalize);
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = body
+        {
+            normalize_nodes(lambda_nodes, outputs.iter_mut());
+        }
+    }
+    roots.for_each(normalize);
+}
+
+/// Merge structurally identical pure operations in `func`, redirecting their
+/// uses to a single canonical copy and then pruning the copies that are left
+/// dead. Operations are identical when they agree on their operation, type,
+/// and (already merged) operands, treating `Operand::Id(x)` and
+/// `Operand::Project(0, x)` as the same operand.
+///
+/// Only `Op`, `Select`, and `Const` nodes (and pure calls) are merged. Anything
+/// that threads the state edge, or that may be impure (like any other call),
+/// is left alone even if it looks identical to another node.
+///
+/// Since nodes are compared by their operands, this doesn't see through the
+/// arguments of regions; see [gvn] for that.
+pub(crate) fn cse(func: &mut RvsdgFunction) {
+    cse_nodes(
+        &mut func.nodes,
+        func.results.iter_mut().chain(once(&mut func.state)),
+    );
+    prune_dead_nodes(func);
+}
+
+fn cse_nodes<'a>(nodes: &mut [RvsdgBody], roots: impl Iterator<Item = &'a mut Operand>) {
+    fn rename(canonical: &[Id], op: &mut Operand) {
+        match op {
+            Operand::Arg(_) => {}
+            Operand::Id(id) | Operand::Project(_, id) => *id = canonical[*id],
+        }
+    }
+
+    let mut canonical = Vec::from_iter(0..nodes.len());
+    // Pure operations, bucketed by their operands.
+    let mut seen = HashMap::<Vec<Operand>, Vec<Id>>::new();
+    for id in 0..nodes.len() {
+        nodes[id].for_each_operand_mut(|op| rename(&canonical, op));
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = &mut nodes[id]
+        {
+            cse_nodes(lambda_nodes, outputs.iter_mut());
+        }
+
+        let RvsdgBody::BasicOp(expr) = &nodes[id] else {
+            continue;
+        };
+        if !is_pure(expr) {
+            continue;
+        }
+        let key = Vec::from_iter(expr.operands().iter().map(|op| match op {
+            Operand::Project(0, id) => Operand::Id(*id),
+            op => *op,
+        }));
+        let candidates = seen.entry(key).or_default();
+        let existing = candidates.iter().copied().find(|other| {
+            matches!(&nodes[*other], RvsdgBody::BasicOp(other) if same_operation(other, expr))
+        });
+        match existing {
+            Some(other) => canonical[id] = other,
+            None => candidates.push(id),
+        }
+    }
+    roots.for_each(|op| rename(&canonical, op));
+}
+
+/// Whether two pure expressions agree on everything but their operands.
+fn same_operation(e1: &Expr<Operand>, e2: &Expr<Operand>) -> bool {
+    match (e1, e2) {
+        (Expr::Op(op1, _, ty1), Expr::Op(op2, _, ty2)) => op1 == op2 && ty1 == ty2,
+        (Expr::UnsignedCmp(op1, _), Expr::UnsignedCmp(op2, _)) => op1 == op2,
+        (Expr::Bitwise(op1, _), Expr::Bitwise(op2, _)) => op1 == op2,
+        (Expr::Select(_, ty1), Expr::Select(_, ty2)) => ty1 == ty2,
+        (Expr::Call(func1, _, n1, ty1, true), Expr::Call(func2, _, n2, ty2, true)) => {
+            func1 == func2 && n1 == n2 && ty1 == ty2
+        }
+        (Expr::Const(op1, lit1, ty1), Expr::Const(op2, lit2, ty2)) => {
+            let lits_equal = match (lit1, lit2) {
+                // Don't merge 0.0 with -0.0.
+                (Literal::Float(f1), Literal::Float(f2)) => f1.to_bits() == f2.to_bits(),
+                _ => lit1 == lit2,
+            };
+            op1 == op2 && ty1 == ty2 && lits_equal
+        }
+        _ => false,
+    }
+}
+
+/// Whether `expr` is a pure operation, which [cse] and [gvn] may merge.
+fn is_pure(expr: &Expr<Operand>) -> bool {
+    matches!(
+        expr,
+        Expr::Op(..)
+            | Expr::UnsignedCmp(..)
+            | Expr::Bitwise(..)
+            | Expr::Select(..)
+            | Expr::Const(..)
+            | Expr::Call(.., true)
+    )
+}
+
+/// Global value numbering: merge pure operations in `func` that compute the
+/// same value, like [cse], but across region boundaries as well. Every operand
+/// is numbered in the context of the region it's evaluated in, so that the
+/// arguments of a gamma branch number the same as the gamma's inputs, and the
+/// loop-invariant arguments of a theta the same as the theta's inputs. The
+/// theta's other arguments change from one iteration to the next, so they get
+/// numbers of their own.
+///
+/// A value that a gamma branch or theta body computes but that the enclosing
+/// region has already computed is passed into the gamma or theta as a new
+/// input instead of being computed again. "Already" means earlier in a
+/// depth-first traversal from the outputs of the enclosing region, so the
+/// value can't depend on the gamma or theta itself. Values that don't depend
+/// on any arguments are shared between regions directly. Lambdas are left
+/// alone.
+pub(crate) fn gvn(func: &mut RvsdgFunction) {
+    let mut gvn = Gvn {
+        nodes: &mut func.nodes,
+        opaque: HashMap::new(),
+        pure: HashMap::new(),
+        closed: HashMap::new(),
+        closed_nodes: HashSet::new(),
+        next: 0,
+    };
+    let mut scopes = vec![GvnScope::default()];
+    for root in func.results.iter_mut().chain(once(&mut func.state)) {
+        *root = gvn.operand(&mut scopes, *root).1;
+    }
+    prune_dead_nodes(func);
+}
+
+/// What a value number that [gvn] gives to something other than a pure
+/// operation stands for.
+#[derive(PartialEq, Eq, Hash)]
+enum Opaque {
+    /// An argument of the function.
+    Arg(usize),
+    /// A loop-carried variable of a theta, which may differ from one iteration
+    /// to the next.
+    LoopVar(Id, usize),
+    /// An output of a node other than a pure operation, given the numbers of
+    /// its operands.
+    Output(Id, usize, Vec<usize>),
+}
+
+struct Gvn<'a> {
+    nodes: &'a mut Vec<RvsdgBody>,
+    opaque: HashMap<Opaque, usize>,
+    /// The numbers of pure operations, bucketed by the numbers of their
+    /// operands, along with a node performing each.
+    pure: HashMap<Vec<usize>, Vec<(Id, usize)>>,
+    /// A node computing each value that doesn't depend on any arguments, and
+    /// so can be used from any region.
+    closed: HashMap<usize, Id>,
+    closed_nodes: HashSet<Id>,
+    next: usize,
+}
+
+/// A region being numbered by [gvn].
+#[derive(Default)]
+struct GvnScope {
+    /// The gamma or theta that this is a region of, or `None` for the
+    /// outermost region of the function.
+    region: Option<Id>,
+    /// The numbers of the region's arguments. The arguments of the function
+    /// are numbered as they are seen instead.
+    args: Vec<usize>,
+    /// An operand computing each value number within the region.
+    leaders: HashMap<usize, Operand>,
+    /// The nodes already visited in the region.
+    visited: HashMap<Id, Visited>,
+}
+
+#[derive(Clone)]
+enum Visited {
+    /// A pure operation, with its number and the operand replacing it.
+    Pure(usize, Operand),
+    /// Any other node, with the node replacing it and the numbers of its
+    /// operands.
+    Node(Id, Vec<usize>),
+}
+
+impl Gvn<'_> {
+    fn fresh(&mut self) -> usize {
+        self.next += 1;
+        self.next - 1
+    }
+
+    fn opaque(&mut self, key: Opaque) -> usize {
+        if let Some(vn) = self.opaque.get(&key) {
+            return *vn;
+        }
+        let vn = self.fresh();
+        self.opaque.insert(key, vn);
+        vn
+    }
+
+    /// The number of `op` in the innermost of `scopes`, and the operand to
+    /// replace it with.
+    fn operand(&mut self, scopes: &mut Vec<GvnScope>, op: Operand) -> (usize, Operand) {
+        let (output, id) = match op {
+            Operand::Arg(arg) => {
+                let vn = match scopes.last().unwrap().args.get(arg) {
+                    Some(vn) => *vn,
+                    None => self.opaque(Opaque::Arg(arg)),
+                };
+                return (vn, op);
+            }
+            Operand::Id(id) => (0, id),
+            Operand::Project(output, id) => (output, id),
+        };
+        let visited = match scopes.last().unwrap().visited.get(&id) {
+            Some(visited) => visited.clone(),
+            None => {
+                let visited = self.visit(scopes, id);
+                let scope = scopes.last_mut().unwrap();
+                scope.visited.insert(id, visited.clone());
+                visited
+            }
+        };
+        match visited {
+            Visited::Pure(vn, op) => (vn, op),
+            Visited::Node(new, numbers) => {
+                let op = match op {
+                    Operand::Id(_) => Operand::Id(new),
+                    _ => Operand::Project(output, new),
+                };
+                let vn = match &self.nodes[new] {
+                    // Loop invariant variables come out of a theta unchanged.
+                    RvsdgBody::Theta { outputs, .. } if outputs[output] == Operand::Arg(output) => {
+                        numbers[output]
+                    }
+                    _ => self.opaque(Opaque::Output(new, output, numbers)),
+                };
+                (vn, op)
+            }
+        }
+    }
+
+    /// Number and rewrite each of `ops`, returning their numbers and whether
+    /// any of them changed.
+    fn operands<'b>(
+        &mut self,
+        scopes: &mut Vec<GvnScope>,
+        ops: impl IntoIterator<Item = &'b mut Operand>,
+    ) -> (Vec<usize>, bool) {
+        let mut changed = false;
+        let numbers = ops
+            .into_iter()
+            .map(|op| {
+                let (vn, new) = self.operand(scopes, *op);
+                changed |= new != *op;
+                *op = new;
+                vn
+            })
+            .collect();
+        (numbers, changed)
+    }
+
+    /// The node to replace `id` with, given its rewritten `body`: a copy if its
+    /// operands changed, since other regions may still use the original.
+    fn copy_if_changed(&mut self, id: Id, body: RvsdgBody, changed: bool) -> Id {
+        if !changed {
+            return id;
+        }
+        self.nodes.push(body);
+        self.nodes.len() - 1
+    }
+
+    fn visit(&mut self, scopes: &mut Vec<GvnScope>, id: Id) -> Visited {
+        match self.nodes[id].clone() {
+            RvsdgBody::BasicOp(mut expr) if is_pure(&expr) => {
+                let (numbers, changed) = self.operands(scopes, expr.operands_mut());
+                let vn = self.pure_number(&expr, numbers, id);
+                if let Some(leader) = self.closed.get(&vn) {
+                    return Visited::Pure(vn, Operand::Id(*leader));
+                }
+                if let Some(op) = self.available(scopes, vn) {
+                    return Visited::Pure(vn, op);
+                }
+                let closed = expr
+                    .operands()
+                    .iter()
+                    .all(|op| node_of(op).is_some_and(|id| self.closed_nodes.contains(&id)));
+                let new = self.copy_if_changed(id, RvsdgBody::BasicOp(expr), changed);
+                if closed {
+                    self.closed.insert(vn, new);
+                    self.closed_nodes.insert(new);
+                } else {
+                    let scope = scopes.last_mut().unwrap();
+                    scope.leaders.insert(vn, Operand::Id(new));
+                }
+                Visited::Pure(vn, Operand::Id(new))
+            }
+            RvsdgBody::Gamma { .. } | RvsdgBody::Theta { .. } => self.visit_regions(scopes, id),
+            RvsdgBody::Lambda { .. } => Visited::Node(id, vec![]),
+            mut body => {
+                let (numbers, changed) = self.operands(scopes, region_operands_mut(&mut body));
+                Visited::Node(self.copy_if_changed(id, body, changed), numbers)
+            }
+        }
+    }
+
+    /// The number of the pure operation `expr` at `id`, whose operands are
+    /// numbered `numbers`.
+    fn pure_number(&mut self, expr: &Expr<Operand>, numbers: Vec<usize>, id: Id) -> usize {
+        let nodes = &*self.nodes;
+        let candidates = self.pure.entry(numbers).or_default();
+        let existing = candidates.iter().find(|(other, _)| {
+            matches!(&nodes[*other], RvsdgBody::BasicOp(other) if same_operation(other, expr))
+        });
+        if let Some((_, vn)) = existing {
+            return *vn;
+        }
+        let vn = self.next;
+        self.next += 1;
+        candidates.push((id, vn));
+        vn
+    }
+
+    /// Number the inputs of the gamma or theta `id`, then rewrite the regions
+    /// inside of it. Gammas and thetas are rewritten in place, since they can
+    /// only be used from one region.
+    fn visit_regions(&mut self, scopes: &mut Vec<GvnScope>, id: Id) -> Visited {
+        let mut body = self.nodes[id].clone();
+        let (numbers, _) = self.operands(scopes, region_operands_mut(&mut body));
+        self.nodes[id] = body;
+
+        let mut args = match &self.nodes[id] {
+            // The arguments of a gamma branch are the gamma's inputs.
+            RvsdgBody::Gamma { .. } => numbers[1..].to_vec(),
+            RvsdgBody::Theta { outputs, .. } => {
+                let outputs = outputs.clone();
+                Vec::from_iter(outputs.iter().enumerate().map(|(i, output)| {
+                    if *output == Operand::Arg(i) {
+                        numbers[i]
+                    } else {
+                        self.opaque(Opaque::LoopVar(id, i))
+                    }
+                }))
+            }
+            _ => unreachable!("only gammas and thetas have regions"),
+        };
+        let n_regions = match &self.nodes[id] {
+            RvsdgBody::Gamma { outputs, .. } => outputs.len(),
+            _ => 1,
+        };
+        for region in 0..n_regions {
+            let mut leaders = HashMap::new();
+            for (i, vn) in args.iter().enumerate() {
+                leaders.entry(*vn).or_insert(Operand::Arg(i));
+            }
+            scopes.push(GvnScope {
+                region: Some(id),
+                args,
+                leaders,
+                visited: HashMap::new(),
+            });
+            // Inputs passed in while rewriting are added after these.
+            let outputs = match &self.nodes[id] {
+                RvsdgBody::Gamma { outputs, .. } => outputs[region].clone(),
+                RvsdgBody::Theta { pred, outputs, .. } => {
+                    once(pred).chain(outputs).copied().collect()
+                }
+                _ => unreachable!(),
+            };
+            let outputs = Vec::from_iter(outputs.into_iter().map(|op| self.operand(scopes, op).1));
+            match &mut self.nodes[id] {
+                RvsdgBody::Gamma {
+                    outputs: old_outputs,
+                    ..
+                } => old_outputs[region] = outputs,
+                RvsdgBody::Theta {
+                    pred,
+                    outputs: old_outputs,
+                    ..
+                } => {
+                    *pred = outputs[0];
+                    old_outputs[..outputs.len() - 1].copy_from_slice(&outputs[1..]);
+                }
+                _ => unreachable!(),
+            }
+            // Later branches see the inputs passed in by earlier ones.
+            args = scopes.pop().unwrap().args;
+        }
+        Visited::Node(id, numbers)
+    }
+
+    /// An operand computing the value numbered `vn` in the innermost of
+    /// `scopes`, if any of them has computed it. A value computed in an
+    /// enclosing region is passed into the regions in between as a new input
+    /// (and, for thetas, an invariant loop variable).
+    fn available(&mut self, scopes: &mut [GvnScope], vn: usize) -> Option<Operand> {
+        let (scope, outer) = scopes.split_last_mut()?;
+        if let Some(op) = scope.leaders.get(&vn) {
+            return Some(*op);
+        }
+        let region = scope.region?;
+        let outside = self.available(outer, vn)?;
+        let arg = match &mut self.nodes[region] {
+            RvsdgBody::Gamma { inputs, .. } => {
+                inputs.push(outside);
+                inputs.len() - 1
+            }
+            RvsdgBody::Theta {
+                inputs, outputs, ..
+            } => {
+                inputs.push(outside);
+                outputs.push(Operand::Arg(inputs.len() - 1));
+                inputs.len() - 1
+            }
+            _ => unreachable!("only gammas and thetas have regions"),
+        };
+        scope.args.push(vn);
+        scope.leaders.insert(vn, Operand::Arg(arg));
+        Some(Operand::Arg(arg))
+    }
+}
+
+/// Replace every gamma in `func` whose predicate is a constant with the outputs
+/// of the branch it selects, then prune the gammas (and untaken branches) that
+/// are left dead.
+///
+/// A branch's outputs refer to the gamma's inputs as `Operand::Arg`s, so the
+/// nodes of the taken branch that depend on them are copied with the inputs
+/// substituted in.
+pub(crate) fn simplify_constant_gammas(func: &mut RvsdgFunction) {
+    simplify_gammas(
+        &mut func.nodes,
+        func.results.iter_mut().chain(once(&mut func.state)),
+        &HashMap::new(),
+    );
+    prune_dead_nodes(func);
+}
+
+/// Simplify the gammas whose predicate is a constant, as well as those in
+/// `decided`, which maps gammas to the branch they are known to take.
+fn simplify_gammas<'a>(
+    nodes: &mut Vec<RvsdgBody>,
+    roots: impl Iterator<Item = &'a mut Operand>,
+    decided: &HashMap<Id, usize>,
+) {
+    // The operands that each simplified gamma's outputs are replaced with.
+    let mut replaced = HashMap::<Id, Vec<Operand>>::new();
+    // Copies of the taken branches may contain constant gammas themselves, so
+    // keep going until we reach the end of the (growing) heap.
+    let mut id = 0;
+    while id < nodes.len() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = &mut nodes[id]
+        {
+            simplify_gammas(lambda_nodes, outputs.iter_mut(), &HashMap::new());
+        }
+        let RvsdgBody::Gamma {
+            pred,
+            inputs,
+            outputs,
+        } = &nodes[id]
+        else {
+            id += 1;
+            continue;
+        };
+        let taken = decided.get(&id).copied().or_else(|| {
+            node_of(pred).and_then(|pred| match &nodes[pred] {
+                RvsdgBody::BasicOp(Expr::Const(_, Literal::Bool(b), _)) => Some(usize::from(*b)),
+                RvsdgBody::BasicOp(Expr::Const(_, Literal::Int(i), _)) => usize::try_from(*i).ok(),
+                _ => None,
+            })
+        });
+        if let Some(branch) = taken.and_then(|taken| outputs.get(taken)) {
+            let (inputs, branch) = (inputs.clone(), branch.clone());
+            let mut substituted = HashMap::new();
+            let outputs = branch
+                .iter()
+                .map(|op| substitute(nodes, &inputs, &mut substituted, *op))
+                .collect();
+            replaced.insert(id, outputs);
+        }
+        id += 1;
+    }
+
+    // Replacement outputs may themselves project out of simplified gammas.
+    let resolve = |op: &mut Operand| {
+        resolve_replaced(op, |i, id| replaced.get(&id).map(|outputs| outputs[i]))
+    };
+    nodes
+        .iter_mut()
+        .for_each(|body| body.for_each_operand_mut(resolve));
+    roots.for_each(resolve);
+}
+
+/// Collapse gammas whose predicate is decided by an enclosing gamma. Inside
+/// branch `b` of a gamma, an input that is the gamma's own predicate is known
+/// to select branch `b`, so a nested gamma on that input always takes branch
+/// `b` too. What is known about arguments carries on into more deeply nested
+/// gammas, and into thetas through loop-invariant variables. The untaken
+/// branches are then pruned, as in [simplify_constant_gammas].
+pub(crate) fn simplify_known_gammas(func: &mut RvsdgFunction) {
+    loop {
+        let roots = Vec::from_iter(func.results.iter().copied().chain(once(func.state)));
+        let mut decided = HashMap::new();
+        decide_gammas(&func.nodes, &roots, &HashMap::new(), &mut decided);
+        // Gammas shared between regions where they take different branches
+        // aren't decided.
+        let decided = HashMap::from_iter(
+            decided
+                .into_iter()
+                .filter_map(|(id, taken)| Some((id, taken?))),
+        );
+        if decided.is_empty() {
+            return;
+        }
+        simplify_gammas(
+            &mut func.nodes,
+            func.results.iter_mut().chain(once(&mut func.state)),
+            &decided,
+        );
+        prune_dead_nodes(func);
+    }
+}
+
+/// Record the branch taken by each gamma in the region computing `outputs`
+/// (and in regions nested inside it), given `known`, which maps some of the
+/// region's arguments to the branch they select. A gamma reached with
+/// different branches is recorded as `None`.
+fn decide_gammas(
+    nodes: &[RvsdgBody],
+    outputs: &[Operand],
+    known: &HashMap<usize, usize>,
+    decided: &mut HashMap<Id, Option<usize>>,
+) {
+    let known_input = |input: &Operand| match input {
+        Operand::Arg(arg) => known.get(arg).copied(),
+        _ => None,
+    };
+    for id in outer_region(nodes, outputs.iter().copied()) {
+        match &nodes[id] {
+            RvsdgBody::Gamma {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                let taken = known_input(pred);
+                if let Some(taken) = taken {
+                    decided
+                        .entry(id)
+                        .and_modify(|prev| *prev = prev.filter(|prev| *prev == taken))
+                        .or_insert(Some(taken));
+                }
+                for (branch, branch_outputs) in outputs.iter().enumerate() {
+                    if taken.is_some_and(|taken| taken != branch) {
+                        continue;
+                    }
+                    let mut inner = HashMap::new();
+                    for (arg, input) in inputs.iter().enumerate() {
+                        let fact = if input == pred {
+                            Some(branch)
+                        } else {
+                            known_input(input)
+                        };
+                        inner.extend(fact.map(|fact| (arg, fact)));
+                    }
+                    if !inner.is_empty() {
+                        decide_gammas(nodes, branch_outputs, &inner, decided);
+                    }
+                }
+            }
+            RvsdgBody::Theta {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                let kinds = theta_variable_kinds(nodes, pred, inputs, outputs);
+                let mut inner = HashMap::new();
+                for (arg, input) in inputs.iter().enumerate() {
+                    if kinds[arg] == VarKind::Invariant {
+                        inner.extend(known_input(input).map(|fact| (arg, fact)));
+                    }
+                }
+                if !inner.is_empty() {
+                    let body = Vec::from_iter(outputs.iter().chain(once(pred)).copied());
+                    decide_gammas(nodes, &body, &inner, decided);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Collapse gammas whose predicate compares integers with known ranges, when
+/// the ranges are enough to decide the comparison. Ranges start at integer
+/// constants and flow through additions and subtractions and into gamma and
+/// theta regions. Inside a theta, a counter that starts in a known range, has
+/// a positive constant added to it every iteration, and keeps the loop going
+/// only while it is below a constant is bounded by that constant, so e.g.
+/// bounds checks on the counter in the loop body can be proven to pass. The
+/// untaken branches are then pruned, as in [simplify_constant_gammas].
+pub(crate) fn simplify_bounded_gammas(func: &mut RvsdgFunction) {
+    let roots = Vec::from_iter(func.results.iter().copied().chain(once(func.state)));
+    let mut decided = HashMap::new();
+    bound_gammas(&func.nodes, &roots, &HashMap::new(), &mut decided);
+    // Gammas shared between regions where they aren't decided the same way
+    // are left alone.
+    let decided = HashMap::from_iter(
+        decided
+            .into_iter()
+            .filter_map(|(id, taken)| Some((id, taken?))),
+    );
+    simplify_gammas(
+        &mut func.nodes,
+        func.results.iter_mut().chain(once(&mut func.state)),
+        &decided,
+    );
+    prune_dead_nodes(func);
+}
+
+/// Record the branch taken by each gamma in the region computing `outputs`
+/// (and in regions nested inside it), given `ranges`, which maps some of the
+/// region's arguments to the (inclusive) minimum and maximum values they take.
+/// A gamma that can't be decided everywhere it is reached is recorded as
+/// `None`.
+fn bound_gammas(
+    nodes: &[RvsdgBody],
+    outputs: &[Operand],
+    ranges: &HashMap<usize, (i64, i64)>,
+    decided: &mut HashMap<Id, Option<usize>>,
+) {
+    let input_ranges = |inputs: &[Operand]| {
+        inputs
+            .iter()
+            .enumerate()
+            .filter_map(|(arg, input)| Some((arg, operand_range(nodes, ranges, *input)?)))
+            .collect::<HashMap<_, _>>()
+    };
+    for id in outer_region(nodes, outputs.iter().copied()) {
+        match &nodes[id] {
+            RvsdgBody::Gamma {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                let taken = compare_ranges(nodes, ranges, *pred).map(usize::from);
+                decided
+                    .entry(id)
+                    .and_modify(|prev| *prev = prev.filter(|prev| Some(*prev) == taken))
+                    .or_insert(taken);
+                let inner = input_ranges(inputs);
+                for branch_outputs in outputs {
+                    bound_gammas(nodes, branch_outputs, &inner, decided);
+                }
+            }
+            RvsdgBody::Theta {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                let mut inner = input_ranges(inputs);
+                inner.retain(|arg, init| {
+                    if outputs[*arg] == Operand::Arg(*arg) {
+                        return true;
+                    }
+                    match counter_range(nodes, *pred, outputs, *arg, *init) {
+                        Some(range) => {
+                            *init = range;
+                            true
+                        }
+                        None => false,
+                    }
+                });
+                let body = Vec::from_iter(outputs.iter().chain(once(pred)).copied());
+                bound_gammas(nodes, &body, &inner, decided);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The minimum and maximum value of the integer `op`, given the ranges of some
+/// of the arguments of the region it lives in, if they are known.
+fn operand_range(
+    nodes: &[RvsdgBody],
+    ranges: &HashMap<usize, (i64, i64)>,
+    op: Operand,
+) -> Option<(i64, i64)> {
+    let id = match op.canonical() {
+        Operand::Arg(arg) => return ranges.get(&arg).copied(),
+        Operand::Id(id) => id,
+        Operand::Project(..) => return None,
+    };
+    match &nodes[id] {
+        RvsdgBody::BasicOp(Expr::Const(_, Literal::Int(i), _)) => Some((*i, *i)),
+        RvsdgBody::BasicOp(Expr::Op(arith @ (ValueOps::Add | ValueOps::Sub), args, _)) => {
+            let (l_min, l_max) = operand_range(nodes, ranges, args[0])?;
+            let (r_min, r_max) = operand_range(nodes, ranges, args[1])?;
+            // Bail out rather than reason about overflow.
+            match arith {
+                ValueOps::Add => Some((l_min.checked_add(r_min)?, l_max.checked_add(r_max)?)),
+                _ => Some((l_min.checked_sub(r_max)?, l_max.checked_sub(r_min)?)),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The value of the comparison `pred`, if the ranges of its operands decide
+/// it.
+fn compare_ranges(
+    nodes: &[RvsdgBody],
+    ranges: &HashMap<usize, (i64, i64)>,
+    pred: Operand,
+) -> Option<bool> {
+    let RvsdgBody::BasicOp(Expr::Op(cmp, args, _)) = &nodes[node_of(&pred.canonical())?] else {
+        return None;
+    };
+    let [l, r] = args[..] else {
+        return None;
+    };
+    let (l_min, l_max) = operand_range(nodes, ranges, l)?;
+    let (r_min, r_max) = operand_range(nodes, ranges, r)?;
+    let (always, never) = match cmp {
+        ValueOps::Lt => (l_max < r_min, l_min >= r_max),
+        ValueOps::Le => (l_max <= r_min, l_min > r_max),
+        ValueOps::Gt => (l_min > r_max, l_max <= r_min),
+        ValueOps::Ge => (l_min >= r_max, l_max < r_min),
+        ValueOps::Eq => (
+            l_min == l_max && r_min == r_max && l_min == r_min,
+            l_max < r_min || r_max < l_min,
+        ),
+        _ => return None,
+    };
+    match (always, never) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// The range of the theta variable `k` in the loop body, given the range
+/// `init` of its input, if it is a counter bounded by the loop predicate `pred`
+/// (see [simplify_bounded_gammas]).
+fn counter_range(
+    nodes: &[RvsdgBody],
+    pred: Operand,
+    outputs: &[Operand],
+    k: usize,
+    init: (i64, i64),
+) -> Option<(i64, i64)> {
+    let int_const = |op: Operand| match &nodes[node_of(&op)?] {
+        RvsdgBody::BasicOp(Expr::Const(_, Literal::Int(i), _)) => Some(*i),
+        _ => None,
+    };
+    let RvsdgBody::BasicOp(Expr::Op(ValueOps::Add, args, _)) = &nodes[node_of(&outputs[k])?] else {
+        return None;
+    };
+    let step = match args[..] {
+        [a, b] if a == Operand::Arg(k) => int_const(b)?,
+        [a, b] if b == Operand::Arg(k) => int_const(a)?,
+        _ => return None,
+    };
+    if step <= 0 {
+        return None;
+    }
+
+    // The largest value of `counter` for which the loop goes around again.
+    let RvsdgBody::BasicOp(Expr::Op(cmp, args, _)) = &nodes[node_of(&pred)?] else {
+        return None;
+    };
+    let (counter, last) = match (cmp, &args[..]) {
+        (ValueOps::Lt, &[counter, bound]) | (ValueOps::Gt, &[bound, counter]) => {
+            (counter, int_const(bound)?.checked_sub(1)?)
+        }
+        (ValueOps::Le, &[counter, bound]) | (ValueOps::Ge, &[bound, counter]) => {
+            (counter, int_const(bound)?)
+        }
+        _ => return None,
+    };
+    // Bail out rather than reason about overflow: an update that wraps
+    // around would make the counter smaller than it started, and it could
+    // still pass the predicate.
+    init.1.checked_add(step)?;
+    last.checked_add(step)?;
+    // The counter only goes up, and every iteration after the first starts
+    // with the value it had when the predicate was last checked.
+    let max = if counter.canonical() == outputs[k].canonical() {
+        last
+    } else if counter == Operand::Arg(k) {
+        last.checked_add(step)?
+    } else {
+        return None;
+    };
+    Some((init.0, init.1.max(max)))
+}
+
+/// Rewrite `op`, which lives in a gamma or theta region with the given
+/// `inputs`, to refer to those inputs in place of the region's arguments. Nodes that depend
+/// on the arguments are copied onto the end of `nodes`; `substituted` caches
+/// the result for every node visited.
+fn substitute(
+    nodes: &mut Vec<RvsdgBody>,
+    inputs: &[Operand],
+    substituted: &mut HashMap<Id, Id>,
+    op: Operand,
+) -> Operand {
+    let leaf = |op: Operand| match op {
+        Operand::Arg(arg) => Some(inputs[arg]),
+        _ => None,
+    };
+    rewrite_region(nodes, &leaf, substituted, op)
+}
+
+/// Rewrite `op` and the nodes in its region, replacing every operand for
+/// which `leaf` returns a new operand. Nodes that change are copied onto the
+/// end of `nodes`, so nodes shared with other regions are left alone;
+/// `rewritten` caches the result for every node visited.
+fn rewrite_region(
+    nodes: &mut Vec<RvsdgBody>,
+    leaf: &dyn Fn(Operand) -> Option<Operand>,
+    rewritten: &mut HashMap<Id, Id>,
+    op: Operand,
+) -> Operand {
+    if let Some(new_op) = leaf(op) {
+        return new_op;
+    }
+    let node = match op {
+        Operand::Arg(_) => return op,
+        Operand::Id(node) | Operand::Project(_, node) => node,
+    };
+    let new_node = match rewritten.get(&node) {
+        Some(new_node) => *new_node,
+        None => {
+            let mut body = nodes[node].clone();
+            let mut changed = false;
+            for operand in region_operands_mut(&mut body) {
+                let new_operand = rewrite_region(nodes, leaf, rewritten, *operand);
+                changed |= new_operand != *operand;
+                *operand = new_operand;
+            }
+            let new_node = if changed {
+                nodes.push(body);
+                nodes.len() - 1
+            } else {
+                node
+            };
+            rewritten.insert(node, new_node);
+            new_node
+        }
+    };
+    match op {
+        Operand::Id(_) => Operand::Id(new_node),
+        Operand::Project(i, _) => Operand::Project(i, new_node),
+        Operand::Arg(_) => unreachable!(),
+    }
+}
+
+/// Renumber every `Operand::Arg(n)` in the region that computes `root_ops`
+/// (including `root_ops` themselves) to `Operand::Arg(n + by)`, e.g. to make
+/// room for the caller's arguments before splicing a callee's `body` into it.
+///
+/// Regions nested inside of that region number their arguments on their own,
+/// so their arguments are left alone, as are `Id`s and `Project`s.
+pub(crate) fn shift_args(body: &mut [RvsdgBody], root_ops: &mut [Operand], by: usize) {
+    let shift = |op: &mut Operand| {
+        if let Operand::Arg(arg) = op {
+            *arg += by;
+        }
+    };
+    for id in outer_region(body, root_ops.iter().copied()) {
+        region_operands_mut(&mut body[id])
+            .into_iter()
+            .for_each(shift);
+    }
+    root_ops.iter_mut().for_each(shift);
+}
+
+/// Replace the outputs of gammas that every branch computes in the same way
+/// (as in [RvsdgFunction::structurally_eq]) with that computation, moved out
+/// of the gamma, then prune the gammas that are left dead.
+///
+/// Every branch refers to the gamma's inputs as the same `Operand::Arg`s, so
+/// the branches' outputs can be compared as they are. The common output is
+/// then copied with the inputs substituted in, as in
+/// [simplify_constant_gammas].
+pub(crate) fn remove_trivial_gammas(func: &mut RvsdgFunction) {
+    trivial_gammas(
+        &mut func.nodes,
+        func.results.iter_mut().chain(once(&mut func.state)),
+    );
+    prune_dead_nodes(func);
+}
+
+fn trivial_gammas<'a>(nodes: &mut Vec<RvsdgBody>, roots: impl Iterator<Item = &'a mut Operand>) {
+    // (output, gamma) => the operand that the output is replaced with.
+    let mut replaced = HashMap::<(usize, Id), Operand>::new();
+    // Copies of common outputs may contain trivial gammas themselves, so keep
+    // going until we reach the end of the (growing) heap.
+    let mut id = 0;
+    while id < nodes.len() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = &mut nodes[id]
+        {
+            trivial_gammas(lambda_nodes, outputs.iter_mut());
+        }
+        let RvsdgBody::Gamma {
+            inputs, outputs, ..
+        } = &nodes[id]
+        else {
+            id += 1;
+            continue;
+        };
+        let Some((first, rest)) = outputs.split_first() else {
+            id += 1;
+            continue;
+        };
+        let common = Vec::from_iter((0..first.len()).filter_map(|i| {
+            rest.iter()
+                .all(|branch| operands_structurally_eq(nodes, &first[i], &branch[i]))
+                .then_some((i, first[i]))
+        }));
+        let inputs = inputs.clone();
+        let mut substituted = HashMap::new();
+        for (i, op) in common {
+            replaced.insert((i, id), substitute(nodes, &inputs, &mut substituted, op));
+        }
+        id += 1;
+    }
+
+    // Replacement outputs may themselves come out of trivial gammas.
+    let resolve = |op: &mut Operand| {
+        resolve_replaced(op, |output, gamma| replaced.get(&(output, gamma)).copied())
+    };
+    nodes
+        .iter_mut()
+        .for_each(|body| body.for_each_operand_mut(resolve));
+    roots.for_each(resolve);
+}
+
+/// Hoist pure computations that every branch of a gamma performs in the same
+/// way (as in [RvsdgFunction::structurally_eq]) out of the gamma, even when
+/// the outputs they feed into differ. Each one is computed once before the
+/// gamma, with the gamma's inputs substituted for the branch arguments, and
+/// passed in as a new input that the branches read instead.
+///
+/// Since the branches compare equal, they read the same gamma inputs through
+/// the same `Operand::Arg`s. Constants, and other computations that read no
+/// arguments, are left where they are. Whole outputs can then be removed with
+/// [remove_trivial_gammas].
+pub(crate) fn hoist_common_gamma_nodes(func: &mut RvsdgFunction) {
+    hoist_common_nodes(&mut func.nodes);
+    prune_dead_nodes(func);
+}
+
+fn hoist_common_nodes(nodes: &mut Vec<RvsdgBody>) {
+    // Hoisted copies are pushed onto the end of the heap, and may contain
+    // gammas themselves.
+    let mut id = 0;
+    while id < nodes.len() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            ..
+        } = &mut nodes[id]
+        {
+            hoist_common_nodes(lambda_nodes);
+        }
+        let RvsdgBody::Gamma {
+            inputs, outputs, ..
+        } = &nodes[id]
+        else {
+            id += 1;
+            continue;
+        };
+        let regions = Vec::from_iter(
+            outputs
+                .iter()
+                .map(|branch| outer_region(nodes, branch.iter().copied())),
+        );
+        let Some((first, _)) = regions.split_first() else {
+            id += 1;
+            continue;
+        };
+        let mut candidates = Vec::from_iter(first.iter().copied().filter(|x| {
+            matches!(&nodes[*x], RvsdgBody::BasicOp(expr)
+                if is_pure(expr) && !matches!(expr, Expr::Const(..)))
+                && !region_args(nodes, once(Operand::Id(*x))).is_empty()
+        }));
+        candidates.sort_unstable();
+        let (inputs, mut outputs) = (inputs.clone(), outputs.clone());
+
+        // The nodes (in any branch) to replace with the new input they are
+        // hoisted into.
+        let mut hoisted = HashMap::<Id, usize>::new();
+        let mut new_inputs = inputs.clone();
+        let mut substituted = HashMap::new();
+        for x in candidates {
+            if hoisted.contains_key(&x) {
+                continue;
+            }
+            let same = |y: &Id| operands_structurally_eq(nodes, &Operand::Id(x), &Operand::Id(*y));
+            let matches = Vec::from_iter(
+                regions
+                    .iter()
+                    .map(|region| Vec::from_iter(region.iter().copied().filter(|y| same(y)))),
+            );
+            if matches.iter().any(Vec::is_empty) {
+                continue;
+            }
+            hoisted.extend(matches.into_iter().flatten().map(|y| (y, new_inputs.len())));
+            new_inputs.push(substitute(nodes, &inputs, &mut substituted, Operand::Id(x)));
+        }
+        if hoisted.is_empty() {
+            id += 1;
+            continue;
+        }
+
+        let leaf = |op: Operand| match op.canonical() {
+            Operand::Id(node) => hoisted.get(&node).map(|arg| Operand::Arg(*arg)),
+            _ => None,
+        };
+        let mut rewritten = HashMap::new();
+        for op in outputs.iter_mut().flatten() {
+            *op = rewrite_region(nodes, &leaf, &mut rewritten, *op);
+        }
+        let RvsdgBody::Gamma {
+            inputs,
+            outputs: gamma_outputs,
+            ..
+        } = &mut nodes[id]
+        else {
+            unreachable!()
+        };
+        *inputs = new_inputs;
+        *gamma_outputs = outputs;
+        id += 1;
+    }
+}
+
+/// Inline calls to functions with fewer than `threshold` nodes, across every
+/// function in `functions`. Calls within a recursive cycle (including calls
+/// from a function to itself) are left alone, as are calls inside of lambdas.
+///
+/// The callee's body is spliced into the caller with its arguments (including
+/// the incoming state edge) replaced by the operands of the call, and uses of
+/// the call's outputs are replaced by the callee's results, followed by its
+/// outgoing state edge.
+pub(crate) fn inline_calls(functions: &mut [RvsdgFunction], threshold: usize) {
+    let calls = call_graph(functions);
+    for caller in 0..functions.len() {
+        // The operands that each inlined call's outputs are replaced with.
+        let mut replaced = HashMap::<Id, Vec<Operand>>::new();
+        // Only inline the calls that were there to begin with, rather than
+        // calls that were spliced in, so recursion can't go on forever.
+        for id in 0..functions[caller].nodes.len() {
+            let RvsdgBody::BasicOp(Expr::Call(callee, ops, ..)) = &functions[caller].nodes[id]
+            else {
+                continue;
+            };
+            let Some(callee) = functions.iter().position(|func| callee.to_string() == func.name)
+            else {
+                continue;
+            };
+            if calls.in_cycle(caller, callee) {
+                continue;
+            }
+            let callee = &functions[callee];
+            if callee.nodes.len() >= threshold || callee.args.len() + 1 != ops.len() {
+                continue;
+            }
+            let ops = ops.clone();
+            let offset = functions[caller].nodes.len();
+            let shift = |op: &mut Operand| match op {
+                Operand::Arg(_) => {}
+                Operand::Id(id) | Operand::Project(_, id) => *id += offset,
+            };
+            let mut body = callee.nodes.clone();
+            body.iter_mut()
+                .for_each(|node| node.for_each_operand_mut(shift));
+            let mut outputs =
+                Vec::from_iter(callee.results.iter().chain(once(&callee.state)).copied());
+            outputs.iter_mut().for_each(shift);
+
+            // The callee's nodes now sit in the caller's heap, but still refer
+            // to the callee's arguments, so substitute the call's operands in.
+            let nodes = &mut functions[caller].nodes;
+            nodes.extend(body);
+            let mut substituted = HashMap::new();
+            let outputs = outputs
+                .into_iter()
+                .map(|op| substitute(nodes, &ops, &mut substituted, op))
+                .collect();
+            replaced.insert(id, outputs);
+        }
+        if replaced.is_empty() {
+            continue;
+        }
+
+        // The callee's results may themselves be outputs of inlined calls.
+        let resolve = |op: &mut Operand| {
+            resolve_replaced(op, |i, id| replaced.get(&id).map(|outputs| outputs[i]))
+        };
+        let func = &mut functions[caller];
+        func.nodes
+            .iter_mut()
+            .for_each(|body| body.for_each_operand_mut(resolve));
+        func.results
+            .iter_mut()
+            .chain(once(&mut func.state))
+            .for_each(resolve);
+        prune_dead_nodes(func);
+    }
+}
+
+/// Mark the calls in `func` to the functions named in `pure` as pure calls (see
+/// [Expr::Call]), taking them off of the state edge: whatever used the state
+/// flowing out of such a call uses the state flowing into it instead. Calls
+/// that return nothing compute nothing either, so they are dropped. Calls
+/// inside of lambdas are left alone.
+///
+/// It is up to the caller to only name functions that really are pure: ones
+/// that don't print, touch memory, or trap, and that always return.
+pub(crate) fn mark_pure_calls(func: &mut RvsdgFunction, pure: &[String]) {
+    // For each marked call, which of its outputs was the state edge, and the
+    // operand that replaces it.
+    let mut bypassed = HashMap::<Id, (usize, Operand)>::new();
+    for (id, body) in func.nodes.iter_mut().enumerate() {
+        let RvsdgBody::BasicOp(Expr::Call(callee, ops, n_outputs, ty, is_pure)) = body else {
+            continue;
+        };
+        if *is_pure || !pure.contains(&callee.to_string()) {
+            continue;
+        }
+        if ty.is_some() {
+            let state = ops.pop().expect("missing state edge");
+            bypassed.insert(id, (1, state));
+            *n_outputs = 1;
+            *is_pure = true;
+        } else {
+            bypassed.insert(id, (0, *ops.last().expect("missing state edge")));
+        }
+    }
+    if bypassed.is_empty() {
+        return;
+    }
+
+    // The incoming state may itself come out of a marked call.
+    let resolve = |op: &mut Operand| {
+        resolve_replaced(op, |output, call| match bypassed.get(&call) {
+            Some((state, replacement)) if *state == output => Some(*replacement),
+            _ => None,
+        })
+    };
+    func.nodes
+        .iter_mut()
+        .for_each(|body| body.for_each_operand_mut(resolve));
+    func.results
+        .iter_mut()
+        .chain(once(&mut func.state))
+        .for_each(resolve);
+    prune_dead_nodes(func);
+}
+
+/// Specialize function parameters that every call in `program` passes the
+/// same constant, replacing uses of the parameter in the function's outermost
+/// region with that constant. Parameters are kept, so calls are unchanged.
+///
+/// Functions that are never called from within `program` are left alone, as
+/// is `main`, which is called from outside of it. A recursive call in the
+/// function's outermost region that passes a parameter along unchanged is
+/// consistent with any constant; anywhere else, passing anything but a
+/// constant means the parameter can't be specialized.
+pub(crate) fn propagate_call_constants(program: &mut [RvsdgFunction]) {
+    /// What the calls seen so far pass for a parameter.
+    #[derive(Clone)]
+    enum Passed {
+        Nothing,
+        Const(Expr<Operand>),
+        Varies,
+    }
+
+    /// Record what the calls in `nodes` pass into `passed`. `caller` is the
+    /// function that `nodes` belong to, with `outer` holding the nodes in its
+    /// outermost region, or `None` inside of a lambda.
+    fn record_calls(
+        nodes: &[RvsdgBody],
+        caller: Option<(usize, &HashSet<Id>)>,
+        program: &[RvsdgFunction],
+        passed: &mut [Vec<Passed>],
+    ) {
+        for (id, body) in nodes.iter().enumerate() {
+            let (callee, ops) = match body {
+                RvsdgBody::Lambda { nodes, .. } => {
+                    record_calls(nodes, None, program, passed);
+                    continue;
+                }
+                RvsdgBody::BasicOp(Expr::Call(callee, ops, ..)) => (callee, ops),
+                _ => continue,
+            };
+            let Some(callee) = program.iter().position(|func| callee.to_string() == func.name)
+            else {
+                continue;
+            };
+            let n_args = program[callee].args.len();
+            if ops.len() != n_args + 1 {
+                passed[callee].fill(Passed::Varies);
+                continue;
+            }
+            let recursive =
+                caller.is_some_and(|(caller, outer)| caller == callee && outer.contains(&id));
+            for (arg, op) in ops[..n_args].iter().enumerate() {
+                let value = node_of(op).and_then(|node| match &nodes[node] {
+                    RvsdgBody::BasicOp(expr @ Expr::Const(..)) => Some(expr),
+                    _ => None,
+                });
+                let seen = &mut passed[callee][arg];
+                *seen = match (value, &seen) {
+                    (None, _) if recursive && *op == Operand::Arg(arg) => continue,
+                    (None, _) => Passed::Varies,
+                    (Some(value), Passed::Nothing) => Passed::Const(value.clone()),
+                    (Some(value), Passed::Const(prev)) if same_operation(prev, value) => continue,
+                    (Some(_), _) => Passed::Varies,
+                };
+            }
+        }
+    }
+
+    let outer = Vec::from_iter(program.iter().map(|func| {
+        outer_region(
+            &func.nodes,
+            func.results.iter().chain(once(&func.state)).copied(),
+        )
+    }));
+    let mut passed = Vec::from_iter(
+        program
+            .iter()
+            .map(|func| vec![Passed::Nothing; func.args.len()]),
+    );
+    for (caller, func) in program.iter().enumerate() {
+        record_calls(
+            &func.nodes,
+            Some((caller, &outer[caller])),
+            program,
+            &mut passed,
+        );
+    }
+
+    for ((func, passed), outer) in program.iter_mut().zip(passed).zip(outer) {
+        if func.name == "main" {
+            continue;
+        }
+        for (arg, seen) in passed.into_iter().enumerate() {
+            let Passed::Const(value) = seen else {
+                continue;
+            };
+            func.nodes.push(RvsdgBody::BasicOp(value));
+            let value = Operand::Id(func.nodes.len() - 1);
+            let specialize = |op: &mut Operand| {
+                if *op == Operand::Arg(arg) {
+                    *op = value;
+                }
+            };
+            for id in &outer {
+                region_operands_mut(&mut func.nodes[*id])
+                    .into_iter()
+                    .for_each(specialize);
+            }
+            func.results.iter_mut().for_each(specialize);
+        }
+    }
+}
+
+/// The most iterations [unroll_theta] will simulate looking for a loop's exit.
+const MAX_UNROLL_TRIP_COUNT: usize = 1 << 16;
+
+/// Unroll every theta in `func` whose trip count is statically known, so that
+/// each iteration of the new loop runs `factor` iterations of the old one.
+///
+/// A trip count is known when the predicate compares a counter against a
+/// constant, where the counter starts at a constant and has a constant added
+/// to it every iteration. Iterations that don't divide evenly by `factor` are
+/// peeled off and run before the loop; loops that run fewer than `factor`
+/// times are left alone.
+pub(crate) fn unroll_theta(func: &mut RvsdgFunction, factor: usize) {
+    unroll_thetas(&mut func.nodes, factor);
+    prune_dead_nodes(func);
+}
+
+fn unroll_thetas(nodes: &mut Vec<RvsdgBody>, factor: usize) {
+    if factor < 2 {
+        return;
+    }
+    // Unrolling appends copies of loop bodies to `nodes`; only the thetas that
+    // were there to begin with are unrolled.
+    for id in 0..nodes.len() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            ..
+        } = &mut nodes[id]
+        {
+            unroll_thetas(lambda_nodes, factor);
+            continue;
+        }
+        let RvsdgBody::Theta {
+            pred,
+            inputs,
+            outputs,
+        } = &nodes[id]
+        else {
+            continue;
+        };
+        let Some(trips) = trip_count(nodes, *pred, inputs, outputs) else {
+            continue;
+        };
+        if trips < factor {
+            continue;
+        }
+        let (pred, mut inputs, outputs) = (*pred, inputs.clone(), outputs.clone());
+
+        // Every peeled iteration but the last is followed by another, so the
+        // predicate is known to hold after each of them.
+        for _ in 0..trips % factor {
+            let mut substituted = HashMap::new();
+            inputs = Vec::from_iter(
+                outputs
+                    .iter()
+                    .map(|op| substitute(nodes, &inputs, &mut substituted, *op)),
+            );
+        }
+
+        // The remaining trip count is a multiple of `factor`, so the loop can
+        // only exit after the last copy of the body.
+        let (mut new_pred, mut new_outputs) = (pred, outputs.clone());
+        for _ in 1..factor {
+            let mut substituted = HashMap::new();
+            let prev = mem::take(&mut new_outputs);
+            new_pred = substitute(nodes, &prev, &mut substituted, pred);
+            new_outputs = Vec::from_iter(
+                outputs
+                    .iter()
+                    .map(|op| substitute(nodes, &prev, &mut substituted, *op)),
+            );
+        }
+        nodes[id] = RvsdgBody::Theta {
+            pred: new_pred,
+            inputs,
+            outputs: new_outputs,
+        };
+    }
+}
+
+/// The number of times a theta with the given predicate, inputs, and outputs
+/// runs its body, if it is statically known (see [unroll_theta]).
+fn trip_count(
+    nodes: &[RvsdgBody],
+    pred: Operand,
+    inputs: &[Operand],
+    outputs: &[Operand],
+) -> Option<usize> {
+    // An integer constant in the region containing the theta.
+    let outer_const = |op: Operand| match &nodes[node_of(&op)?] {
+        RvsdgBody::BasicOp(Expr::Const(_, Literal::Int(i), _)) => Some(*i),
+        _ => None,
+    };
+    // An integer constant in the loop body: either a constant node, or an
+    // argument that the loop passes through unchanged.
+    let body_const = |op: Operand| match op {
+        Operand::Arg(arg) if outputs.get(arg) == Some(&op) => outer_const(inputs[arg]),
+        Operand::Arg(_) => None,
+        _ => outer_const(op),
+    };
+    // The value `op` has the first time the predicate is checked, and how much
+    // it changes by every iteration, if it is a counter.
+    let counter = |op: Operand| {
+        outputs.iter().enumerate().find_map(|(k, output)| {
+            let RvsdgBody::BasicOp(Expr::Op(ValueOps::Add, args, _)) = &nodes[node_of(output)?]
+            else {
+                return None;
+            };
+            let step = match args[..] {
+                [a, b] if a == Operand::Arg(k) => body_const(b)?,
+                [a, b] if b == Operand::Arg(k) => body_const(a)?,
+                _ => return None,
+            };
+            let init = outer_const(inputs[k])?;
+            if op == Operand::Arg(k) {
+                Some((init, step))
+            } else if op.canonical() == output.canonical() {
+                Some((init.wrapping_add(step), step))
+            } else {
+                None
+            }
+        })
+    };
+
+    let RvsdgBody::BasicOp(Expr::Op(cmp, args, _)) = &nodes[node_of(&pred)?] else {
+        return None;
+    };
+    let [l, r] = args[..] else {
+        return None;
+    };
+    // Put the counter on the left.
+    let (first, step, bound, cmp) = match (counter(l), body_const(r)) {
+        (Some((first, step)), Some(bound)) => (first, step, bound, *cmp),
+        _ => {
+            let (first, step) = counter(r)?;
+            let flipped = match cmp {
+                ValueOps::Lt => ValueOps::Gt,
+                ValueOps::Le => ValueOps::Ge,
+                ValueOps::Gt => ValueOps::Lt,
+                ValueOps::Ge => ValueOps::Le,
+                op => *op,
+            };
+            (first, step, body_const(l)?, flipped)
+        }
+    };
+    let holds = |value: i64| match cmp {
+        ValueOps::Lt => Some(value < bound),
+        ValueOps::Le => Some(value <= bound),
+        ValueOps::Gt => Some(value > bound),
+        ValueOps::Ge => Some(value >= bound),
+        ValueOps::Eq => Some(value == bound),
+        _ => None,
+    };
+
+    let mut value = first;
+    for trips in 1..=MAX_UNROLL_TRIP_COUNT {
+        if !holds(value)? {
+            return Some(trips);
+        }
+        value = value.wrapping_add(step);
+    }
+    None
+}
+
+/// Replace every theta in `func` that is equivalent to its inputs, or to one
+/// run of its body, then prune the thetas that are left dead:
+///
+/// * A theta whose body passes every input through unchanged is replaced by
+///   its inputs. Its predicate is the same every time around, so the loop
+///   either runs once or never terminates; like pruning dead loops, this
+///   assumes the latter doesn't happen.
+/// * A theta whose predicate is false the first time it is checked runs its
+///   body once, so it is replaced by the outputs of its body, with the inputs
+///   substituted for the body's arguments (as in [simplify_constant_gammas]).
+///   The predicate is known to be false when it is a constant (or an argument
+///   whose input is one), or when the trip count is known to be one (see
+///   [unroll_theta]).
+pub(crate) fn remove_identity_thetas(func: &mut RvsdgFunction) {
+    identity_thetas(
+        &mut func.nodes,
+        func.results.iter_mut().chain(once(&mut func.state)),
+    );
+    prune_dead_nodes(func);
+}
+
+fn identity_thetas<'a>(nodes: &mut Vec<RvsdgBody>, roots: impl Iterator<Item = &'a mut Operand>) {
+    // The operands that each removed theta's outputs are replaced with.
+    let mut replaced = HashMap::<Id, Vec<Operand>>::new();
+    // Copies of loop bodies may contain thetas themselves, so keep going until
+    // we reach the end of the (growing) heap.
+    let mut id = 0;
+    while id < nodes.len() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = &mut nodes[id]
+        {
+            identity_thetas(lambda_nodes, outputs.iter_mut());
+        }
+        let RvsdgBody::Theta {
+            pred,
+            inputs,
+            outputs,
+        } = &nodes[id]
+        else {
+            id += 1;
+            continue;
+        };
+        let identity = outputs
+            .iter()
+            .enumerate()
+            .all(|(arg, output)| *output == Operand::Arg(arg));
+        let first_pred = match *pred {
+            Operand::Arg(arg) => inputs[arg],
+            pred => pred,
+        };
+        let runs_once = || {
+            matches!(
+                node_of(&first_pred).map(|pred| &nodes[pred]),
+                Some(RvsdgBody::BasicOp(Expr::Const(_, Literal::Bool(false), _)))
+            ) || trip_count(nodes, *pred, inputs, outputs) == Some(1)
+        };
+        if identity {
+            replaced.insert(id, inputs.clone());
+        } else if runs_once() {
+            let (inputs, outputs) = (inputs.clone(), outputs.clone());
+            let mut substituted = HashMap::new();
+            let outputs = outputs
+                .iter()
+                .map(|op| substitute(nodes, &inputs, &mut substituted, *op))
+                .collect();
+            replaced.insert(id, outputs);
+        }
+        id += 1;
+    }
+
+    // Replacement outputs may themselves project out of removed thetas.
+    let resolve = |op: &mut Operand| {
+        resolve_replaced(op, |i, id| replaced.get(&id).map(|outputs| outputs[i]))
+    };
+    nodes
+        .iter_mut()
+        .for_each(|body| body.for_each_operand_mut(resolve));
+    roots.for_each(resolve);
+}
+
+/// Fuse pairs of thetas in the top-level region of `func` (or of a lambda)
+/// that run their bodies the same number of times and don't depend on one
+/// another into a single theta, then prune the nodes left dead.
+///
+/// Loop variables that a theta passes through without reading (like values
+/// that are live across the loop, or the state edge of a loop without
+/// effects) are dropped from the fused theta, and their uses read the input
+/// of the original theta instead. Two thetas are then independent when
+/// neither one's remaining inputs are computed from the other's outputs. Two
+/// loops that both perform effects always depend on one another through the
+/// state, so they are never fused.
+///
+/// The loops run the same number of times when their predicates are computed
+/// in the same way from loop variables that start out with the same values
+/// and are updated in the same way (see [lockstep]). The fused theta takes the
+/// remaining inputs of the first theta followed by those of the second, and
+/// uses the first theta's predicate.
+pub(crate) fn fuse_thetas(func: &mut RvsdgFunction) {
+    fuse_theta_nodes(
+        &mut func.nodes,
+        func.results
+            .iter_mut()
+            .chain(once(&mut func.state))
+            .collect(),
+    );
+    prune_dead_nodes(func);
+}
+
+fn fuse_theta_nodes(nodes: &mut Vec<RvsdgBody>, mut roots: Vec<&mut Operand>) {
+    for body in nodes.iter_mut() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = body
+        {
+            fuse_theta_nodes(lambda_nodes, outputs.iter_mut().collect());
+        }
+    }
+    while let Some((t1, t2)) = fusable_thetas(nodes, roots.iter().map(|op| **op)) {
+        let (mut inputs, mut outputs, mut pred) = (vec![], vec![], None);
+        // The operands that replace the outputs of the two thetas: either an
+        // output of the fused theta, or an input that was passed through.
+        let mut moved = HashMap::new();
+        let mut passed = vec![];
+        for (theta, other) in [(t1, t2), (t2, t1)] {
+            let RvsdgBody::Theta {
+                pred: theta_pred,
+                inputs: theta_inputs,
+                outputs: theta_outputs,
+            } = nodes[theta].clone()
+            else {
+                unreachable!("fusable_thetas only returns thetas");
+            };
+            let theta_inputs = forward_invariant(nodes, other, &theta_inputs);
+            let read = read_slots(nodes, theta);
+            // The body doesn't read the slots that are dropped, so their
+            // arguments can stay as they are.
+            let mut args = Vec::from_iter((0..theta_inputs.len()).map(Operand::Arg));
+            for (k, input) in theta_inputs.iter().enumerate() {
+                if read.contains(&k) {
+                    args[k] = Operand::Arg(inputs.len());
+                    moved.insert((theta, k), Operand::Project(inputs.len(), t1));
+                    inputs.push(*input);
+                } else {
+                    passed.push(((theta, k), *input));
+                }
+            }
+            let mut substituted = HashMap::new();
+            if theta == t1 {
+                pred = Some(substitute(nodes, &args, &mut substituted, theta_pred));
+            }
+            outputs.extend(
+                read.iter()
+                    .map(|k| substitute(nodes, &args, &mut substituted, theta_outputs[*k])),
+            );
+        }
+        // An input that was passed through may come out of the other theta.
+        for (slot, input) in passed {
+            let input = match input.canonical() {
+                Operand::Id(id) if id == t1 || id == t2 => moved[&(id, 0)],
+                Operand::Project(k, id) if id == t1 || id == t2 => moved[&(id, k)],
+                _ => input,
+            };
+            moved.insert(slot, input);
+        }
+        nodes[t1] = RvsdgBody::Theta {
+            pred: pred.unwrap(),
+            inputs,
+            outputs,
+        };
+
+        // Only the region containing the thetas refers to them, but the nodes
+        // of the region aren't kept together, so look everywhere.
+        let redirect = |op: &mut Operand| match op.canonical() {
+            Operand::Id(id) if id == t1 || id == t2 => *op = moved[&(id, 0)],
+            Operand::Project(k, id) if id == t1 || id == t2 => *op = moved[&(id, k)],
+            _ => {}
+        };
+        for (id, body) in nodes.iter_mut().enumerate() {
+            if id != t1 {
+                body.for_each_operand_mut(redirect);
+            }
+        }
+        for op in &mut roots {
+            redirect(op);
+        }
+    }
+}
+
+/// The loop variables of `theta` that its body reads, or that it doesn't
+/// pass through unchanged, in order.
+fn read_slots(nodes: &[RvsdgBody], theta: Id) -> Vec<usize> {
+    let RvsdgBody::Theta { pred, outputs, .. } = &nodes[theta] else {
+        panic!("expected node {theta} to be a theta");
+    };
+    let updated = |k: usize| outputs[k] != Operand::Arg(k);
+    let read = region_args(
+        nodes,
+        once(*pred).chain(
+            (0..outputs.len())
+                .filter(|k| updated(*k))
+                .map(|k| outputs[k]),
+        ),
+    );
+    Vec::from_iter((0..outputs.len()).filter(|k| updated(*k) || read.contains(k)))
+}
+
+/// `inputs` with each output of `theta` that the theta passes through
+/// unchanged replaced by the corresponding input of the theta.
+fn forward_invariant(nodes: &[RvsdgBody], theta: Id, inputs: &[Operand]) -> Vec<Operand> {
+    let RvsdgBody::Theta {
+        inputs: theta_inputs,
+        outputs,
+        ..
+    } = &nodes[theta]
+    else {
+        panic!("expected node {theta} to be a theta");
+    };
+    let forward = |k: usize, op: Operand| {
+        if outputs[k] == Operand::Arg(k) {
+            theta_inputs[k]
+        } else {
+            op
+        }
+    };
+    Vec::from_iter(inputs.iter().map(|op| match op.canonical() {
+        Operand::Id(id) if id == theta => forward(0, *op),
+        Operand::Project(k, id) if id == theta => forward(k, *op),
+        op => op,
+    }))
+}
+
+/// A pair of thetas in the region computing `outputs` that [fuse_thetas] can
+/// fuse, if there is one.
+fn fusable_thetas(nodes: &[RvsdgBody], outputs: impl Iterator<Item = Operand>) -> Option<(Id, Id)> {
+    let mut thetas = Vec::from_iter(
+        outer_region(nodes, outputs)
+            .into_iter()
+            .filter(|id| matches!(nodes[*id], RvsdgBody::Theta { .. })),
+    );
+    thetas.sort_unstable();
+    // Whether the inputs that `theta` reads are computed without `other`.
+    let independent = |theta: Id, other: Id| {
+        let RvsdgBody::Theta { inputs, .. } = &nodes[theta] else {
+            unreachable!()
+        };
+        let inputs = forward_invariant(nodes, other, inputs);
+        let read = read_slots(nodes, theta);
+        !outer_region(nodes, read.iter().map(|k| inputs[*k])).contains(&other)
+    };
+    for (i, &t1) in thetas.iter().enumerate() {
+        for &t2 in &thetas[i + 1..] {
+            if independent(t1, t2) && independent(t2, t1) && lockstep(nodes, t1, t2) {
+                return Some((t1, t2));
+            }
+        }
+    }
+    None
+}
+
+/// Whether thetas `t1` and `t2` always run their bodies the same number of
+/// times: their predicates compute the same thing from loop variables that
+/// are related in the same way.
+///
+/// A loop variable of `t1` is related to one of `t2` when their inputs are
+/// structurally equal (looking through the outputs that the other theta
+/// passes through unchanged) and their outputs are computed in the same way
+/// from related loop variables. Loop variables are assumed to be related
+/// while their outputs are being compared, so that counters (which depend on
+/// themselves) can be related.
+fn lockstep(nodes: &[RvsdgBody], t1: Id, t2: Id) -> bool {
+    fn same(
+        nodes: &[RvsdgBody],
+        loops: [(&[Operand], &[Operand]); 2],
+        assumed: &mut HashSet<(usize, usize)>,
+        op1: Operand,
+        op2: Operand,
+    ) -> bool {
+        match (op1.canonical(), op2.canonical()) {
+            (Operand::Arg(a1), Operand::Arg(a2)) => {
+                let [(inputs1, outputs1), (inputs2, outputs2)] = loops;
+                if !assumed.insert((a1, a2)) {
+                    return true;
+                }
+                operands_structurally_eq(nodes, &inputs1[a1], &inputs2[a2])
+                    && same(nodes, loops, assumed, outputs1[a1], outputs2[a2])
+            }
+            (Operand::Id(id1), Operand::Id(id2)) => match (&nodes[id1], &nodes[id2]) {
+                (RvsdgBody::BasicOp(e1), RvsdgBody::BasicOp(e2)) => {
+                    same_operation(e1, e2)
+                        && e1.operands().len() == e2.operands().len()
+                        && e1
+                            .operands()
+                            .iter()
+                            .zip(e2.operands())
+                            .all(|(o1, o2)| same(nodes, loops, assumed, *o1, *o2))
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    let (
+        RvsdgBody::Theta {
+            pred: pred1,
+            inputs: inputs1,
+            outputs: outputs1,
+        },
+        RvsdgBody::Theta {
+            pred: pred2,
+            inputs: inputs2,
+            outputs: outputs2,
+        },
+    ) = (&nodes[t1], &nodes[t2])
+    else {
+        return false;
+    };
+    // Inputs that come out of the other theta unchanged are compared as they
+    // were before it.
+    let inputs1 = forward_invariant(nodes, t2, inputs1);
+    let inputs2 = forward_invariant(nodes, t1, inputs2);
+    let loops = [
+        (inputs1.as_slice(), outputs1.as_slice()),
+        (inputs2.as_slice(), outputs2.as_slice()),
+    ];
+    same(nodes, loops, &mut HashSet::new(), *pred1, *pred2)
+}
+
+/// How a loop variable of a theta changes from one iteration to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VarKind {
+    /// The body passes the variable through unchanged.
+    Invariant,
+    /// The body computes a new value for the variable, and reads the old one
+    /// along the way (not necessarily to compute the new value).
+    LoopCarried,
+    /// The body computes a new value for the variable, but never reads the
+    /// old one.
+    Unused,
+}
+
+/// Classify each loop variable of a theta with the given predicate, inputs,
+/// and outputs, whose body lives in `nodes`.
+///
+/// Slot `k` is [VarKind::Invariant] when `outputs[k]` is `Operand::Arg(k)`.
+/// Otherwise it is [VarKind::LoopCarried] if the predicate or some output
+/// depends on `Operand::Arg(k)` (including through the inputs of regions
+/// nested in the body), and [VarKind::Unused] if nothing does.
+pub(crate) fn theta_variable_kinds(
+    nodes: &[RvsdgBody],
+    pred: &Operand,
+    inputs: &[Operand],
+    outputs: &[Operand],
+) -> Vec<VarKind> {
+    assert_eq!(inputs.len(), outputs.len(), "malformed theta");
+    let read = region_args(nodes, once(pred).chain(outputs).copied());
+    Vec::from_iter(outputs.iter().enumerate().map(|(k, output)| {
+        if *output == Operand::Arg(k) {
+            VarKind::Invariant
+        } else if read.contains(&k) {
+            VarKind::LoopCarried
+        } else {
+            VarKind::Unused
+        }
+    }))
+}
+
+/// A branch of a gamma that only takes the inputs it uses, as produced by
+/// [split_gamma_inputs].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct GammaBranch {
+    /// The inputs to the branch, in the order they are passed to the gamma.
+    pub(crate) inputs: Vec<Operand>,
+    /// The outputs of the branch, where `Operand::Arg(k)` refers to
+    /// `inputs[k]`.
+    pub(crate) outputs: Vec<Operand>,
+}
+
+/// Split the inputs of `gamma` up between its branches, so that each branch
+/// only takes the inputs it reads. Each branch's arguments are renumbered to
+/// match, copying the nodes in the branch that depend on them onto the end of
+/// `nodes`; `gamma` itself is left alone.
+///
+/// [merge_gamma_inputs] does the opposite, so that the branches can be
+/// turned back into an ordinary gamma.
+pub(crate) fn split_gamma_inputs(nodes: &mut Vec<RvsdgBody>, gamma: Id) -> Vec<GammaBranch> {
+    let RvsdgBody::Gamma {
+        inputs, outputs, ..
+    } = &nodes[gamma]
+    else {
+        panic!("expected a gamma");
+    };
+    let (inputs, outputs) = (inputs.clone(), outputs.clone());
+    Vec::from_iter(outputs.into_iter().map(|branch| {
+        let mut used = Vec::from_iter(region_args(nodes, branch.iter().copied()));
+        used.sort_unstable();
+        // Arguments the branch doesn't read are never substituted.
+        let mut renumbered = Vec::from_iter((0..inputs.len()).map(Operand::Arg));
+        for (new, old) in used.iter().enumerate() {
+            renumbered[*old] = Operand::Arg(new);
+        }
+        let mut substituted = HashMap::new();
+        let outputs = branch
+            .iter()
+            .map(|op| substitute(nodes, &renumbered, &mut substituted, *op))
+            .collect();
+        GammaBranch {
+            inputs: used.iter().map(|arg| inputs[*arg]).collect(),
+            outputs,
+        }
+    }))
+}
+
+/// Build a gamma on `pred` out of `branches`, which may each take different
+/// inputs. The gamma takes every input that some branch takes (once, in the
+/// order they are first taken), and each branch's arguments are renumbered to
+/// match, copying the nodes in the branch that depend on them onto the end of
+/// `nodes`. This is the inverse of [split_gamma_inputs].
+pub(crate) fn merge_gamma_inputs(
+    nodes: &mut Vec<RvsdgBody>,
+    pred: Operand,
+    branches: &[GammaBranch],
+) -> RvsdgBody {
+    let mut inputs: Vec<Operand> = vec![];
+    for input in branches.iter().flat_map(|branch| &branch.inputs) {
+        if !inputs.contains(input) {
+            inputs.push(*input);
+        }
+    }
+    let outputs = Vec::from_iter(branches.iter().map(|branch| {
+        let renumbered = Vec::from_iter(
+            branch
+                .inputs
+                .iter()
+                .map(|input| Operand::Arg(inputs.iter().position(|op| op == input).unwrap())),
+        );
+        let mut substituted = HashMap::new();
+        Vec::from_iter(
+            branch
+                .outputs
+                .iter()
+                .map(|op| substitute(nodes, &renumbered, &mut substituted, *op)),
+        )
+    }));
+    RvsdgBody::Gamma {
+        pred,
+        inputs,
+        outputs,
+    }
+}
+
+/// Hoist gammas whose predicate is loop invariant out of the thetas they sit
+/// in, turning each such theta into a gamma with a copy of the loop in every
+/// branch (i.e. loop unswitching).
+///
+/// An argument of a theta's body is invariant when the body passes it through
+/// unchanged, and a pure operation is invariant when all of its operands are.
+/// In each copy of the loop, the hoisted gamma's predicate is replaced with
+/// the constant selecting the copy's branch, and the gamma is then folded away
+/// as in [simplify_constant_gammas]. Only gammas directly in a theta's body
+/// are hoisted, rather than ones in a region nested inside of it.
+pub(crate) fn hoist_invariant_gammas(func: &mut RvsdgFunction) {
+    // Restructuring routes every loop variable through the gammas in the
+    // body, so see through the ones that pass a variable along unchanged.
+    simplify_pure_gamma_state(func);
+    hoist_gammas(&mut func.nodes);
+    simplify_constant_gammas(func);
+}
+
+fn hoist_gammas(nodes: &mut Vec<RvsdgBody>) {
+    // The copies of a loop may contain invariant gammas themselves, so keep
+    // going until we reach the end of the (growing) heap.
+    let mut id = 0;
+    while id < nodes.len() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            ..
+        } = &mut nodes[id]
+        {
+            hoist_gammas(lambda_nodes);
+        }
+        if let Some(gamma) = invariant_gamma(nodes, id) {
+            unswitch(nodes, id, gamma);
+        }
+        id += 1;
+    }
+}
+
+/// A gamma in the body of `theta` whose predicate is loop invariant, if
+/// `theta` is a theta and there is one. Gammas on a constant are left for
+/// [simplify_constant_gammas].
+fn invariant_gamma(nodes: &[RvsdgBody], theta: Id) -> Option<Id> {
+    fn invariant(
+        nodes: &[RvsdgBody],
+        outputs: &[Operand],
+        memo: &mut HashMap<Id, bool>,
+        op: Operand,
+    ) -> bool {
+        let id = match op {
+            Operand::Arg(arg) => return outputs[arg] == op,
+            Operand::Id(id) | Operand::Project(_, id) => id,
+        };
+        if let Some(invariant) = memo.get(&id) {
+            return *invariant;
+        }
+        let result = match &nodes[id] {
+            RvsdgBody::BasicOp(
+                expr @ (Expr::Op(..)
+                | Expr::UnsignedCmp(..)
+                | Expr::Bitwise(..)
+                | Expr::Select(..)
+                | Expr::Const(..)),
+            ) => expr
+                .operands()
+                .iter()
+                .all(|op| invariant(nodes, outputs, memo, *op)),
+            _ => false,
+        };
+        memo.insert(id, result);
+        result
+    }
+
+    let RvsdgBody::Theta { pred, outputs, .. } = &nodes[theta] else {
+        return None;
+    };
+    let mut memo = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut stack = Vec::from_iter(once(pred).chain(outputs).filter_map(node_of));
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        if let RvsdgBody::Gamma { pred, .. } = &nodes[id] {
+            let constant = node_of(pred)
+                .is_some_and(|pred| matches!(nodes[pred], RvsdgBody::BasicOp(Expr::Const(..))));
+            if !constant && invariant(nodes, outputs, &mut memo, *pred) {
+                return Some(id);
+            }
+        }
+        stack.extend(region_operands(&nodes[id]).iter().filter_map(node_of));
+    }
+    None
+}
+
+/// Replace `theta` with a gamma on the predicate of `gamma`, which is in the
+/// theta's body, whose branches each run a copy of the loop with `gamma`
+/// specialized to that branch.
+fn unswitch(nodes: &mut Vec<RvsdgBody>, theta: Id, gamma: Id) {
+    let RvsdgBody::Theta {
+        pred,
+        inputs,
+        outputs,
+    } = nodes[theta].clone()
+    else {
+        panic!("expected node {theta} to be a theta");
+    };
+    let RvsdgBody::Gamma {
+        pred: gamma_pred,
+        inputs: gamma_inputs,
+        outputs: branches,
+    } = nodes[gamma].clone()
+    else {
+        panic!("expected node {gamma} to be a gamma");
+    };
+
+    // The predicate is the same on every iteration as on the first, where the
+    // arguments of the body are the inputs of the theta.
+    let hoisted = substitute(nodes, &inputs, &mut HashMap::new(), gamma_pred);
+    // Each copy of the loop gets the theta's inputs passed straight through.
+    let args = Vec::from_iter((0..inputs.len()).map(Operand::Arg));
+    let loops = Vec::from_iter((0..branches.len()).map(|branch| {
+        let (lit, ty) = if branches.len() == 2 {
+            (Literal::Bool(branch == 1), Type::Bool)
+        } else {
+            (Literal::Int(branch as i64), Type::Int)
+        };
+        nodes.push(RvsdgBody::BasicOp(Expr::Const(ConstOps::Const, lit, ty)));
+        nodes.push(RvsdgBody::Gamma {
+            pred: Operand::Id(nodes.len() - 1),
+            inputs: gamma_inputs.clone(),
+            outputs: branches.clone(),
+        });
+        let mut substituted = HashMap::from([(gamma, nodes.len() - 1)]);
+        let pred = substitute(nodes, &args, &mut substituted, pred);
+        let outputs = Vec::from_iter(
+            outputs
+                .iter()
+                .map(|op| substitute(nodes, &args, &mut substituted, *op)),
+        );
+        nodes.push(RvsdgBody::Theta {
+            pred,
+            inputs: args.clone(),
+            outputs,
+        });
+        let copy = nodes.len() - 1;
+        Vec::from_iter((0..inputs.len()).map(|i| Operand::Project(i, copy)))
+    }));
+    // The gamma has an output for every output of the theta, so uses of the
+    // theta can be left as they are.
+    nodes[theta] = RvsdgBody::Gamma {
+        pred: hoisted,
+        inputs,
+        outputs: loops,
+    };
+}
+
+/// Turn a function that ends in a self-call into a loop.
+///
+/// Lambdas are closed, so they can't refer to themselves; self-recursion only
+/// shows up as an `Expr::Call` of the function's own name. The call is in tail
+/// position when the function's results and state all come out of a gamma,
+/// and in some branch of the gamma they are exactly the outputs of the call.
+/// The function body then becomes the body of a theta whose loop variables are
+/// the arguments, the state, and the results: branches ending in the call
+/// loop around with the call's arguments, while the others exit with their
+/// outputs. Results start out as placeholder constants, so functions returning
+/// pointers are left alone.
+pub(crate) fn tailcall_to_loop(func: &mut RvsdgFunction) {
+    let Some(gamma) = node_of(&func.state) else {
+        return;
+    };
+    let RvsdgBody::Gamma {
+        pred,
+        inputs,
+        outputs,
+    } = &func.nodes[gamma]
+    else {
+        return;
+    };
+    // Which output of the gamma each result and the state come from.
+    let output_of = |op: &Operand| match op.canonical() {
+        Operand::Id(id) if id == gamma => Some(0),
+        Operand::Project(i, id) if id == gamma => Some(i),
+        _ => None,
+    };
+    let Some(state) = output_of(&func.state) else {
+        return;
+    };
+    let Some(results) = func.results.iter().map(output_of).collect::<Option<Vec<_>>>() else {
+        return;
+    };
+
+    // For each branch that ends in a self-call, the call's operands.
+    let n_args = func.args.len();
+    let mut result_ty = None;
+    let tail_calls = Vec::from_iter(outputs.iter().map(|branch| {
+        let call = node_of(&branch[state])?;
+        let RvsdgBody::BasicOp(Expr::Call(callee, ops, n_outputs, ty, false)) = &func.nodes[call]
+        else {
+            return None;
+        };
+        let is_tail = callee.to_string() == func.name
+            && ops.len() == n_args + 1
+            && *n_outputs == results.len() + 1
+            && branch[state].canonical() == Operand::Project(results.len(), call).canonical()
+            && results.iter().enumerate().all(|(i, output)| {
+                branch[*output].canonical() == Operand::Project(i, call).canonical()
+            });
+        is_tail.then(|| {
+            result_ty = ty.clone();
+            ops.clone()
+        })
+    }));
+    if tail_calls.iter().all(Option::is_none) {
+        return;
+    }
+    let placeholders = Vec::from_iter(result_ty.iter().take(results.len()).map(|ty| {
+        let lit = match ty {
+            Type::Int => Literal::Int(0),
+            Type::Bool => Literal::Bool(false),
+            Type::Float => Literal::Float(0.0),
+            Type::Char => Literal::Char('\0'),
+            Type::Pointer(_) => return None,
+        };
+        Some(Expr::Const(ConstOps::Const, lit, ty.clone()))
+    }));
+    let Some(placeholders) = placeholders.into_iter().collect::<Option<Vec<_>>>() else {
+        return;
+    };
+    if placeholders.len() != results.len() {
+        return;
+    }
+
+    // The loop variables are the arguments, then the state, then the results.
+    // They are passed into the gamma after its original inputs.
+    let n_vars = n_args + 1 + results.len();
+    let (pred, mut inputs, outputs) = (*pred, inputs.clone(), outputs.clone());
+    let var = |v: usize| Operand::Arg(inputs.len() + v);
+    let mut push = |body: RvsdgBody| {
+        func.nodes.push(body);
+        func.nodes.len() - 1
+    };
+    let repeat = push(RvsdgBody::BasicOp(Expr::Const(
+        ConstOps::Const,
+        Literal::Bool(true),
+        Type::Bool,
+    )));
+    let exit = push(RvsdgBody::BasicOp(Expr::Const(
+        ConstOps::Const,
+        Literal::Bool(false),
+        Type::Bool,
+    )));
+    let branches = Vec::from_iter(outputs.iter().zip(tail_calls).map(
+        |(branch, call)| match call {
+            Some(ops) => {
+                let carried = (n_args + 1..n_vars).map(var);
+                Vec::from_iter(
+                    ops.into_iter()
+                        .chain(carried)
+                        .chain(once(Operand::Id(repeat))),
+                )
+            }
+            None => {
+                let args = (0..n_args).map(var);
+                let rest = once(state).chain(results.iter().copied());
+                let rest = rest.map(|output| branch[output]);
+                Vec::from_iter(args.chain(rest).chain(once(Operand::Id(exit))))
+            }
+        },
+    ));
+    inputs.extend((0..n_vars).map(Operand::Arg));
+    let body = push(RvsdgBody::Gamma {
+        pred,
+        inputs,
+        outputs: branches,
+    });
+    let initial = Vec::from_iter(
+        (0..=n_args).map(Operand::Arg).chain(
+            placeholders
+                .into_iter()
+                .map(|expr| Operand::Id(push(RvsdgBody::BasicOp(expr)))),
+        ),
+    );
+    let theta = push(RvsdgBody::Theta {
+        pred: Operand::Project(n_vars, body),
+        inputs: initial,
+        outputs: Vec::from_iter((0..n_vars).map(|v| Operand::Project(v, body))),
+    });
+    func.state = Operand::Project(n_args, theta);
+    func.results = Vec::from_iter((n_args + 1..n_vars).map(|v| Operand::Project(v, theta)));
+    prune_dead_nodes(func);
+}
+
+/// Replace two-way gammas on a boolean predicate whose branches only pass
+/// their inputs out with [Expr::Select]s, then prune the gammas.
+///
+/// Outputs that are the same input in both branches (like the state edge of a
+/// gamma with no effects) are forwarded to that input, and every other output
+/// becomes a select between the inputs that each branch picks. Gammas whose
+/// predicate or outputs have types that can't be read off of the graph (e.g.
+/// arguments of a nested region) are left alone.
+pub(crate) fn gammas_to_selects(func: &mut RvsdgFunction) {
+    selects_from_gammas(
+        &mut func.nodes,
+        &func.args,
+        func.results
+            .iter_mut()
+            .chain(once(&mut func.state))
+            .collect(),
+    );
+    prune_dead_nodes(func);
+}
+
+fn selects_from_gammas(nodes: &mut Vec<RvsdgBody>, arg_types: &[Type], roots: Vec<&mut Operand>) {
+    /// The type of `op`, when it is known. Argument types are only known in
+    /// the outermost region.
+    fn type_of(nodes: &[RvsdgBody], arg_types: Option<&[Type]>, op: Operand) -> Option<Type> {
+        match op.canonical() {
+            Operand::Arg(arg) => arg_types?.get(arg).cloned(),
+            Operand::Id(id) => match &nodes[id] {
+                RvsdgBody::BasicOp(
+                    Expr::Op(_, _, ty)
+                    | Expr::EffectfulOp(_, _, ty)
+                    | Expr::Select(_, ty)
+                    | Expr::Const(_, _, ty),
+                ) => Some(ty.clone()),
+                RvsdgBody::BasicOp(Expr::UnsignedCmp(..)) => Some(Type::Bool),
+                RvsdgBody::BasicOp(Expr::Bitwise(..)) => Some(Type::Int),
+                RvsdgBody::BasicOp(Expr::Call(_, _, _, ty, _)) => ty.clone(),
+                _ => None,
+            },
+            Operand::Project(..) => None,
+        }
+    }
+
+    // The nodes in the outermost region, where `arg_types` applies.
+    let outer = outer_region(nodes, roots.iter().map(|op| **op));
+
+    // The operands that each replaced gamma's outputs are replaced with.
+    let mut replaced = HashMap::<Id, Vec<Operand>>::new();
+    for id in 0..nodes.len() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = &mut nodes[id]
+        {
+            selects_from_gammas(lambda_nodes, &[], outputs.iter_mut().collect());
+            continue;
+        }
+        let RvsdgBody::Gamma {
+            pred,
+            inputs,
+            outputs,
+        } = &nodes[id]
+        else {
+            continue;
+        };
+        let [els, then] = outputs.as_slice() else {
+            continue;
+        };
+        let arg_types = Some(arg_types).filter(|_| outer.contains(&id));
+        if type_of(nodes, arg_types, *pred) != Some(Type::Bool) {
+            continue;
+        }
+        let (pred, n_outputs) = (*pred, then.len());
+        let Some(choices) = then
+            .iter()
+            .zip(els)
+            .map(|(then, els)| match (*then, *els) {
+                (Operand::Arg(then), Operand::Arg(els)) => Some((inputs[then], inputs[els])),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        let mut new_outputs = Vec::with_capacity(choices.len());
+        let mut selects = Vec::new();
+        for (then, els) in choices {
+            if then == els {
+                new_outputs.push(then);
+                continue;
+            }
+            let ty = type_of(nodes, arg_types, then).or_else(|| type_of(nodes, arg_types, els));
+            let Some(ty) = ty else {
+                break;
+            };
+            new_outputs.push(Operand::Id(nodes.len() + selects.len()));
+            selects.push(RvsdgBody::BasicOp(Expr::Select(vec![pred, then, els], ty)));
+        }
+        if new_outputs.len() == n_outputs {
+            nodes.extend(selects);
+            replaced.insert(id, new_outputs);
+        }
+    }
+
+    // Replacement outputs may themselves come out of replaced gammas.
+    let resolve = |op: &mut Operand| {
+        resolve_replaced(op, |i, id| replaced.get(&id).map(|outputs| outputs[i]))
+    };
+    nodes
+        .iter_mut()
+        .for_each(|body| body.for_each_operand_mut(resolve));
+    roots.into_iter().for_each(resolve);
+}
+
+/// The nodes in the region that computes `outputs`, not counting the nodes in
+/// regions nested inside of it.
+fn outer_region(nodes: &[RvsdgBody], outputs: impl Iterator<Item = Operand>) -> HashSet<Id> {
+    let mut outer = HashSet::new();
+    let mut stack = Vec::from_iter(outputs.filter_map(|op| node_of(&op)));
+    while let Some(id) = stack.pop() {
+        if outer.insert(id) {
+            stack.extend(region_operands(&nodes[id]).iter().filter_map(node_of));
+        }
+    }
+    outer
+}
+
+/// The arguments that the region computing `outputs` reads, not counting
+/// those of regions nested inside of it.
+fn region_args(nodes: &[RvsdgBody], outputs: impl Iterator<Item = Operand>) -> HashSet<usize> {
+    let mut args = HashSet::new();
+    let mut seen = HashSet::new();
+    let mut stack = Vec::from_iter(outputs);
+    while let Some(op) = stack.pop() {
+        match op {
+            Operand::Arg(arg) => {
+                args.insert(arg);
+            }
+            Operand::Id(id) | Operand::Project(_, id) => {
+                if seen.insert(id) {
+                    stack.extend(region_operands(&nodes[id]));
+                }
+            }
+        }
+    }
+    args
+}
+
+/// The operands of `body` that are evaluated in the region containing `body`.
+pub(crate) fn region_operands(body: &RvsdgBody) -> Vec<Operand> {
+    match body {
+        RvsdgBody::BasicOp(expr) => expr.operands().to_vec(),
+        RvsdgBody::Gamma { pred, inputs, .. } => once(pred).chain(inputs).copied().collect(),
+        RvsdgBody::Theta { inputs, .. } => inputs.clone(),
+        RvsdgBody::Lambda { .. } => Vec::new(),
+        RvsdgBody::Apply { func, args } => once(func).chain(args).copied().collect(),
+    }
+}
+
+/// The operands of `body` that are evaluated in the region containing `body`,
+/// as opposed to in a region nested inside it.
+fn region_operands_mut(body: &mut RvsdgBody) -> Vec<&mut Operand> {
+    match body {
+        RvsdgBody::BasicOp(expr) => expr.operands_mut().iter_mut().collect(),
+        RvsdgBody::Gamma { pred, inputs, .. } => once(pred).chain(inputs.iter_mut()).collect(),
+        RvsdgBody::Theta { inputs, .. } => inputs.iter_mut().collect(),
+        RvsdgBody::Lambda { .. } => Vec::new(),
+        RvsdgBody::Apply { func, args } => once(func).chain(args.iter_mut()).collect(),
+    }
+}
+
+/// Reroute uses of gamma outputs that every branch passes through unchanged
+/// to the corresponding gamma input. Most importantly, this takes gammas whose
+/// branches perform no effects off of the state path.
+///
+/// An output is passed through if it is the same `Operand::Arg(k)` in every
+/// branch. The state edge is not necessarily the last input of a gamma, so
+/// `k` is read off of the branches rather than assumed.
+pub(crate) fn simplify_pure_gamma_state(func: &mut RvsdgFunction) {
+    forward_gamma_args(
+        &mut func.nodes,
+        func.results.iter_mut().chain(once(&mut func.state)),
+    );
+}
+
+fn forward_gamma_args<'a>(nodes: &mut [RvsdgBody], roots: impl Iterator<Item = &'a mut Operand>) {
+    // (output, gamma) => the input that the output always equals.
+    let mut forwarded = HashMap::<(usize, Id), Operand>::new();
+    for (id, body) in nodes.iter_mut().enumerate() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = body
+        {
+            forward_gamma_args(lambda_nodes, outputs.iter_mut());
+            continue;
+        }
+        let RvsdgBody::Gamma {
+            inputs, outputs, ..
+        } = body
+        else {
+            continue;
+        };
+        let Some(first) = outputs.first() else {
+            continue;
+        };
+        for (output, op) in first.iter().enumerate() {
+            let Operand::Arg(arg) = *op else {
+                continue;
+            };
+            if outputs
+                .iter()
+                .all(|branch| branch[output] == Operand::Arg(arg))
+            {
+                forwarded.insert((output, id), inputs[arg]);
+            }
+        }
+    }
+
+    // The input of one gamma may itself be forwarded from another.
+    let resolve = |op: &mut Operand| {
+        resolve_replaced(op, |output, gamma| forwarded.get(&(output, gamma)).copied())
+    };
+    nodes
+        .iter_mut()
+        .for_each(|body| body.for_each_operand_mut(resolve));
+    roots.for_each(resolve);
+}
+
+/// Merge chains of prints in `func` with nothing in between them on the state
+/// edge into single prints of all of their values, in order.
+///
+/// Bril prints the arguments of a single print on one line, separated by
+/// spaces, so unlike the other optimizations here this changes what the
+/// program outputs. It is meant for backends that batch up their output and
+/// don't care where the line breaks are.
+pub(crate) fn batch_prints(func: &mut RvsdgFunction) {
+    batch_print_nodes(
+        &mut func.nodes,
+        func.results.iter().chain(once(&func.state)).copied(),
+    );
+    prune_dead_nodes(func);
+}
+
+/// How many times each of `nodes` is used, by the other nodes and by `roots`.
+fn use_counts(nodes: &[RvsdgBody], roots: impl Iterator<Item = Operand>) -> Vec<usize> {
+    let mut uses = vec![0; nodes.len()];
+    let mut count = |op: &Operand| {
+        if let Some(id) = node_of(op) {
+            uses[id] += 1;
+        }
+    };
+    nodes
+        .iter()
+        .for_each(|body| body.for_each_operand(&mut count));
+    roots.for_each(|op| count(&op));
+    uses
+}
+
+fn batch_print_nodes(nodes: &mut [RvsdgBody], roots: impl Iterator<Item = Operand>) {
+    let uses = use_counts(nodes, roots);
+    for id in 0..nodes.len() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = &mut nodes[id]
+        {
+            batch_print_nodes(lambda_nodes, outputs.clone().into_iter());
+            continue;
+        }
+        let RvsdgBody::BasicOp(Expr::Print(ops)) = &nodes[id] else {
+            continue;
+        };
+        let Some((state, values)) = ops.split_last() else {
+            continue;
+        };
+        // The earlier print's state must only flow into this one: anything
+        // else reading it would be between the two prints. That also rules
+        // out the values printed here depending on the earlier print.
+        let Some(prev) = node_of(state).filter(|prev| uses[*prev] == 1) else {
+            continue;
+        };
+        let RvsdgBody::BasicOp(Expr::Print(prev_ops)) = &nodes[prev] else {
+            continue;
+        };
+        let Some((prev_state, prev_values)) = prev_ops.split_last() else {
+            continue;
+        };
+        let merged = Vec::from_iter(
+            prev_values
+                .iter()
+                .chain(values)
+                .chain(once(prev_state))
+                .copied(),
+        );
+        nodes[id] = RvsdgBody::BasicOp(Expr::Print(merged));
+    }
+}
+
+/// Remove the stores in `func` that are overwritten by the next operation on
+/// the state edge: another store to the same pointer.
+///
+/// This is conservative. Pointers are only the same if they are structurally
+/// equal, and the two stores have to be adjacent on the state edge, with
+/// nothing else reading the state in between, so no load (or call, which may
+/// load) can see the overwritten value.
+pub(crate) fn dead_store_elimination(func: &mut RvsdgFunction) {
+    dead_store_nodes(
+        &mut func.nodes,
+        func.results.iter().chain(once(&func.state)).copied(),
+    );
+    prune_dead_nodes(func);
+}
+
+fn dead_store_nodes(nodes: &mut [RvsdgBody], roots: impl Iterator<Item = Operand>) {
+    let uses = use_counts(nodes, roots);
+    for id in 0..nodes.len() {
+        if let RvsdgBody::Lambda {
+            nodes: lambda_nodes,
+            outputs,
+            ..
+        } = &mut nodes[id]
+        {
+            dead_store_nodes(lambda_nodes, outputs.clone().into_iter());
+            continue;
+        }
+        let RvsdgBody::BasicOp(Expr::Effect(EffectOps::Store, ops)) = &nodes[id] else {
+            continue;
+        };
+        let [ptr, _, state] = ops[..] else {
+            continue;
+        };
+        // Only this store may read the earlier one's state. That also means
+        // neither the pointer nor the value stored here depend on it.
+        let Some(prev) = node_of(&state).filter(|prev| uses[*prev] == 1) else {
+            continue;
+        };
+        let RvsdgBody::BasicOp(Expr::Effect(EffectOps::Store, prev_ops)) = &nodes[prev] else {
+            continue;
+        };
+        let [prev_ptr, _, prev_state] = prev_ops[..] else {
+            continue;
+        };
+        if operands_structurally_eq(nodes, &ptr, &prev_ptr) {
+            let RvsdgBody::BasicOp(Expr::Effect(_, ops)) = &mut nodes[id] else {
+                unreachable!()
+            };
+            ops[2] = prev_state;
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Generating random [RvsdgFunction]s, e.g. for property tests.
+//!
+//! The functions that [random_function] produces are meaningless as programs,
+//! but they are well-formed in the sense of [validate](super::validate):
+//! every operand refers to an earlier node or to an argument of the region it
+//! is used in, the branches of a gamma all have the same outputs, and thetas
+//! have as many outputs as inputs. Values are also used at consistent types,
+//! and the state edge is threaded through every effectful node, gamma, and
+//! theta.
+//!
+//! Generation is deterministic in the seed, so a failing seed can be replayed.
+use bril_rs::{ConstOps, Literal, Type, ValueOps};
+
+use super::{Expr, Id, Operand, RvsdgBody, RvsdgFunction};
+
+/// How deeply gammas and thetas are nested.
+const MAX_DEPTH: usize = 2;
+
+/// Generate a random function named `main` from `seed`. Nodes stop being added
+/// once there are `max_nodes` of them, though a few more may be needed to
+/// finish off regions that are under construction (e.g. constants to return).
+///
+/// Only integer and boolean operations, `div`, `print`, gammas, and thetas
+/// are generated, which is everything that survives a round trip through
+/// [RvsdgFunction::to_egglog_expr] and
+/// [RvsdgFunction::egglog_expr_to_function].
+pub(crate) fn random_function(seed: u64, max_nodes: usize) -> RvsdgFunction {
+    let mut generator = Generator {
+        rng: Rng(seed),
+        nodes: vec![],
+        max_nodes,
+    };
+    let n_args = generator.rng.below(3);
+    let mut scope = Scope {
+        ints: (0..n_args).map(Operand::Arg).collect(),
+        bools: vec![],
+        state: Operand::Arg(n_args),
+    };
+    generator.fill(&mut scope, 0, max_nodes);
+    let n_results = generator.rng.below(3);
+    let results = Vec::from_iter((0..n_results).map(|_| generator.int(&mut scope)));
+    RvsdgFunction {
+        name: "main".into(),
+        args: vec![Type::Int; n_args],
+        return_type: (n_results > 0).then_some(Type::Int),
+        nodes: generator.nodes,
+        results,
+        state: scope.state,
+    }
+}
+
+/// A splitmix64 generator. We don't need good randomness, just a cheap and
+/// reproducible source of it.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn coin(&mut self) -> bool {
+        self.below(2) == 0
+    }
+
+    fn choose<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+}
+
+/// The values available in the region being generated, by type.
+#[derive(Clone)]
+struct Scope {
+    ints: Vec<Operand>,
+    bools: Vec<Operand>,
+    state: Operand,
+}
+
+struct Generator {
+    rng: Rng,
+    nodes: Vec<RvsdgBody>,
+    max_nodes: usize,
+}
+
+impl Generator {
+    /// Add up to `steps` nodes to the region of `scope`, which is nested
+    /// `depth` gammas and thetas deep.
+    fn fill(&mut self, scope: &mut Scope, depth: usize, steps: usize) {
+        for _ in 0..steps {
+            if self.nodes.len() >= self.max_nodes {
+                break;
+            }
+            self.step(scope, depth);
+        }
+    }
+
+    fn step(&mut self, scope: &mut Scope, depth: usize) {
+        let choices = if depth < MAX_DEPTH { 9 } else { 7 };
+        match self.rng.below(choices) {
+            0 => {
+                let lit = self.lit_int();
+                scope.ints.push(lit);
+            }
+            1 => {
+                let lit = self.lit_bool();
+                scope.bools.push(lit);
+            }
+            2 => {
+                let op = self
+                    .rng
+                    .choose(&[ValueOps::Add, ValueOps::Sub, ValueOps::Mul]);
+                let (l, r) = (self.int(scope), self.int(scope));
+                let res = self.push(RvsdgBody::BasicOp(Expr::Op(op, vec![l, r], Type::Int)));
+                scope.ints.push(Operand::Id(res));
+            }
+            3 => {
+                let op = self.rng.choose(&[ValueOps::Lt, ValueOps::Eq]);
+                let (l, r) = (self.int(scope), self.int(scope));
+                let res = self.push(RvsdgBody::BasicOp(Expr::Op(op, vec![l, r], Type::Bool)));
+                scope.bools.push(Operand::Id(res));
+            }
+            4 => {
+                let expr = match self.rng.below(3) {
+                    0 => Expr::Op(ValueOps::Not, vec![self.bool(scope)], Type::Bool),
+                    n => {
+                        let op = if n == 1 { ValueOps::And } else { ValueOps::Or };
+                        let (l, r) = (self.bool(scope), self.bool(scope));
+                        Expr::Op(op, vec![l, r], Type::Bool)
+                    }
+                };
+                let res = self.push(RvsdgBody::BasicOp(expr));
+                scope.bools.push(Operand::Id(res));
+            }
+            5 => {
+                let (l, r) = (self.int(scope), self.int(scope));
+                let res = self.push(RvsdgBody::BasicOp(Expr::EffectfulOp(
+                    ValueOps::Div,
+                    vec![l, r, scope.state],
+                    Type::Int,
+                )));
+                scope.ints.push(Operand::Project(0, res));
+                scope.state = Operand::Project(1, res);
+            }
+            6 => {
+                let x = if self.rng.coin() {
+                    self.int(scope)
+                } else {
+                    self.bool(scope)
+                };
+                let res = self.push(RvsdgBody::BasicOp(Expr::Print(vec![x, scope.state])));
+                scope.state = Operand::Id(res);
+            }
+            7 => self.gamma(scope, depth),
+            _ => self.theta(scope, depth),
+        }
+    }
+
+    /// Pick a random subset of the values in `scope` to pass into a region,
+    /// returning the inputs and the scope inside the region. The state edge
+    /// is always passed, as the first input.
+    fn region_inputs(&mut self, scope: &Scope) -> (Vec<Operand>, Scope) {
+        let ints = Vec::from_iter(scope.ints.iter().copied().filter(|_| self.rng.coin()));
+        let bools = Vec::from_iter(scope.bools.iter().copied().filter(|_| self.rng.coin()));
+        let inner = Scope {
+            ints: (1..=ints.len()).map(Operand::Arg).collect(),
+            bools: (ints.len() + 1..=ints.len() + bools.len())
+                .map(Operand::Arg)
+                .collect(),
+            state: Operand::Arg(0),
+        };
+        let inputs = Vec::from_iter([scope.state].into_iter().chain(ints).chain(bools));
+        (inputs, inner)
+    }
+
+    fn gamma(&mut self, scope: &mut Scope, depth: usize) {
+        let pred = self.bool(scope);
+        let (inputs, inner) = self.region_inputs(scope);
+        let (n_ints, n_bools) = (self.rng.below(3), self.rng.below(2));
+        let mut outputs = vec![];
+        for _ in 0..2 {
+            let mut branch = inner.clone();
+            let steps = self.rng.below(4);
+            self.fill(&mut branch, depth + 1, steps);
+            let mut branch_outputs = vec![branch.state];
+            for _ in 0..n_ints {
+                branch_outputs.push(self.int(&mut branch));
+            }
+            for _ in 0..n_bools {
+                branch_outputs.push(self.bool(&mut branch));
+            }
+            outputs.push(branch_outputs);
+        }
+        let gamma = self.push(RvsdgBody::Gamma {
+            pred,
+            inputs,
+            outputs,
+        });
+        scope.state = Operand::Project(0, gamma);
+        scope
+            .ints
+            .extend((1..=n_ints).map(|i| Operand::Project(i, gamma)));
+        scope
+            .bools
+            .extend((n_ints + 1..=n_ints + n_bools).map(|i| Operand::Project(i, gamma)));
+    }
+
+    fn theta(&mut self, scope: &mut Scope, depth: usize) {
+        let (inputs, inner) = self.region_inputs(scope);
+        let (n_ints, n_bools) = (inner.ints.len(), inner.bools.len());
+        let mut body = inner;
+        let steps = self.rng.below(4);
+        self.fill(&mut body, depth + 1, steps);
+        // Each loop variable must keep its type from one iteration to the
+        // next.
+        let mut outputs = vec![body.state];
+        for _ in 0..n_ints {
+            outputs.push(self.int(&mut body));
+        }
+        for _ in 0..n_bools {
+            outputs.push(self.bool(&mut body));
+        }
+        let pred = self.bool(&mut body);
+        let theta = self.push(RvsdgBody::Theta {
+            pred,
+            inputs,
+            outputs,
+        });
+        scope.state = Operand::Project(0, theta);
+        scope
+            .ints
+            .extend((1..=n_ints).map(|i| Operand::Project(i, theta)));
+        scope
+            .bools
+            .extend((n_ints + 1..=n_ints + n_bools).map(|i| Operand::Project(i, theta)));
+    }
+
+    /// An integer in `scope`, adding a constant if there are none.
+    fn int(&mut self, scope: &mut Scope) -> Operand {
+        if scope.ints.is_empty() {
+            let lit = self.lit_int();
+            scope.ints.push(lit);
+        }
+        self.rng.choose(&scope.ints)
+    }
+
+    /// A boolean in `scope`, adding a constant if there are none.
+    fn bool(&mut self, scope: &mut Scope) -> Operand {
+        if scope.bools.is_empty() {
+            let lit = self.lit_bool();
+            scope.bools.push(lit);
+        }
+        self.rng.choose(&scope.bools)
+    }
+
+    fn lit_int(&mut self) -> Operand {
+        let n = self.rng.below(21) as i64 - 10;
+        Operand::Id(self.push(RvsdgBody::BasicOp(Expr::Const(
+            ConstOps::Const,
+            Literal::Int(n),
+            Type::Int,
+        ))))
+    }
+
+    fn lit_bool(&mut self) -> Operand {
+        let b = self.rng.coin();
+        Operand::Id(self.push(RvsdgBody::BasicOp(Expr::Const(
+            ConstOps::Const,
+            Literal::Bool(b),
+            Type::Bool,
+        ))))
+    }
+
+    fn push(&mut self, body: RvsdgBody) -> Id {
+        self.nodes.push(body);
+        self.nodes.len() - 1
+    }
+}
@@ -1,11 +1,14 @@
 //! Convert a potentially irreducible CFG to a reducible one.
+//!
+//! The sets and maps here iterate in insertion order, so that the blocks and
+//! branches added only depend on the CFG being restructured.
 
-use hashbrown::{HashMap, HashSet};
+use indexmap::{IndexMap, IndexSet};
 use petgraph::{
     algo::{dominators, tarjan_scc},
     graph::NodeIndex,
     stable_graph::EdgeIndex,
-    visit::{EdgeRef, NodeFiltered, VisitMap},
+    visit::{EdgeRef, NodeFiltered, NodeIndexable, VisitMap},
     Direction,
 };
 
@@ -45,7 +48,7 @@ impl Cfg {
 
     pub(crate) fn restructure(&mut self) {
         let mut state = RestructureState { n_names: 0 };
-        let mut all = NodeSet::with_capacity(self.graph.node_count());
+        let mut all = NodeSet::with_capacity(self.graph.node_bound());
         self.graph.node_indices().for_each(|node| {
             all.visit(node);
         });
@@ -117,8 +120,8 @@ impl Cfg {
             // The following follows the paper fairly literally.
 
             let scc_set = node_set(scc.iter().copied());
-            let mut entry_arcs = HashSet::new();
-            let mut entry_vertices = HashSet::new();
+            let mut entry_arcs = IndexSet::new();
+            let mut entry_vertices = IndexSet::new();
             for edge_ref in scc
                 .iter()
                 .flat_map(|node| self.graph.edges_directed(*node, Direction::Incoming))
@@ -128,8 +131,8 @@ impl Cfg {
                 entry_vertices.insert(edge_ref.target());
             }
 
-            let mut exit_arcs = HashSet::new();
-            let mut exit_vertices = HashSet::new();
+            let mut exit_arcs = IndexSet::new();
+            let mut exit_vertices = IndexSet::new();
 
             for edge_ref in scc
                 .iter()
@@ -140,7 +143,7 @@ impl Cfg {
                 exit_vertices.insert(edge_ref.target());
             }
 
-            let repetition_arcs: HashSet<EdgeIndex> = entry_vertices
+            let repetition_arcs: IndexSet<EdgeIndex> = entry_vertices
                 .iter()
                 .flat_map(|node| self.graph.edges_directed(*node, Direction::Incoming))
                 .filter(|e| scc_set.is_visited(&e.source()))
@@ -272,8 +275,8 @@ impl Cfg {
         node: NodeIndex,
         targets: impl IntoIterator<Item = NodeIndex>,
         state: &mut RestructureState,
-    ) -> (HashMap<NodeIndex, u32>, Identifier) {
-        let mut blocks = HashMap::new();
+    ) -> (IndexMap<NodeIndex, u32>, Identifier) {
+        let mut blocks = IndexMap::new();
         for node in targets {
             let cur_len = u32::try_from(blocks.len()).unwrap();
             blocks.entry(node).or_insert(cur_len);
@@ -314,7 +317,7 @@ impl Cfg {
         // (called the "Head" in the paper), then add a mux node in front of the
         // continuations if there is more than one.
 
-        let mut tail_continuations = HashMap::<NodeIndex, Vec<NodeIndex>>::new();
+        let mut tail_continuations = IndexMap::<NodeIndex, Vec<NodeIndex>>::new();
 
         for ix in self.graph.node_indices() {
             if let Some(idom) = dom.immediate_dominator(ix) {
@@ -0,0 +1,216 @@
+//! Render [RvsdgFunction]s as Graphviz DOT, e.g. for `dot -Tsvg`.
+//!
+//! Every region (the function itself, each branch of a gamma, the body of a
+//! theta or lambda) becomes a cluster, with a record node for its arguments
+//! and one for its results. Nodes are records with a port per input (`i{n}`)
+//! and per output (`o{n}`), and structured nodes are clusters that nest their
+//! regions. Since the same node can be shared between regions, DOT ids are
+//! prefixed with the id of the region they are drawn in.
+use std::{collections::BTreeSet, fmt::Write, iter::once};
+
+use crate::conversions::{bitop_to_egglog, unsigned_cmp_to_egglog};
+
+use super::{optimize::region_operands, Expr, Id, Operand, RvsdgBody, RvsdgFunction, RvsdgProgram};
+
+impl RvsdgFunction {
+    /// Render this function as a standalone DOT graph.
+    pub fn to_dot(&self) -> String {
+        let mut dot = Dot::default();
+        dot.line("digraph {");
+        dot.line("compound=true;");
+        dot.line("node [shape=record];");
+        dot.function(self);
+        dot.line("}");
+        dot.out
+    }
+}
+
+impl RvsdgProgram {
+    /// One DOT graph per function, one after the other.
+    pub fn to_dot(&self) -> String {
+        self.functions.iter().map(RvsdgFunction::to_dot).collect()
+    }
+}
+
+#[derive(Default)]
+struct Dot {
+    out: String,
+    depth: usize,
+    next_region: usize,
+}
+
+/// Escape `text` for use in a record label.
+fn escape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '{' | '}' | '|' | '<' | '>' | '"' | '\\') {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+/// A record label with a row of `n_inputs` input ports, `text`, and a row of
+/// `n_outputs` output ports.
+fn record(text: &str, n_inputs: usize, n_outputs: usize) -> String {
+    let ports = |prefix: &str, n: usize| {
+        (0..n)
+            .map(|i| format!("<{prefix}{i}> {i}"))
+            .collect::<Vec<_>>()
+            .join("|")
+    };
+    let mut rows = vec![];
+    if n_inputs > 0 {
+        rows.push(format!("{{{}}}", ports("i", n_inputs)));
+    }
+    rows.push(escape(text));
+    if n_outputs > 0 {
+        rows.push(format!("{{{}}}", ports("o", n_outputs)));
+    }
+    format!("{{{}}}", rows.join("|"))
+}
+
+impl Dot {
+    fn line(&mut self, line: &str) {
+        writeln!(self.out, "{}{line}", "  ".repeat(self.depth)).unwrap();
+    }
+
+    fn open_cluster(&mut self, name: &str, label: &str) {
+        self.line(&format!("subgraph cluster_{name} {{"));
+        self.depth += 1;
+        self.line(&format!("label=\"{}\";", label.replace('"', "\\\"")));
+    }
+
+    fn close_cluster(&mut self) {
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn function(&mut self, func: &RvsdgFunction) {
+        let outputs = Vec::from_iter(func.results.iter().copied().chain(once(func.state)));
+        self.region(
+            &format!("@{}", func.name),
+            func.args.len() + 1,
+            &outputs,
+            &func.nodes,
+        );
+    }
+
+    /// Emit a region with `n_args` arguments and the given outputs, whose
+    /// nodes live in `nodes`.
+    fn region(&mut self, label: &str, n_args: usize, outputs: &[Operand], nodes: &[RvsdgBody]) {
+        let region = format!("r{}", self.next_region);
+        self.next_region += 1;
+        self.open_cluster(&region, label);
+
+        let args = record("args", 0, n_args);
+        self.line(&format!("{region}_args [label=\"{args}\"];"));
+        let results = record("results", outputs.len(), 0);
+        self.line(&format!("{region}_results [label=\"{results}\"];"));
+
+        let mut live = BTreeSet::new();
+        outputs
+            .iter()
+            .for_each(|op| reachable(nodes, *op, &mut live));
+        for id in &live {
+            self.node(&region, *id, nodes);
+        }
+        for id in &live {
+            for (i, op) in region_operands(&nodes[*id]).into_iter().enumerate() {
+                let src = source(&region, op);
+                self.line(&format!("{src} -> {region}_n{id}:i{i};"));
+            }
+        }
+        for (i, op) in outputs.iter().enumerate() {
+            let src = source(&region, *op);
+            self.line(&format!("{src} -> {region}_results:i{i};"));
+        }
+
+        self.close_cluster();
+    }
+
+    fn node(&mut self, region: &str, id: Id, nodes: &[RvsdgBody]) {
+        let name = format!("{region}_n{id}");
+        let n_inputs = region_operands(&nodes[id]).len();
+        match &nodes[id] {
+            RvsdgBody::BasicOp(expr) => {
+                let (text, n_outputs) = match expr {
+                    Expr::Op(op, _, _) => (op.to_string(), 1),
+                    Expr::UnsignedCmp(op, _) => (unsigned_cmp_to_egglog(*op).to_owned(), 1),
+                    Expr::Bitwise(op, _) => (bitop_to_egglog(*op).to_owned(), 1),
+                    Expr::EffectfulOp(op, _, _) => (op.to_string(), 2),
+                    Expr::Effect(op, _) | Expr::Speculation(op, _) => (op.to_string(), 1),
+                    Expr::Call(func, _, n_outputs, ..) => (format!("call @{func}"), *n_outputs),
+                    Expr::Print(_) => ("print".to_owned(), 1),
+                    Expr::Select(..) => ("select".to_owned(), 1),
+                    Expr::Const(_, lit, _) => (format!("const {lit}"), 1),
+                };
+                let label = record(&text, n_inputs, n_outputs);
+                self.line(&format!("{name} [label=\"{label}\"];"));
+            }
+            RvsdgBody::Gamma {
+                inputs, outputs, ..
+            } => {
+                self.open_cluster(&name, &format!("gamma #{id}"));
+                let label = record("gamma", n_inputs, outputs[0].len());
+                self.line(&format!("{name} [label=\"{label}\"];"));
+                for (i, branch) in outputs.iter().enumerate() {
+                    self.region(&format!("branch {i}"), inputs.len(), branch, nodes);
+                }
+                self.close_cluster();
+            }
+            RvsdgBody::Theta {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                self.open_cluster(&name, &format!("theta #{id}"));
+                let label = record("theta", n_inputs, outputs.len());
+                self.line(&format!("{name} [label=\"{label}\"];"));
+                let body = Vec::from_iter(once(pred).chain(outputs).copied());
+                self.region("body", inputs.len(), &body, nodes);
+                self.close_cluster();
+            }
+            RvsdgBody::Lambda {
+                n_args,
+                nodes: body,
+                outputs,
+            } => {
+                self.open_cluster(&name, &format!("lambda #{id}"));
+                let label = record("lambda", 0, 1);
+                self.line(&format!("{name} [label=\"{label}\"];"));
+                self.region("body", n_args + 1, outputs, body);
+                self.close_cluster();
+            }
+            RvsdgBody::Apply { .. } => {
+                // We can't see the number of outputs from here, so draw the
+                // usual result and state edge.
+                let label = record("apply", n_inputs, 2);
+                self.line(&format!("{name} [label=\"{label}\"];"));
+            }
+        }
+    }
+}
+
+/// The DOT endpoint for `op`, used in the region with prefix `region`.
+fn source(region: &str, op: Operand) -> String {
+    match op {
+        Operand::Arg(i) => format!("{region}_args:o{i}"),
+        Operand::Id(id) => format!("{region}_n{id}:o0"),
+        Operand::Project(i, id) => format!("{region}_n{id}:o{i}"),
+    }
+}
+
+/// Add the nodes in the same region as `op` that it depends on to `live`.
+fn reachable(nodes: &[RvsdgBody], op: Operand, live: &mut BTreeSet<Id>) {
+    let id = match op {
+        Operand::Arg(_) => return,
+        Operand::Id(id) | Operand::Project(_, id) => id,
+    };
+    if live.insert(id) {
+        for op in region_operands(&nodes[id]) {
+            reachable(nodes, op, live);
+        }
+    }
+}
@@ -3,7 +3,12 @@ use std::iter::once;
 
 use bril_rs::ConstOps;
 
-use super::{Expr, Id, Operand, RvsdgBody, RvsdgFunction, RvsdgProgram};
+use crate::conversions::{bitop_to_egglog, unsigned_cmp_to_egglog};
+
+use super::{
+    call_graph::call_graph, optimize::renumber, Expr, Id, Operand, RvsdgBody, RvsdgFunction,
+    RvsdgProgram,
+};
 
 const SIMPLE_NODE_SIZE: f32 = 100.0;
 const STROKE_WIDTH: f32 = SIMPLE_NODE_SIZE * 0.02;
@@ -12,6 +17,7 @@ const FONT_SIZE: f32 = SIMPLE_NODE_SIZE * 0.5;
 const PORT_RADIUS: f32 = STROKE_WIDTH * 2.0;
 const CORNER_RADIUS: f32 = NODE_SPACING * 0.2;
 const REGION_SPACING: f32 = NODE_SPACING * 0.5;
+const HEADER_HEIGHT: f32 = FONT_SIZE + REGION_SPACING;
 
 #[derive(Debug)]
 pub(crate) struct Region {
@@ -19,6 +25,8 @@ pub(crate) struct Region {
     dsts: usize,
     nodes: BTreeMap<Id, Node>,
     edges: Vec<Edge>,
+    // The subset of `edges` that carry the state edge.
+    state_edges: BTreeSet<Edge>,
 }
 
 #[derive(Debug)]
@@ -26,6 +34,7 @@ enum Node {
     Unit(String, usize, usize),
     Match(Vec<(String, Region)>), // vec must be nonempty
     Loop(Region),
+    Lambda(Region),
 }
 
 // Each edge goes from an output port to an input port.
@@ -103,6 +112,7 @@ impl Node {
             Node::Loop(region) => (0..region.srcs)
                 .map(|p| REGION_SPACING + blend(width - REGION_SPACING * 2.0, region.srcs, p))
                 .collect(),
+            Node::Lambda(_) => vec![],
         }
     }
 
@@ -115,6 +125,7 @@ impl Node {
             Node::Loop(region) => (1..region.dsts)
                 .map(|p| REGION_SPACING + blend(width - REGION_SPACING * 2.0, region.dsts, p))
                 .collect(),
+            Node::Lambda(_) => vec![blend(width, 1, 0)],
         }
     }
 }
@@ -135,7 +146,7 @@ fn port(x: f32, y: f32, color: &str) -> Xml {
 }
 
 impl Node {
-    fn to_xml(&self) -> (Size, Xml) {
+    fn to_xml(&self, debug: bool) -> (Size, Xml) {
         match self {
             Node::Unit(text, _, _) => {
                 let size = Size {
@@ -179,7 +190,7 @@ impl Node {
                 (size, group)
             }
             Node::Match(branches) => {
-                let children: Vec<_> = branches.iter().map(|t| t.1.to_xml(false)).collect();
+                let children: Vec<_> = branches.iter().map(|t| t.1.to_xml(false, debug)).collect();
                 let size = Size {
                     width: REGION_SPACING * (children.len() + 1) as f32
                         + children.iter().map(|t| t.0.width).sum::<f32>(),
@@ -266,8 +277,9 @@ impl Node {
                 );
                 (size, group)
             }
-            Node::Loop(region) => {
-                let (s, mut xml) = region.to_xml(true);
+            Node::Loop(region) | Node::Lambda(region) => {
+                let in_loop = matches!(self, Node::Loop(_));
+                let (s, mut xml) = region.to_xml(in_loop, debug);
                 let size = Size {
                     width: s.width + REGION_SPACING * 2.0,
                     height: s.height + FONT_SIZE + REGION_SPACING * 2.0,
@@ -276,7 +288,7 @@ impl Node {
                 let background = Xml::new(
                     "rect",
                     [
-                        ("fill", "#FF8080"),
+                        ("fill", if in_loop { "#FF8080" } else { "#8080FF" }),
                         ("stroke", "black"),
                         ("width", &format!("{}", size.width)),
                         ("height", &format!("{}", size.height)),
@@ -293,7 +305,7 @@ impl Node {
                         ("y", &format!("{}", FONT_SIZE)),
                         ("font-size", &format!("{}", FONT_SIZE)),
                     ],
-                    "loop",
+                    if in_loop { "loop" } else { "lambda" },
                 );
 
                 let (x, y) = (REGION_SPACING, REGION_SPACING + FONT_SIZE);
@@ -321,11 +333,17 @@ impl Node {
 }
 
 impl Region {
-    fn to_xml(&self, in_loop: bool) -> (Size, Xml) {
+    /// Lay out the region. In `debug` mode, state edges are drawn dashed and in
+    /// red, and each node is labeled with its id.
+    fn to_xml(&self, in_loop: bool, debug: bool) -> (Size, Xml) {
         let mut edges = self.edges.clone();
         edges.sort();
 
-        let mut children: BTreeMap<_, _> = self.nodes.iter().map(|t| (t.0, t.1.to_xml())).collect();
+        let mut children: BTreeMap<_, _> = self
+            .nodes
+            .iter()
+            .map(|t| (t.0, t.1.to_xml(debug)))
+            .collect();
 
         let mut layers: Vec<Vec<Id>> = vec![];
         let mut to_order: BTreeSet<Id> = self.nodes.keys().copied().collect();
@@ -386,7 +404,8 @@ impl Region {
         assert_eq!(w, size.width);
         assert_eq!(h, size.height);
 
-        let edges = Xml::group(edges.iter().map(|((a, i), (b, j))| {
+        let edges = Xml::group(edges.iter().map(|edge| {
+            let ((a, i), (b, j)) = edge;
             let (a_x, a_y) = match a {
                 None => (blend(size.width, self.srcs, *i), 0.0),
                 Some(a) => (
@@ -447,17 +466,20 @@ impl Region {
                  Q {b_x} {break_y} {b_x} {arc_2_b_y}\
                  V {b_y}"
             );
-            Xml::new(
-                "path",
-                [
-                    ("fill", "transparent"),
-                    ("stroke", "black"),
-                    ("stroke-linecap", "round"),
-                    ("stroke-width", &format!("{}", STROKE_WIDTH)),
-                    ("d", &path_string),
-                ],
-                "",
-            )
+            let is_state = debug && self.state_edges.contains(edge);
+            let stroke_width = format!("{}", STROKE_WIDTH);
+            let dashes = format!("{} {}", STROKE_WIDTH * 4.0, STROKE_WIDTH * 3.0);
+            let mut attributes = vec![
+                ("fill", "transparent"),
+                ("stroke", if is_state { "#C00000" } else { "black" }),
+                ("stroke-linecap", "round"),
+                ("stroke-width", stroke_width.as_str()),
+                ("d", path_string.as_str()),
+            ];
+            if is_state {
+                attributes.push(("stroke-dasharray", dashes.as_str()));
+            }
+            Xml::new("path", attributes, "")
         }));
 
         let (s, d) = (self.srcs, self.dsts);
@@ -466,10 +488,24 @@ impl Region {
         let dsts = Xml::group((0..d).map(|p| port(blend(size.width, d, p), size.height, c(p))));
 
         let nodes = Xml::group(positions.iter().map(|(id, (x, y))| {
-            let (_, mut xml) = children.remove(id).unwrap();
+            let (size, mut xml) = children.remove(id).unwrap();
             xml.attributes
                 .insert("transform".to_owned(), format!("translate({x}, {y})"));
-            xml
+            if !debug {
+                return xml;
+            }
+            let label = Xml::new(
+                "text",
+                [
+                    ("text-anchor", "end"),
+                    ("fill", "#404040"),
+                    ("x", &format!("{}", x + size.width - CORNER_RADIUS)),
+                    ("y", &format!("{}", y + FONT_SIZE * 0.4)),
+                    ("font-size", &format!("{}", FONT_SIZE * 0.3)),
+                ],
+                &format!("#{id}"),
+            );
+            Xml::group([xml, label])
         }));
 
         let background = Xml::new(
@@ -491,7 +527,7 @@ impl Region {
 
 impl Region {
     fn to_svg(&self) -> String {
-        let (size, xml) = self.to_xml(false);
+        let (size, xml) = self.to_xml(false, false);
         let svg = Xml::new(
             "svg",
             [
@@ -507,17 +543,38 @@ impl Region {
     }
 }
 
-fn mk_node_and_input_edges(index: Id, nodes: &[RvsdgBody]) -> (Node, Vec<Edge>) {
+fn mk_node_and_input_edges(
+    index: Id,
+    nodes: &[RvsdgBody],
+    state_args: &BTreeSet<usize>,
+) -> (Node, Vec<Edge>) {
     let (node, operands): (Node, Vec<Operand>) = match &nodes[index] {
         RvsdgBody::BasicOp(Expr::Op(f, xs, _ty)) => {
             (Node::Unit(format!("{f}"), xs.len(), 1), xs.to_vec())
         }
-        RvsdgBody::BasicOp(Expr::Call(f, xs, n_outputs, _ty)) => {
+        RvsdgBody::BasicOp(Expr::EffectfulOp(f, xs, _ty)) => {
+            (Node::Unit(format!("{f}"), xs.len(), 2), xs.to_vec())
+        }
+        RvsdgBody::BasicOp(Expr::Effect(f, xs) | Expr::Speculation(f, xs)) => {
+            (Node::Unit(format!("{f}"), xs.len(), 1), xs.to_vec())
+        }
+        RvsdgBody::BasicOp(Expr::Call(f, xs, n_outputs, ..)) => {
             (Node::Unit(f.to_string(), xs.len(), *n_outputs), xs.to_vec())
         }
         RvsdgBody::BasicOp(Expr::Print(xs)) => {
             (Node::Unit("PRINT".into(), xs.len(), 2), xs.to_vec())
         }
+        RvsdgBody::BasicOp(Expr::Select(xs, _ty)) => {
+            (Node::Unit("select".into(), xs.len(), 1), xs.to_vec())
+        }
+        RvsdgBody::BasicOp(Expr::UnsignedCmp(f, xs)) => (
+            Node::Unit(unsigned_cmp_to_egglog(*f).into(), xs.len(), 1),
+            xs.to_vec(),
+        ),
+        RvsdgBody::BasicOp(Expr::Bitwise(f, xs)) => (
+            Node::Unit(bitop_to_egglog(*f).into(), xs.len(), 1),
+            xs.to_vec(),
+        ),
         RvsdgBody::BasicOp(Expr::Const(ConstOps::Const, v, _ty)) => {
             (Node::Unit(format!("{v}"), 0, 1), vec![])
         }
@@ -530,7 +587,13 @@ fn mk_node_and_input_edges(index: Id, nodes: &[RvsdgBody]) -> (Node, Vec<Edge>)
                 outputs
                     .iter()
                     .enumerate()
-                    .map(|(pred, os)| (format!("{pred}"), mk_region(inputs.len(), os, nodes)))
+                    .map(|(pred, os)| {
+                        let branch_state = state_args_of(nodes, inputs, state_args);
+                        (
+                            format!("{pred}"),
+                            mk_region(inputs.len(), os, nodes, &branch_state),
+                        )
+                    })
                     .collect(),
             ),
             once(pred).chain(inputs).copied().collect::<Vec<_>>(),
@@ -544,9 +607,27 @@ fn mk_node_and_input_edges(index: Id, nodes: &[RvsdgBody]) -> (Node, Vec<Edge>)
                 inputs.len(),
                 &once(pred).chain(outputs).copied().collect::<Vec<_>>(),
                 nodes,
+                &state_args_of(nodes, inputs, state_args),
             )),
             inputs.to_vec(),
         ),
+        RvsdgBody::Lambda {
+            n_args,
+            nodes: body,
+            outputs,
+        } => (
+            Node::Lambda(mk_region(
+                n_args + 1,
+                outputs,
+                body,
+                &BTreeSet::from([*n_args]),
+            )),
+            vec![],
+        ),
+        RvsdgBody::Apply { func, args } => (
+            Node::Unit("apply".into(), args.len() + 1, apply_outputs(func, nodes)),
+            once(func).chain(args).copied().collect(),
+        ),
     };
     let input_edges = operands
         .iter()
@@ -565,6 +646,68 @@ fn mk_node_and_input_edges(index: Id, nodes: &[RvsdgBody]) -> (Node, Vec<Edge>)
     (node, input_edges)
 }
 
+/// The number of outputs of an `Apply` of `func`. We look through to the
+/// lambda being applied, assuming a single result and the state edge
+/// otherwise.
+fn apply_outputs(func: &Operand, nodes: &[RvsdgBody]) -> usize {
+    match func {
+        Operand::Id(id) | Operand::Project(_, id) => match &nodes[*id] {
+            RvsdgBody::Lambda { outputs, .. } => outputs.len(),
+            _ => 2,
+        },
+        Operand::Arg(_) => 2,
+    }
+}
+
+/// Whether `op` carries the state edge, given the arguments of the enclosing
+/// region that do.
+fn is_state(nodes: &[RvsdgBody], op: Operand, state_args: &BTreeSet<usize>) -> bool {
+    let (output, id) = match op {
+        Operand::Arg(arg) => return state_args.contains(&arg),
+        Operand::Id(id) => (0, id),
+        Operand::Project(output, id) => (output, id),
+    };
+    match &nodes[id] {
+        RvsdgBody::BasicOp(
+            Expr::Op(..)
+            | Expr::UnsignedCmp(..)
+            | Expr::Bitwise(..)
+            | Expr::Select(..)
+            | Expr::Const(..),
+        ) => false,
+        RvsdgBody::BasicOp(Expr::EffectfulOp(..)) => output == 1,
+        RvsdgBody::BasicOp(Expr::Effect(..) | Expr::Speculation(..) | Expr::Print(..)) => {
+            output == 0
+        }
+        RvsdgBody::BasicOp(Expr::Call(_, _, n_outputs, _, pure)) => {
+            !pure && output + 1 == *n_outputs
+        }
+        // Branches agree on which outputs are state, so look at the first.
+        RvsdgBody::Gamma {
+            inputs, outputs, ..
+        } => is_state(
+            nodes,
+            outputs[0][output],
+            &state_args_of(nodes, inputs, state_args),
+        ),
+        // Loop variables keep their kind from one iteration to the next.
+        RvsdgBody::Theta { inputs, .. } => is_state(nodes, inputs[output], state_args),
+        RvsdgBody::Lambda { .. } => false,
+        RvsdgBody::Apply { func, .. } => output + 1 == apply_outputs(func, nodes),
+    }
+}
+
+/// The arguments of a region with the given `inputs` that carry state.
+fn state_args_of(
+    nodes: &[RvsdgBody],
+    inputs: &[Operand],
+    state_args: &BTreeSet<usize>,
+) -> BTreeSet<usize> {
+    (0..inputs.len())
+        .filter(|i| is_state(nodes, inputs[*i], state_args))
+        .collect()
+}
+
 // returns only nodes in the same REGION as `output`
 fn reachable_nodes(reachable: &mut BTreeSet<Id>, all: &[RvsdgBody], output: Operand) {
     let id = match output {
@@ -575,11 +718,20 @@ fn reachable_nodes(reachable: &mut BTreeSet<Id>, all: &[RvsdgBody], output: Oper
     if reachable.insert(id) {
         let inputs = match &all[id] {
             RvsdgBody::BasicOp(Expr::Op(_, xs, _))
-            | RvsdgBody::BasicOp(Expr::Call(_, xs, _, _))
+            | RvsdgBody::BasicOp(Expr::UnsignedCmp(_, xs))
+            | RvsdgBody::BasicOp(Expr::Bitwise(_, xs))
+            | RvsdgBody::BasicOp(Expr::EffectfulOp(_, xs, _))
+            | RvsdgBody::BasicOp(Expr::Effect(_, xs))
+            | RvsdgBody::BasicOp(Expr::Speculation(_, xs))
+            | RvsdgBody::BasicOp(Expr::Call(_, xs, ..))
+            | RvsdgBody::BasicOp(Expr::Select(xs, _))
             | RvsdgBody::BasicOp(Expr::Print(xs)) => xs.clone(),
             RvsdgBody::BasicOp(Expr::Const(..)) => vec![],
             RvsdgBody::Gamma { pred, inputs, .. } => once(pred).chain(inputs).copied().collect(),
             RvsdgBody::Theta { inputs, .. } => inputs.clone(),
+            // Lambdas are closed, so nothing in this region flows into them.
+            RvsdgBody::Lambda { .. } => vec![],
+            RvsdgBody::Apply { func, args } => once(func).chain(args).copied().collect(),
         };
         for input in inputs {
             reachable_nodes(reachable, all, input);
@@ -587,16 +739,21 @@ fn reachable_nodes(reachable: &mut BTreeSet<Id>, all: &[RvsdgBody], output: Oper
     }
 }
 
-fn mk_region(srcs: usize, dsts: &[Operand], nodes: &[RvsdgBody]) -> Region {
+fn mk_region(
+    srcs: usize,
+    dsts: &[Operand],
+    nodes: &[RvsdgBody],
+    state_args: &BTreeSet<usize>,
+) -> Region {
     let mut reachable = BTreeSet::new();
     dsts.iter().for_each(|operand| {
         reachable_nodes(&mut reachable, nodes, *operand);
     });
 
-    let (nodes, edges): (BTreeMap<_, _>, Vec<_>) = reachable
+    let (region_nodes, edges): (BTreeMap<_, _>, Vec<_>) = reachable
         .iter()
         .map(|i| {
-            let (node, edges) = mk_node_and_input_edges(*i, nodes);
+            let (node, edges) = mk_node_and_input_edges(*i, nodes, state_args);
             ((*i, node), edges)
         })
         .unzip();
@@ -612,28 +769,71 @@ fn mk_region(srcs: usize, dsts: &[Operand], nodes: &[RvsdgBody]) -> Region {
             (None, j),
         )
     }));
+    let state_edges = edges
+        .iter()
+        .filter(|((src, output), _)| {
+            let op = match src {
+                None => Operand::Arg(*output),
+                Some(id) => Operand::Project(*output, *id),
+            };
+            is_state(nodes, op, state_args)
+        })
+        .copied()
+        .collect();
 
     Region {
         srcs,
         dsts: dsts.len(),
-        nodes,
+        nodes: region_nodes,
         edges,
+        state_edges,
     }
 }
 
 impl RvsdgProgram {
+    /// Draw every function in the program, one below the other, each under a
+    /// header with its name. Nodes are numbered canonically first, so the
+    /// drawing only depends on the structure of each function.
+    ///
+    /// Calls between functions are drawn as dashed edges to the right of the
+    /// functions, from the header of the caller to the header of the callee.
     pub fn to_svg(&self) -> String {
+        self.to_svg_with(false)
+    }
+
+    /// Like [RvsdgProgram::to_svg], but with state edges drawn dashed and in
+    /// red, and with every node labeled with its id.
+    pub fn to_debug_svg(&self) -> String {
+        self.to_svg_with(true)
+    }
+
+    fn to_svg_with(&self, debug: bool) -> String {
         let mut xmls: Vec<Xml> = vec![];
         let mut height: f32 = 0.0;
         let mut width: f32 = 0.0;
         let spacing = 50.0;
+        // The top of each function's header.
+        let mut headers = Vec::with_capacity(self.functions.len());
 
         for (i, function) in self.functions.iter().enumerate() {
             if i > 0 {
                 height += spacing;
             }
 
-            let (size, mut xml) = function.to_region().to_xml(false);
+            headers.push(height);
+            xmls.push(Xml::new(
+                "text",
+                [
+                    ("fill", "black"),
+                    ("font-size", &format!("{}", FONT_SIZE)),
+                    ("x", "0"),
+                    ("y", &format!("{}", height + FONT_SIZE)),
+                ],
+                &format!("@{}", function.name),
+            ));
+            height += HEADER_HEIGHT;
+
+            let (size, mut xml) = function.to_canonical_region().to_xml(false, debug);
             // assert that it doesn't have a transform yet
             assert!(xml.attributes.get("transform").is_none());
             xml.attributes
@@ -642,6 +842,38 @@ impl RvsdgProgram {
             height += size.height;
             width = width.max(size.width);
         }
+
+        // Each call edge gets a lane of its own. Edges leave the caller's
+        // header a bit higher than they enter the callee's, so that a
+        // recursive call still shows up as a loop.
+        let calls = call_graph(&self.functions);
+        let n = self.functions.len();
+        let edges = (0..n).flat_map(|caller| (0..n).map(move |callee| (caller, callee)));
+        let edges = Vec::from_iter(edges.filter(|(caller, callee)| calls.calls(*caller, *callee)));
+        let stroke_width = format!("{}", STROKE_WIDTH);
+        let dashes = format!("{} {}", STROKE_WIDTH * 4.0, STROKE_WIDTH * 3.0);
+        for (lane, (caller, callee)) in edges.iter().enumerate() {
+            let x = width + REGION_SPACING * (lane + 1) as f32;
+            let from = headers[*caller] + HEADER_HEIGHT / 3.0;
+            let to = headers[*callee] + HEADER_HEIGHT * 2.0 / 3.0;
+            let path = format!("M {width} {from} H {x} V {to} H {width}");
+            xmls.push(Xml::new(
+                "path",
+                [
+                    ("d", path.as_str()),
+                    ("fill", "transparent"),
+                    ("stroke", "black"),
+                    ("stroke-dasharray", dashes.as_str()),
+                    ("stroke-linecap", "round"),
+                    ("stroke-width", stroke_width.as_str()),
+                ],
+                "",
+            ));
+        }
+        if !edges.is_empty() {
+            width += REGION_SPACING * (edges.len() + 1) as f32;
+        }
+
         Xml::new(
             "svg",
             [
@@ -667,12 +899,38 @@ impl RvsdgFunction {
 
     pub(crate) fn to_region(&self) -> Region {
         let dsts: Vec<_> = self
-            .result
+            .results
+            .iter()
+            .copied()
+            .chain(once(self.state))
+            .collect();
+        mk_region(
+            self.args.len() + 1,
+            &dsts,
+            &self.nodes,
+            &BTreeSet::from([self.args.len()]),
+        )
+    }
+
+    /// Like [RvsdgFunction::to_region], but with the nodes numbered
+    /// canonically (see [renumber_nodes](super::optimize::renumber_nodes)),
+    /// so that the layout doesn't depend on the order the nodes happened to
+    /// be added in.
+    pub(crate) fn to_canonical_region(&self) -> Region {
+        let mut dsts: Vec<_> = self
+            .results
             .iter()
             .copied()
             .chain(once(self.state))
             .collect();
-        mk_region(self.n_args + 1, &dsts, &self.nodes)
+        let mut nodes = self.nodes.clone();
+        renumber(&mut nodes, dsts.iter_mut());
+        mk_region(
+            self.args.len() + 1,
+            &dsts,
+            &nodes,
+            &BTreeSet::from([self.args.len()]),
+        )
     }
 }
 
@@ -684,7 +942,9 @@ mod tests {
     #[test]
     fn rvsdg2svg_basic() {
         let svg_new = RvsdgFunction {
-            n_args: 2,
+            name: "main".into(),
+            args: vec![Type::Int, Type::Int],
+            return_type: Some(Type::Int),
             nodes: vec![
                 RvsdgBody::BasicOp(Expr::Const(ConstOps::Const, Literal::Int(0), Type::Int)),
                 RvsdgBody::BasicOp(Expr::Op(
@@ -730,11 +990,91 @@ mod tests {
                     Type::Int,
                 )),
             ],
-            result: Some(Operand::Id(10)),
+            results: vec![Operand::Id(10)],
             state: Operand::Arg(2),
         }
         .to_svg();
 
         insta::assert_snapshot!(svg_new);
     }
+
+    #[test]
+    fn rvsdg2svg_debug_state_edges() {
+        const PROGRAM: &str = r#"
+        @main(x: int) {
+            zero: int = const 0;
+            c: bool = lt x zero;
+            br c .T .F;
+        .T:
+            print x;
+            jmp .J;
+        .F:
+            y: int = sub zero x;
+            jmp .J;
+        .J:
+            print x;
+        }
+        "#;
+        let prog = crate::util::parse_from_string(PROGRAM);
+        let rvsdg = crate::rvsdg::cfg_to_rvsdg(&crate::cfg::program_to_cfg(&prog)).unwrap();
+
+        let region = rvsdg.functions[0].to_region();
+        // The state flows from the function's argument, through the gamma and
+        // the final print, to the function's state result.
+        assert!(region.state_edges.iter().any(|edge| edge.0 == (None, 1)));
+        assert!(region.state_edges.iter().any(|edge| edge.1 == (None, 0)));
+        // `x` and the predicate are values.
+        assert!(region
+            .edges
+            .iter()
+            .filter(|edge| edge.0 == (None, 0))
+            .all(|edge| !region.state_edges.contains(edge)));
+        let (_, Node::Match(branches)) = region
+            .nodes
+            .iter()
+            .find(|(_, node)| matches!(node, Node::Match(_)))
+            .unwrap()
+        else {
+            unreachable!()
+        };
+        // Both branches thread the state through, whether or not they print.
+        for (_, branch) in branches {
+            assert_eq!(
+                branch.state_edges.len(),
+                if branch.nodes.is_empty() { 1 } else { 2 }
+            );
+        }
+
+        let svg = rvsdg.to_debug_svg();
+        assert!(svg.contains("stroke-dasharray"));
+        assert!(svg.contains("#0"));
+        assert!(!rvsdg.to_svg().contains("stroke-dasharray"));
+    }
+
+    #[test]
+    fn rvsdg2svg_call_edges() {
+        const PROGRAM: &str = r#"
+        @double(x: int): int {
+            y: int = add x x;
+            ret y;
+        }
+        @main {
+            one: int = const 1;
+            v: int = call @double one;
+            print v;
+        }
+        "#;
+        let prog = crate::util::parse_from_string(PROGRAM);
+        let rvsdg = crate::rvsdg::cfg_to_rvsdg(&crate::cfg::program_to_cfg(&prog)).unwrap();
+        let svg = rvsdg.to_svg();
+
+        // Both functions are drawn under their names, with `@double` on top.
+        let double = svg.find(">\n\t\t@double\n").unwrap();
+        let main = svg.find(">\n\t\t@main\n").unwrap();
+        assert!(double < main, "{svg}");
+        // The call from `@main` is drawn as a dashed edge, ending two thirds of
+        // the way down the header of `@double`, which is at the very top.
+        assert_eq!(svg.matches("stroke-dasharray").count(), 1, "{svg}");
+        assert!(svg.contains("V 50 H"), "{svg}");
+    }
 }
@@ -0,0 +1,113 @@
+//! Summary statistics about [RvsdgFunction]s, e.g. for comparing a function
+//! before and after an optimization.
+use std::collections::HashSet;
+
+use super::{Expr, Id, Operand, RvsdgBody, RvsdgFunction};
+
+/// Counts of the live nodes in a function. Nodes that are not reachable from
+/// the function's results or state edge are not counted, and nodes shared
+/// between regions are only counted once.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RvsdgStats {
+    pub basic_ops: usize,
+    pub gammas: usize,
+    pub thetas: usize,
+    pub lambdas: usize,
+    pub applies: usize,
+    /// The total number of nodes, of any kind.
+    pub nodes: usize,
+    /// How deeply regions are nested. A function with no gammas, thetas, or
+    /// lambdas has depth 0, and every structured node adds one to the depth
+    /// of the region it is in.
+    pub max_depth: usize,
+    /// The number of operations that thread the state edge: calls, prints,
+    /// applies, and effectful operations.
+    pub stateful: usize,
+}
+
+impl RvsdgFunction {
+    /// Compute [RvsdgStats] for this function.
+    pub fn stats(&self) -> RvsdgStats {
+        let mut stats = RvsdgStats::default();
+        let roots = Vec::from_iter(self.results.iter().chain([&self.state]).copied());
+        stats.region(&self.nodes, &roots, 0, &mut HashSet::new());
+        stats
+    }
+}
+
+impl RvsdgStats {
+    /// Count the nodes in the region at nesting depth `depth` that computes
+    /// `outputs`. `seen` holds the nodes in `nodes` that were already counted.
+    fn region(
+        &mut self,
+        nodes: &[RvsdgBody],
+        outputs: &[Operand],
+        depth: usize,
+        seen: &mut HashSet<Id>,
+    ) {
+        self.max_depth = self.max_depth.max(depth);
+        let mut stack = outputs.to_vec();
+        while let Some(op) = stack.pop() {
+            let id = match op {
+                Operand::Arg(_) => continue,
+                Operand::Id(id) | Operand::Project(_, id) => id,
+            };
+            if !seen.insert(id) {
+                continue;
+            }
+            self.nodes += 1;
+            match &nodes[id] {
+                RvsdgBody::BasicOp(expr) => {
+                    self.basic_ops += 1;
+                    if matches!(
+                        expr,
+                        Expr::EffectfulOp(..)
+                            | Expr::Effect(..)
+                            | Expr::Speculation(..)
+                            | Expr::Call(.., false)
+                            | Expr::Print(..)
+                    ) {
+                        self.stateful += 1;
+                    }
+                    stack.extend(expr.operands());
+                }
+                RvsdgBody::Gamma {
+                    pred,
+                    inputs,
+                    outputs,
+                } => {
+                    self.gammas += 1;
+                    stack.push(*pred);
+                    stack.extend(inputs);
+                    for branch in outputs {
+                        self.region(nodes, branch, depth + 1, seen);
+                    }
+                }
+                RvsdgBody::Theta {
+                    pred,
+                    inputs,
+                    outputs,
+                } => {
+                    self.thetas += 1;
+                    stack.extend(inputs);
+                    let body = Vec::from_iter([*pred].into_iter().chain(outputs.iter().copied()));
+                    self.region(nodes, &body, depth + 1, seen);
+                }
+                RvsdgBody::Lambda {
+                    nodes: body,
+                    outputs,
+                    ..
+                } => {
+                    self.lambdas += 1;
+                    self.region(body, outputs, depth + 1, &mut HashSet::new());
+                }
+                RvsdgBody::Apply { func, args } => {
+                    self.applies += 1;
+                    self.stateful += 1;
+                    stack.push(*func);
+                    stack.extend(args);
+                }
+            }
+        }
+    }
+}
@@ -1,110 +1,47 @@
-use bril_rs::{ConstOps, Literal, Type, ValueOps};
-
-use crate::{
-    cfg::{program_to_cfg, Identifier},
-    rvsdg::{cfg_to_rvsdg, new_rvsdg_egraph, EgglogFunctionResult, Expr, Id, Operand, RvsdgBody},
-    util::parse_from_string,
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
 };
 
-use super::RvsdgFunction;
-
-/// Utility struct for building an RVSDG.
-#[derive(Default)]
-struct RvsdgTest {
-    nodes: Vec<RvsdgBody>,
-}
-
-impl RvsdgTest {
-    /// "pure" functions are ones whose state edges 'pass through'.
-    fn into_pure_function(self, n_args: usize, output: Operand) -> RvsdgFunction {
-        self.into_function(n_args, Some(output), Operand::Arg(n_args))
-    }
-
-    fn into_function(
-        self,
-        n_args: usize,
-        result: Option<Operand>,
-        state: Operand,
-    ) -> RvsdgFunction {
-        RvsdgFunction {
-            n_args,
-            nodes: self.nodes,
-            result,
-            state,
-        }
-    }
-
-    fn lit_int(&mut self, i: i64) -> Operand {
-        self.make_node(RvsdgBody::BasicOp(Expr::Const(
-            ConstOps::Const,
-            Literal::Int(i),
-            Type::Int,
-        )))
-    }
-
-    fn lit_bool(&mut self, b: bool) -> Operand {
-        self.make_node(RvsdgBody::BasicOp(Expr::Const(
-            ConstOps::Const,
-            Literal::Bool(b),
-            Type::Bool,
-        )))
-    }
-
-    fn void_function(&mut self, func: impl Into<Identifier>, args: &[Operand]) -> Operand {
-        self.make_node(RvsdgBody::BasicOp(Expr::Call(
-            func.into(),
-            args.to_vec(),
-            1,
-            None,
-        )))
-    }
-
-    fn lt(&mut self, l: Operand, r: Operand) -> Operand {
-        self.make_node(RvsdgBody::BasicOp(Expr::Op(
-            ValueOps::Lt,
-            vec![l, r],
-            Type::Bool,
-        )))
-    }
-
-    fn add(&mut self, l: Operand, r: Operand, ty: Type) -> Operand {
-        self.make_node(RvsdgBody::BasicOp(Expr::Op(ValueOps::Add, vec![l, r], ty)))
-    }
-
-    fn mul(&mut self, l: Operand, r: Operand, ty: Type) -> Operand {
-        self.make_node(RvsdgBody::BasicOp(Expr::Op(ValueOps::Mul, vec![l, r], ty)))
-    }
-
-    fn print(&mut self, x: Operand, state: Operand) -> Operand {
-        self.make_node(RvsdgBody::BasicOp(Expr::Print(vec![x, state])))
-    }
-
-    fn gamma(&mut self, pred: Operand, inputs: &[Operand], outputs: &[&[Operand]]) -> Id {
-        let res = self.nodes.len();
-        self.nodes.push(RvsdgBody::Gamma {
-            pred,
-            inputs: inputs.to_vec(),
-            outputs: outputs.iter().map(|outs| outs.to_vec()).collect(),
-        });
-        res
-    }
+use bril_rs::{Code, ConstOps, EffectOps, Instruction, Literal, Program, Type, ValueOps};
 
-    fn theta(&mut self, pred: Operand, inputs: &[Operand], outputs: &[Operand]) -> Id {
-        let res = self.nodes.len();
-        self.nodes.push(RvsdgBody::Theta {
-            pred,
-            inputs: inputs.to_vec(),
-            outputs: outputs.to_vec(),
-        });
-        res
-    }
+use crate::{
+    cfg::{program_to_cfg, simplify::simplify_cfg},
+    rvsdg::{
+        add_custom_rules, assert_egraph_equivalent,
+        builder::RvsdgBuilder,
+        call_graph::call_graph,
+        cfg_to_rvsdg,
+        diff::diff,
+        entry_function,
+        extract::{
+            cost_report, extract_cheapest, extract_with_report, new_rvsdg_egraph_with_costs,
+        },
+        new_rvsdg_egraph,
+        optimize::{
+            batch_prints, cse, dead_store_elimination, drop_unused_args, fuse_thetas,
+            gammas_to_selects, gvn, hoist_common_gamma_nodes, hoist_invariant_gammas, inline_calls,
+            merge_gamma_inputs, normalize_operands, propagate_call_constants, prune_dead_nodes,
+            remove_identity_thetas, remove_trivial_gammas, renumber_nodes, shift_args,
+            simplify_bounded_gammas, simplify_constant_gammas, simplify_known_gammas,
+            simplify_pure_gamma_state, split_gamma_inputs, tailcall_to_loop, theta_variable_kinds,
+            unroll_theta, VarKind,
+        },
+        program_to_egglog,
+        random::random_function,
+        stats::RvsdgStats,
+        to_cfg::{rvsdg_to_bril, rvsdg_to_cfg},
+        validate::{validate, ValidationError},
+        BitOp, EgglogFunctionResult, Expr, Id, Operand, ProgramRvsdg, RvsdgBody, RvsdgError,
+        RvsdgProgram, ALGEBRAIC_SIMPLIFY_RULESET, BOOL_SIMPLIFY_RULESET, CMP_SIMPLIFY_RULESET,
+        COMMUTE_RULESET, CONST_FOLD_RULESET, GAMMA_SIMPLIFY_RULESET, NORMALIZE_RULESET,
+        REASSOCIATE_RULESET, STRENGTH_REDUCE_RULESET,
+    },
+    util::{parse_from_string, TestProgram},
+    CostModel, DefaultCostModel, EggCCError, Optimizer, StateDepthCostModel,
+};
 
-    fn make_node(&mut self, body: RvsdgBody) -> Operand {
-        let res = Operand::Project(0, self.nodes.len());
-        self.nodes.push(body);
-        res
-    }
-}
+use super::{FunctionSignature, RvsdgFunction};
 
 #[test]
 fn rvsdg_expr() {
@@ -120,14 +57,13 @@ fn rvsdg_expr() {
     let cfg = program_to_cfg(&prog);
     let rvsdg = cfg_to_rvsdg(&cfg).unwrap();
 
-    let mut expected = RvsdgTest::default();
+    let mut expected = RvsdgBuilder::default();
     let one = expected.lit_int(1);
     let two = expected.lit_int(2);
     let res = expected.add(one, two, Type::Int);
-    assert!(deep_equal(
-        &expected.into_pure_function(0, res),
-        &rvsdg.functions[0]
-    ));
+    assert!(expected
+        .into_pure_function(&[], &[res])
+        .structurally_eq(&rvsdg.functions[0]));
 }
 
 #[test]
@@ -145,16 +81,128 @@ fn rvsdg_print() {
     let cfg = program_to_cfg(&prog);
     let rvsdg = cfg_to_rvsdg(&cfg).unwrap();
 
-    let mut expected = RvsdgTest::default();
+    let mut expected = RvsdgBuilder::default();
     let v0 = expected.lit_int(1);
     let v1 = expected.lit_int(2);
     let v2 = expected.add(v0, v1, Type::Int);
     let res1 = expected.print(v2, Operand::Arg(0));
     let res2 = expected.print(v1, res1);
-    assert!(deep_equal(
-        &expected.into_function(0, None, res2),
-        &rvsdg.functions[0]
+    assert!(expected
+        .into_function(&[], &[], res2)
+        .structurally_eq(&rvsdg.functions[0]));
+}
+
+#[test]
+fn rvsdg_print_multiple() {
+    const PROGRAM: &str = r#"
+    @main() {
+        x: int = const 1;
+        b: bool = const true;
+        print x b x;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let actual = &rvsdg.functions[0];
+
+    // One print of all three values, rather than three prints.
+    let mut expected = RvsdgBuilder::default();
+    let x = expected.lit_int(1);
+    let b = expected.lit_bool(true);
+    let state = expected.print_all(&[x, b, x], Operand::Arg(0));
+    let expected = expected.into_function(&[], &[], state);
+    assert!(expected.structurally_eq(actual));
+
+    let roundtrip =
+        RvsdgFunction::egglog_expr_to_function(&actual.to_egglog_expr(), "main", &[]).unwrap();
+    assert!(expected.structurally_eq(&roundtrip));
+    assert_eq!(Optimizer::interp(&prog, vec![], None), "1 true 1\n");
+    assert_eq!(
+        Optimizer::interp(&rvsdg_to_bril(&roundtrip).unwrap(), vec![], None),
+        "1 true 1\n"
+    );
+}
+
+#[test]
+fn rvsdg_pure_ops_off_state_path() {
+    // Prints `a`, then `a + b` (or `b + a`), and returns the sum too.
+    let build = |commuted: bool, sum_first: bool| {
+        let mut f = RvsdgBuilder::default();
+        let (a, b) = (Operand::Arg(0), Operand::Arg(1));
+        let sum = if commuted {
+            f.add(b, a, Type::Int)
+        } else {
+            f.add(a, b, Type::Int)
+        };
+        let state = if sum_first {
+            let state = f.print(sum, Operand::Arg(2));
+            f.print(a, state)
+        } else {
+            let state = f.print(a, Operand::Arg(2));
+            f.print(sum, state)
+        };
+        f.into_function(&[Type::Int, Type::Int], &[sum], state)
+    };
+
+    // The sum is encoded on its own, not after the first print, so it is free
+    // to be computed anywhere.
+    let EgglogFunctionResult::StateAndValue { state, value } = build(false, false).to_egglog_expr()
+    else {
+        panic!("expected a state edge and a value");
+    };
+    assert!(!value.to_string().contains("Effectful"), "{value}");
+    assert!(state.to_string().contains(&value.to_string()), "{state}");
+
+    // So rules can rewrite it without regard to the prints, but the prints
+    // themselves stay in order.
+    assert!(assert_egraph_equivalent(
+        &build(false, false),
+        &build(true, false)
     ));
+    assert!(!assert_egraph_equivalent(
+        &build(false, false),
+        &build(false, true)
+    ));
+}
+
+#[test]
+fn rvsdg_batch_prints() {
+    // The two prints from `rvsdg_print` become one.
+    let mut prints = RvsdgBuilder::default();
+    let v0 = prints.lit_int(1);
+    let v1 = prints.lit_int(2);
+    let v2 = prints.add(v0, v1, Type::Int);
+    let res1 = prints.print(v2, Operand::Arg(0));
+    let res2 = prints.print(v1, res1);
+    let mut func = prints.into_function(&[], &[], res2);
+    batch_prints(&mut func);
+
+    let mut expected = RvsdgBuilder::default();
+    let v0 = expected.lit_int(1);
+    let v1 = expected.lit_int(2);
+    let v2 = expected.add(v0, v1, Type::Int);
+    let mut expected = expected.into_function(&[], &[], Operand::Arg(0));
+    expected.nodes.push(RvsdgBody::BasicOp(Expr::Print(vec![
+        v2,
+        v1,
+        Operand::Arg(0),
+    ])));
+    expected.state = Operand::Id(expected.nodes.len() - 1);
+    assert!(func.structurally_eq(&expected));
+
+    // A load between the prints keeps them apart, even though it doesn't
+    // feed into the second print.
+    let memory = || {
+        let mut memory = RvsdgBuilder::default();
+        let one = memory.lit_int(1);
+        let res1 = memory.print(one, Operand::Arg(1));
+        let load = memory.load(Operand::Arg(0), res1, Type::Int);
+        let res2 = memory.print(one, Operand::Project(1, load));
+        memory.into_function(&[Type::Pointer(Box::new(Type::Int))], &[], res2)
+    };
+    let mut func = memory();
+    batch_prints(&mut func);
+    assert!(func.structurally_eq(&memory()));
 }
 
 #[test]
@@ -183,17 +231,77 @@ fn rvsdg_state_gamma() {
     let cfg = program_to_cfg(&prog);
     let rvsdg = cfg_to_rvsdg(&cfg).unwrap();
 
-    let mut expected = RvsdgTest::default();
+    let mut expected = RvsdgBuilder::default();
     let c = expected.lit_bool(true);
     let some_func = expected.void_function("some_func", &[Operand::Arg(0)]);
     let other_func = expected.void_function("other_func", &[Operand::Arg(0)]);
     let gamma = expected.gamma(c, &[Operand::Arg(0)], &[&[other_func], &[some_func]]);
     let res = Operand::Project(0, gamma);
 
-    assert!(deep_equal(
-        &expected.into_function(0, None, res),
-        &rvsdg.functions[0]
-    ));
+    assert!(expected
+        .into_function(&[], &[], res)
+        .structurally_eq(&rvsdg.functions[0]));
+}
+
+#[test]
+fn rvsdg_value_calls() {
+    // Calls to a function that returns a value have the same shape whether
+    // the result is used or discarded; in the latter case, only the state
+    // edge is projected out.
+    const PROGRAM: &str = r#"
+    @main(a: int) {
+        x: int = call @double a;
+        print x;
+        call @double x;
+    }
+
+    @double(a: int): int {
+        print a;
+        res: int = add a a;
+        ret res;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+
+    let mut expected = RvsdgBuilder::default();
+    let used = expected.call("double", &[Operand::Arg(0), Operand::Arg(1)], Type::Int);
+    let print = expected.print(Operand::Id(used), Operand::Project(1, used));
+    let discarded = expected.call("double", &[Operand::Id(used), print], Type::Int);
+    let expected = expected.into_function(&[Type::Int], &[], Operand::Project(1, discarded));
+    assert!(expected.structurally_eq(&rvsdg.functions[0]));
+    assert_eq!(validate(&rvsdg.functions[0]), Ok(()));
+
+    rvsdg_to_cfg_roundtrip(PROGRAM, &["3"]);
+}
+
+#[test]
+fn rvsdg_empty_functions() {
+    // Functions that neither return nor perform an effect just pass their
+    // state edge through.
+    const PROGRAM: &str = r#"
+    @main {
+        call @empty;
+        t: bool = const true;
+        call @ignores_arg t;
+    }
+    @empty() {
+    }
+    @ignores_arg(b: bool) {
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    for func in &rvsdg.functions[1..] {
+        assert!(func.nodes.is_empty(), "@{}", func.name);
+        assert!(func.results.is_empty(), "@{}", func.name);
+        assert_eq!(func.state, Operand::Arg(func.args.len()), "@{}", func.name);
+        let EgglogFunctionResult::StateOnly(state) = func.to_egglog_expr() else {
+            panic!("expected only a state edge for @{}", func.name);
+        };
+        assert_eq!(state.to_string(), format!("(Arg {})", func.args.len()));
+    }
+    rvsdg_to_cfg_roundtrip(PROGRAM, &[]);
 }
 
 #[test]
@@ -223,7 +331,7 @@ fn rvsdg_unstructured() {
     // 1. A theta node: .B and .C form a cycle.
     // 2. A gamma node, as there is a join point in .B for the value of `x`
     // (whether the predecessor is .B or the entry block).
-    assert!(rvsdg.result.is_some());
+    assert_eq!(rvsdg.results.len(), 1);
     assert!(search_for(rvsdg, |body| matches!(
         body,
         RvsdgBody::Theta { .. }
@@ -235,11 +343,102 @@ fn rvsdg_unstructured() {
 }
 
 #[test]
-fn rvsdg_basic_odd_branch() {
-    // Bril program summing the numbers from 1 to n, multiplying by 2 if that
-    // value is larger than 5. This gives us a theta node and a gamma
-    // node, with the gamma requiring branch restructuring.
+fn rvsdg_nops() {
+    // Other tools may leave `nop`s anywhere, including in blocks that do
+    // nothing else.
     const PROGRAM: &str = r#"
+ @main(n: int): int {
+    nop;
+    res: int = const 0;
+    i: int = const 0;
+ .loop:
+    one: int = const 1;
+    nop;
+    res: int = add res i;
+    i: int = add i one;
+    loop_cond: bool = lt i n;
+    br loop_cond .loop .tail;
+ .tail:
+   five: int = const 5;
+   rescale_cond: bool = lt res five;
+   nop;
+   br rescale_cond .rescale .skip;
+ .skip:
+   nop;
+   jmp .exit;
+ .rescale:
+   nop;
+   two: int = const 2;
+   res: int = mul res two;
+ .exit:
+  nop;
+  ret res;
+}"#;
+    let without_nops = String::from_iter(
+        PROGRAM
+            .lines()
+            .filter(|line| line.trim() != "nop;")
+            .map(|line| format!("{line}\n")),
+    );
+    let lower = |program: &str| {
+        let cfg = program_to_cfg(&parse_from_string(program));
+        cfg_to_rvsdg(&cfg).unwrap().functions.remove(0)
+    };
+    let with_nops = lower(PROGRAM);
+    assert!(with_nops.structurally_eq(&lower(&without_nops)));
+    assert_eq!(validate(&with_nops), Ok(()));
+}
+
+#[test]
+fn rvsdg_simplify_cfg() {
+    // `.then` and `.done` only jump on to the next block, and `.show` prints
+    // a copy of a copy.
+    const MESSY: &str = r#"
+    @main(c: bool) {
+        one: int = const 1;
+        br c .then .else;
+    .then:
+        jmp .show;
+    .show:
+        x: int = id one;
+        y: int = id x;
+        print y;
+        jmp .done;
+    .else:
+        two: int = const 2;
+        print two;
+    .done:
+    }"#;
+    const CLEAN: &str = r#"
+    @main(c: bool) {
+        one: int = const 1;
+        br c .show .else;
+    .show:
+        print one;
+        ret;
+    .else:
+        two: int = const 2;
+        print two;
+    }"#;
+    let mut messy = program_to_cfg(&parse_from_string(MESSY));
+    simplify_cfg(&mut messy.functions[0]);
+    let cfg = &messy.functions[0];
+    assert!(cfg.block("then").is_none() && cfg.block("done").is_none());
+    let show = &cfg.graph[cfg.block("show").unwrap()];
+    let Some(Instruction::Effect { args, .. }) = show.instrs.last() else {
+        panic!("expected .show to end in a print");
+    };
+    assert_eq!(args, &["one"]);
+
+    let clean = program_to_cfg(&parse_from_string(CLEAN));
+    let lowered = cfg_to_rvsdg(&messy).unwrap().functions.remove(0);
+    assert!(lowered.structurally_eq(&cfg_to_rvsdg(&clean).unwrap().functions[0]));
+}
+
+// Bril program summing the numbers from 1 to n, multiplying by 2 if that
+// value is larger than 5. This gives us a theta node and a gamma
+// node, with the gamma requiring branch restructuring.
+const ODD_BRANCH: &str = r#"
  @main(n: int): int {
     res: int = const 0;
     i: int = const 0;
@@ -260,8 +459,10 @@ fn rvsdg_basic_odd_branch() {
   ret res;
 }"#;
 
+#[test]
+fn rvsdg_basic_odd_branch() {
     // construct expected program
-    let mut expected = RvsdgTest::default();
+    let mut expected = RvsdgBuilder::default();
     let state = Operand::Arg(1);
     let zero = expected.lit_int(0);
     let one = expected.lit_int(1);
@@ -299,16 +500,167 @@ fn rvsdg_basic_odd_branch() {
         ],
     );
     let expected = expected.into_function(
-        1,
-        Some(Operand::Project(1, gamma)),
+        &[Type::Int],
+        &[Operand::Project(1, gamma)],
         Operand::Project(0, gamma),
     );
 
     // test correctness of RVSDGs converted from CFG
-    let prog = parse_from_string(PROGRAM);
+    let prog = parse_from_string(ODD_BRANCH);
     let cfg = program_to_cfg(&prog);
     let actual = &cfg_to_rvsdg(&cfg).unwrap().functions[0];
-    assert!(deep_equal(&expected, actual));
+    assert!(expected.structurally_eq(actual));
+
+    let stats = expected.stats();
+    assert_eq!(
+        stats,
+        RvsdgStats {
+            basic_ops: 9,
+            gammas: 1,
+            thetas: 1,
+            lambdas: 0,
+            applies: 0,
+            nodes: 11,
+            max_depth: 1,
+            stateful: 0,
+        }
+    );
+    // Lowering may not share constants the way `expected` does, but it has
+    // the same structure.
+    let actual = actual.stats();
+    assert_eq!(
+        (
+            actual.gammas,
+            actual.thetas,
+            actual.max_depth,
+            actual.stateful
+        ),
+        (1, 1, 1, 0)
+    );
+
+    let RvsdgBody::Theta {
+        pred,
+        inputs,
+        outputs,
+    } = &expected.nodes[theta]
+    else {
+        panic!("expected a theta");
+    };
+    assert_eq!(
+        theta_variable_kinds(&expected.nodes, pred, inputs, outputs),
+        vec![
+            VarKind::Invariant,   // state
+            VarKind::LoopCarried, // res
+            VarKind::LoopCarried, // i
+            VarKind::Invariant,   // n
+        ]
+    );
+
+    // Every node is visited once, after the nodes producing its operands.
+    let order: Vec<Id> = expected.nodes_topo().map(|(id, _)| id).collect();
+    assert_eq!(order.len(), expected.nodes.len());
+    let position = |id: Id| order.iter().position(|x| *x == id).unwrap();
+    for (id, body) in expected.nodes_topo() {
+        body.for_each_operand(|op| {
+            if let Operand::Id(x) | Operand::Project(_, x) = op {
+                assert!(position(*x) < position(id));
+            }
+        });
+    }
+    for input in inputs {
+        if let Operand::Id(x) | Operand::Project(_, x) = input {
+            assert!(position(*x) < position(theta));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&expected).unwrap();
+        let deserialized: RvsdgFunction = serde_json::from_str(&json).unwrap();
+        assert!(expected.structurally_eq(&deserialized));
+    }
+}
+
+#[test]
+fn rvsdg_basic_odd_branch_results() {
+    // The loop runs at least once, adding 0 up to max(n, 1) - 1, and sums
+    // under 5 are doubled.
+    let prog = parse_from_string(ODD_BRANCH);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let roundtrip = rvsdg_to_bril(&rvsdg.functions[0]).unwrap();
+    for (n, sum) in [(-1, 0), (0, 0), (1, 0), (2, 2), (3, 6), (4, 6), (5, 10)] {
+        let args = [Literal::Int(n)];
+        let expected = (String::new(), Some(Literal::Int(sum)));
+        assert_eq!(Optimizer::interp_main(&prog, &args).unwrap(), expected);
+        assert_eq!(Optimizer::interp_main(&roundtrip, &args).unwrap(), expected);
+    }
+    // The arguments have to match the parameters of `main`.
+    assert!(Optimizer::interp_main(&prog, &[Literal::Bool(true)]).is_err());
+    assert!(Optimizer::interp_main(&prog, &[]).is_err());
+}
+
+#[test]
+fn rvsdg_signature() {
+    let prog = parse_from_string(ODD_BRANCH);
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let func = rvsdg.functions.remove(0);
+    let expected = FunctionSignature {
+        args: vec![Type::Int],
+        return_type: Some(Type::Int),
+    };
+    assert_eq!(func.signature(), expected);
+
+    // Decoding from egglog infers the same return type from the result.
+    let expr = func.to_egglog_expr();
+    let decoded = RvsdgFunction::egglog_expr_to_function(&expr, "main", &func.args).unwrap();
+    assert_eq!(decoded.signature(), expected);
+
+    // Functions with no results return nothing.
+    let f = RvsdgBuilder::default().into_pure_function(&[Type::Bool], &[]);
+    assert_eq!(f.signature().return_type, None);
+}
+
+#[test]
+fn rvsdg_stats_nesting() {
+    // A theta inside of a gamma.
+    let mut f = RvsdgBuilder::default();
+    let c = f.lit_bool(true);
+    let stop = f.lit_bool(false);
+    let theta = f.theta(stop, &[Operand::Arg(0)], &[Operand::Arg(0)]);
+    let gamma = f.gamma(
+        c,
+        &[Operand::Arg(0)],
+        &[&[Operand::Arg(0)], &[Operand::Id(theta)]],
+    );
+    let f = f.into_pure_function(&[Type::Int], &[Operand::Id(gamma)]);
+    let stats = f.stats();
+    assert_eq!((stats.gammas, stats.thetas), (1, 1));
+    assert_eq!(stats.nodes, 4);
+    assert_eq!(stats.max_depth, 2);
+
+    // A gamma inside of a theta, inside of another theta.
+    let mut f = RvsdgBuilder::default();
+    let c = f.lit_bool(true);
+    let gamma = f.gamma(
+        c,
+        &[Operand::Arg(0)],
+        &[&[Operand::Arg(0)], &[Operand::Arg(0)]],
+    );
+    let inner = f.theta(c, &[Operand::Arg(0)], &[Operand::Id(gamma)]);
+    let outer = f.theta(c, &[Operand::Arg(0)], &[Operand::Id(inner)]);
+    let f = f.into_pure_function(&[Type::Int], &[Operand::Id(outer)]);
+    let stats = f.stats();
+    assert_eq!((stats.gammas, stats.thetas), (1, 2));
+    assert_eq!(stats.max_depth, 3);
+
+    // Stateful operations are counted wherever they are.
+    let mut f = RvsdgBuilder::default();
+    let one = f.lit_int(1);
+    let print = f.print(one, Operand::Arg(0));
+    let stop = f.lit_bool(false);
+    let theta = f.theta(stop, &[Operand::Arg(0)], &[print]);
+    let f = f.into_function(&[], &[], Operand::Id(theta));
+    assert_eq!(f.stats().stateful, 1);
 }
 
 #[test]
@@ -338,7 +690,7 @@ fn rvsdg_odd_branch_egg_roundtrip() {
 }"#;
 
     // construct expected program
-    let mut expected = RvsdgTest::default();
+    let mut expected = RvsdgBuilder::default();
     let state = Operand::Arg(1);
     let zero = expected.lit_int(0);
     let one = expected.lit_int(1);
@@ -376,8 +728,8 @@ fn rvsdg_odd_branch_egg_roundtrip() {
         ],
     );
     let expected = expected.into_function(
-        1,
-        Some(Operand::Project(1, gamma)),
+        &[Type::Int],
+        &[Operand::Project(1, gamma)],
         Operand::Project(0, gamma),
     );
 
@@ -385,12 +737,12 @@ fn rvsdg_odd_branch_egg_roundtrip() {
     let prog = parse_from_string(PROGRAM);
     let cfg = program_to_cfg(&prog);
     let actual = &cfg_to_rvsdg(&cfg).unwrap().functions[0];
-    assert!(deep_equal(&expected, actual));
+    assert!(expected.structurally_eq(actual));
 
     // test equalties of egglog programs generated by RVSDG
     let (actual_state, actual_result) = match actual.to_egglog_expr() {
-        EgglogFunctionResult::StateOnly(_) => panic!("expected state and value"),
         EgglogFunctionResult::StateAndValue { state, value } => (state, value),
+        _ => panic!("expected state and value"),
     };
 
     let actual_result_command = egglog::ast::Command::Action(egglog::ast::Action::Let(
@@ -458,185 +810,3639 @@ fn rvsdg_odd_branch_egg_roundtrip() {
             state: actual_state,
             value: actual_result,
         },
-        1,
-    );
-    assert!(deep_equal(&expected, &actual));
+        "main",
+        &[Type::Int],
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&actual));
 }
 
-fn search_for(f: &RvsdgFunction, mut pred: impl FnMut(&RvsdgBody) -> bool) -> bool {
-    fn search_op(
-        f: &RvsdgFunction,
-        op: &Operand,
-        pred: &mut impl FnMut(&RvsdgBody) -> bool,
-    ) -> bool {
-        match op {
-            Operand::Arg(_) => false,
-            Operand::Id(x) | Operand::Project(_, x) => search_node(f, &f.nodes[*x], pred),
+#[test]
+fn rvsdg_odd_branch_to_dot() {
+    const PROGRAM: &str = r#"
+ @main(n: int): int {
+    res: int = const 0;
+    i: int = const 0;
+ .loop:
+    one: int = const 1;
+    res: int = add res i;
+    i: int = add i one;
+    loop_cond: bool = lt i n;
+    br loop_cond .loop .tail;
+ .tail:
+   five: int = const 5;
+   rescale_cond: bool = lt res five;
+   br rescale_cond .rescale .exit;
+ .rescale:
+   two: int = const 2;
+   res: int = mul res two;
+ .exit:
+  ret res;
+}"#;
+
+    let prog = parse_from_string(PROGRAM);
+    let cfg = program_to_cfg(&prog);
+    let dot = cfg_to_rvsdg(&cfg).unwrap().functions[0].to_dot();
+
+    // Braces outside of quoted labels must balance.
+    let mut depth = 0;
+    let mut in_label = false;
+    let mut prev = ' ';
+    for c in dot.chars() {
+        match c {
+            '"' if prev != '\\' => in_label = !in_label,
+            '{' if !in_label => depth += 1,
+            '}' if !in_label => {
+                assert!(depth > 0, "unbalanced braces in:\n{dot}");
+                depth -= 1;
+            }
+            _ => {}
         }
+        prev = c;
     }
-    fn search_node(
-        f: &RvsdgFunction,
-        node: &RvsdgBody,
-        pred: &mut impl FnMut(&RvsdgBody) -> bool,
-    ) -> bool {
-        if pred(node) {
-            return true;
+    assert_eq!(depth, 0, "unbalanced braces in:\n{dot}");
+    assert!(!in_label);
+
+    // Every edge connects nodes that are declared.
+    let is_id = |id: &str| {
+        !id.is_empty()
+            && !id.starts_with(|c: char| c.is_ascii_digit())
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    let mut declared = std::collections::HashSet::new();
+    for line in dot.lines().map(str::trim) {
+        if let Some((id, _)) = line.split_once(" [label=") {
+            assert!(is_id(id), "invalid node id {id:?}");
+            declared.insert(id.to_owned());
         }
-        match node {
-            RvsdgBody::BasicOp(x) => match x {
-                Expr::Op(_, args, _) | Expr::Call(_, args, _, _) | Expr::Print(args) => {
-                    args.iter().any(|arg| search_op(f, arg, pred))
-                }
-                Expr::Const(_, _, _) => false,
-            },
-            RvsdgBody::Gamma {
-                pred: p,
-                inputs,
-                outputs,
-            } => {
-                search_op(f, p, pred)
-                    || inputs.iter().any(|arg| search_op(f, arg, pred))
-                    || outputs
-                        .iter()
-                        .any(|outs| outs.iter().any(|arg| search_op(f, arg, pred)))
-            }
-            RvsdgBody::Theta {
-                pred: p,
-                inputs,
-                outputs,
-            } => {
-                search_op(f, p, pred)
-                    || inputs.iter().any(|arg| search_op(f, arg, pred))
-                    || outputs.iter().any(|arg| search_op(f, arg, pred))
+    }
+    let mut edges = 0;
+    for line in dot.lines().map(str::trim) {
+        if let Some((src, dst)) = line.strip_suffix(';').and_then(|l| l.split_once(" -> ")) {
+            for endpoint in [src, dst] {
+                let (id, _port) = endpoint.split_once(':').unwrap();
+                assert!(declared.contains(id), "undeclared node {id:?} in:\n{dot}");
             }
+            edges += 1;
         }
     }
-    if search_op(f, &f.state, &mut pred) {
-        return true;
-    }
-    f.result
-        .as_ref()
-        .map(|res| search_op(f, res, &mut pred))
-        .unwrap_or(false)
+    assert!(edges > 0);
+    // One cluster for the function, the theta and its body, and the gamma and
+    // its two branches.
+    assert_eq!(dot.matches("subgraph cluster_").count(), 6);
 }
 
-/// We don't want to commit to the order in which nodes are laid out, so we do a
-/// DFS to check if two functions are equal for the purposes of testing.
-fn deep_equal(f1: &RvsdgFunction, f2: &RvsdgFunction) -> bool {
-    if f1.n_args != f2.n_args {
-        return false;
-    }
+#[test]
+fn rvsdg_canonical_numbering() {
+    // The same program, with its constants defined in different orders.
+    const PROGRAM: &str = r#"
+    @main(x: int) {
+      one: int = const 1;
+      two: int = const 2;
+      y: int = add x one;
+      z: int = mul y two;
+      print z;
+    }"#;
+    let swapped = PROGRAM.replace(
+        "one: int = const 1;\n      two: int = const 2;",
+        "two: int = const 2;\n      one: int = const 1;",
+    );
+    assert_ne!(PROGRAM, swapped);
+    let lower = |program: &str| cfg_to_rvsdg(&program_to_cfg(&parse_from_string(program))).unwrap();
+    let (first, second) = (lower(PROGRAM), lower(&swapped));
+    assert!(!matches!(
+        (&first.functions[0].nodes[0], &second.functions[0].nodes[0]),
+        (RvsdgBody::BasicOp(a), RvsdgBody::BasicOp(b)) if a == b
+    ));
 
-    fn ops_equal(o1: &Operand, o2: &Operand, f1: &RvsdgFunction, f2: &RvsdgFunction) -> bool {
-        match (o1, o2) {
-            (Operand::Arg(x), Operand::Arg(y)) => x == y,
-            (Operand::Project(p1, l), Operand::Project(p2, r)) => {
-                p1 == p2 && ids_equal(*l, *r, f1, f2)
-            }
-            (Operand::Id(l), Operand::Id(r))
-            | (Operand::Project(0, l), Operand::Id(r))
-            | (Operand::Id(l), Operand::Project(0, r)) => ids_equal(*l, *r, f1, f2),
-            (Operand::Arg(_), Operand::Id(_))
-            | (Operand::Arg(_), Operand::Project(_, _))
-            | (Operand::Id(_), Operand::Arg(_))
-            | (Operand::Project(_, _), Operand::Arg(_))
-            | (Operand::Project(_, _), Operand::Id(_))
-            | (Operand::Id(_), Operand::Project(_, _)) => false,
-        }
+    // Debug visualizations label nodes with their ids, so they would differ
+    // if the nodes weren't renumbered.
+    assert_eq!(first.to_debug_svg(), second.to_debug_svg());
+    assert_eq!(first.to_svg(), second.to_svg());
+
+    let [mut first, mut second] = [first, second].map(|mut prog| prog.functions.remove(0));
+    renumber_nodes(&mut first);
+    renumber_nodes(&mut second);
+    assert!(first.structurally_eq(&second));
+    for (a, b) in first.nodes.iter().zip(&second.nodes) {
+        assert_eq!(format!("{a:?}"), format!("{b:?}"));
     }
+}
 
-    fn all_equal(
-        ops1: &[Operand],
-        ops2: &[Operand],
-        f1: &RvsdgFunction,
-        f2: &RvsdgFunction,
-    ) -> bool {
-        ops1.len() == ops2.len()
-            && ops1
-                .iter()
-                .zip(ops2.iter())
-                .all(|(l, r)| ops_equal(l, r, f1, f2))
-    }
-
-    fn ids_equal(i1: Id, i2: Id, f1: &RvsdgFunction, f2: &RvsdgFunction) -> bool {
-        match (&f1.nodes[i1], &f2.nodes[i2]) {
-            (RvsdgBody::BasicOp(l), RvsdgBody::BasicOp(r)) => match (l, r) {
-                (Expr::Op(vo1, as1, ty1), Expr::Op(vo2, as2, ty2)) => {
-                    vo1 == vo2 && all_equal(as1, as2, f1, f2) && ty1 == ty2
-                }
-                (Expr::Call(func1, as1, n1, ty1), Expr::Call(func2, as2, n2, ty2)) => {
-                    func1 == func2 && n1 == n2 && all_equal(as1, as2, f1, f2) && ty1 == ty2
-                }
-                (Expr::Const(c1, ty1, lit1), Expr::Const(c2, ty2, lit2)) => {
-                    c1 == c2 && ty1 == ty2 && lit1 == lit2
-                }
-                (Expr::Print(as1), Expr::Print(as2)) => all_equal(as1, as2, f1, f2),
-                (Expr::Call(_, _, _, _), Expr::Op(_, _, _))
-                | (Expr::Call(_, _, _, _), Expr::Const(_, _, _))
-                | (Expr::Call(_, _, _, _), Expr::Print(_))
-                | (Expr::Const(_, _, _), Expr::Call(_, _, _, _))
-                | (Expr::Const(_, _, _), Expr::Op(_, _, _))
-                | (Expr::Const(_, _, _), Expr::Print(_))
-                | (Expr::Op(_, _, _), Expr::Call(_, _, _, _))
-                | (Expr::Op(_, _, _), Expr::Const(_, _, _))
-                | (Expr::Op(_, _, _), Expr::Print(_))
-                | (Expr::Print(_), Expr::Call(_, _, _, _))
-                | (Expr::Print(_), Expr::Const(_, _, _))
-                | (Expr::Print(_), Expr::Op(_, _, _)) => false,
-            },
-            (
-                RvsdgBody::Theta {
-                    pred: p1,
-                    inputs: is1,
-                    outputs: os1,
-                },
-                RvsdgBody::Theta {
-                    pred: p2,
-                    inputs: is2,
-                    outputs: os2,
-                },
-            ) => {
-                ops_equal(p1, p2, f1, f2)
-                    && all_equal(is1, is2, f1, f2)
-                    && all_equal(os1, os2, f1, f2)
-            }
-            (
-                RvsdgBody::Gamma {
-                    pred: p1,
-                    inputs: is1,
-                    outputs: os1,
-                },
-                RvsdgBody::Gamma {
-                    pred: p2,
-                    inputs: is2,
-                    outputs: os2,
-                },
-            ) => {
-                if !ops_equal(p1, p2, f1, f2) || !all_equal(is1, is2, f1, f2) {
-                    return false;
-                }
-                os1.len() == os2.len()
-                    && os1
-                        .iter()
-                        .zip(os2.iter())
-                        .all(|(l, r)| all_equal(l, r, f1, f2))
-            }
-            (RvsdgBody::BasicOp(_), RvsdgBody::Gamma { .. })
-            | (RvsdgBody::BasicOp(_), RvsdgBody::Theta { .. })
-            | (RvsdgBody::Gamma { .. }, RvsdgBody::Theta { .. })
-            | (RvsdgBody::Gamma { .. }, RvsdgBody::BasicOp(_))
-            | (RvsdgBody::Theta { .. }, RvsdgBody::BasicOp(_))
-            | (RvsdgBody::Theta { .. }, RvsdgBody::Gamma { .. }) => false,
-        }
+#[test]
+fn rvsdg_from_json() {
+    let text = TestProgram::File("tests/small/add.bril".into()).read_program();
+    let json = TestProgram::BrilJson("tests/small/add.json".into()).read_program();
+    let from_text = cfg_to_rvsdg(&program_to_cfg(&text.program)).unwrap();
+    let from_json = cfg_to_rvsdg(&program_to_cfg(&json.program)).unwrap();
+    assert_eq!(from_text.functions.len(), from_json.functions.len());
+    for (expected, actual) in from_text.functions.iter().zip(&from_json.functions) {
+        assert!(expected.structurally_eq(actual));
     }
+}
 
-    if !ops_equal(&f1.state, &f2.state, f1, f2) {
-        return false;
+#[test]
+fn rvsdg_sub_egg_roundtrip() {
+    const PROGRAM: &str = r#"
+    @main(x: int): int {
+        one: int = const 1;
+        res: int = sub x one;
+        ret res;
     }
+    "#;
+
+    let mut expected = RvsdgBuilder::default();
+    let one = expected.lit_int(1);
+    let res = expected.sub(Operand::Arg(0), one, Type::Int);
+    let expected = expected.into_pure_function(&[Type::Int], &[res]);
+
+    let prog = parse_from_string(PROGRAM);
+    let cfg = program_to_cfg(&prog);
+    let actual = &cfg_to_rvsdg(&cfg).unwrap().functions[0];
+    assert!(expected.structurally_eq(actual));
 
-    match (&f1.result, &f2.result) {
-        (Some(o1), Some(o2)) => ops_equal(o1, o2, f1, f2),
-        (None, None) => true,
-        (None, Some(_)) | (Some(_), None) => false,
+    let (actual_state, actual_result) = match actual.to_egglog_expr() {
+        EgglogFunctionResult::StateAndValue { state, value } => (state, value),
+        _ => panic!("expected state and value"),
+    };
+
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .process_commands(
+            vec![
+                egglog::ast::Command::Action(egglog::ast::Action::Let(
+                    "actual-result".into(),
+                    actual_result.clone(),
+                )),
+                egglog::ast::Command::Action(egglog::ast::Action::Let(
+                    "actual-state".into(),
+                    actual_state.clone(),
+                )),
+            ],
+            egglog::CompilerPassStop::All,
+        )
+        .unwrap();
+    egraph
+        .parse_and_run_program(
+            r#"
+    (check (= actual-result
+              (Node (PureOp (sub (IntT) (Arg 0)
+                                 (Node (PureOp (Const (IntT) (const) (Num 1)))))))))
+    (check (= actual-state (Arg 1)))
+    "#,
+        )
+        .unwrap();
+
+    let actual = RvsdgFunction::egglog_expr_to_function(
+        &EgglogFunctionResult::StateAndValue {
+            state: actual_state,
+            value: actual_result,
+        },
+        "main",
+        &[Type::Int],
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_float_egg_roundtrip() {
+    const PROGRAM: &str = r#"
+    @main(): float {
+        a: float = const 1.5;
+        b: float = const 2.5;
+        c: float = fadd a b;
+        ret c;
+    }
+    "#;
+
+    let mut expected = RvsdgBuilder::default();
+    let a = expected.lit_float(1.5);
+    let b = expected.lit_float(2.5);
+    let c = expected.fadd(a, b);
+    let expected = expected.into_pure_function(&[], &[c]);
+
+    let prog = parse_from_string(PROGRAM);
+    let cfg = program_to_cfg(&prog);
+    let actual = &cfg_to_rvsdg(&cfg).unwrap().functions[0];
+    assert!(expected.structurally_eq(actual));
+
+    let (actual_state, actual_result) = match actual.to_egglog_expr() {
+        EgglogFunctionResult::StateAndValue { state, value } => (state, value),
+        _ => panic!("expected state and value"),
+    };
+
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .process_commands(
+            vec![egglog::ast::Command::Action(egglog::ast::Action::Let(
+                "actual-result".into(),
+                actual_result.clone(),
+            ))],
+            egglog::CompilerPassStop::All,
+        )
+        .unwrap();
+    egraph
+        .parse_and_run_program(
+            r#"
+    (check (= actual-result
+              (Node (PureOp (fadd (FloatT)
+                                  (Node (PureOp (Const (FloatT) (const) (Float 1.5))))
+                                  (Node (PureOp (Const (FloatT) (const) (Float 2.5)))))))))
+    (let int-one (Node (PureOp (Const (IntT) (const) (Num 1)))))
+    (let float-one (Node (PureOp (Const (FloatT) (const) (Float 1.0)))))
+    "#,
+        )
+        .unwrap();
+    // Float and int constants must be kept apart.
+    assert!(egraph
+        .parse_and_run_program("(check (= int-one float-one))")
+        .is_err());
+
+    let actual = RvsdgFunction::egglog_expr_to_function(
+        &EgglogFunctionResult::StateAndValue {
+            state: actual_state,
+            value: actual_result,
+        },
+        "main",
+        &[],
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_char_egg_roundtrip() {
+    const PROGRAM: &str = r#"
+    @main() {
+        c: char = const 'λ';
+        print c;
+    }
+    "#;
+
+    let mut expected = RvsdgBuilder::default();
+    let c = expected.lit_char('λ');
+    let state = expected.print(c, Operand::Arg(0));
+    let expected = expected.into_function(&[], &[], state);
+
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let actual = &rvsdg.functions[0];
+    assert!(expected.structurally_eq(actual));
+
+    // The print is an effect on the state edge, in the egraph as well.
+    let state = match actual.to_egglog_expr() {
+        EgglogFunctionResult::StateOnly(state) => state,
+        _ => panic!("expected a state edge only"),
+    };
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .process_commands(
+            vec![egglog::ast::Command::Action(egglog::ast::Action::Let(
+                "actual-state".into(),
+                state.clone(),
+            ))],
+            egglog::CompilerPassStop::All,
+        )
+        .unwrap();
+    egraph
+        .parse_and_run_program(
+            r#"
+    (check (= actual-state
+              (Node (Effectful (print (vec-of (Node (PureOp (Const (CharT) (const) (Char "λ"))))))
+                               (Arg 0)))))
+    "#,
+        )
+        .unwrap();
+
+    let roundtrip = RvsdgFunction::egglog_expr_to_function(
+        &EgglogFunctionResult::StateOnly(state),
+        "main",
+        &[],
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&roundtrip));
+    assert_eq!(
+        Optimizer::interp(&rvsdg_to_bril(&roundtrip).unwrap(), vec![], None),
+        "λ\n"
+    );
+}
+
+#[test]
+fn rvsdg_multiple_results() {
+    // Computes (a + b, a + b + c); Bril functions can't return more than one
+    // value, so this is built by hand.
+    let mut expected = RvsdgBuilder::default();
+    let ab = expected.add(Operand::Arg(0), Operand::Arg(1), Type::Int);
+    let abc = expected.add(ab, Operand::Arg(2), Type::Int);
+    let expected = expected.into_pure_function(&[Type::Int, Type::Int, Type::Int], &[ab, abc]);
+
+    let (state, values) = match expected.to_egglog_expr() {
+        EgglogFunctionResult::StateAndValues { state, values } => (state, values),
+        _ => panic!("expected state and values"),
+    };
+    assert_eq!(values.len(), 2);
+
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .process_commands(
+            vec![
+                egglog::ast::Command::Action(egglog::ast::Action::Let(
+                    "sum-ab".into(),
+                    values[0].clone(),
+                )),
+                egglog::ast::Command::Action(egglog::ast::Action::Let(
+                    "sum-abc".into(),
+                    values[1].clone(),
+                )),
+            ],
+            egglog::CompilerPassStop::All,
+        )
+        .unwrap();
+    egraph
+        .parse_and_run_program(
+            r#"
+    (check (= sum-ab (Node (PureOp (add (IntT) (Arg 0) (Arg 1))))))
+    (check (= sum-abc (Node (PureOp (add (IntT) sum-ab (Arg 2))))))
+    "#,
+        )
+        .unwrap();
+
+    let actual = RvsdgFunction::egglog_expr_to_function(
+        &EgglogFunctionResult::StateAndValues { state, values },
+        &expected.name,
+        &expected.args,
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&actual));
+
+    // The order of the results matters.
+    let mut swapped = RvsdgBuilder::default();
+    let ab = swapped.add(Operand::Arg(0), Operand::Arg(1), Type::Int);
+    let abc = swapped.add(ab, Operand::Arg(2), Type::Int);
+    let swapped = swapped.into_pure_function(&[Type::Int, Type::Int, Type::Int], &[abc, ab]);
+    assert!(!swapped.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_structurally_eq_commutative() {
+    // Both functions return `1 + 2`, but with the operands in a different
+    // order (and created in a different order, too).
+    let mut f = RvsdgBuilder::default();
+    let one = f.lit_int(1);
+    let two = f.lit_int(2);
+    let sum = f.add(one, two, Type::Int);
+    let f = f.into_pure_function(&[], &[sum]);
+
+    let mut g = RvsdgBuilder::default();
+    let two = g.lit_int(2);
+    let one = g.lit_int(1);
+    let sum = g.add(two, one, Type::Int);
+    let g = g.into_pure_function(&[], &[sum]);
+
+    assert!(!f.structurally_eq(&g));
+    assert!(f.structurally_eq_commutative(&g));
+    assert!(g.structurally_eq_commutative(&f));
+
+    // Non-commutative operations are still compared in order.
+    let mut h = RvsdgBuilder::default();
+    let two = h.lit_int(2);
+    let one = h.lit_int(1);
+    let diff = h.sub(two, one, Type::Int);
+    let h = h.into_pure_function(&[], &[diff]);
+    let mut k = RvsdgBuilder::default();
+    let one = k.lit_int(1);
+    let two = k.lit_int(2);
+    let diff = k.sub(one, two, Type::Int);
+    let k = k.into_pure_function(&[], &[diff]);
+    assert!(!h.structurally_eq_commutative(&k));
+}
+
+#[test]
+fn rvsdg_structurally_eq_ignoring_types() {
+    // The same call, once annotated with its result type and once before
+    // that type has been inferred.
+    let call = |lit: i64, ty: Option<Type>| {
+        let mut f = RvsdgBuilder::default();
+        let arg = f.lit_int(lit);
+        let mut f = f.into_function(&[], &[], Operand::Arg(0));
+        f.nodes.push(RvsdgBody::BasicOp(Expr::Call(
+            "callee".into(),
+            vec![arg, Operand::Arg(0)],
+            2,
+            ty,
+            false,
+        )));
+        f.results = vec![Operand::Project(0, 1)];
+        f.state = Operand::Project(1, 1);
+        f
+    };
+    let annotated = call(1, Some(Type::Int));
+    let inferred = call(1, None);
+    assert!(!annotated.structurally_eq(&inferred));
+    assert!(annotated.structurally_eq_ignoring_types(&inferred));
+    assert!(inferred.structurally_eq_ignoring_types(&annotated));
+
+    // Constants still have to agree on their literals.
+    assert!(!annotated.structurally_eq_ignoring_types(&call(2, None)));
+}
+
+#[test]
+fn rvsdg_diff() {
+    // `c ? x op x : x`, where only the operation in the second branch differs.
+    let build = |mul: bool| {
+        let mut f = RvsdgBuilder::default();
+        let res = if mul {
+            f.mul(Operand::Arg(0), Operand::Arg(0), Type::Int)
+        } else {
+            f.add(Operand::Arg(0), Operand::Arg(0), Type::Int)
+        };
+        let gamma = f.gamma(
+            Operand::Arg(1),
+            &[Operand::Arg(0)],
+            &[&[Operand::Arg(0)], &[res]],
+        );
+        f.into_pure_function(&[Type::Int, Type::Bool], &[Operand::Id(gamma)])
+    };
+    let (add, mul) = (build(false), build(true));
+    assert_eq!(diff(&add, &build(false)), None);
+
+    let found = diff(&add, &mul).unwrap();
+    assert!(!add.structurally_eq(&mul));
+    assert_eq!(found.path, ["result 0", "gamma branch 1, output 0"]);
+    assert_eq!(
+        found.to_string(),
+        "result 0 → gamma branch 1, output 0: \
+         Op(Add, int) with 2 operands vs Op(Mul, int) with 2 operands"
+    );
+
+    // Differences in what the function takes are reported up front.
+    let fewer_args = {
+        let mut f = RvsdgBuilder::default();
+        let res = f.add(Operand::Arg(0), Operand::Arg(0), Type::Int);
+        f.into_pure_function(&[Type::Int], &[res])
+    };
+    assert_eq!(diff(&add, &fewer_args).unwrap().path, ["arguments"]);
+}
+
+#[test]
+fn rvsdg_return_type_is_compared() {
+    let hash = |f: &RvsdgFunction| {
+        let mut hasher = DefaultHasher::new();
+        f.hash(&mut hasher);
+        hasher.finish()
+    };
+    // The same body, declared once to return its argument and once to return
+    // nothing.
+    let build = |return_type: Option<Type>| {
+        let f = RvsdgBuilder::default();
+        let mut f = f.into_pure_function(&[Type::Int], &[Operand::Arg(0)]);
+        f.return_type = return_type;
+        f
+    };
+    let returns = build(Some(Type::Int));
+    let void = build(None);
+    assert!(returns.structurally_eq(&build(Some(Type::Int))));
+    assert!(!returns.structurally_eq(&void));
+    assert!(!void.structurally_eq_ignoring_types(&returns));
+    assert_ne!(hash(&returns), hash(&void));
+
+    let found = diff(&returns, &void).unwrap();
+    assert_eq!(found.path, ["return type"]);
+    assert_eq!(found.to_string(), "return type: Some(Int) vs None");
+}
+
+#[test]
+fn rvsdg_lambda_egg_roundtrip() {
+    // Applies `\x. x + k` to the argument of the function.
+    let build = |k: i64| {
+        let mut body = RvsdgBuilder::default();
+        let k = body.lit_int(k);
+        let res = body.add(Operand::Arg(0), k, Type::Int);
+
+        let mut f = RvsdgBuilder::default();
+        let lambda = f.lambda(1, body, &[res, Operand::Arg(1)]);
+        let apply = f.apply(lambda, &[Operand::Arg(0), Operand::Arg(1)]);
+        f.into_function(
+            &[Type::Int],
+            &[Operand::Project(0, apply)],
+            Operand::Project(1, apply),
+        )
+    };
+    let expected = build(1);
+    // structurally_eq looks inside of lambdas.
+    assert!(!expected.structurally_eq(&build(2)));
+
+    let (state, value) = match expected.to_egglog_expr() {
+        EgglogFunctionResult::StateAndValue { state, value } => (state, value),
+        _ => panic!("expected state and value"),
+    };
+
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .process_commands(
+            vec![egglog::ast::Command::Action(egglog::ast::Action::Let(
+                "actual-result".into(),
+                value.clone(),
+            ))],
+            egglog::CompilerPassStop::All,
+        )
+        .unwrap();
+    egraph
+        .parse_and_run_program(
+            r#"
+    (let inc
+        (Lambda 1
+                (vec-of (Project 0 (PureOp (add (IntT) (Arg 0)
+                                                (Project 0 (PureOp (Const (IntT) (const) (Num 1)))))))
+                        (Arg 1))))
+    (check (= actual-result (Project 0 (Apply (Project 0 inc) (vec-of (Arg 0) (Arg 1))))))
+    "#,
+        )
+        .unwrap();
+
+    let actual = RvsdgFunction::egglog_expr_to_function(
+        &EgglogFunctionResult::StateAndValue { state, value },
+        &expected.name,
+        &expected.args,
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_to_cfg_errors() {
+    // Bril functions return at most one value.
+    let mut f = RvsdgBuilder::default();
+    let one = f.lit_int(1);
+    let two = f.lit_int(2);
+    let pair = f.into_function(&[], &[one, two], Operand::Arg(0));
+    assert!(matches!(
+        rvsdg_to_cfg(&pair),
+        Err(RvsdgError::MultipleResults { func, results: 2 }) if func == "main"
+    ));
+
+    // Bril has no first-class functions either, but lambdas that are never
+    // applied aren't emitted in the first place.
+    let build = |applied: bool| {
+        let mut f = RvsdgBuilder::default();
+        let lambda = f.lambda(1, RvsdgBuilder::default(), &[Operand::Arg(0)]);
+        let apply = f.apply(lambda, &[Operand::Arg(0), Operand::Arg(1)]);
+        let result = if applied {
+            Operand::Project(0, apply)
+        } else {
+            Operand::Arg(0)
+        };
+        f.into_function(&[Type::Int], &[result], Operand::Arg(1))
+    };
+    assert!(matches!(
+        rvsdg_to_bril(&build(true)),
+        Err(RvsdgError::FirstClassFunction { func }) if func == "main"
+    ));
+    let unapplied = rvsdg_to_bril(&build(false)).unwrap();
+    assert_eq!(Optimizer::interp(&unapplied, vec!["5".into()], None), "");
+}
+
+#[test]
+fn rvsdg_random_egg_roundtrip() {
+    for seed in 0..200 {
+        let func = random_function(seed, 30);
+        assert_eq!(validate(&func), Ok(()), "seed {seed}");
+        let roundtrip =
+            RvsdgFunction::egglog_expr_to_function(&func.to_egglog_expr(), &func.name, &func.args)
+                .unwrap();
+        assert!(
+            func.structurally_eq(&roundtrip),
+            "seed {seed}:\n{func:?}\n{roundtrip:?}"
+        );
+    }
+}
+
+#[test]
+fn rvsdg_egg_arg_out_of_range() {
+    // (Arg 3) in a function with one argument (and the state edge).
+    use egglog::ast::{Expr::*, Literal::*};
+    let arg = |i| Call("Arg".into(), vec![Lit(Int(i))]);
+    let add = Call(
+        "add".into(),
+        vec![Call("IntT".into(), vec![]), arg(0), arg(3)],
+    );
+    let value = Call("Node".into(), vec![Call("PureOp".into(), vec![add])]);
+    let res = EgglogFunctionResult::StateAndValue {
+        state: arg(1),
+        value,
+    };
+    assert_eq!(
+        RvsdgFunction::egglog_expr_to_function(&res, "main", &[Type::Int]).err(),
+        Some(ValidationError::ArgOutOfRange { arg: 3, n_args: 2 })
+    );
+}
+
+#[test]
+fn rvsdg_div_threads_state() {
+    const PROGRAM: &str = r#"
+    @main(x: int, y: int): int {
+        res: int = div x y;
+        print x;
+        ret res;
+    }
+    "#;
+
+    let mut expected = RvsdgBuilder::default();
+    let div = expected.div(Operand::Arg(0), Operand::Arg(1), Operand::Arg(2), Type::Int);
+    let print = expected.print(Operand::Arg(0), Operand::Project(1, div));
+    let expected =
+        expected.into_function(&[Type::Int, Type::Int], &[Operand::Project(0, div)], print);
+
+    let prog = parse_from_string(PROGRAM);
+    let cfg = program_to_cfg(&prog);
+    let actual = &cfg_to_rvsdg(&cfg).unwrap().functions[0];
+    assert!(expected.structurally_eq(actual));
+
+    // The state edge survives the round trip through egglog.
+    let actual = RvsdgFunction::egglog_expr_to_function(
+        &actual.to_egglog_expr(),
+        &actual.name,
+        &actual.args,
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_memory() {
+    const PROGRAM: &str = r#"
+    @main(): int {
+        one: int = const 1;
+        p: ptr<int> = alloc one;
+        x: int = const 4;
+        store p x;
+        v: int = load p;
+        free p;
+        ret v;
+    }
+    "#;
+
+    let mut expected = RvsdgBuilder::default();
+    let one = expected.lit_int(1);
+    let alloc = expected.alloc(one, Operand::Arg(0), Type::Pointer(Box::new(Type::Int)));
+    let p = Operand::Project(0, alloc);
+    let x = expected.lit_int(4);
+    let store = expected.store(p, x, Operand::Project(1, alloc));
+    // The load has to observe the state produced by the store.
+    let load = expected.load(p, store, Type::Int);
+    let free = expected.free(p, Operand::Project(1, load));
+    let expected = expected.into_function(&[], &[Operand::Project(0, load)], free);
+
+    let prog = parse_from_string(PROGRAM);
+    let cfg = program_to_cfg(&prog);
+    let actual = &cfg_to_rvsdg(&cfg).unwrap().functions[0];
+    assert!(expected.structurally_eq(actual));
+
+    let actual = RvsdgFunction::egglog_expr_to_function(
+        &actual.to_egglog_expr(),
+        &actual.name,
+        &actual.args,
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_dead_store_elimination() {
+    // The first store is overwritten before anything reads it, but the load
+    // between the second and third stores keeps them both.
+    const PROGRAM: &str = r#"
+    @main() {
+        one: int = const 1;
+        p: ptr<int> = alloc one;
+        two: int = const 2;
+        three: int = const 3;
+        store p one;
+        store p two;
+        v: int = load p;
+        store p three;
+        w: int = load p;
+        print v w;
+        free p;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let mut func = rvsdg.functions.remove(0);
+    dead_store_elimination(&mut func);
+
+    let mut expected = RvsdgBuilder::default();
+    let one = expected.lit_int(1);
+    let alloc = expected.alloc(one, Operand::Arg(0), Type::Pointer(Box::new(Type::Int)));
+    let p = Operand::Project(0, alloc);
+    let two = expected.lit_int(2);
+    let three = expected.lit_int(3);
+    let store = expected.store(p, two, Operand::Project(1, alloc));
+    let v = expected.load(p, store, Type::Int);
+    let store = expected.store(p, three, Operand::Project(1, v));
+    let w = expected.load(p, store, Type::Int);
+    let state = expected.print_all(
+        &[Operand::Project(0, v), Operand::Project(0, w)],
+        Operand::Project(1, w),
+    );
+    let state = expected.free(p, state);
+    let expected = expected.into_function(&[], &[], state);
+    assert!(func.structurally_eq(&expected));
+
+    assert_eq!(
+        Optimizer::interp(&prog, vec![], None),
+        Optimizer::interp(&rvsdg_to_bril(&func).unwrap(), vec![], None)
+    );
+}
+
+#[test]
+fn rvsdg_ptradd() {
+    const PROGRAM: &str = r#"
+    @main(): int {
+        ten: int = const 10;
+        ptr1: ptr<int> = alloc ten;
+        offset: int = const 2;
+        ptr2: ptr<int> = ptradd ptr1 offset;
+        v: int = load ptr2;
+        free ptr1;
+        ret v;
+    }
+    "#;
+    let ptr_ty = Type::Pointer(Box::new(Type::Int));
+
+    let build = |ptradd_ty: Type| {
+        let mut expected = RvsdgBuilder::default();
+        let ten = expected.lit_int(10);
+        let alloc = expected.alloc(ten, Operand::Arg(0), ptr_ty.clone());
+        let ptr1 = Operand::Project(0, alloc);
+        let offset = expected.lit_int(2);
+        let ptr2 = expected.ptradd(ptr1, offset, ptradd_ty);
+        let load = expected.load(ptr2, Operand::Project(1, alloc), Type::Int);
+        let free = expected.free(ptr1, Operand::Project(1, load));
+        expected.into_function(&[], &[Operand::Project(0, load)], free)
+    };
+
+    let prog = parse_from_string(PROGRAM);
+    let cfg = program_to_cfg(&prog);
+    let actual = &cfg_to_rvsdg(&cfg).unwrap().functions[0];
+    assert!(build(ptr_ty.clone()).structurally_eq(actual));
+    // The element type of the pointer matters.
+    assert!(!build(Type::Pointer(Box::new(Type::Bool))).structurally_eq(actual));
+
+    let actual = RvsdgFunction::egglog_expr_to_function(
+        &actual.to_egglog_expr(),
+        &actual.name,
+        &actual.args,
+    )
+    .unwrap();
+    assert!(build(ptr_ty.clone()).structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_guarded_div() {
+    const PROGRAM: &str = r#"
+    @main(x: int, y: int) {
+        zero: int = const 0;
+        is_zero: bool = eq y zero;
+        br is_zero .end .divide;
+    .divide:
+        res: int = div x y;
+        print res;
+    .end:
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let cfg = program_to_cfg(&prog);
+    let rvsdg = &cfg_to_rvsdg(&cfg).unwrap().functions[0];
+
+    let mut is_div = |body: &RvsdgBody| {
+        matches!(
+            body,
+            RvsdgBody::BasicOp(Expr::EffectfulOp(ValueOps::Div, _, _))
+        )
+    };
+    assert!(search_for(rvsdg, &mut is_div));
+
+    // The division must only be reachable through the outputs of the gamma
+    // node guarding it: it cannot be hoisted into the enclosing region.
+    let (Operand::Project(_, gamma) | Operand::Id(gamma)) = rvsdg.state else {
+        panic!("expected the state edge to flow out of a gamma")
+    };
+    let RvsdgBody::Gamma { pred, inputs, outputs } = &rvsdg.nodes[gamma] else {
+        panic!("expected the state edge to flow out of a gamma")
+    };
+    assert!(!search_op_for(&rvsdg.nodes, pred, &mut is_div));
+    assert!(!inputs
+        .iter()
+        .any(|input| search_op_for(&rvsdg.nodes, input, &mut is_div)));
+    assert!(outputs.iter().flatten().any(|output| search_op_for(
+        &rvsdg.nodes,
+        output,
+        &mut is_div
+    )));
+}
+
+#[test]
+fn rvsdg_id_is_an_alias() {
+    // `id` of a function argument.
+    const ARG: &str = r#"
+    @main(y: int) {
+        x: int = id y;
+        print x;
+    }
+    "#;
+    let prog = parse_from_string(ARG);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let mut expected = RvsdgBuilder::default();
+    let res = expected.print(Operand::Arg(0), Operand::Arg(1));
+    assert_eq!(rvsdg.functions[0].nodes.len(), 1);
+    assert!(expected
+        .into_function(&[Type::Int], &[], res)
+        .structurally_eq(&rvsdg.functions[0]));
+
+    // `id` of one of the outputs of a gamma.
+    const PROJECTION: &str = r#"
+    @main(c: bool) {
+        br c .T .F;
+    .T:
+        a: int = const 1;
+        jmp .J;
+    .F:
+        a: int = const 2;
+        jmp .J;
+    .J:
+        b: int = id a;
+        print b;
+    }
+    "#;
+    let prog = parse_from_string(PROJECTION);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let func = &rvsdg.functions[0];
+    assert!(!func
+        .nodes
+        .iter()
+        .any(|body| matches!(body, RvsdgBody::BasicOp(Expr::Op(ValueOps::Id, ..)))));
+    let Operand::Id(print) = func.state else {
+        panic!("expected the state to come from a print")
+    };
+    let RvsdgBody::BasicOp(Expr::Print(args)) = &func.nodes[print] else {
+        panic!("expected the state to come from a print")
+    };
+    let Operand::Project(_, gamma) = args[0] else {
+        panic!("expected the print to use the gamma's output directly")
+    };
+    assert!(matches!(func.nodes[gamma], RvsdgBody::Gamma { .. }));
+}
+
+#[test]
+fn rvsdg_speculation() {
+    // The speculated instructions and the guard are all on the state edge.
+    // The guard always passes, so it never jumps back to `.retry`.
+    const PROGRAM: &str = r#"
+    @main() {
+        one: int = const 1;
+        speculate;
+        two: int = add one one;
+        ok: bool = const true;
+        guard ok .retry;
+        commit;
+    .retry:
+        print two;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let actual = &rvsdg.functions[0];
+
+    let mut expected = RvsdgBuilder::default();
+    let one = expected.lit_int(1);
+    let two = expected.add(one, one, Type::Int);
+    let ok = expected.lit_bool(true);
+    let state = expected.speculate(Operand::Arg(0));
+    let state = expected.guard(ok, state);
+    let state = expected.commit(state);
+    let state = expected.print(two, state);
+    let expected = expected.into_function(&[], &[], state);
+    assert!(expected.structurally_eq(actual));
+
+    let roundtrip =
+        RvsdgFunction::egglog_expr_to_function(&actual.to_egglog_expr(), "main", &[]).unwrap();
+    assert!(expected.structurally_eq(&roundtrip));
+    // brilirs doesn't support speculation, so only the converted program,
+    // which runs the speculated code directly, is interpreted.
+    assert_eq!(
+        Optimizer::interp(&rvsdg_to_bril(&roundtrip).unwrap(), vec![], None),
+        "2\n"
+    );
+
+    // A failing guard would have to roll back and jump to its label, which
+    // isn't modeled, so guards that may fail are rejected in both directions.
+    let fallible = PROGRAM.replace("const true", "lt one two");
+    let err = cfg_to_rvsdg(&program_to_cfg(&parse_from_string(&fallible)))
+        .err()
+        .unwrap();
+    assert!(
+        matches!(
+            &err,
+            EggCCError::RvsdgError(RvsdgError::FallibleGuard { func, .. })
+                if func == "main"
+        ),
+        "unexpected error: {err}"
+    );
+
+    let mut f = RvsdgBuilder::default();
+    let zero = f.lit_int(0);
+    let ok = f.lt(zero, Operand::Arg(0));
+    let state = f.guard(ok, Operand::Arg(1));
+    let f = f.into_function(&[Type::Int], &[], state);
+    assert!(matches!(
+        rvsdg_to_cfg(&f),
+        Err(RvsdgError::FallibleGuard { func, pos: None }) if func == "main"
+    ));
+}
+
+#[test]
+fn rvsdg_void_returns() {
+    // Returning early from a function with no return type is fine.
+    const PROGRAM: &str = r#"
+    @main(c: bool) {
+        br c .early .late;
+    .early:
+        ret;
+    .late:
+        one: int = const 1;
+        print one;
+        ret;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    assert!(rvsdg.functions[0].results.is_empty());
+    rvsdg_to_cfg_roundtrip(PROGRAM, &["true"]);
+    rvsdg_to_cfg_roundtrip(PROGRAM, &["false"]);
+}
+
+#[test]
+fn rvsdg_early_value_return() {
+    // The branch that returns early produces the result and state directly,
+    // while the other branch continues on to the final `ret`.
+    const PROGRAM: &str = r#"
+    @f(c: bool, x: int): int {
+        br c .ret_early .continue;
+    .ret_early:
+        ret x;
+    .continue:
+        doubled: int = add x x;
+        print doubled;
+        ret doubled;
+    }
+    @main(c: bool) {
+        five: int = const 5;
+        res: int = call @f c five;
+        print res;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    for func in &rvsdg.functions {
+        assert_eq!(validate(func), Ok(()));
+    }
+    assert_eq!(rvsdg.functions[0].results.len(), 1);
+    rvsdg_to_cfg_roundtrip(PROGRAM, &["true"]);
+    rvsdg_to_cfg_roundtrip(PROGRAM, &["false"]);
+}
+
+#[test]
+fn rvsdg_return_from_loop() {
+    // Returns the first `i` whose square is `target`, or -1 if there is none
+    // below `n`. Restructuring turns the two ways out of the loop into one
+    // exit followed by a branch on which way was taken, and the predicate for
+    // that branch is only assigned on the way out of the loop.
+    const PROGRAM: &str = r#"
+    @main(n: int, target: int): int {
+        i: int = const 0;
+        one: int = const 1;
+    .loop:
+        sq: int = mul i i;
+        found: bool = eq sq target;
+        br found .found .next;
+    .next:
+        i: int = add i one;
+        more: bool = lt i n;
+        br more .loop .done;
+    .found:
+        ret i;
+    .done:
+        none: int = const -1;
+        ret none;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let func = &rvsdg.functions[0];
+    assert_eq!(validate(func), Ok(()));
+    assert!(search_for(func, |body| matches!(
+        body,
+        RvsdgBody::Theta { .. }
+    )));
+
+    let roundtrip = rvsdg_to_bril(func).unwrap();
+    for (n, target, index) in [(5, 9, 3), (5, 16, 4), (5, 25, -1), (5, 7, -1), (0, 0, 0)] {
+        let args = [Literal::Int(n), Literal::Int(target)];
+        let expected = (String::new(), Some(Literal::Int(index)));
+        assert_eq!(Optimizer::interp_main(&prog, &args).unwrap(), expected);
+        assert_eq!(Optimizer::interp_main(&roundtrip, &args).unwrap(), expected);
+    }
+}
+
+#[test]
+fn rvsdg_inconsistent_returns_error() {
+    const MISSING_VALUE: &str = r#"
+    @f(c: bool): int {
+        br c .value .none;
+    .value:
+        one: int = const 1;
+        ret one;
+    .none:
+        ret;
+    }
+    "#;
+    let prog = parse_from_string(MISSING_VALUE);
+    let err = cfg_to_rvsdg(&program_to_cfg(&prog)).err().unwrap();
+    assert!(
+        matches!(
+            &err,
+            EggCCError::RvsdgError(RvsdgError::MissingReturnValue { func, block, .. })
+                if func == "f" && block == "none"
+        ),
+        "unexpected error: {err}"
+    );
+
+    const UNEXPECTED_VALUE: &str = r#"
+    @f(c: bool) {
+        br c .value .none;
+    .value:
+        one: int = const 1;
+        ret one;
+    .none:
+        ret;
+    }
+    "#;
+    let prog = parse_from_string(UNEXPECTED_VALUE);
+    let err = cfg_to_rvsdg(&program_to_cfg(&prog)).err().unwrap();
+    assert!(
+        matches!(
+            &err,
+            EggCCError::RvsdgError(RvsdgError::UnexpectedReturnValue { func, block, .. })
+                if func == "f" && block == "value"
+        ),
+        "unexpected error: {err}"
+    );
+}
+
+#[test]
+fn rvsdg_unknown_function_error() {
+    const EFFECT_CALL: &str = r#"
+    @main() {
+        call @missing;
+    }
+    "#;
+    const VALUE_CALL: &str = r#"
+    @main() {
+        x: int = call @missing;
+        print x;
+    }
+    "#;
+    for program in [EFFECT_CALL, VALUE_CALL] {
+        let prog = parse_from_string(program);
+        let err = cfg_to_rvsdg(&program_to_cfg(&prog)).err().unwrap();
+        assert!(
+            matches!(
+                &err,
+                EggCCError::RvsdgError(RvsdgError::UnknownFunction { func, .. }) if func == "missing"
+            ),
+            "unexpected error: {err}"
+        );
+    }
+}
+
+#[test]
+fn rvsdg_bool_ops() {
+    const PROGRAM: &str = r#"
+    @main(a: bool, b: bool): bool {
+        c: bool = and a b;
+        d: bool = not c;
+        e: bool = or d a;
+        ret e;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let actual = &rvsdg.functions[0];
+
+    let mut expected = RvsdgBuilder::default();
+    let c = expected.and(Operand::Arg(0), Operand::Arg(1));
+    let d = expected.not(c);
+    let e = expected.or(d, Operand::Arg(0));
+    let expected = expected.into_pure_function(&[Type::Bool, Type::Bool], &[e]);
+    assert!(expected.structurally_eq(actual));
+
+    let actual = RvsdgFunction::egglog_expr_to_function(
+        &actual.to_egglog_expr(),
+        &actual.name,
+        &actual.args,
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_comparisons() {
+    const PROGRAM: &str = r#"
+    @main(a: int, b: int): bool {
+        c1: bool = eq a b;
+        c2: bool = lt a b;
+        c3: bool = gt a b;
+        c4: bool = le a b;
+        c5: bool = ge a b;
+        r1: bool = and c1 c2;
+        r2: bool = and c3 c4;
+        r3: bool = or r1 r2;
+        r4: bool = or r3 c5;
+        ret r4;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let actual = &rvsdg.functions[0];
+
+    let build = |ops: [ValueOps; 5]| {
+        let mut f = RvsdgBuilder::default();
+        let [c1, c2, c3, c4, c5] = ops.map(|op| f.cmp(op, Operand::Arg(0), Operand::Arg(1)));
+        let r1 = f.and(c1, c2);
+        let r2 = f.and(c3, c4);
+        let r3 = f.or(r1, r2);
+        let r4 = f.or(r3, c5);
+        f.into_pure_function(&[Type::Int, Type::Int], &[r4])
+    };
+    let mut expected = RvsdgBuilder::default();
+    let c1 = expected.eq(Operand::Arg(0), Operand::Arg(1));
+    let c2 = expected.lt(Operand::Arg(0), Operand::Arg(1));
+    let c3 = expected.gt(Operand::Arg(0), Operand::Arg(1));
+    let c4 = expected.le(Operand::Arg(0), Operand::Arg(1));
+    let c5 = expected.ge(Operand::Arg(0), Operand::Arg(1));
+    let r1 = expected.and(c1, c2);
+    let r2 = expected.and(c3, c4);
+    let r3 = expected.or(r1, r2);
+    let r4 = expected.or(r3, c5);
+    let expected = expected.into_pure_function(&[Type::Int, Type::Int], &[r4]);
+    assert!(expected.structurally_eq(actual));
+    // Every comparison is distinct from the others.
+    assert!(!build([
+        ValueOps::Eq,
+        ValueOps::Lt,
+        ValueOps::Gt,
+        ValueOps::Ge,
+        ValueOps::Le
+    ])
+    .structurally_eq(actual));
+    assert!(!build([
+        ValueOps::Eq,
+        ValueOps::Gt,
+        ValueOps::Lt,
+        ValueOps::Le,
+        ValueOps::Ge
+    ])
+    .structurally_eq(actual));
+
+    let roundtrip = RvsdgFunction::egglog_expr_to_function(
+        &actual.to_egglog_expr(),
+        &actual.name,
+        &actual.args,
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&roundtrip));
+}
+
+#[test]
+fn rvsdg_bool_simplify() {
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .parse_and_run_program(&format!(
+            r#"
+    (let const-true (Node (PureOp (Const (BoolT) (const) (Bool 1)))))
+    (let const-false (Node (PureOp (Const (BoolT) (const) (Bool 0)))))
+    (let and-true (Project 0 (PureOp (and (BoolT) (Arg 0) const-true))))
+    (let or-false (Node (PureOp (or (BoolT) const-false (Arg 0)))))
+    (let not-not (Node (PureOp (not (BoolT) (Node (PureOp (not (BoolT) (Arg 0))))))))
+    (run {NORMALIZE_RULESET} 1)
+    (run {BOOL_SIMPLIFY_RULESET} 10)
+    (check (= and-true (Arg 0)))
+    (check (= or-false (Arg 0)))
+    (check (= not-not (Arg 0)))
+    "#
+        ))
+        .unwrap();
+}
+
+#[test]
+fn rvsdg_cmp_simplify() {
+    // `lt x x` guards a gamma, as in `rvsdg_unstructured`. Once it folds to
+    // false the gamma picks its false branch.
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .parse_and_run_program(&format!(
+            r#"
+    (let pred (Node (PureOp (lt (BoolT) (Arg 0) (Arg 0)))))
+    (let seven (Node (PureOp (Const (IntT) (const) (Num 7)))))
+    (let gamma (Gamma pred (vec-of (Arg 1) (Arg 0) seven)
+                      (vec-of (VO (vec-of (Arg 0) (Arg 1)))
+                              (VO (vec-of (Arg 0) (Arg 2))))))
+    (let res (Project 1 gamma))
+    (let eq-self (eq (BoolT) (Arg 0) (Arg 0)))
+    (let lt (Node (PureOp (lt (BoolT) (Arg 0) (Arg 1)))))
+    (let not-not-lt (Node (PureOp (not (BoolT) (Node (PureOp (not (BoolT) lt)))))))
+    (run {CMP_SIMPLIFY_RULESET} 10)
+    (run {GAMMA_SIMPLIFY_RULESET} 10)
+    (check (= pred (Node (PureOp (Const (BoolT) (const) (Bool 0))))))
+    (check (= res (Arg 0)))
+    (check (= eq-self (Const (BoolT) (const) (Bool 1))))
+    (check (= not-not-lt lt))
+    "#
+        ))
+        .unwrap();
+}
+
+#[test]
+fn rvsdg_strength_reduce() {
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .parse_and_run_program(&format!(
+            r#"
+    (let zero (Node (PureOp (Const (IntT) (const) (Num 0)))))
+    (let one (Node (PureOp (Const (IntT) (const) (Num 1)))))
+    (let times-one (Node (PureOp (mul (IntT) one (Arg 0)))))
+    (let times-zero (mul (IntT) (Arg 0) zero))
+    (run {STRENGTH_REDUCE_RULESET} 10)
+    (check (= times-one (Arg 0)))
+    (check (= times-zero (Const (IntT) (const) (Num 0))))
+    "#
+        ))
+        .unwrap();
+
+    // x * 4 becomes x << 2, but only when the ruleset runs.
+    let mut prog = RvsdgBuilder::default();
+    let four = prog.lit_int(4);
+    let res = prog.mul(Operand::Arg(0), four, Type::Int);
+    let prog = RvsdgProgram {
+        functions: vec![prog.into_pure_function(&[Type::Int], &[res])],
+    };
+    let extract_value = |rulesets: &[&str]| {
+        let optimized = prog
+            .optimize_egglog_with(3, rulesets, &DefaultCostModel)
+            .unwrap();
+        // The state comes before the value.
+        optimized[0][1].to_string()
+    };
+    let reduced = extract_value(&[STRENGTH_REDUCE_RULESET]);
+    assert!(reduced.contains("shl"), "{reduced}");
+    assert!(reduced.contains("(Num 2)"), "{reduced}");
+    assert!(!reduced.contains("mul"), "{reduced}");
+    let unreduced = extract_value(&[]);
+    assert!(unreduced.contains("mul"), "{unreduced}");
+}
+
+#[test]
+fn rvsdg_algebraic_simplify() {
+    let extract_value = |prog: RvsdgBuilder, res: Operand, rulesets: &[&str]| {
+        let prog = RvsdgProgram {
+            functions: vec![prog.into_pure_function(&[Type::Int, Type::Int], &[res])],
+        };
+        let optimized = prog
+            .optimize_egglog_with(3, rulesets, &DefaultCostModel)
+            .unwrap();
+        optimized[0][1].to_string()
+    };
+
+    let mut prog = RvsdgBuilder::default();
+    let zero = prog.lit_int(0);
+    let res = prog.add(Operand::Arg(0), zero, Type::Int);
+    assert_eq!(
+        extract_value(prog, res, &[ALGEBRAIC_SIMPLIFY_RULESET]),
+        "(Arg 0)"
+    );
+
+    let mut prog = RvsdgBuilder::default();
+    let res = prog.sub(Operand::Arg(1), Operand::Arg(1), Type::Int);
+    assert_eq!(
+        extract_value(prog, res, &[ALGEBRAIC_SIMPLIFY_RULESET]),
+        "(Node (PureOp (Const (IntT) (const) (Num 0))))"
+    );
+
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .parse_and_run_program(&format!(
+            r#"
+    (let sum (add (IntT) (Arg 0) (Arg 1)))
+    (run {COMMUTE_RULESET} 1)
+    (check (= sum (add (IntT) (Arg 1) (Arg 0))))
+    "#
+        ))
+        .unwrap();
+}
+
+#[test]
+fn rvsdg_reassociate() {
+    // a + 1 + 2 folds to a + 3, but only when reassociated.
+    let mut prog = RvsdgBuilder::default();
+    let one = prog.lit_int(1);
+    let two = prog.lit_int(2);
+    let sum = prog.add(Operand::Arg(0), one, Type::Int);
+    let res = prog.add(sum, two, Type::Int);
+    let prog = RvsdgProgram {
+        functions: vec![prog.into_pure_function(&[Type::Int], &[res])],
+    };
+    let extract_value = |rulesets: &[&str]| {
+        let optimized = prog
+            .optimize_egglog_with(3, rulesets, &DefaultCostModel)
+            .unwrap();
+        optimized[0][1].to_string()
+    };
+    let folded = extract_value(&[REASSOCIATE_RULESET, CONST_FOLD_RULESET]);
+    assert!(folded.contains("(Num 3)"), "{folded}");
+    assert_eq!(folded.matches("add").count(), 1, "{folded}");
+    let unfolded = extract_value(&[CONST_FOLD_RULESET]);
+    assert!(!unfolded.contains("(Num 3)"), "{unfolded}");
+
+    // Multiplication reassociates too, in either direction.
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .parse_and_run_program(&format!(
+            r#"
+    (let ab (Node (PureOp (mul (IntT) (Arg 0) (Arg 1)))))
+    (let bc (Node (PureOp (mul (IntT) (Arg 1) (Arg 2)))))
+    (let left (mul (IntT) ab (Arg 2)))
+    (run {REASSOCIATE_RULESET} 1)
+    (check (= left (mul (IntT) (Arg 0) bc)))
+    "#
+        ))
+        .unwrap();
+}
+
+#[test]
+fn rvsdg_const_fold() {
+    const PROGRAM: &str = r#"
+    @sub() : int {
+        v0: int = const 1;
+        v1: int = const 2;
+        v2: int = add v0 v1;
+        ret v2;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let value = match rvsdg.functions[0].to_egglog_expr() {
+        EgglogFunctionResult::StateAndValue { value, .. } => value,
+        _ => panic!("expected state and value"),
+    };
+
+    // Comparisons fold to booleans, including through projections.
+    let mut lt = RvsdgBuilder::default();
+    let one = lt.lit_int(1);
+    let two = lt.lit_int(2);
+    let res = lt.lt(one, two);
+    let lt_value = match lt.into_pure_function(&[], &[res]).to_egglog_expr() {
+        EgglogFunctionResult::StateAndValue { value, .. } => value,
+        _ => panic!("expected state and value"),
+    };
+
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .process_commands(
+            vec![
+                egglog::ast::Command::Action(egglog::ast::Action::Let(
+                    "actual-result".into(),
+                    value,
+                )),
+                egglog::ast::Command::Action(egglog::ast::Action::Let(
+                    "lt-result".into(),
+                    lt_value,
+                )),
+            ],
+            egglog::CompilerPassStop::All,
+        )
+        .unwrap();
+    egraph
+        .parse_and_run_program(&format!(
+            r#"
+    (run {CONST_FOLD_RULESET} 10)
+    (check (= actual-result (Node (PureOp (Const (IntT) (const) (Num 3))))))
+    (check (= lt-result (Node (PureOp (Const (BoolT) (const) (Bool 1))))))
+    "#
+        ))
+        .unwrap();
+
+    // Extraction picks the folded constant.
+    let mut expected = RvsdgBuilder::default();
+    let three = expected.lit_int(3);
+    let expected = expected.into_pure_function(&[], &[three]);
+    let optimized = rvsdg.optimize_egglog(10).unwrap();
+    let actual = RvsdgFunction::egglog_expr_to_function(
+        &EgglogFunctionResult::StateAndValue {
+            state: optimized[0][0].clone(),
+            value: optimized[0][1].clone(),
+        },
+        "sub",
+        &[],
+    )
+    .unwrap();
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_assert_egraph_equivalent() {
+    let mut sum = RvsdgBuilder::default();
+    let one = sum.lit_int(1);
+    let two = sum.lit_int(2);
+    let res = sum.add(one, two, Type::Int);
+    let sum = sum.into_pure_function(&[], &[res]);
+
+    let constant = |n| {
+        let mut f = RvsdgBuilder::default();
+        let res = f.lit_int(n);
+        f.into_pure_function(&[], &[res])
+    };
+    assert!(!sum.structurally_eq(&constant(3)));
+    assert!(assert_egraph_equivalent(&sum, &constant(3)));
+    assert!(assert_egraph_equivalent(&constant(3), &sum));
+    assert!(!assert_egraph_equivalent(&sum, &constant(4)));
+}
+
+#[test]
+fn rvsdg_unsigned_cmp() {
+    let compare = |unsigned| {
+        let mut f = RvsdgBuilder::default();
+        let res = if unsigned {
+            f.unsigned_cmp(ValueOps::Lt, Operand::Arg(0), Operand::Arg(1))
+        } else {
+            f.lt(Operand::Arg(0), Operand::Arg(1))
+        };
+        f.into_pure_function(&[Type::Int, Type::Int], &[res])
+    };
+    let (signed, unsigned) = (compare(false), compare(true));
+    assert!(!signed.structurally_eq(&unsigned));
+    assert!(!assert_egraph_equivalent(&signed, &unsigned));
+    let decoded = RvsdgFunction::egglog_expr_to_function(
+        &unsigned.to_egglog_expr(),
+        &unsigned.name,
+        &unsigned.args,
+    )
+    .unwrap();
+    assert!(decoded.structurally_eq(&unsigned));
+
+    // Constant folding only knows about signed comparisons.
+    let constant = |unsigned| {
+        let mut f = RvsdgBuilder::default();
+        let (neg, pos) = (f.lit_int(-1), f.lit_int(1));
+        let res = if unsigned {
+            f.unsigned_cmp(ValueOps::Lt, neg, pos)
+        } else {
+            f.lt(neg, pos)
+        };
+        f.into_pure_function(&[], &[res])
+    };
+    let yes = {
+        let mut f = RvsdgBuilder::default();
+        let res = f.lit_bool(true);
+        f.into_pure_function(&[], &[res])
+    };
+    assert!(assert_egraph_equivalent(&constant(false), &yes));
+    assert!(!assert_egraph_equivalent(&constant(true), &yes));
+
+    // Bril only has signed comparisons, so unsigned ones are lowered to them.
+    let mut f = RvsdgBuilder::default();
+    let signed = f.lt(Operand::Arg(0), Operand::Arg(1));
+    let unsigned = f.unsigned_cmp(ValueOps::Lt, Operand::Arg(0), Operand::Arg(1));
+    let state = f.print(signed, Operand::Arg(2));
+    let state = f.print(unsigned, state);
+    let func = f.into_function(&[Type::Int, Type::Int], &[], state);
+    let prog = rvsdg_to_bril(&func).unwrap();
+    let run = |a: &str, b: &str| Optimizer::interp(&prog, vec![a.into(), b.into()], None);
+    assert_eq!(run("-1", "1"), "true\nfalse\n");
+    assert_eq!(run("1", "-1"), "false\ntrue\n");
+    assert_eq!(run("1", "2"), "true\ntrue\n");
+}
+
+#[test]
+fn rvsdg_bitwise() {
+    // (x << 3) & y
+    let mut f = RvsdgBuilder::default();
+    let three = f.lit_int(3);
+    let shifted = f.shl(Operand::Arg(0), three);
+    let res = f.bitand(shifted, Operand::Arg(1));
+    let func = f.into_pure_function(&[Type::Int, Type::Int], &[res]);
+    let decoded =
+        RvsdgFunction::egglog_expr_to_function(&func.to_egglog_expr(), &func.name, &func.args)
+            .unwrap();
+    assert!(decoded.structurally_eq(&func));
+    let prog = rvsdg_to_bril(&decoded).unwrap();
+    for (x, y) in [(5, -1), (-7, 0b1010_1000), (i64::MAX, i64::MIN)] {
+        assert_eq!(
+            Optimizer::interp_main(&prog, &[Literal::Int(x), Literal::Int(y)]).unwrap(),
+            (String::new(), Some(Literal::Int((x << 3) & y)))
+        );
+    }
+
+    // Bril has no bitwise operations, so they are emulated when converting
+    // back to it, including for negative numbers and out of range shifts.
+    let mut f = RvsdgBuilder::default();
+    let ops = [BitOp::Shl, BitOp::Shr, BitOp::And, BitOp::Or, BitOp::Xor];
+    let mut state = Operand::Arg(2);
+    for op in ops {
+        let res = f.bitwise(op, Operand::Arg(0), Operand::Arg(1));
+        state = f.print(res, state);
+    }
+    let prog = rvsdg_to_bril(&f.into_function(&[Type::Int, Type::Int], &[], state)).unwrap();
+    for (x, y) in [(6, 3), (-7, 2), (-1, 70), (12345, -2), (i64::MIN, 63)] {
+        let expected = String::from_iter(ops.map(|op| format!("{}\n", op.eval(x, y))));
+        assert_eq!(
+            Optimizer::interp(&prog, vec![x.to_string(), y.to_string()], None),
+            expected,
+            "{x} {y}"
+        );
+    }
+
+    // Bitwise operations fold on integers, but aren't mistaken for the
+    // boolean operations: `x & 1` is not `x`.
+    let constant = |op| {
+        let mut f = RvsdgBuilder::default();
+        let (six, three) = (f.lit_int(6), f.lit_int(3));
+        let res = f.bitwise(op, six, three);
+        f.into_pure_function(&[], &[res])
+    };
+    for (op, folded) in [
+        (BitOp::Shl, 48),
+        (BitOp::Shr, 0),
+        (BitOp::And, 2),
+        (BitOp::Or, 7),
+        (BitOp::Xor, 5),
+    ] {
+        let mut f = RvsdgBuilder::default();
+        let res = f.lit_int(folded);
+        let folded = f.into_pure_function(&[], &[res]);
+        assert!(assert_egraph_equivalent(&constant(op), &folded), "{op:?}");
+    }
+    let mut f = RvsdgBuilder::default();
+    let one = f.lit_int(1);
+    let res = f.bitand(Operand::Arg(0), one);
+    let masked = f.into_pure_function(&[Type::Int], &[res]);
+    let identity = RvsdgBuilder::default().into_pure_function(&[Type::Int], &[Operand::Arg(0)]);
+    assert!(!assert_egraph_equivalent(&masked, &identity));
+}
+
+#[test]
+fn rvsdg_extract_cheapest() {
+    let mut f = RvsdgBuilder::default();
+    let one = f.lit_int(1);
+    let two = f.lit_int(2);
+    let res = f.add(one, two, Type::Int);
+    let func = f.into_pure_function(&[], &[res]);
+
+    let mut egraph = new_rvsdg_egraph();
+    for expr in func.to_egglog_expr().exprs() {
+        egraph.eval_expr(expr, None, true).unwrap();
+    }
+    egraph
+        .parse_and_run_program(&format!("(run {CONST_FOLD_RULESET} 10)"))
+        .unwrap();
+    // Both `(add 1 2)` and `(Const 3)` are in the egraph now, but the constant
+    // is cheaper.
+    let actual = extract_cheapest(&mut egraph, &func).unwrap();
+
+    let mut expected = RvsdgBuilder::default();
+    let three = expected.lit_int(3);
+    let expected = expected.into_pure_function(&[], &[three]);
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_extract_with_report() {
+    // `c ? (1 + 2) * x : x`, so that the cost of the branches counts too.
+    let mut f = RvsdgBuilder::default();
+    let one = f.lit_int(1);
+    let two = f.lit_int(2);
+    let sum = f.add(one, two, Type::Int);
+    let product = f.mul(sum, Operand::Arg(0), Type::Int);
+    let gamma = f.gamma(
+        Operand::Arg(1),
+        &[Operand::Arg(0)],
+        &[&[Operand::Arg(0)], &[product]],
+    );
+    let func = f.into_pure_function(&[Type::Int, Type::Bool], &[Operand::Id(gamma)]);
+    let model = DefaultCostModel;
+
+    let unfolded = cost_report(&func, &model);
+    assert_eq!(unfolded.total, 1 + 1 + 10 + 30);
+    assert_eq!(unfolded.by_op["Const"], 2);
+
+    let mut egraph = new_rvsdg_egraph_with_costs(&model).unwrap();
+    for expr in func.to_egglog_expr().exprs() {
+        egraph.eval_expr(expr, None, true).unwrap();
+    }
+    egraph
+        .parse_and_run_program(&format!("(run {CONST_FOLD_RULESET} 10)"))
+        .unwrap();
+    let (_, folded) = extract_with_report(&mut egraph, &func, &model).unwrap();
+    assert_eq!(folded.total, 1 + 30);
+    assert!(!folded.by_op.contains_key("add"));
+    assert!(folded.total < unfolded.total);
+}
+
+#[test]
+fn rvsdg_custom_rules() {
+    const DOUBLE_TO_ADD: &str = r#"
+    (ruleset double-to-add)
+    (rewrite (mul ty x (Node (PureOp (Const ty (const) (Num 2)))))
+             (add ty x x)
+             :ruleset double-to-add)
+    "#;
+    let mut egraph = new_rvsdg_egraph();
+    let rulesets = add_custom_rules(&mut egraph, &[DOUBLE_TO_ADD.to_string()]).unwrap();
+    assert_eq!(rulesets, vec!["double-to-add".to_string()]);
+    egraph
+        .parse_and_run_program(
+            r#"
+    (let doubled
+         (Node (PureOp (mul (IntT) (Arg 0) (Node (PureOp (Const (IntT) (const) (Num 2))))))))
+    (run double-to-add 1)
+    (check (= doubled (Node (PureOp (add (IntT) (Arg 0) (Arg 0))))))
+    "#,
+        )
+        .unwrap();
+
+    // Rules that don't parse, or that use constructors the schema doesn't
+    // have, are errors rather than panics.
+    for bad in ["(rewrite (mul ty x", "(rewrite (frobnicate x) x)"] {
+        let res = add_custom_rules(&mut new_rvsdg_egraph(), &[bad.to_string()]);
+        assert!(matches!(res, Err(EggCCError::InvalidRules(_))), "{bad}");
+    }
+}
+
+#[test]
+fn rvsdg_extract_shallow_state() {
+    // Two independent divisions, where the second is needlessly ordered after
+    // the first.
+    let mut f = RvsdgBuilder::default();
+    let first = f.div(Operand::Arg(0), Operand::Arg(1), Operand::Arg(3), Type::Int);
+    let second = f.div(
+        Operand::Arg(0),
+        Operand::Arg(2),
+        Operand::Project(1, first),
+        Type::Int,
+    );
+    let func = f.into_function(
+        &[Type::Int, Type::Int, Type::Int],
+        &[Operand::Project(0, second)],
+        Operand::Project(1, second),
+    );
+
+    // The result, and the second division straight off the incoming state. The
+    // latter divides by `(c * 1) * 1` so that it is more expensive as a term.
+    let deep = "(Project 0 (Effectful (div (IntT) (Arg 0) (Arg 2))
+                   (Project 1 (Effectful (div (IntT) (Arg 0) (Arg 1)) (Arg 3)))))";
+    let one = "(Node (PureOp (Const (IntT) (const) (Num 1))))";
+    let shallow = format!(
+        "(Project 0 (Effectful (div (IntT) (Arg 0)
+            (Node (PureOp (mul (IntT) (Node (PureOp (mul (IntT) (Arg 2) {one}))) {one}))))
+            (Arg 3)))"
+    );
+    let extract = |model: &dyn CostModel| {
+        let mut egraph = new_rvsdg_egraph_with_costs(model).unwrap();
+        for expr in func.to_egglog_expr().exprs() {
+            egraph.eval_expr(expr, None, true).unwrap();
+        }
+        egraph
+            .parse_and_run_program(&format!("(union {deep} {shallow})"))
+            .unwrap();
+        extract_cheapest(&mut egraph, &func).unwrap()
+    };
+    // The state edge that the division producing the result depends on.
+    let result_state = |func: &RvsdgFunction| {
+        let Operand::Project(0, id) = func.results[0] else {
+            panic!("expected a projection, got {:?}", func.results[0])
+        };
+        let RvsdgBody::BasicOp(Expr::EffectfulOp(ValueOps::Div, args, _)) = &func.nodes[id] else {
+            panic!("expected a division, got {:?}", func.nodes[id])
+        };
+        *args.last().unwrap()
+    };
+
+    let cheapest = extract(&DefaultCostModel);
+    assert_ne!(result_state(&cheapest), Operand::Arg(3));
+    let shallowest = extract(&StateDepthCostModel {
+        model: DefaultCostModel,
+        hop_cost: 100,
+    });
+    assert_eq!(result_state(&shallowest), Operand::Arg(3));
+}
+
+#[test]
+fn rvsdg_prune_dead_nodes() {
+    let build = |with_dead: bool| {
+        let mut f = RvsdgBuilder::default();
+        if with_dead {
+            f.lit_int(7);
+        }
+        let one = f.lit_int(1);
+        let x = f.add(Operand::Arg(0), one, Type::Int);
+        // `x` is shared between the result and the state edge.
+        let sq = f.mul(x, x, Type::Int);
+        let print = f.print(sq, Operand::Arg(1));
+        f.into_function(&[Type::Int], &[x], print)
+    };
+    let mut actual = build(true);
+    prune_dead_nodes(&mut actual);
+    assert_eq!(actual.nodes.len(), 4);
+    assert!(build(false).structurally_eq(&actual));
+    let adds = actual
+        .nodes
+        .iter()
+        .filter(|body| matches!(body, RvsdgBody::BasicOp(Expr::Op(ValueOps::Add, ..))))
+        .count();
+    assert_eq!(adds, 1);
+}
+
+#[test]
+fn rvsdg_prune_keeps_gamma_branches() {
+    let build = |with_dead: bool| {
+        let mut f = RvsdgBuilder::default();
+        if with_dead {
+            let dead = f.lit_int(5);
+            f.add(dead, dead, Type::Int);
+        }
+        let zero = f.lit_int(0);
+        let pred = f.lt(Operand::Arg(0), zero);
+        // Only reachable through the second branch of the gamma.
+        let neg = f.sub(zero, Operand::Arg(1), Type::Int);
+        let gamma = f.gamma(
+            pred,
+            &[Operand::Arg(1), Operand::Arg(0)],
+            &[&[Operand::Arg(0), Operand::Arg(1)], &[Operand::Arg(0), neg]],
+        );
+        f.into_function(
+            &[Type::Int],
+            &[Operand::Project(1, gamma)],
+            Operand::Project(0, gamma),
+        )
+    };
+    let mut actual = build(true);
+    prune_dead_nodes(&mut actual);
+    let expected = build(false);
+    assert_eq!(actual.nodes.len(), expected.nodes.len());
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_drop_unused_args() {
+    // `b` is never read. The branches of the gamma read their own second
+    // argument, which is `a`.
+    let build = |with_b: bool| {
+        let mut f = RvsdgBuilder::default();
+        let zero = f.lit_int(0);
+        let pred = f.lt(Operand::Arg(0), zero);
+        let neg = f.sub(zero, Operand::Arg(1), Type::Int);
+        let state = Operand::Arg(if with_b { 2 } else { 1 });
+        let gamma = f.gamma(
+            pred,
+            &[state, Operand::Arg(0)],
+            &[&[Operand::Arg(0), Operand::Arg(1)], &[Operand::Arg(0), neg]],
+        );
+        let args = if with_b {
+            vec![Type::Int, Type::Bool]
+        } else {
+            vec![Type::Int]
+        };
+        f.into_function(
+            &args,
+            &[Operand::Project(1, gamma)],
+            Operand::Project(0, gamma),
+        )
+    };
+    let mut actual = build(true);
+    assert_eq!(drop_unused_args(&mut actual), vec![1]);
+    assert_eq!(actual.args, vec![Type::Int]);
+    let RvsdgBody::Gamma { inputs, .. } = &actual.nodes[3] else {
+        panic!("expected a gamma")
+    };
+    assert_eq!(inputs, &[Operand::Arg(1), Operand::Arg(0)]);
+    assert!(build(false).structurally_eq(&actual));
+    assert_eq!(validate(&actual), Ok(()));
+
+    // Nothing is dropped once every argument is read.
+    assert_eq!(drop_unused_args(&mut actual), Vec::<usize>::new());
+}
+
+#[test]
+fn rvsdg_cse() {
+    let mut f = RvsdgBuilder::default();
+    let one = f.lit_int(1);
+    let a = f.add(Operand::Arg(0), one, Type::Int);
+    let one2 = f.lit_int(1);
+    let b = f.add(Operand::Arg(0), one2, Type::Int);
+    // Refer to `a` both by projection and by id.
+    let Operand::Project(0, a_id) = a else {
+        panic!("expected a projection")
+    };
+    let sq1 = f.mul(a, a, Type::Int);
+    let sq2 = f.mul(Operand::Id(a_id), Operand::Id(a_id), Type::Int);
+    let print1 = f.print(b, Operand::Arg(1));
+    let print2 = f.print(sq1, print1);
+    let print3 = f.print(sq2, print2);
+    let mut actual = f.into_function(&[Type::Int], &[], print3);
+    cse(&mut actual);
+
+    let count = |pred: fn(&RvsdgBody) -> bool| actual.nodes.iter().filter(|b| pred(b)).count();
+    assert_eq!(
+        count(|body| matches!(body, RvsdgBody::BasicOp(Expr::Const(..)))),
+        1
+    );
+    assert_eq!(
+        count(|body| matches!(body, RvsdgBody::BasicOp(Expr::Op(ValueOps::Add, ..)))),
+        1
+    );
+    assert_eq!(
+        count(|body| matches!(body, RvsdgBody::BasicOp(Expr::Op(ValueOps::Mul, ..)))),
+        1
+    );
+    // Prints thread state, so they are never merged.
+    assert_eq!(
+        count(|body| matches!(body, RvsdgBody::BasicOp(Expr::Print(..)))),
+        3
+    );
+
+    let mut expected = RvsdgBuilder::default();
+    let one = expected.lit_int(1);
+    let a = expected.add(Operand::Arg(0), one, Type::Int);
+    let sq = expected.mul(a, a, Type::Int);
+    let print1 = expected.print(a, Operand::Arg(1));
+    let print2 = expected.print(sq, print1);
+    let print3 = expected.print(sq, print2);
+    let expected = expected.into_function(&[Type::Int], &[], print3);
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_gvn() {
+    // `n * 2` is computed both before the loop and, on loop invariant
+    // variables, inside of it.
+    const PROGRAM: &str = r#"
+    @main(n: int) {
+        two: int = const 2;
+        i: int = const 0;
+        sum: int = mul n two;
+    .loop:
+        inner: int = mul n two;
+        sum: int = add sum inner;
+        one: int = const 1;
+        i: int = add i one;
+        cond: bool = lt i n;
+        br cond .loop .done;
+    .done:
+        print sum;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let muls = |func: &RvsdgFunction| {
+        func.nodes
+            .iter()
+            .filter(|body| matches!(body, RvsdgBody::BasicOp(Expr::Op(ValueOps::Mul, ..))))
+            .count()
+    };
+    let theta_inputs = |func: &RvsdgFunction| {
+        func.nodes.iter().find_map(|body| match body {
+            RvsdgBody::Theta { inputs, .. } => Some(inputs.len()),
+            _ => None,
+        })
+    };
+
+    // Inside the loop, `n` and `two` are arguments of the theta, so the two
+    // multiplications look different to CSE.
+    let mut with_cse = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap().functions;
+    cse(&mut with_cse[0]);
+    assert_eq!(muls(&with_cse[0]), 2);
+
+    // GVN sees that they are equal, and passes the product computed outside
+    // of the loop into it instead.
+    let mut with_gvn = rvsdg.functions;
+    let before = theta_inputs(&with_gvn[0]).unwrap();
+    gvn(&mut with_gvn[0]);
+    assert_eq!(muls(&with_gvn[0]), 1);
+    assert_eq!(theta_inputs(&with_gvn[0]), Some(before + 1));
+
+    let optimized = RvsdgProgram {
+        functions: with_gvn,
+    };
+    assert_eq!(
+        Optimizer::interp(&prog, vec!["3".to_string()], None),
+        Optimizer::interp(&optimized.to_bril().unwrap(), vec!["3".to_string()], None)
+    );
+}
+
+#[test]
+fn rvsdg_simplify_constant_gamma() {
+    // Like `rvsdg_state_gamma`, but with a literal predicate.
+    let mut f = RvsdgBuilder::default();
+    let c = f.lit_bool(true);
+    let some_func = f.void_function("some_func", &[Operand::Arg(0)]);
+    let other_func = f.void_function("other_func", &[Operand::Arg(0)]);
+    let gamma = f.gamma(c, &[Operand::Arg(0)], &[&[other_func], &[some_func]]);
+    let mut actual = f.into_function(&[], &[], Operand::Project(0, gamma));
+    simplify_constant_gammas(&mut actual);
+
+    assert!(!actual
+        .nodes
+        .iter()
+        .any(|body| matches!(body, RvsdgBody::Gamma { .. })));
+    let mut expected = RvsdgBuilder::default();
+    let some_func = expected.void_function("some_func", &[Operand::Arg(0)]);
+    let expected = expected.into_function(&[], &[], some_func);
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_simplify_constant_gamma_substitutes_inputs() {
+    let mut f = RvsdgBuilder::default();
+    let c = f.lit_bool(false);
+    let zero = f.lit_int(0);
+    let one = f.lit_int(1);
+    let double = f.add(Operand::Arg(0), Operand::Arg(0), Type::Int);
+    let neg = f.sub(zero, Operand::Arg(0), Type::Int);
+    let inc = f.add(Operand::Arg(0), one, Type::Int);
+    let gamma = f.gamma(c, &[double], &[&[neg], &[inc]]);
+    let res = f.print(Operand::Project(0, gamma), Operand::Arg(1));
+    let mut actual = f.into_function(&[Type::Int], &[], res);
+    simplify_constant_gammas(&mut actual);
+
+    let mut expected = RvsdgBuilder::default();
+    let zero = expected.lit_int(0);
+    let double = expected.add(Operand::Arg(0), Operand::Arg(0), Type::Int);
+    let neg = expected.sub(zero, double, Type::Int);
+    let res = expected.print(neg, Operand::Arg(1));
+    let expected = expected.into_function(&[Type::Int], &[], res);
+    assert!(expected.structurally_eq(&actual));
+}
+
+#[test]
+fn rvsdg_simplify_constant_gamma_egglog() {
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .parse_and_run_program(&format!(
+            r#"
+    (let forwarded
+        (Project 0 (Gamma (Project 0 (PureOp (Const (BoolT) (const) (Bool 1))))
+                          (vec-of (Arg 0) (Arg 1))
+                          (vec-of (VO (vec-of (Arg 0))) (VO (vec-of (Arg 1)))))))
+    (run {NORMALIZE_RULESET} 1)
+    (run {GAMMA_SIMPLIFY_RULESET} 10)
+    (check (= forwarded (Arg 1)))
+    "#
+        ))
+        .unwrap();
+}
+
+#[test]
+fn rvsdg_simplify_known_gammas() {
+    // if c { if c { x } else { 7 } } else { x }
+    let build = |nested: bool| {
+        let mut f = RvsdgBuilder::default();
+        let then = if nested {
+            let seven = f.lit_int(7);
+            let inner = f.gamma(
+                Operand::Arg(1),
+                &[Operand::Arg(0), Operand::Arg(2)],
+                &[
+                    &[Operand::Arg(0), seven],
+                    &[Operand::Arg(0), Operand::Arg(1)],
+                ],
+            );
+            [Operand::Project(0, inner), Operand::Project(1, inner)]
+        } else {
+            [Operand::Arg(0), Operand::Arg(2)]
+        };
+        let outer = f.gamma(
+            Operand::Arg(0),
+            &[Operand::Arg(2), Operand::Arg(0), Operand::Arg(1)],
+            &[&[Operand::Arg(0), Operand::Arg(2)], &then],
+        );
+        f.into_function(
+            &[Type::Bool, Type::Int],
+            &[Operand::Project(1, outer)],
+            Operand::Project(0, outer),
+        )
+    };
+    let mut actual = build(true);
+    simplify_known_gammas(&mut actual);
+    assert!(build(false).structurally_eq(&actual));
+    assert_eq!(actual.stats().gammas, 1);
+
+    // A gamma on an unrelated predicate is left alone.
+    let mut f = RvsdgBuilder::default();
+    let inner = f.gamma(
+        Operand::Arg(0),
+        &[Operand::Arg(1)],
+        &[&[Operand::Arg(0)], &[Operand::Arg(0)]],
+    );
+    let outer = f.gamma(
+        Operand::Arg(0),
+        &[Operand::Arg(1), Operand::Arg(0)],
+        &[&[Operand::Arg(0)], &[Operand::Project(0, inner)]],
+    );
+    let mut unrelated =
+        f.into_pure_function(&[Type::Bool, Type::Bool], &[Operand::Project(0, outer)]);
+    simplify_known_gammas(&mut unrelated);
+    assert_eq!(unrelated.stats().gammas, 2);
+}
+
+#[test]
+fn rvsdg_simplify_bounded_gammas() {
+    // for (i = 0; i < 10; i++) { if i < len { print i } else { print -1 } }
+    let build = |len: Option<i64>| {
+        let mut f = RvsdgBuilder::default();
+        let zero = f.lit_int(0);
+        let one = f.lit_int(1);
+        let ten = f.lit_int(10);
+        let next = f.add(Operand::Arg(0), one, Type::Int);
+        let state = match len {
+            Some(len) => {
+                let len = f.lit_int(len);
+                let in_bounds = f.lt(Operand::Arg(0), len);
+                let minus_one = f.lit_int(-1);
+                let out_of_bounds = f.print(minus_one, Operand::Arg(1));
+                let ok = f.print(Operand::Arg(0), Operand::Arg(1));
+                let gamma = f.gamma(
+                    in_bounds,
+                    &[Operand::Arg(0), Operand::Arg(1)],
+                    &[&[out_of_bounds], &[ok]],
+                );
+                Operand::Project(0, gamma)
+            }
+            None => f.print(Operand::Arg(0), Operand::Arg(1)),
+        };
+        let pred = f.lt(next, ten);
+        let theta = f.theta(pred, &[zero, Operand::Arg(0)], &[next, state]);
+        f.into_function(&[], &[], Operand::Project(1, theta))
+    };
+    // The counter never gets past 9, so the check always passes.
+    let mut actual = build(Some(10));
+    simplify_bounded_gammas(&mut actual);
+    assert!(build(None).structurally_eq(&actual));
+    assert_eq!(actual.stats().gammas, 0);
+
+    // A check that fails partway through the loop is left alone.
+    let mut actual = build(Some(5));
+    simplify_bounded_gammas(&mut actual);
+    assert_eq!(actual.stats().gammas, 1);
+
+    // for (i = 0; i <= MAX; i++) { if i >= 0 { print i } else { print -1 } }
+    // wraps around to negative values, so the check is left alone.
+    let mut f = RvsdgBuilder::default();
+    let zero = f.lit_int(0);
+    let one = f.lit_int(1);
+    let max = f.lit_int(i64::MAX);
+    let next = f.add(Operand::Arg(0), one, Type::Int);
+    let non_negative = f.ge(Operand::Arg(0), zero);
+    let minus_one = f.lit_int(-1);
+    let negative = f.print(minus_one, Operand::Arg(1));
+    let ok = f.print(Operand::Arg(0), Operand::Arg(1));
+    let gamma = f.gamma(
+        non_negative,
+        &[Operand::Arg(0), Operand::Arg(1)],
+        &[&[negative], &[ok]],
+    );
+    let pred = f.le(next, max);
+    let theta = f.theta(
+        pred,
+        &[zero, Operand::Arg(0)],
+        &[next, Operand::Project(0, gamma)],
+    );
+    let mut wrapping = f.into_function(&[], &[], Operand::Project(1, theta));
+    simplify_bounded_gammas(&mut wrapping);
+    assert_eq!(wrapping.stats().gammas, 1);
+}
+
+#[test]
+fn rvsdg_remove_trivial_gammas() {
+    // Both branches compute `Arg(0) + Arg(1)`, but out of separate nodes.
+    let mut f = RvsdgBuilder::default();
+    let els = f.add(Operand::Arg(0), Operand::Arg(1), Type::Int);
+    let then = f.add(Operand::Arg(0), Operand::Arg(1), Type::Int);
+    let gamma = f.gamma(
+        Operand::Arg(0),
+        &[Operand::Arg(1), Operand::Arg(2)],
+        &[&[els], &[then]],
+    );
+    let mut actual = f.into_pure_function(
+        &[Type::Bool, Type::Int, Type::Int],
+        &[Operand::Project(0, gamma)],
+    );
+    remove_trivial_gammas(&mut actual);
+
+    let mut expected = RvsdgBuilder::default();
+    let sum = expected.add(Operand::Arg(1), Operand::Arg(2), Type::Int);
+    let expected = expected.into_pure_function(&[Type::Bool, Type::Int, Type::Int], &[sum]);
+    assert!(expected.structurally_eq(&actual));
+    assert_eq!(actual.stats().gammas, 0);
+}
+
+#[test]
+fn rvsdg_remove_trivial_gammas_egglog() {
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .parse_and_run_program(&format!(
+            r#"
+    (let forwarded
+        (Project 0 (Gamma (Arg 0)
+                          (vec-of (Arg 1) (Arg 2))
+                          (vec-of (VO (vec-of (Arg 1))) (VO (vec-of (Arg 1)))))))
+    (run {GAMMA_SIMPLIFY_RULESET} 10)
+    (check (= forwarded (Arg 2)))
+    "#
+        ))
+        .unwrap();
+}
+
+#[test]
+fn rvsdg_hoist_common_gamma_nodes() {
+    // Both branches compute `Arg(0) * 2`, out of separate nodes, but use it
+    // differently.
+    let mut f = RvsdgBuilder::default();
+    let two = f.lit_int(2);
+    let one = f.lit_int(1);
+    let doubled = f.mul(Operand::Arg(0), two, Type::Int);
+    let els = f.sub(doubled, one, Type::Int);
+    let two = f.lit_int(2);
+    let one = f.lit_int(1);
+    let doubled = f.mul(Operand::Arg(0), two, Type::Int);
+    let then = f.add(doubled, one, Type::Int);
+    let gamma = f.gamma(Operand::Arg(0), &[Operand::Arg(1)], &[&[els], &[then]]);
+    let mut actual = f.into_pure_function(&[Type::Bool, Type::Int], &[Operand::Project(0, gamma)]);
+    hoist_common_gamma_nodes(&mut actual);
+
+    // The multiply is computed once, before the gamma, and passed in.
+    let mut expected = RvsdgBuilder::default();
+    let two = expected.lit_int(2);
+    let doubled = expected.mul(Operand::Arg(1), two, Type::Int);
+    let one = expected.lit_int(1);
+    let els = expected.sub(Operand::Arg(1), one, Type::Int);
+    let then = expected.add(Operand::Arg(1), one, Type::Int);
+    let gamma = expected.gamma(
+        Operand::Arg(0),
+        &[Operand::Arg(1), doubled],
+        &[&[els], &[then]],
+    );
+    let expected =
+        expected.into_pure_function(&[Type::Bool, Type::Int], &[Operand::Project(0, gamma)]);
+    assert_eq!(diff(&expected, &actual), None);
+
+    // Without a shared computation, nothing changes.
+    let build = || {
+        let mut f = RvsdgBuilder::default();
+        let one = f.lit_int(1);
+        let els = f.sub(Operand::Arg(0), one, Type::Int);
+        let then = f.add(Operand::Arg(0), one, Type::Int);
+        let gamma = f.gamma(Operand::Arg(0), &[Operand::Arg(1)], &[&[els], &[then]]);
+        f.into_pure_function(&[Type::Bool, Type::Int], &[Operand::Project(0, gamma)])
+    };
+    let mut actual = build();
+    hoist_common_gamma_nodes(&mut actual);
+    assert_eq!(diff(&build(), &actual), None);
+
+    // In egglog, an output that both branches compute as `Arg(0) * 2` is
+    // computed from the corresponding input instead.
+    let mut egraph = new_rvsdg_egraph();
+    egraph
+        .parse_and_run_program(&format!(
+            r#"
+    (let two (Node (PureOp (Const (IntT) (const) (Num 2)))))
+    (let doubled (Node (PureOp (mul (IntT) (Arg 0) two))))
+    (let hoisted
+        (Project 0 (Gamma (Arg 0)
+                          (vec-of (Arg 1))
+                          (vec-of (VO (vec-of doubled)) (VO (vec-of doubled))))))
+    (run {GAMMA_SIMPLIFY_RULESET} 10)
+    (check (= hoisted (Node (PureOp (mul (IntT) (Arg 1) two)))))
+    "#
+        ))
+        .unwrap();
+}
+
+#[test]
+fn rvsdg_unroll_theta() {
+    // Sum the numbers from 1 to n. For n = 5 one iteration has to be peeled
+    // off before the unrolled loop, while for n = 6 none are.
+    for n in [5, 6] {
+        let program = format!(
+            r#"
+    @main {{
+        n: int = const {n};
+        i: int = const 1;
+        sum: int = const 0;
+        one: int = const 1;
+    .loop:
+        sum: int = add sum i;
+        i: int = add i one;
+        cond: bool = le i n;
+        br cond .loop .done;
+    .done:
+        print sum;
+    }}"#
+        );
+        let prog = parse_from_string(&program);
+        let lower = || {
+            let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+            rvsdg.functions.remove(0)
+        };
+        let mut unrolled = lower();
+        unroll_theta(&mut unrolled, 2);
+        assert!(!unrolled.structurally_eq(&lower()), "n = {n}");
+        assert_eq!(
+            unrolled
+                .nodes
+                .iter()
+                .filter(|node| matches!(node, RvsdgBody::Theta { .. }))
+                .count(),
+            1,
+            "n = {n}"
+        );
+        let expected = Optimizer::interp(&prog, vec![], None);
+        assert_eq!(expected, format!("{}\n", n * (n + 1) / 2));
+        assert_eq!(
+            Optimizer::interp(&rvsdg_to_bril(&unrolled).unwrap(), vec![], None),
+            expected,
+            "n = {n}"
+        );
+    }
+}
+
+#[test]
+fn rvsdg_remove_identity_thetas() {
+    // The body passes every variable through, so the loop is just its inputs.
+    let mut f = RvsdgBuilder::default();
+    let pred = f.lt(Operand::Arg(0), Operand::Arg(1));
+    let vars = [Operand::Arg(0), Operand::Arg(1), Operand::Arg(2)];
+    let theta = f.theta(pred, &vars, &vars);
+    let (x, y) = (Operand::Project(0, theta), Operand::Project(1, theta));
+    let sum = f.add(x, y, Type::Int);
+    let args = [Type::Int, Type::Int];
+    let mut actual = f.into_function(&args, &[sum], Operand::Project(2, theta));
+    remove_identity_thetas(&mut actual);
+
+    let mut expected = RvsdgBuilder::default();
+    let sum = expected.add(Operand::Arg(0), Operand::Arg(1), Type::Int);
+    let expected = expected.into_pure_function(&args, &[sum]);
+    assert_eq!(diff(&expected, &actual), None);
+    assert_eq!(actual.stats().thetas, 0);
+
+    // A loop whose predicate is false to begin with runs its body once.
+    let mut f = RvsdgBuilder::default();
+    let stop = f.lit_bool(false);
+    let one = f.lit_int(1);
+    let next = f.add(Operand::Arg(0), one, Type::Int);
+    let theta = f.theta(stop, &[Operand::Arg(0)], &[next]);
+    let mut actual = f.into_pure_function(&[Type::Int], &[Operand::Project(0, theta)]);
+    remove_identity_thetas(&mut actual);
+
+    let mut expected = RvsdgBuilder::default();
+    let one = expected.lit_int(1);
+    let next = expected.add(Operand::Arg(0), one, Type::Int);
+    let expected = expected.into_pure_function(&[Type::Int], &[next]);
+    assert_eq!(diff(&expected, &actual), None);
+
+    // Loops that go around more than once are left alone.
+    let mut f = RvsdgBuilder::default();
+    let one = f.lit_int(1);
+    let next = f.add(Operand::Arg(0), one, Type::Int);
+    let pred = f.lt(next, Operand::Arg(1));
+    let inputs = [Operand::Arg(0), Operand::Arg(1)];
+    let theta = f.theta(pred, &inputs, &[next, Operand::Arg(1)]);
+    let mut actual = f.into_pure_function(&args, &[Operand::Project(0, theta)]);
+    remove_identity_thetas(&mut actual);
+    assert_eq!(actual.stats().thetas, 1);
+}
+
+#[test]
+fn rvsdg_fuse_thetas() {
+    // Both loops count from 0 up to n, and neither uses the other's results.
+    const PROGRAM: &str = r#"
+    @main(n: int) {
+        zero: int = const 0;
+        one: int = const 1;
+        i: int = id zero;
+        sum: int = id zero;
+    .sum_loop:
+        sum: int = add sum i;
+        i: int = add i one;
+        more_sum: bool = lt i n;
+        br more_sum .sum_loop .between;
+    .between:
+        j: int = id zero;
+        squares: int = id zero;
+    .square_loop:
+        square: int = mul j j;
+        squares: int = add squares square;
+        j: int = add j one;
+        more_squares: bool = lt j n;
+        br more_squares .square_loop .done;
+    .done:
+        print sum;
+        print squares;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let mut fused = cfg_to_rvsdg(&program_to_cfg(&prog))
+        .unwrap()
+        .functions
+        .remove(0);
+    let count_thetas = |func: &RvsdgFunction| {
+        func.nodes
+            .iter()
+            .filter(|node| matches!(node, RvsdgBody::Theta { .. }))
+            .count()
+    };
+    assert_eq!(count_thetas(&fused), 2);
+    fuse_thetas(&mut fused);
+    assert_eq!(count_thetas(&fused), 1);
+    assert_eq!(validate(&fused), Ok(()));
+    for n in ["1", "4"] {
+        assert_eq!(
+            Optimizer::interp(&rvsdg_to_bril(&fused).unwrap(), vec![n.to_string()], None),
+            Optimizer::interp(&prog, vec![n.to_string()], None),
+            "n = {n}"
+        );
+    }
+}
+
+#[test]
+fn rvsdg_hoist_invariant_gammas() {
+    // `flag` never changes inside the loop, so which branch is taken is
+    // decided once, before the loop.
+    const PROGRAM: &str = r#"
+    @main(flag: bool) {
+        i: int = const 0;
+        sum: int = const 0;
+        one: int = const 1;
+        two: int = const 2;
+        n: int = const 4;
+    .loop:
+        br flag .double .single;
+    .double:
+        sum: int = add sum two;
+        jmp .next;
+    .single:
+        sum: int = add sum one;
+    .next:
+        i: int = add i one;
+        cond: bool = lt i n;
+        br cond .loop .done;
+    .done:
+        print sum;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let mut func = rvsdg.functions.remove(0);
+    let before = func.stats();
+    // Restructuring also puts the loop's condition in a gamma.
+    assert_eq!((before.thetas, before.gammas), (1, 2));
+
+    hoist_invariant_gammas(&mut func);
+    validate(&func).unwrap();
+    let after = func.stats();
+    // One gamma on `flag` picking between two loops, which only branch on
+    // their condition.
+    assert_eq!((after.thetas, after.gammas), (2, 3));
+    assert!(func.nodes.iter().any(|node| matches!(
+        node,
+        RvsdgBody::Gamma {
+            pred: Operand::Arg(0),
+            ..
+        }
+    )));
+
+    let hoisted = rvsdg_to_bril(&func).unwrap();
+    for flag in ["true", "false"] {
+        assert_eq!(
+            Optimizer::interp(&prog, vec![flag.to_string()], None),
+            Optimizer::interp(&hoisted, vec![flag.to_string()], None),
+            "flag = {flag}"
+        );
+    }
+}
+
+#[test]
+fn rvsdg_tailcall_to_loop() {
+    const PROGRAM: &str = r#"
+    @fact(n: int, acc: int): int {
+        one: int = const 1;
+        done: bool = le n one;
+        br done .base .rec;
+    .base:
+        ret acc;
+    .rec:
+        m: int = sub n one;
+        acc: int = mul acc n;
+        res: int = call @fact m acc;
+        ret res;
+    }
+    @main(n: int) {
+        one: int = const 1;
+        res: int = call @fact n one;
+        print res;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let is_self_call = |body: &RvsdgBody| matches!(body, RvsdgBody::BasicOp(Expr::Call(f, ..)) if f.to_string() == "fact");
+    assert!(search_for(&rvsdg.functions[0], is_self_call));
+    for func in &mut rvsdg.functions {
+        tailcall_to_loop(func);
+    }
+    assert!(!search_for(&rvsdg.functions[0], is_self_call));
+    assert!(search_for(&rvsdg.functions[0], |body| matches!(
+        body,
+        RvsdgBody::Theta { .. }
+    )));
+    // `main` doesn't call itself, so it is left alone.
+    assert!(search_for(&rvsdg.functions[1], |body| matches!(
+        body,
+        RvsdgBody::BasicOp(Expr::Call(..))
+    )));
+
+    let looped = Program {
+        functions: rvsdg
+            .functions
+            .iter()
+            .map(|func| rvsdg_to_cfg(func).unwrap().to_bril())
+            .collect(),
+        imports: vec![],
+    };
+    for n in ["1", "5"] {
+        assert_eq!(
+            Optimizer::interp(&prog, vec![n.to_string()], None),
+            Optimizer::interp(&looped, vec![n.to_string()], None)
+        );
+    }
+}
+
+#[test]
+fn rvsdg_resolve_calls() {
+    const PROGRAM: &str = r#"
+    @main(n: int) {
+        res: int = call @double n;
+        print res;
+    }
+    @double(x: int): int {
+        res: int = add x x;
+        ret res;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let program = ProgramRvsdg::from(cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap());
+    assert_eq!(program.index_of("main"), Some(0));
+    assert_eq!(program.index_of("double"), Some(1));
+    assert_eq!(program.index_of("triple"), None);
+
+    let calls = Vec::from_iter(
+        program.functions[0]
+            .nodes
+            .iter()
+            .filter_map(|body| match body {
+                RvsdgBody::BasicOp(expr @ Expr::Call(..)) => Some(expr),
+                _ => None,
+            }),
+    );
+    assert_eq!(calls.len(), 1);
+    assert_eq!(program.resolve_call(calls[0]), Some(1));
+    assert_eq!(program.callee(calls[0]).unwrap().name, "double");
+
+    // Other operations don't call anything.
+    let add = program.functions[1]
+        .nodes
+        .iter()
+        .find_map(|body| match body {
+            RvsdgBody::BasicOp(expr @ Expr::Op(ValueOps::Add, ..)) => Some(expr),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(program.resolve_call(add), None);
+}
+
+#[test]
+fn rvsdg_update_function() {
+    const PROGRAM: &str = r#"
+    @main(n: int) {
+        res: int = call @double n;
+        print res;
+    }
+    @double(x: int): int {
+        res: int = add x x;
+        ret res;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let mut program = ProgramRvsdg::from(cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap());
+    let original = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let main = &original.functions[0];
+
+    // Same signature, different body.
+    const DOUBLE: &str = r#"
+    @double(x: int): int {
+        two: int = const 2;
+        res: int = mul x two;
+        ret res;
+    }"#;
+    assert!(!program.update_function(&"double".into(), DOUBLE).unwrap());
+    assert!(program.functions[0].structurally_eq(main));
+    let expected = &cfg_to_rvsdg(&program_to_cfg(&parse_from_string(DOUBLE)))
+        .unwrap()
+        .functions[0];
+    assert!(program.functions[1].structurally_eq(expected));
+    let updated = Program {
+        functions: Vec::from_iter(
+            program
+                .functions
+                .iter()
+                .map(|func| rvsdg_to_cfg(func).unwrap().to_bril()),
+        ),
+        imports: vec![],
+    };
+    assert_eq!(
+        Optimizer::interp(&updated, vec!["3".to_string()], None),
+        "6\n"
+    );
+
+    // Changing the return type changes the signature.
+    const PRINT_DOUBLE: &str = r#"
+    @double(x: int) {
+        res: int = add x x;
+        print res;
+    }"#;
+    assert!(program
+        .update_function(&"double".into(), PRINT_DOUBLE)
+        .unwrap());
+    assert!(program.functions[0].structurally_eq(main));
+
+    // The source has to define the function being replaced.
+    assert!(matches!(
+        program.update_function(&"triple".into(), DOUBLE),
+        Err(EggCCError::RvsdgError(RvsdgError::UnknownFunction { .. }))
+    ));
+    assert!(matches!(
+        program.update_function(&"main".into(), DOUBLE),
+        Err(EggCCError::Parse(_))
+    ));
+}
+
+#[test]
+fn rvsdg_shift_args() {
+    // (a + b) and, in a gamma on `c`, either the sum or `b`.
+    let build = |shift: usize| {
+        let mut f = RvsdgBuilder::default();
+        let (a, b, c) = (
+            Operand::Arg(shift),
+            Operand::Arg(shift + 1),
+            Operand::Arg(shift + 2),
+        );
+        let sum = f.add(a, b, Type::Int);
+        let gamma = f.gamma(c, &[sum, b], &[&[Operand::Arg(1)], &[Operand::Arg(0)]]);
+        let args = [Type::Int, Type::Int, Type::Bool];
+        f.into_pure_function(&args, &[sum, Operand::Id(gamma)])
+    };
+    let mut func = build(0);
+    shift_args(&mut func.nodes, &mut func.results, 3);
+    assert!(func.structurally_eq(&build(3)));
+
+    // Arguments that are outputs of the region are shifted too.
+    let mut outputs = [Operand::Arg(0), Operand::Id(0)];
+    shift_args(&mut func.nodes, &mut outputs, 1);
+    assert_eq!(outputs, [Operand::Arg(1), Operand::Id(0)]);
+}
+
+#[test]
+fn rvsdg_inline_calls() {
+    const PROGRAM: &str = r#"
+    @double(x: int): int {
+        res: int = add x x;
+        ret res;
+    }
+    @main(n: int) {
+        res: int = call @double n;
+        print res;
+        res: int = call @double res;
+        print res;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let is_call = |body: &RvsdgBody| matches!(body, RvsdgBody::BasicOp(Expr::Call(..)));
+    assert!(search_for(&rvsdg.functions[1], is_call));
+
+    // `double` has a single node, which is too big for a threshold of one.
+    inline_calls(&mut rvsdg.functions, 1);
+    assert!(search_for(&rvsdg.functions[1], is_call));
+    inline_calls(&mut rvsdg.functions, 2);
+    assert!(!search_for(&rvsdg.functions[1], is_call));
+
+    let inlined = Program {
+        functions: rvsdg
+            .functions
+            .iter()
+            .map(|func| rvsdg_to_cfg(func).unwrap().to_bril())
+            .collect(),
+        imports: vec![],
+    };
+    assert_eq!(
+        Optimizer::interp(&prog, vec!["3".to_string()], None),
+        Optimizer::interp(&inlined, vec!["3".to_string()], None)
+    );
+}
+
+#[test]
+fn rvsdg_call_graph() {
+    // `is_even` and `is_odd` call each other, and `main` calls into the cycle
+    // without being part of it.
+    const PROGRAM: &str = r#"
+    @is_even(n: int): bool {
+        zero: int = const 0;
+        done: bool = eq n zero;
+        br done .yes .recur;
+    .yes:
+        t: bool = const true;
+        ret t;
+    .recur:
+        one: int = const 1;
+        m: int = sub n one;
+        res: bool = call @is_odd m;
+        ret res;
+    }
+    @is_odd(n: int): bool {
+        zero: int = const 0;
+        done: bool = eq n zero;
+        br done .no .recur;
+    .no:
+        f: bool = const false;
+        ret f;
+    .recur:
+        one: int = const 1;
+        m: int = sub n one;
+        res: bool = call @is_even m;
+        ret res;
+    }
+    @main(n: int) {
+        res: bool = call @is_even n;
+        print res;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let calls = call_graph(&rvsdg.functions);
+    assert!(calls.calls(0, 1) && calls.calls(1, 0) && calls.calls(2, 0));
+    assert!(!calls.calls(0, 2));
+    assert!(calls.sccs().contains(&vec![0, 1]));
+    assert_eq!(calls.scc_of(2), [2]);
+    assert!(calls.is_recursive(0) && calls.is_recursive(1));
+    assert!(!calls.is_recursive(2));
+    assert!(calls.in_cycle(0, 1));
+    assert!(!calls.in_cycle(2, 0));
+
+    // Only the call from `main` is inlined.
+    let is_call = |body: &RvsdgBody| matches!(body, RvsdgBody::BasicOp(Expr::Call(..)));
+    inline_calls(&mut rvsdg.functions, usize::MAX);
+    assert!(search_for(&rvsdg.functions[0], is_call));
+    assert!(search_for(&rvsdg.functions[1], is_call));
+    // `main` now calls `is_odd` from the inlined body of `is_even`.
+    assert!(call_graph(&rvsdg.functions).calls(2, 1));
+    let inlined = Program {
+        functions: rvsdg
+            .functions
+            .iter()
+            .map(|func| rvsdg_to_cfg(func).unwrap().to_bril())
+            .collect(),
+        imports: vec![],
+    };
+    assert_eq!(
+        Optimizer::interp(&prog, vec!["3".to_string()], None),
+        Optimizer::interp(&inlined, vec!["3".to_string()], None)
+    );
+}
+
+#[test]
+fn rvsdg_pure_calls() {
+    const PROGRAM: &str = r#"
+    @square(x: int): int {
+        res: int = mul x x;
+        ret res;
+    }
+    @noisy(x: int): int {
+        print x;
+        ret x;
+    }
+    @main(n: int) {
+        a: int = call @square n;
+        b: int = call @noisy n;
+        c: int = call @square n;
+        d: int = call @noisy n;
+        ab: int = add a b;
+        cd: int = add c d;
+        res: int = add ab cd;
+        print res;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let calls_to = |func: &RvsdgFunction, name: &str| {
+        Vec::from_iter(func.nodes.iter().filter_map(|body| match body {
+            RvsdgBody::BasicOp(Expr::Call(callee, ops, _, _, pure))
+                if callee.to_string() == name =>
+            {
+                Some((ops.len(), *pure))
+            }
+            _ => None,
+        }))
+    };
+
+    // Each call takes a different state edge, so none of them are merged.
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    cse(&mut rvsdg.functions[2]);
+    assert_eq!(calls_to(&rvsdg.functions[2], "square").len(), 2);
+
+    // Once `square` is known to be pure, its calls are taken off the state
+    // edge and merged, while the calls to `noisy` stay put.
+    rvsdg.mark_pure_calls(&["square".to_string()]);
+    cse(&mut rvsdg.functions[2]);
+    assert_eq!(calls_to(&rvsdg.functions[2], "square"), [(1, true)]);
+    assert_eq!(
+        calls_to(&rvsdg.functions[2], "noisy"),
+        [(2, false), (2, false)]
+    );
+    assert_eq!(
+        Optimizer::interp(&prog, vec!["3".to_string()], None),
+        Optimizer::interp(&rvsdg.to_bril().unwrap(), vec!["3".to_string()], None)
+    );
+}
+
+#[test]
+fn rvsdg_entry_function() {
+    // `main` is defined after the function it calls.
+    const PROGRAM: &str = r#"
+    @double(n: int): int {
+        res: int = add n n;
+        ret res;
+    }
+    @main(n: int) {
+        res: int = call @double n;
+        print res;
+    }
+    @start {
+        n: int = const 1;
+        call @main n;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let entry = |name: &str| entry_function(&rvsdg.functions, &[name.into()]);
+    assert!(matches!(entry("main"), Ok(1)));
+    assert!(matches!(entry("start"), Ok(2)));
+    assert!(matches!(
+        entry("entry"),
+        Err(RvsdgError::MissingEntryFunction { .. })
+    ));
+    assert!(matches!(
+        entry_function(&rvsdg.functions, &["main".into(), "start".into()]),
+        Err(RvsdgError::AmbiguousEntryFunction { funcs }) if funcs == ["main", "start"]
+    ));
+}
+
+#[test]
+fn rvsdg_egglog_lets() {
+    // A chain of diamonds: each sum adds the previous one to itself.
+    let doubling = |n| {
+        let mut f = RvsdgBuilder::default();
+        let mut x = Operand::Arg(0);
+        for _ in 0..n {
+            x = f.add(x, x, Type::Int);
+        }
+        f.into_pure_function(&[Type::Int], &[x])
+    };
+
+    // Every sum but the last is used twice, and bound once.
+    let (lets, res) = doubling(40).to_egglog_lets("f");
+    assert_eq!(lets.len(), 39);
+    let program = String::from_iter(
+        lets.iter()
+            .map(|(name, expr)| format!("(let {name} {expr})\n"))
+            .chain(res.exprs().iter().map(|expr| format!("{expr}\n"))),
+    );
+    for (name, _) in &lets {
+        assert_eq!(program.matches(&format!("(let {name} ")).count(), 1);
+        assert_eq!(program.matches(&format!("{name} ")).count(), 1);
+        assert_eq!(program.matches(&format!("{name})")).count(), 2);
+    }
+
+    // The bindings encode the same function as the closed expressions.
+    let func = doubling(4);
+    let (lets, res) = func.to_egglog_lets("f");
+    let mut program = String::from_iter(
+        lets.iter()
+            .map(|(name, expr)| format!("(let {name} {expr})\n")),
+    );
+    let closed = func.to_egglog_expr();
+    for (i, (l, r)) in res.exprs().iter().zip(closed.exprs()).enumerate() {
+        program.push_str(&format!("(let lets-{i} {l})\n(check (= lets-{i} {r}))\n"));
+    }
+    new_rvsdg_egraph().parse_and_run_program(&program).unwrap();
+}
+
+#[test]
+fn rvsdg_program_to_egglog() {
+    const PROGRAM: &str = r#"
+    @main(n: int) {
+        res: int = call @double n;
+        print res;
+    }
+    @double(x: int): int {
+        res: int = add x x;
+        ret res;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let program = program_to_egglog(&rvsdg.functions);
+    // `double` is bound first, so that `main` can apply it.
+    let double = program.find("(let lambda-double (Lambda 2 ").unwrap();
+    let main = program.find("(let lambda-main (Lambda 2 ").unwrap();
+    assert!(double < main);
+    assert!(program[main..].contains("(Apply (Node lambda-double) "));
+    new_rvsdg_egraph().parse_and_run_program(&program).unwrap();
+}
+
+#[test]
+fn rvsdg_propagate_call_constants() {
+    // Both calls pass 5 for `x`, but different values for `y`.
+    const PROGRAM: &str = r#"
+    @add(x: int, y: int): int {
+        res: int = add x y;
+        ret res;
+    }
+    @main(n: int) {
+        five: int = const 5;
+        a: int = call @add five n;
+        print a;
+        b: int = call @add five a;
+        print b;
+    }"#;
+    let prog = parse_from_string(PROGRAM);
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    propagate_call_constants(&mut rvsdg.functions);
+
+    let mut expected = RvsdgBuilder::default();
+    let five = expected.lit_int(5);
+    let sum = expected.add(five, Operand::Arg(1), Type::Int);
+    let expected = expected.into_pure_function(&[Type::Int, Type::Int], &[sum]);
+    assert!(expected.structurally_eq(&rvsdg.functions[0]));
+
+    let specialized = Program {
+        functions: rvsdg
+            .functions
+            .iter()
+            .map(|func| rvsdg_to_cfg(func).unwrap().to_bril())
+            .collect(),
+        imports: vec![],
+    };
+    assert_eq!(
+        Optimizer::interp(&prog, vec!["3".to_string()], None),
+        Optimizer::interp(&specialized, vec!["3".to_string()], None)
+    );
+
+    // Calls passing different constants, and recursive calls that pass `k`
+    // along from inside a branch, leave the parameters alone.
+    const UNCHANGED: &str = r#"
+    @add(x: int, y: int): int {
+        res: int = add x y;
+        ret res;
+    }
+    @sum(k: int, n: int): int {
+        zero: int = const 0;
+        done: bool = le n zero;
+        br done .base .recur;
+    .base:
+        ret k;
+    .recur:
+        one: int = const 1;
+        m: int = sub n one;
+        r: int = call @sum k m;
+        s: int = add r k;
+        ret s;
+    }
+    @main(n: int) {
+        five: int = const 5;
+        six: int = const 6;
+        a: int = call @add five n;
+        b: int = call @add six a;
+        c: int = call @sum five b;
+        print c;
+    }"#;
+    let lower = || cfg_to_rvsdg(&program_to_cfg(&parse_from_string(UNCHANGED))).unwrap();
+    let mut rvsdg = lower();
+    propagate_call_constants(&mut rvsdg.functions);
+    for (actual, expected) in rvsdg.functions.iter().zip(&lower().functions) {
+        assert!(actual.structurally_eq(expected), "{}", expected.name);
+    }
+}
+
+#[test]
+fn rvsdg_structurally_eq_project_zero_is_id() {
+    // `RvsdgBuilder` refers to nodes with `Project(0, _)`.
+    let mut projected = RvsdgBuilder::default();
+    let one = projected.lit_int(1);
+    let sum = projected.add(Operand::Arg(0), one, Type::Int);
+    let projected = projected.into_pure_function(&[Type::Int], &[sum]);
+
+    let by_id = |result: Operand| RvsdgFunction {
+        name: "main".into(),
+        args: vec![Type::Int],
+        return_type: Some(Type::Int),
+        nodes: vec![
+            RvsdgBody::BasicOp(Expr::Const(ConstOps::Const, Literal::Int(1), Type::Int)),
+            RvsdgBody::BasicOp(Expr::Op(
+                ValueOps::Add,
+                vec![Operand::Arg(0), Operand::Id(0)],
+                Type::Int,
+            )),
+        ],
+        results: vec![result],
+        state: Operand::Arg(1),
+    };
+    assert!(projected.structurally_eq(&by_id(Operand::Id(1))));
+    assert!(by_id(Operand::Id(1)).structurally_eq(&projected));
+    assert!(by_id(Operand::Project(0, 1)).structurally_eq(&by_id(Operand::Id(1))));
+    // Other projections are different outputs entirely.
+    assert!(!by_id(Operand::Project(1, 1)).structurally_eq(&by_id(Operand::Id(1))));
+    assert!(!by_id(Operand::Id(1)).structurally_eq(&by_id(Operand::Project(1, 1))));
+}
+
+#[test]
+fn rvsdg_hash_matches_structurally_eq() {
+    let hash = |f: &RvsdgFunction| {
+        let mut hasher = DefaultHasher::new();
+        f.hash(&mut hasher);
+        hasher.finish()
+    };
+    // A gamma choosing between the argument and 1. The second copy starts
+    // with a dead node, so its nodes are all at different indices, and it
+    // refers to the gamma by `Id` rather than `Project(0, _)`.
+    let build = |dead: bool, result: fn(Id) -> Operand| {
+        let mut f = RvsdgBuilder::default();
+        if dead {
+            f.lit_int(2);
+        }
+        let one = f.lit_int(1);
+        let lt = f.lt(Operand::Arg(0), one);
+        let gamma = f.gamma(
+            lt,
+            &[Operand::Arg(0), one],
+            &[&[Operand::Arg(1)], &[Operand::Arg(0)]],
+        );
+        f.into_pure_function(&[Type::Int], &[result(gamma)])
+    };
+    let projected = build(false, |id| Operand::Project(0, id));
+    let by_id = build(true, Operand::Id);
+    assert!(projected.structurally_eq(&by_id));
+    assert_eq!(projected, by_id);
+    assert_eq!(hash(&projected), hash(&by_id));
+
+    let mut cache = HashMap::new();
+    cache.insert(projected, "cached");
+    assert_eq!(cache.get(&by_id), Some(&"cached"));
+
+    // Any other output of the gamma is something else.
+    let other = build(false, |id| Operand::Project(1, id));
+    assert!(!cache.contains_key(&other));
+    assert_ne!(hash(&by_id), hash(&other));
+}
+
+#[test]
+fn rvsdg_normalize_operands() {
+    let build = || {
+        let mut f = RvsdgBuilder::default();
+        let one = f.lit_int(1);
+        let two = f.lit_int(2);
+        // `RvsdgBuilder` projects out of every node, so mix in some `Id`s.
+        let sum = f.add(one, Operand::Id(1), Type::Int);
+        let quot = f.div(sum, two, Operand::Arg(0), Type::Int);
+        let res = f.print(Operand::Project(0, quot), Operand::Project(1, quot));
+        f.into_function(&[], &[], res)
+    };
+    let mut func = build();
+    normalize_operands(&mut func);
+    assert!(func.structurally_eq(&build()));
+
+    let mut operands = vec![func.state];
+    func.nodes
+        .iter()
+        .for_each(|body| body.for_each_operand(|op| operands.push(*op)));
+    assert_eq!(
+        operands,
+        [
+            Operand::Id(4),
+            Operand::Id(0),
+            Operand::Id(1),
+            Operand::Id(2),
+            Operand::Id(1),
+            Operand::Arg(0),
+            Operand::Project(0, 3),
+            Operand::Project(1, 3),
+        ]
+    );
+}
+
+#[test]
+fn rvsdg_split_gamma_inputs() {
+    // @main(a: int, b: int) { print (a < b ? a + a : b * b); }
+    let build = || {
+        let mut builder = RvsdgBuilder::default();
+        let (a, b, state) = (Operand::Arg(0), Operand::Arg(1), Operand::Arg(2));
+        let pred = builder.lt(a, b);
+        let add = builder.add(Operand::Arg(1), Operand::Arg(1), Type::Int);
+        let mul = builder.mul(Operand::Arg(2), Operand::Arg(2), Type::Int);
+        let gamma = builder.gamma(
+            pred,
+            &[state, a, b],
+            &[&[Operand::Arg(0), mul], &[Operand::Arg(0), add]],
+        );
+        let state = builder.print(Operand::Project(1, gamma), Operand::Project(0, gamma));
+        (
+            builder.into_function(&[Type::Int, Type::Int], &[], state),
+            gamma,
+        )
+    };
+    let (original, _) = build();
+    let (mut func, gamma) = build();
+
+    // Each branch only takes the state edge and the one input it reads.
+    let branches = split_gamma_inputs(&mut func.nodes, gamma);
+    assert_eq!(branches.len(), 2);
+    assert_eq!(branches[0].inputs, vec![Operand::Arg(2), Operand::Arg(1)]);
+    assert_eq!(branches[1].inputs, vec![Operand::Arg(2), Operand::Arg(0)]);
+    for (branch, op) in branches.iter().zip([ValueOps::Mul, ValueOps::Add]) {
+        assert_eq!(branch.outputs[0], Operand::Arg(0));
+        let (Operand::Id(id) | Operand::Project(_, id)) = branch.outputs[1] else {
+            panic!("expected a node");
+        };
+        let RvsdgBody::BasicOp(Expr::Op(actual, args, _)) = &func.nodes[id] else {
+            panic!("expected an operation");
+        };
+        assert_eq!(
+            (*actual, args.as_slice()),
+            (op, &[Operand::Arg(1), Operand::Arg(1)][..])
+        );
+    }
+
+    // Merging the branches back together gives an equivalent gamma.
+    let RvsdgBody::Gamma { pred, .. } = func.nodes[gamma] else {
+        panic!("expected a gamma");
+    };
+    func.nodes[gamma] = merge_gamma_inputs(&mut func.nodes, pred, &branches);
+    assert_eq!(validate(&func), Ok(()));
+    for args in [["1", "2"], ["2", "1"]] {
+        let args = Vec::from_iter(args.iter().map(|arg| arg.to_string()));
+        assert_eq!(
+            Optimizer::interp(&rvsdg_to_bril(&original).unwrap(), args.clone(), None),
+            Optimizer::interp(&rvsdg_to_bril(&func).unwrap(), args, None)
+        );
+    }
+}
+
+#[test]
+fn rvsdg_simplify_pure_gamma_state() {
+    let build = |gamma_state: bool| {
+        let mut f = RvsdgBuilder::default();
+        let zero = f.lit_int(0);
+        let one = f.lit_int(1);
+        let c = f.lt(Operand::Arg(0), zero);
+        let neg = f.sub(zero, Operand::Arg(0), Type::Int);
+        let inc = f.add(Operand::Arg(0), one, Type::Int);
+        // The state is the first input, so it is `Arg(0)` in the branches.
+        let gamma = f.gamma(
+            c,
+            &[Operand::Arg(1), Operand::Arg(0)],
+            &[&[Operand::Arg(0), neg], &[Operand::Arg(0), inc]],
+        );
+        let state = if gamma_state {
+            Operand::Project(0, gamma)
+        } else {
+            Operand::Arg(1)
+        };
+        let res = f.print(Operand::Project(1, gamma), state);
+        f.into_function(&[Type::Int], &[], res)
+    };
+    let mut actual = build(true);
+    simplify_pure_gamma_state(&mut actual);
+    assert!(build(false).structurally_eq(&actual));
+
+    // The same, starting from Bril.
+    const PROGRAM: &str = r#"
+    @main(x: int) {
+        zero: int = const 0;
+        c: bool = lt x zero;
+        br c .N .P;
+    .N:
+        y: int = sub zero x;
+        jmp .J;
+    .P:
+        y: int = id x;
+        jmp .J;
+    .J:
+        print y;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let func = &mut rvsdg.functions[0];
+    simplify_pure_gamma_state(func);
+    let Operand::Id(print) = func.state else {
+        panic!("expected the state to come from a print")
+    };
+    let RvsdgBody::BasicOp(Expr::Print(args)) = &func.nodes[print] else {
+        panic!("expected the state to come from a print")
+    };
+    assert_eq!(args[1], Operand::Arg(1));
+}
+
+#[test]
+fn rvsdg_gammas_to_selects() {
+    // x = c ? a : b
+    const PROGRAM: &str = r#"
+    @main(c: bool, a: int, b: int): int {
+        br c .T .F;
+    .T:
+        x: int = id a;
+        jmp .J;
+    .F:
+        x: int = id b;
+        jmp .J;
+    .J:
+        ret x;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let mut rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let func = &mut rvsdg.functions[0];
+    assert!(search_for(func, |body| matches!(
+        body,
+        RvsdgBody::Gamma { .. }
+    )));
+    gammas_to_selects(func);
+
+    assert_eq!(func.nodes.len(), 1);
+    assert_eq!(func.results, vec![Operand::Id(0)]);
+    assert_eq!(func.state, Operand::Arg(3));
+    let RvsdgBody::BasicOp(Expr::Select(args, Type::Int)) = &func.nodes[0] else {
+        panic!("expected a select, got {:?}", func.nodes[0])
+    };
+    assert_eq!(
+        args,
+        &vec![Operand::Arg(0), Operand::Arg(1), Operand::Arg(2)]
+    );
+
+    // Selects become branches again when converting back to Bril.
+    let roundtrip = rvsdg_to_bril(func).unwrap();
+    for c in ["true", "false"] {
+        let args = Vec::from_iter([c, "1", "2"].map(String::from));
+        assert_eq!(
+            Optimizer::interp(&prog, args.clone(), None),
+            Optimizer::interp(&roundtrip, args, None)
+        );
+    }
+}
+
+#[test]
+fn rvsdg_validate() {
+    // Everything that lowering produces is well-formed.
+    const PROGRAM: &str = r#"
+    @main(n: int) {
+        i: int = const 0;
+        one: int = const 1;
+    .loop:
+        i: int = add i one;
+        c: bool = lt i n;
+        br c .odd .even;
+    .odd:
+        print i;
+    .even:
+        br c .loop .done;
+    .done:
+        print i;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    assert_eq!(validate(&rvsdg.functions[0]), Ok(()));
+
+    let mut f = RvsdgBuilder::default();
+    f.lit_int(1);
+    let f = f.into_pure_function(&[Type::Int], &[Operand::Id(1)]);
+    assert_eq!(
+        validate(&f),
+        Err(ValidationError::DanglingId { id: 1, n_nodes: 1 })
+    );
+
+    // The function has one argument, plus the state edge.
+    let f = RvsdgBuilder::default().into_pure_function(&[Type::Int], &[Operand::Arg(2)]);
+    assert_eq!(
+        validate(&f),
+        Err(ValidationError::ArgOutOfRange { arg: 2, n_args: 2 })
+    );
+
+    // Gamma branches only see the inputs of the gamma.
+    let mut f = RvsdgBuilder::default();
+    let c = f.lit_bool(true);
+    let gamma = f.gamma(
+        c,
+        &[Operand::Arg(0)],
+        &[&[Operand::Arg(0)], &[Operand::Arg(1)]],
+    );
+    let f = f.into_pure_function(&[Type::Int], &[Operand::Id(gamma)]);
+    assert_eq!(
+        validate(&f),
+        Err(ValidationError::ArgOutOfRange { arg: 1, n_args: 1 })
+    );
+
+    let mut f = RvsdgBuilder::default();
+    let c = f.lit_bool(true);
+    let gamma = f.gamma(
+        c,
+        &[Operand::Arg(0)],
+        &[&[Operand::Arg(0)], &[Operand::Arg(0), Operand::Arg(0)]],
+    );
+    let f = f.into_pure_function(&[Type::Int], &[Operand::Id(gamma)]);
+    assert_eq!(
+        validate(&f),
+        Err(ValidationError::GammaArity {
+            gamma,
+            branch: 1,
+            expected: 1,
+            actual: 2
+        })
+    );
+
+    let mut f = RvsdgBuilder::default();
+    let c = f.lit_bool(false);
+    let theta = f.theta(c, &[Operand::Arg(0)], &[Operand::Arg(0), Operand::Arg(0)]);
+    let f = f.into_pure_function(&[Type::Int], &[Operand::Id(theta)]);
+    assert_eq!(
+        validate(&f),
+        Err(ValidationError::ThetaArity {
+            theta,
+            inputs: 1,
+            outputs: 2
+        })
+    );
+
+    // Lambda bodies have their own heap and arguments.
+    let mut f = RvsdgBuilder::default();
+    let lambda = f.lambda(1, RvsdgBuilder::default(), &[Operand::Arg(2)]);
+    let apply = f.apply(lambda, &[Operand::Arg(0), Operand::Arg(1)]);
+    let f = f.into_function(
+        &[Type::Int],
+        &[Operand::Project(0, apply)],
+        Operand::Project(1, apply),
+    );
+    assert_eq!(
+        validate(&f),
+        Err(ValidationError::ArgOutOfRange { arg: 2, n_args: 2 })
+    );
+}
+
+/// Check that converting `program` to an RVSDG and back to a CFG preserves the
+/// output of running it on `args`.
+fn rvsdg_to_cfg_roundtrip(program: &str, args: &[&str]) {
+    let prog = parse_from_string(program);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let roundtrip = Program {
+        functions: rvsdg
+            .functions
+            .iter()
+            .map(|func| rvsdg_to_cfg(func).unwrap().to_bril())
+            .collect(),
+        imports: vec![],
+    };
+    let args = Vec::from_iter(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(
+        Optimizer::interp(&prog, args.clone(), None),
+        Optimizer::interp(&roundtrip, args, None)
+    );
+}
+
+#[test]
+fn rvsdg_to_cfg_branch_and_loop() {
+    // The odd-branch program from above, with a call and some output.
+    const PROGRAM: &str = r#"
+    @double(x: int): int {
+        res: int = add x x;
+        ret res;
+    }
+    @main(n: int) {
+        i: int = const 0;
+        sum: int = const 0;
+        one: int = const 1;
+    .loop:
+        sum: int = add sum i;
+        i: int = add i one;
+        done: bool = lt n i;
+        br done .exit .loop;
+    .exit:
+        five: int = const 5;
+        big: bool = lt five sum;
+        br big .double .print;
+    .double:
+        sum: int = call @double sum;
+    .print:
+        print sum;
+    }
+    "#;
+    rvsdg_to_cfg_roundtrip(PROGRAM, &["1"]);
+    rvsdg_to_cfg_roundtrip(PROGRAM, &["4"]);
+}
+
+#[test]
+fn rvsdg_to_cfg_nested_loops() {
+    const PROGRAM: &str = r#"
+    @main {
+        i: int = const 0;
+        one: int = const 1;
+        three: int = const 3;
+    .outer:
+        j: int = const 0;
+    .inner:
+        prod: int = mul i j;
+        print prod;
+        j: int = add j one;
+        more_j: bool = lt j three;
+        br more_j .inner .outer_tail;
+    .outer_tail:
+        i: int = add i one;
+        more_i: bool = lt i three;
+        br more_i .outer .done;
+    .done:
+        print i;
+    }
+    "#;
+    rvsdg_to_cfg_roundtrip(PROGRAM, &[]);
+}
+
+#[test]
+fn rvsdg_to_cfg_swapped_loop_vars() {
+    // Fibonacci: the loop outputs read the loop variables directly.
+    const PROGRAM: &str = r#"
+    @main(n: int) {
+        a: int = const 0;
+        b: int = const 1;
+        i: int = const 0;
+        one: int = const 1;
+    .loop:
+        t: int = add a b;
+        a: int = id b;
+        b: int = id t;
+        i: int = add i one;
+        more: bool = lt i n;
+        br more .loop .done;
+    .done:
+        print a;
+    }
+    "#;
+    rvsdg_to_cfg_roundtrip(PROGRAM, &["10"]);
+}
+
+#[test]
+fn rvsdg_to_cfg_unstructured() {
+    const PROGRAM: &str = r#"
+    @main {
+        x: int = const 4;
+        a_cond: bool = lt x x;
+        br a_cond .B .C;
+      .B:
+        a: int = const 1;
+        b_cond: bool = lt x a;
+        x: int = add x a;
+        br b_cond .C .D;
+      .C:
+        jmp .B;
+      .D:
+        print x;
+    }
+    "#;
+    rvsdg_to_cfg_roundtrip(PROGRAM, &[]);
+}
+
+#[test]
+fn rvsdg_to_cfg_memory() {
+    const PROGRAM: &str = r#"
+    @main {
+        one: int = const 1;
+        two: int = const 2;
+        p: ptr<int> = alloc two;
+        q: ptr<int> = ptradd p one;
+        store p one;
+        store q two;
+        x: int = load q;
+        store q one;
+        y: int = load q;
+        print x y;
+        free p;
+    }
+    "#;
+    rvsdg_to_cfg_roundtrip(PROGRAM, &[]);
+}
+
+#[test]
+fn rvsdg_multiway_branch() {
+    // A `switch` on x, compiled to a chain of comparisons: every case joins
+    // at .end.
+    const PROGRAM: &str = r#"
+    @main(x: int) {
+        zero: int = const 0;
+        one: int = const 1;
+        is_zero: bool = eq x zero;
+        br is_zero .A .rest;
+    .rest:
+        is_one: bool = eq x one;
+        br is_one .B .C;
+    .A:
+        ten: int = const 10;
+        print ten;
+        jmp .end;
+    .B:
+        print one;
+        jmp .end;
+    .C:
+        print x;
+        jmp .end;
+    .end:
+        print zero;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap();
+    let func = &rvsdg.functions[0];
+    assert_eq!(func.stats().gammas, 1);
+    assert!(search_for(func, |body| matches!(
+        body,
+        RvsdgBody::Gamma { outputs, .. } if outputs.len() == 3
+    )));
+    for x in ["0", "1", "2"] {
+        rvsdg_to_cfg_roundtrip(PROGRAM, &[x]);
+    }
+}
+
+#[test]
+fn rvsdg_to_cfg_multiway_gamma() {
+    // Gammas produced by restructuring can have more than two cases, selected
+    // by an integer predicate.
+    let build = |k: i64| {
+        let mut f = RvsdgBuilder::default();
+        let pred = f.lit_int(k);
+        let cases = Vec::from_iter((0..3).map(|i| {
+            let x = f.lit_int(10 * i);
+            f.print(x, Operand::Arg(0))
+        }));
+        let gamma = f.gamma(
+            pred,
+            &[Operand::Arg(0)],
+            &[&cases[0..1], &cases[1..2], &cases[2..3]],
+        );
+        f.into_function(&[], &[], Operand::Project(0, gamma))
+    };
+    for k in 0..3 {
+        let prog = rvsdg_to_bril(&build(k)).unwrap();
+        assert_eq!(
+            Optimizer::interp(&prog, vec![], None),
+            format!("{}\n", 10 * k)
+        );
+    }
+}
+
+#[test]
+fn rvsdg_to_bril_text_roundtrip() {
+    const PROGRAM: &str = r#"
+    @main(n: int) {
+        i: int = const 0;
+        one: int = const 1;
+    .loop:
+        print i;
+        i: int = add i one;
+        more: bool = lt i n;
+        br more .loop .done;
+    .done:
+        zero: int = const 0;
+        pos: bool = lt zero n;
+        br pos .yes .no;
+    .yes:
+        print n;
+        jmp .end;
+    .no:
+        print zero;
+    .end:
+        print one;
+    }
+    "#;
+    let prog = parse_from_string(PROGRAM);
+    let rvsdg = &cfg_to_rvsdg(&program_to_cfg(&prog)).unwrap().functions[0];
+    assert!(matches!(
+        rvsdg.to_egglog_expr(),
+        EgglogFunctionResult::StateOnly(_)
+    ));
+
+    let emitted = rvsdg_to_bril(rvsdg).unwrap();
+    let func = &emitted.functions[0];
+    assert_eq!(func.return_type, None);
+    assert!(func.instrs.iter().all(|code| !matches!(
+        code,
+        Code::Instruction(Instruction::Effect {
+            op: EffectOps::Return,
+            args,
+            ..
+        }) if !args.is_empty()
+    )));
+
+    // The emitted program survives a trip through the text format.
+    let reparsed = parse_from_string(&emitted.to_string());
+    for n in ["0", "3"] {
+        assert_eq!(
+            Optimizer::interp(&prog, vec![n.to_string()], None),
+            Optimizer::interp(&reparsed, vec![n.to_string()], None)
+        );
+    }
+}
+
+fn search_for(f: &RvsdgFunction, mut pred: impl FnMut(&RvsdgBody) -> bool) -> bool {
+    if search_op_for(&f.nodes, &f.state, &mut pred) {
+        return true;
+    }
+    f.results
+        .iter()
+        .any(|res| search_op_for(&f.nodes, res, &mut pred))
+}
+
+fn search_op_for(
+    nodes: &[RvsdgBody],
+    op: &Operand,
+    pred: &mut impl FnMut(&RvsdgBody) -> bool,
+) -> bool {
+    fn search_node(
+        nodes: &[RvsdgBody],
+        node: &RvsdgBody,
+        pred: &mut impl FnMut(&RvsdgBody) -> bool,
+    ) -> bool {
+        if pred(node) {
+            return true;
+        }
+        match node {
+            RvsdgBody::BasicOp(x) => match x {
+                Expr::Op(_, args, _)
+                | Expr::UnsignedCmp(_, args)
+                | Expr::Bitwise(_, args)
+                | Expr::EffectfulOp(_, args, _)
+                | Expr::Effect(_, args)
+                | Expr::Speculation(_, args)
+                | Expr::Call(_, args, ..)
+                | Expr::Select(args, _)
+                | Expr::Print(args) => args.iter().any(|arg| search_op_for(nodes, arg, pred)),
+                Expr::Const(_, _, _) => false,
+            },
+            RvsdgBody::Gamma {
+                pred: p,
+                inputs,
+                outputs,
+            } => {
+                search_op_for(nodes, p, pred)
+                    || inputs.iter().any(|arg| search_op_for(nodes, arg, pred))
+                    || outputs
+                        .iter()
+                        .any(|outs| outs.iter().any(|arg| search_op_for(nodes, arg, pred)))
+            }
+            RvsdgBody::Theta {
+                pred: p,
+                inputs,
+                outputs,
+            } => {
+                search_op_for(nodes, p, pred)
+                    || inputs.iter().any(|arg| search_op_for(nodes, arg, pred))
+                    || outputs.iter().any(|arg| search_op_for(nodes, arg, pred))
+            }
+            RvsdgBody::Lambda {
+                nodes: body,
+                outputs,
+                ..
+            } => outputs.iter().any(|arg| search_op_for(body, arg, pred)),
+            RvsdgBody::Apply { func, args } => {
+                search_op_for(nodes, func, pred)
+                    || args.iter().any(|arg| search_op_for(nodes, arg, pred))
+            }
+        }
+    }
+    match op {
+        Operand::Arg(_) => false,
+        Operand::Id(x) | Operand::Project(_, x) => search_node(nodes, &nodes[*x], pred),
     }
 }
@@ -0,0 +1,653 @@
+//! Convert RVSDGs back into CFGs.
+//!
+//! The conversion works backwards from the results and the state edge of a
+//! function, emitting instructions for a node the first time one of its
+//! outputs is demanded. Every effectful node takes the state produced by the
+//! previous one as its last operand, so emitting a node's operands before the
+//! node itself replays effects in their original order.
+//!
+//! Structured control flow is rebuilt directly from the RVSDG:
+//!
+//! * Gamma nodes become a conditional branch to one block per case, all of
+//! which jump to a fresh join block. Boolean predicates use a single `br`;
+//! integer predicates (e.g. from restructuring) become a chain of comparisons.
+//!
+//! * Theta nodes become do-while loops. The loop-carried values live in one
+//! variable per input, which the tail of the loop updates before branching
+//! back to the head.
+use std::{collections::HashMap, iter::once};
+
+use bril_rs::{Argument, ConstOps, EffectOps, Instruction, Literal, Program, Type, ValueOps};
+use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph};
+
+use crate::cfg::{Annotation, BasicBlock, BlockName, Branch, BranchOp, Cfg};
+
+use super::{BitOp, Expr, Id, Operand, Result, RvsdgBody, RvsdgError, RvsdgFunction};
+
+/// Convert an RVSDG function into a CFG.
+///
+/// Argument and variable names are not preserved: the arguments are named
+/// `arg0`, `arg1`, ... and every other value gets a fresh variable.
+///
+/// Fails if `func` has no Bril counterpart: if it has more than one result,
+/// uses a lambda or an apply node, or has a guard that may fail.
+pub(crate) fn rvsdg_to_cfg(func: &RvsdgFunction) -> Result<Cfg> {
+    check_lowerable(func)?;
+    let args = Vec::from_iter(func.args.iter().enumerate().map(|(i, ty)| Argument {
+        name: format!("arg{i}"),
+        arg_type: ty.clone(),
+    }));
+    let mut graph = StableDiGraph::default();
+    let entry = graph.add_node(BasicBlock::empty(BlockName::Entry));
+    let exit = graph.add_node(BasicBlock::empty(BlockName::Exit));
+    let mut builder = CfgBuilder {
+        nodes: &func.nodes,
+        cfg: Cfg {
+            args: args.clone(),
+            graph,
+            entry,
+            exit,
+            name: func.name.clone(),
+            return_ty: None,
+        },
+        current: entry,
+        n_vars: 0,
+        n_blocks: 0,
+    };
+
+    let mut scope = Scope::new(
+        args.into_iter()
+            .map(|arg| Value::Var(arg.name, arg.arg_type))
+            .chain(once(Value::State))
+            .collect(),
+    );
+    let results = builder.operands(&mut scope, &func.results);
+    builder.operand(&mut scope, &func.state);
+    match results.as_slice() {
+        [] => {}
+        [Value::Var(name, ty)] => {
+            builder.cfg.return_ty = Some(ty.clone());
+            let current = builder.current;
+            builder.cfg.graph[current]
+                .footer
+                .push(Annotation::AssignRet { src: name.into() });
+        }
+        _ => unreachable!("checked by check_lowerable, got {results:?}"),
+    }
+    builder.jump(exit);
+    Ok(builder.cfg)
+}
+
+/// Check that everything `func` demands can be lowered to Bril, so that
+/// building the CFG can't fail partway through. Nodes that nothing demands
+/// are never emitted, so they aren't checked either.
+fn check_lowerable(func: &RvsdgFunction) -> Result {
+    if func.results.len() > 1 {
+        return Err(RvsdgError::MultipleResults {
+            func: func.name.clone(),
+            results: func.results.len(),
+        });
+    }
+    let mut seen = vec![false; func.nodes.len()];
+    let mut todo = Vec::from_iter(func.results.iter().chain(once(&func.state)).copied());
+    while let Some(op) = todo.pop() {
+        let (Operand::Id(id) | Operand::Project(_, id)) = op else {
+            continue;
+        };
+        if std::mem::replace(&mut seen[id], true) {
+            continue;
+        }
+        match &func.nodes[id] {
+            RvsdgBody::Lambda { .. } | RvsdgBody::Apply { .. } => {
+                return Err(RvsdgError::FirstClassFunction {
+                    func: func.name.clone(),
+                })
+            }
+            RvsdgBody::BasicOp(Expr::Speculation(EffectOps::Guard, args))
+                if !args[0].is_literal_true(&func.nodes) =>
+            {
+                return Err(RvsdgError::FallibleGuard {
+                    func: func.name.clone(),
+                    pos: None,
+                })
+            }
+            _ => {}
+        }
+        func.nodes[id].for_each_operand(|op| todo.push(*op));
+    }
+    Ok(())
+}
+
+/// Convert an RVSDG function into a standalone Bril program, e.g. to run it
+/// through the interpreter. Functions with no results (only a state edge)
+/// `ret` without a value.
+pub(crate) fn rvsdg_to_bril(func: &RvsdgFunction) -> Result<Program> {
+    Ok(Program {
+        functions: vec![rvsdg_to_cfg(func)?.to_bril()],
+        imports: vec![],
+    })
+}
+
+/// A single output of an RVSDG node, once lowered to Bril.
+#[derive(Clone, Debug)]
+enum Value {
+    /// The state edge. It has no runtime representation: the order in which
+    /// instructions are emitted is what preserves it.
+    State,
+    /// A Bril variable of the given type.
+    Var(String, Type),
+}
+
+impl Value {
+    fn var(&self) -> &str {
+        match self {
+            Value::Var(name, _) => name,
+            Value::State => panic!("state edge used as a value"),
+        }
+    }
+}
+
+/// The values bound in a single region of the RVSDG.
+///
+/// Regions share a single heap of nodes, but an `Operand::Arg` means something
+/// different in each region. Nodes are therefore emitted (and cached) once per
+/// region that uses them.
+struct Scope {
+    args: Vec<Value>,
+    emitted: HashMap<Id, Vec<Value>>,
+}
+
+impl Scope {
+    fn new(args: Vec<Value>) -> Scope {
+        Scope {
+            args,
+            emitted: HashMap::new(),
+        }
+    }
+}
+
+struct CfgBuilder<'a> {
+    nodes: &'a [RvsdgBody],
+    cfg: Cfg,
+    /// The block that instructions are currently appended to.
+    current: NodeIndex,
+    n_vars: usize,
+    n_blocks: usize,
+}
+
+impl<'a> CfgBuilder<'a> {
+    fn operand(&mut self, scope: &mut Scope, op: &Operand) -> Value {
+        match op {
+            Operand::Arg(i) => scope.args[*i].clone(),
+            Operand::Id(id) => self.node(scope, *id)[0].clone(),
+            Operand::Project(i, id) => self.node(scope, *id)[*i].clone(),
+        }
+    }
+
+    fn operands(&mut self, scope: &mut Scope, ops: &[Operand]) -> Vec<Value> {
+        ops.iter().map(|op| self.operand(scope, op)).collect()
+    }
+
+    /// Emit the operands of an instruction, returning the variables to pass
+    /// as arguments. State operands are emitted, but are not arguments.
+    fn args(&mut self, scope: &mut Scope, ops: &[Operand]) -> Vec<String> {
+        let mut args = Vec::with_capacity(ops.len());
+        for op in ops {
+            if let Value::Var(name, _) = self.operand(scope, op) {
+                args.push(name);
+            }
+        }
+        args
+    }
+
+    fn node(&mut self, scope: &mut Scope, id: Id) -> Vec<Value> {
+        if let Some(outputs) = scope.emitted.get(&id) {
+            return outputs.clone();
+        }
+        let nodes = self.nodes;
+        let outputs = match &nodes[id] {
+            RvsdgBody::BasicOp(expr) => self.expr(scope, expr),
+            RvsdgBody::Gamma {
+                pred,
+                inputs,
+                outputs,
+            } => self.gamma(scope, pred, inputs, outputs),
+            RvsdgBody::Theta {
+                pred,
+                inputs,
+                outputs,
+            } => self.theta(scope, pred, inputs, outputs),
+            RvsdgBody::Lambda { .. } | RvsdgBody::Apply { .. } => {
+                unreachable!("checked by check_lowerable")
+            }
+        };
+        scope.emitted.insert(id, outputs.clone());
+        outputs
+    }
+
+    fn expr(&mut self, scope: &mut Scope, expr: &Expr<Operand>) -> Vec<Value> {
+        match expr {
+            Expr::Op(op, args, ty) => {
+                let args = self.args(scope, args);
+                vec![self.value(*op, args, vec![], ty)]
+            }
+            // Bril's comparisons are signed, but offsetting both operands by
+            // the smallest integer (with wrapping addition) maps the unsigned
+            // order onto the signed one.
+            Expr::UnsignedCmp(op, args) => {
+                let min = self.constant(ConstOps::Const, Literal::Int(i64::MIN), &Type::Int);
+                let args = Vec::from_iter(self.args(scope, args).into_iter().map(|arg| {
+                    let args = vec![arg, min.var().to_string()];
+                    self.value(ValueOps::Add, args, vec![], &Type::Int)
+                        .var()
+                        .to_string()
+                }));
+                vec![self.value(*op, args, vec![], &Type::Bool)]
+            }
+            Expr::Bitwise(op, args) => match (op, self.shift_amount(&args[1])) {
+                // Shifting left by a constant is multiplying by a power of
+                // two, which is a single instruction rather than a loop.
+                (BitOp::Shl, Some(amount)) => {
+                    let x = self.operand(scope, &args[0]);
+                    let factor = self.int(1 << amount);
+                    vec![self.arith(ValueOps::Mul, &x, &factor)]
+                }
+                _ => {
+                    let [x, y] = self.operands(scope, args).try_into().unwrap();
+                    vec![self.bitwise(*op, &x, &y)]
+                }
+            },
+            Expr::EffectfulOp(op, args, ty) => {
+                let args = self.args(scope, args);
+                vec![self.value(*op, args, vec![], ty), Value::State]
+            }
+            Expr::Effect(op, args) => {
+                let args = self.args(scope, args);
+                self.effect(*op, args, vec![]);
+                vec![Value::State]
+            }
+            // Not every Bril interpreter supports speculation, and the
+            // guards can't fail (see check_lowerable), so speculative regions
+            // are run as ordinary code.
+            Expr::Speculation(_, args) => {
+                self.args(scope, args);
+                vec![Value::State]
+            }
+            Expr::Call(func, args, _, ty, pure) => {
+                let args = self.args(scope, args);
+                let funcs = vec![func.to_string()];
+                match ty {
+                    Some(ty) if *pure => vec![self.value(ValueOps::Call, args, funcs, ty)],
+                    Some(ty) => vec![self.value(ValueOps::Call, args, funcs, ty), Value::State],
+                    None => {
+                        self.effect(EffectOps::Call, args, funcs);
+                        vec![Value::State]
+                    }
+                }
+            }
+            Expr::Const(op, lit, ty) => vec![self.constant(*op, lit.clone(), ty)],
+            Expr::Print(args) => {
+                let args = self.args(scope, args);
+                self.effect(EffectOps::Print, args, vec![]);
+                vec![Value::State]
+            }
+            Expr::Select(args, _) => {
+                let [pred, then, els] = self.operands(scope, args).try_into().unwrap();
+                let result = self.fresh_like(&then);
+                let (then_block, els_block, join) =
+                    (self.fresh_block(), self.fresh_block(), self.fresh_block());
+                self.branch(pred.var(), then_block, els_block);
+                for (block, value) in [(then_block, &then), (els_block, &els)] {
+                    self.current = block;
+                    self.copy(&result, value);
+                    self.jump(join);
+                }
+                self.current = join;
+                vec![result]
+            }
+        }
+    }
+
+    fn gamma(
+        &mut self,
+        scope: &mut Scope,
+        pred: &Operand,
+        inputs: &[Operand],
+        outputs: &[Vec<Operand>],
+    ) -> Vec<Value> {
+        let pred = self.operand(scope, pred);
+        let inputs = self.operands(scope, inputs);
+        let cases = Vec::from_iter(outputs.iter().map(|_| self.fresh_block()));
+        self.switch(&pred, &cases);
+
+        let join = self.fresh_block();
+        let mut results: Option<Vec<Value>> = None;
+        for (case, outputs) in cases.into_iter().zip(outputs) {
+            self.current = case;
+            let mut inner = Scope::new(inputs.clone());
+            let values = self.operands(&mut inner, outputs);
+            let results =
+                results.get_or_insert_with(|| values.iter().map(|v| self.fresh_like(v)).collect());
+            for (dst, src) in results.iter().zip(&values) {
+                self.copy(dst, src);
+            }
+            self.jump(join);
+        }
+        self.current = join;
+        results.unwrap_or_default()
+    }
+
+    fn theta(
+        &mut self,
+        scope: &mut Scope,
+        pred: &Operand,
+        inputs: &[Operand],
+        outputs: &[Operand],
+    ) -> Vec<Value> {
+        let inputs = self.operands(scope, inputs);
+        let vars = Vec::from_iter(inputs.iter().map(|v| self.fresh_like(v)));
+        for (dst, src) in vars.iter().zip(&inputs) {
+            self.copy(dst, src);
+        }
+        let head = self.fresh_block();
+        self.jump(head);
+        self.current = head;
+
+        let mut body = Scope::new(vars.clone());
+        let next = self.operands(&mut body, outputs);
+        let pred = self.operand(&mut body, pred);
+
+        // The outputs and the predicate may read the loop variables directly
+        // (e.g. when swapping two of them), so stage the next iteration's
+        // values in temporaries before overwriting anything.
+        let staged = Vec::from_iter(next.iter().chain(once(&pred)).map(|v| {
+            let tmp = self.fresh_like(v);
+            self.copy(&tmp, v);
+            tmp
+        }));
+        for (dst, src) in vars.iter().zip(&staged) {
+            self.copy(dst, src);
+        }
+
+        let tail = self.fresh_block();
+        self.switch(staged.last().unwrap(), &[tail, head]);
+        self.current = tail;
+        vars
+    }
+
+    /// Emulate a [BitOp] with Bril's arithmetic, which wraps around on overflow.
+    fn bitwise(&mut self, op: BitOp, x: &Value, y: &Value) -> Value {
+        let result = self.fresh_like(x);
+        match op {
+            BitOp::Shl | BitOp::Shr => {
+                // Double or halve `x` once per bit shifted.
+                self.copy(&result, x);
+                // Every bit has been shifted out after 64 shifts.
+                let amount = self.at_most(y, 64);
+                self.repeat(&amount, |b| {
+                    let shifted = match op {
+                        BitOp::Shl => b.arith(ValueOps::Add, &result, &result),
+                        _ => b.halve(&result),
+                    };
+                    b.copy(&result, &shifted);
+                });
+            }
+            BitOp::And | BitOp::Or | BitOp::Xor => {
+                // Go through the bits of `x` and `y` one at a time, from the
+                // least significant up.
+                let zero = self.int(0);
+                self.copy(&result, &zero);
+                let (x_rest, y_rest, place) =
+                    (self.fresh_like(x), self.fresh_like(y), self.fresh_like(x));
+                self.copy(&x_rest, x);
+                self.copy(&y_rest, y);
+                let one = self.int(1);
+                self.copy(&place, &one);
+                let bits = self.int(64);
+                self.repeat(&bits, |b| {
+                    let (x_bit, y_bit) = (b.low_bit(&x_rest), b.low_bit(&y_rest));
+                    let both = b.arith(ValueOps::Mul, &x_bit, &y_bit);
+                    let either = b.arith(ValueOps::Add, &x_bit, &y_bit);
+                    let bit = match op {
+                        BitOp::And => both,
+                        BitOp::Or => b.arith(ValueOps::Sub, &either, &both),
+                        _ => {
+                            let either = b.arith(ValueOps::Sub, &either, &both);
+                            b.arith(ValueOps::Sub, &either, &both)
+                        }
+                    };
+                    let value = b.arith(ValueOps::Mul, &bit, &place);
+                    let next = b.arith(ValueOps::Add, &result, &value);
+                    b.copy(&result, &next);
+                    for (rest, bit) in [(&x_rest, &x_bit), (&y_rest, &y_bit)] {
+                        let rest_bits = b.arith(ValueOps::Sub, rest, bit);
+                        let two = b.int(2);
+                        let next = b.arith(ValueOps::Div, &rest_bits, &two);
+                        b.copy(rest, &next);
+                    }
+                    let next = b.arith(ValueOps::Add, &place, &place);
+                    b.copy(&place, &next);
+                });
+            }
+        }
+        result
+    }
+
+    /// The amount `op` shifts by, if it is a constant that doesn't shift
+    /// every bit out.
+    fn shift_amount(&self, op: &Operand) -> Option<u32> {
+        let (Operand::Id(id) | Operand::Project(0, id)) = *op else {
+            return None;
+        };
+        match &self.nodes[id] {
+            RvsdgBody::BasicOp(Expr::Const(_, Literal::Int(n @ 0..=63), _)) => Some(*n as u32),
+            _ => None,
+        }
+    }
+
+    /// The least significant bit of the integer `x`, as 0 or 1.
+    fn low_bit(&mut self, x: &Value) -> Value {
+        // Division truncates, so the remainder is -1 for odd negative numbers.
+        let two = self.int(2);
+        let half = self.arith(ValueOps::Div, x, &two);
+        let even = self.arith(ValueOps::Mul, &half, &two);
+        let rem = self.arith(ValueOps::Sub, x, &even);
+        self.arith(ValueOps::Mul, &rem, &rem)
+    }
+
+    /// `x` divided by two, rounding down (an arithmetic shift right by one).
+    fn halve(&mut self, x: &Value) -> Value {
+        let bit = self.low_bit(x);
+        let even = self.arith(ValueOps::Sub, x, &bit);
+        let two = self.int(2);
+        self.arith(ValueOps::Div, &even, &two)
+    }
+
+    /// The integer `x`, or `max` if `x` is greater.
+    fn at_most(&mut self, x: &Value, max: i64) -> Value {
+        let max = self.int(max);
+        let result = self.fresh_like(x);
+        let over = self.value(
+            ValueOps::Gt,
+            vec![x.var().to_string(), max.var().to_string()],
+            vec![],
+            &Type::Bool,
+        );
+        let (then_block, els_block, join) =
+            (self.fresh_block(), self.fresh_block(), self.fresh_block());
+        self.branch(over.var(), then_block, els_block);
+        for (block, value) in [(then_block, &max), (els_block, x)] {
+            self.current = block;
+            self.copy(&result, value);
+            self.jump(join);
+        }
+        self.current = join;
+        result
+    }
+
+    /// Emit a loop that runs `body` `count` times, or not at all if `count`
+    /// isn't positive. Values carried between iterations have to be copied
+    /// into variables from before the loop.
+    fn repeat(&mut self, count: &Value, body: impl FnOnce(&mut Self)) {
+        let (zero, one) = (self.int(0), self.int(1));
+        let remaining = self.fresh_like(count);
+        self.copy(&remaining, count);
+        let (head, body_block, done) = (self.fresh_block(), self.fresh_block(), self.fresh_block());
+        self.jump(head);
+        self.current = head;
+        let more = self.value(
+            ValueOps::Gt,
+            vec![remaining.var().to_string(), zero.var().to_string()],
+            vec![],
+            &Type::Bool,
+        );
+        self.branch(more.var(), body_block, done);
+        self.current = body_block;
+        body(self);
+        let next = self.arith(ValueOps::Sub, &remaining, &one);
+        self.copy(&remaining, &next);
+        self.jump(head);
+        self.current = done;
+    }
+
+    /// Branch to `targets[i]` when `pred` is `i`. Boolean predicates choose
+    /// between two targets with a single `br`; other predicates are compared
+    /// against each case in turn, with the last target taken by default.
+    fn switch(&mut self, pred: &Value, targets: &[NodeIndex]) {
+        match (pred, targets) {
+            (_, [target]) => self.jump(*target),
+            (Value::Var(pred, Type::Bool), [els, then]) => self.branch(pred, *then, *els),
+            (Value::Var(pred, _), [cases @ .., default]) => {
+                for (i, case) in cases.iter().enumerate() {
+                    let i = self.constant(ConstOps::Const, Literal::Int(i as i64), &Type::Int);
+                    let cond = self.value(
+                        ValueOps::Eq,
+                        vec![pred.clone(), i.var().to_string()],
+                        vec![],
+                        &Type::Bool,
+                    );
+                    let next = self.fresh_block();
+                    self.branch(cond.var(), *case, next);
+                    self.current = next;
+                }
+                self.jump(*default);
+            }
+            (Value::State, _) | (_, []) => panic!("invalid predicate {pred:?}"),
+        }
+    }
+
+    fn constant(&mut self, op: ConstOps, value: Literal, ty: &Type) -> Value {
+        let dest = self.fresh_var();
+        self.push(Instruction::Constant {
+            dest: dest.clone(),
+            op,
+            pos: None,
+            const_type: ty.clone(),
+            value,
+        });
+        Value::Var(dest, ty.clone())
+    }
+
+    fn value(&mut self, op: ValueOps, args: Vec<String>, funcs: Vec<String>, ty: &Type) -> Value {
+        let dest = self.fresh_var();
+        self.push(Instruction::Value {
+            args,
+            dest: dest.clone(),
+            funcs,
+            labels: vec![],
+            op,
+            pos: None,
+            op_type: ty.clone(),
+        });
+        Value::Var(dest, ty.clone())
+    }
+
+    fn int(&mut self, n: i64) -> Value {
+        self.constant(ConstOps::Const, Literal::Int(n), &Type::Int)
+    }
+
+    /// An integer operation on `l` and `r`.
+    fn arith(&mut self, op: ValueOps, l: &Value, r: &Value) -> Value {
+        let args = vec![l.var().to_string(), r.var().to_string()];
+        self.value(op, args, vec![], &Type::Int)
+    }
+
+    fn effect(&mut self, op: EffectOps, args: Vec<String>, funcs: Vec<String>) {
+        self.push(Instruction::Effect {
+            args,
+            funcs,
+            labels: vec![],
+            op,
+            pos: None,
+        });
+    }
+
+    /// Copy `src` into `dst`. Copying the state edge is a no-op.
+    fn copy(&mut self, dst: &Value, src: &Value) {
+        match (dst, src) {
+            (Value::State, Value::State) => {}
+            (Value::Var(dst, ty), Value::Var(src, _)) => self.push(Instruction::Value {
+                args: vec![src.clone()],
+                dest: dst.clone(),
+                funcs: vec![],
+                labels: vec![],
+                op: ValueOps::Id,
+                pos: None,
+                op_type: ty.clone(),
+            }),
+            _ => panic!("mismatched copy from {src:?} to {dst:?}"),
+        }
+    }
+
+    /// A fresh variable with the same type as `value`.
+    fn fresh_like(&mut self, value: &Value) -> Value {
+        match value {
+            Value::State => Value::State,
+            Value::Var(_, ty) => Value::Var(self.fresh_var(), ty.clone()),
+        }
+    }
+
+    fn fresh_var(&mut self) -> String {
+        let res = format!("v{}_", self.n_vars);
+        self.n_vars += 1;
+        res
+    }
+
+    fn fresh_block(&mut self) -> NodeIndex {
+        let name = BlockName::Placeholder(self.n_blocks);
+        self.n_blocks += 1;
+        self.cfg.graph.add_node(BasicBlock::empty(name))
+    }
+
+    fn push(&mut self, instr: Instruction) {
+        let current = self.current;
+        self.cfg.graph[current].instrs.push(instr);
+    }
+
+    fn jump(&mut self, target: NodeIndex) {
+        self.cfg.graph.add_edge(
+            self.current,
+            target,
+            Branch {
+                op: BranchOp::Jmp,
+                pos: None,
+            },
+        );
+    }
+
+    fn branch(&mut self, cond: &str, then: NodeIndex, els: NodeIndex) {
+        for (target, val) in [(then, true), (els, false)] {
+            self.cfg.graph.add_edge(
+                self.current,
+                target,
+                Branch {
+                    op: BranchOp::Cond {
+                        arg: cond.into(),
+                        val: val.into(),
+                    },
+                    pos: None,
+                },
+            );
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Checking that an [RvsdgFunction] is well-formed.
+//!
+//! Nodes live in a single heap that is shared between regions, and operands
+//! refer to them (and to the arguments of their region) by index. Nothing
+//! about the representation keeps those indices in range, so a lowering or
+//! optimization bug shows up much later as a panic (or a wrong answer) far
+//! from the code that caused it. [validate] catches these bugs early.
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+use super::{Id, Operand, RvsdgBody, RvsdgFunction};
+
+/// The ways in which an [RvsdgFunction] can be ill-formed.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("operand refers to node {id}, but there are only {n_nodes} nodes")]
+    DanglingId { id: Id, n_nodes: usize },
+
+    #[error("operand refers to argument {arg} of a region with {n_args} arguments")]
+    ArgOutOfRange { arg: usize, n_args: usize },
+
+    #[error("branch {branch} of gamma {gamma} has {actual} outputs, expected {expected}")]
+    GammaArity {
+        gamma: Id,
+        branch: usize,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("theta {theta} has {inputs} inputs but {outputs} outputs")]
+    ThetaArity {
+        theta: Id,
+        inputs: usize,
+        outputs: usize,
+    },
+}
+
+/// Check that every operand in `func` refers to a node that exists or to an
+/// argument of the region it is used in, and that the regions of gammas and
+/// thetas have consistent numbers of outputs.
+///
+/// The arguments of the function's own region are its arguments followed by
+/// the state edge. Gamma branches and theta bodies have one argument per
+/// input of the node, and lambda bodies have one per argument of the lambda
+/// plus the state edge.
+pub(crate) fn validate(func: &RvsdgFunction) -> Result<(), ValidationError> {
+    let roots = Vec::from_iter(func.results.iter().chain([&func.state]).copied());
+    validate_region(&func.nodes, func.args.len() + 1, &roots)
+}
+
+/// Validate the region with `n_args` arguments that computes `outputs`, whose
+/// nodes live in `nodes`.
+fn validate_region(
+    nodes: &[RvsdgBody],
+    n_args: usize,
+    outputs: &[Operand],
+) -> Result<(), ValidationError> {
+    // Arguments mean something different in every region, so the nodes that
+    // have been checked are tracked per region.
+    let mut seen = HashSet::new();
+    let mut stack = outputs.to_vec();
+    while let Some(op) = stack.pop() {
+        let id = match op {
+            Operand::Arg(arg) if arg < n_args => continue,
+            Operand::Arg(arg) => return Err(ValidationError::ArgOutOfRange { arg, n_args }),
+            Operand::Id(id) | Operand::Project(_, id) => id,
+        };
+        let Some(body) = nodes.get(id) else {
+            return Err(ValidationError::DanglingId {
+                id,
+                n_nodes: nodes.len(),
+            });
+        };
+        if !seen.insert(id) {
+            continue;
+        }
+        match body {
+            RvsdgBody::BasicOp(expr) => stack.extend(expr.operands()),
+            RvsdgBody::Gamma {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                stack.push(*pred);
+                stack.extend(inputs);
+                let expected = outputs.first().map_or(0, Vec::len);
+                for (branch, branch_outputs) in outputs.iter().enumerate() {
+                    if branch_outputs.len() != expected {
+                        return Err(ValidationError::GammaArity {
+                            gamma: id,
+                            branch,
+                            expected,
+                            actual: branch_outputs.len(),
+                        });
+                    }
+                    validate_region(nodes, inputs.len(), branch_outputs)?;
+                }
+            }
+            RvsdgBody::Theta {
+                pred,
+                inputs,
+                outputs,
+            } => {
+                stack.extend(inputs);
+                if inputs.len() != outputs.len() {
+                    return Err(ValidationError::ThetaArity {
+                        theta: id,
+                        inputs: inputs.len(),
+                        outputs: outputs.len(),
+                    });
+                }
+                let body = Vec::from_iter([*pred].into_iter().chain(outputs.iter().copied()));
+                validate_region(nodes, inputs.len(), &body)?;
+            }
+            RvsdgBody::Lambda {
+                n_args,
+                nodes: body,
+                outputs,
+            } => validate_region(body, n_args + 1, outputs)?,
+            RvsdgBody::Apply { func, args } => {
+                stack.push(*func);
+                stack.extend(args);
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,13 +1,19 @@
-use bril_rs::Program;
+use bril_rs::{Literal, Program};
 
-use crate::Optimizer;
+use crate::rvsdg::{add_custom_rules, new_rvsdg_egraph, Rules, RvsdgProgram};
+use crate::{
+    Captures, CostModel, DefaultCostModel, EggCCError, InterpResult, OptLevel, Optimizer,
+    RunTimings, SaturationLimit,
+};
 use std::fmt::Debug;
 use std::{
     ffi::OsStr,
     fmt::{Display, Formatter},
-    io,
+    io::{self, Read},
     path::PathBuf,
     str::FromStr,
+    sync::Arc,
+    time::Instant,
 };
 
 pub(crate) struct ListDisplay<'a, TS>(pub TS, pub &'a str);
@@ -36,13 +42,18 @@ where
 #[allow(unused)]
 pub(crate) fn parse_from_string(input: &str) -> bril_rs::Program {
     use bril2json::parse_abstract_program_from_read;
-    use bril_rs::load_program_from_read;
     let abs_program = parse_abstract_program_from_read(input.as_bytes(), true, false, None);
     let mut buf = Vec::new();
     serde_json::to_writer_pretty(&mut buf, &abs_program).unwrap();
     buf.push(b'\n');
     let json_str = String::from_utf8(buf).unwrap();
-    load_program_from_read(json_str.as_bytes())
+    parse_from_json(&json_str)
+}
+
+/// Parse a Bril program in the JSON interchange format, e.g. the output of
+/// `bril2json` or of another Bril tool.
+pub(crate) fn parse_from_json(input: &str) -> bril_rs::Program {
+    bril_rs::load_program_from_read(input.as_bytes())
 }
 
 /// Write the visualizations to output files in the output directory.
@@ -63,9 +74,10 @@ pub fn visualize(test: TestProgram, output_dir: PathBuf) -> io::Result<()> {
 
     let all_configs = Run::all_configurations_for(test);
 
-    let results = all_configs.iter().map(|run| (run, run.run()));
-
-    for (run, result) in results {
+    for run in &all_configs {
+        let result = run
+            .run()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
         let mut output_path = output_dir.clone();
         assert!(output_path.is_dir());
         output_path.push(format!(
@@ -124,7 +136,20 @@ where
 pub enum RunType {
     StructuredConversion,
     RvsdgConversion,
+    /// Like `RvsdgConversion`, but drawing state edges distinctly and labeling
+    /// each node with its id.
+    RvsdgDebugConversion,
+    /// Run the RVSDG rewrite rules in egglog, outputting the optimized egglog
+    /// expressions for each function.
+    RvsdgOptimize,
     NaiiveOptimization,
+    /// Like `RvsdgOptimize`, but converting the optimized RVSDG back into a
+    /// Bril program, e.g. to feed to external Bril benchmarking tools.
+    OptimizedBril,
+    /// Convert to an RVSDG and straight back into a Bril program, without
+    /// running any rewrites, to tell bugs in the conversions apart from bugs
+    /// in the optimizations.
+    Passthrough,
 }
 
 impl Debug for RunType {
@@ -140,7 +165,11 @@ impl FromStr for RunType {
         match s {
             "structured" => Ok(RunType::StructuredConversion),
             "rvsdg" => Ok(RunType::RvsdgConversion),
+            "rvsdg-debug" => Ok(RunType::RvsdgDebugConversion),
+            "rvsdg-optimize" => Ok(RunType::RvsdgOptimize),
             "naiive" => Ok(RunType::NaiiveOptimization),
+            "optimized-bril" => Ok(RunType::OptimizedBril),
+            "passthrough" => Ok(RunType::Passthrough),
             _ => Err(format!("Unknown run type: {}", s)),
         }
     }
@@ -151,7 +180,11 @@ impl Display for RunType {
         match self {
             RunType::StructuredConversion => write!(f, "structured"),
             RunType::RvsdgConversion => write!(f, "rvsdg"),
+            RunType::RvsdgDebugConversion => write!(f, "rvsdg-debug"),
+            RunType::RvsdgOptimize => write!(f, "rvsdg-optimize"),
             RunType::NaiiveOptimization => write!(f, "naiive"),
+            RunType::OptimizedBril => write!(f, "optimized-bril"),
+            RunType::Passthrough => write!(f, "passthrough"),
         }
     }
 }
@@ -161,22 +194,36 @@ impl RunType {
         match self {
             RunType::StructuredConversion => false,
             RunType::RvsdgConversion => false,
+            RunType::RvsdgDebugConversion => false,
+            RunType::RvsdgOptimize => false,
             RunType::NaiiveOptimization => true,
+            RunType::OptimizedBril => true,
+            RunType::Passthrough => true,
         }
     }
 }
 
 #[derive(Clone)]
 pub struct ProgWithArguments {
-    program: Program,
-    name: String,
-    args: Vec<String>,
+    pub(crate) program: Program,
+    pub(crate) name: String,
+    pub(crate) args: Vec<String>,
 }
 
 #[derive(Clone)]
 pub enum TestProgram {
     Prog(ProgWithArguments),
+    /// A Bril program in the text format.
     File(PathBuf),
+    /// A Bril program in the JSON format. JSON programs have no `# ARGS:`
+    /// line, so they are run without arguments.
+    BrilJson(PathBuf),
+    /// A program that has already been parsed, e.g. by a larger pipeline. It
+    /// is named `program` and run without arguments.
+    Program(Program),
+    /// A Bril program in the text format, read from standard input so that
+    /// eggcc can be used in a shell pipeline. It is named `stdin`.
+    Stdin,
 }
 
 impl TestProgram {
@@ -184,17 +231,44 @@ impl TestProgram {
         match self {
             TestProgram::Prog(prog) => prog,
             TestProgram::File(path) => {
+                let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+                Self::read_text(std::fs::File::open(path).unwrap(), name)
+            }
+            TestProgram::BrilJson(path) => {
                 let program_read = std::fs::read_to_string(path.clone()).unwrap();
-                let args = Optimizer::parse_bril_args(&program_read);
-                let program = Optimizer::parse_bril(&program_read).unwrap();
+                let program = parse_from_json(&program_read);
+                // Reject the same programs that `parse_bril` rejects for text
+                // input.
+                Optimizer::check_for_uninitialized_vars(&program).unwrap();
                 let name = path.file_stem().unwrap().to_str().unwrap().to_string();
 
                 ProgWithArguments {
                     program,
                     name,
-                    args,
+                    args: vec![],
                 }
             }
+            TestProgram::Program(program) => ProgWithArguments {
+                program,
+                name: "program".to_string(),
+                args: vec![],
+            },
+            TestProgram::Stdin => Self::read_text(io::stdin().lock(), "stdin".to_string()),
+        }
+    }
+
+    /// Read a Bril program in the text format, along with the arguments on its
+    /// `# ARGS:` line, from `reader`.
+    pub fn read_text(mut reader: impl Read, name: String) -> ProgWithArguments {
+        let mut program_read = String::new();
+        reader.read_to_string(&mut program_read).unwrap();
+        let args = Optimizer::parse_bril_args(&program_read);
+        let program = Optimizer::parse_bril(&program_read).unwrap();
+
+        ProgWithArguments {
+            program,
+            name,
+            args,
         }
     }
 }
@@ -205,6 +279,27 @@ pub struct Run {
     pub test_type: RunType,
     // Also interpret the resulting program
     pub interp: bool,
+    // The cost model to extract optimized programs with, if not the default
+    pub cost_model: Option<Arc<dyn CostModel>>,
+    // Which rewrites to run when optimizing. The naiive optimizer has a single
+    // set of rules, which is skipped entirely at `OptLevel::None`.
+    pub opt_level: OptLevel,
+    // When to stop saturating early
+    pub limit: SaturationLimit,
+    // Keep the egglog program that was run in the output
+    pub capture_egglog: bool,
+    // Give up on interpreting a program that takes more steps than this (see
+    // `Optimizer::interp_with_budget`)
+    pub interp_budget: Option<usize>,
+    // Write the saturated egraph to this file as JSON, for run types that
+    // optimize an RVSDG
+    pub dump_egraph: Option<PathBuf>,
+    // Extra egglog rules to saturate with, for run types that optimize an
+    // RVSDG. Set with `with_custom_rules` to check that they parse.
+    pub custom_rules: Vec<String>,
+    // Functions known to be pure, whose calls are kept off the state edge by
+    // run types that convert to an RVSDG
+    pub pure_functions: Vec<String>,
 }
 
 #[derive(Clone)]
@@ -216,21 +311,53 @@ pub struct RunOutput {
     // if the result was interpreted, the stdout of interpreting it
     pub result_interpreted: Option<String>,
     pub original_interpreted: String,
+    // if requested, the egglog program that was run, for run types that use
+    // egglog
+    pub egglog_program: Option<String>,
+    // whether interpreting the original or the resulting program ran out of
+    // time, in which case its output is empty
+    pub diverged: bool,
+    // how many iterations of saturation were run, for run types that
+    // optimize
+    pub iterations: usize,
 }
 
+/// How many steps [Run::all_configurations_for] lets programs be interpreted
+/// for.
+pub const DEFAULT_INTERP_BUDGET: usize = 1_000_000;
+
 impl Run {
+    /// A run of `test_type` on `prog_with_args` that doesn't interpret the
+    /// result and otherwise uses the defaults. Set other fields with the
+    /// `with_` methods or with struct update syntax.
+    pub fn new(prog_with_args: ProgWithArguments, test_type: RunType) -> Run {
+        Run {
+            prog_with_args,
+            test_type,
+            interp: false,
+            cost_model: None,
+            opt_level: OptLevel::default(),
+            limit: SaturationLimit::default(),
+            capture_egglog: false,
+            interp_budget: None,
+            dump_egraph: None,
+            custom_rules: vec![],
+            pure_functions: vec![],
+        }
+    }
+
     pub fn all_configurations_for(test: TestProgram) -> Vec<Run> {
         let prog = test.read_program();
         let mut res = vec![];
         for test_type in [
             RunType::StructuredConversion,
             RunType::RvsdgConversion,
+            RunType::RvsdgDebugConversion,
             RunType::NaiiveOptimization,
         ] {
             let default = Run {
-                test_type,
-                interp: false,
-                prog_with_args: prog.clone(),
+                interp_budget: Some(DEFAULT_INTERP_BUDGET),
+                ..Run::new(prog.clone(), test_type)
             };
             res.push(default.clone());
             if test_type.produces_bril() {
@@ -241,58 +368,673 @@ impl Run {
                 res.push(interp);
             }
         }
+        // The programs converted back from an RVSDG are checked by
+        // interpreting them, rather than against a snapshot of their text.
+        for test_type in [RunType::Passthrough, RunType::OptimizedBril] {
+            res.push(Run {
+                interp: true,
+                interp_budget: Some(DEFAULT_INTERP_BUDGET),
+                ..Run::new(prog.clone(), test_type)
+            });
+        }
         res
     }
 
+    pub fn with_opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    pub fn with_limit(mut self, limit: SaturationLimit) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn with_interp_budget(mut self, steps: usize) -> Self {
+        self.interp_budget = Some(steps);
+        self
+    }
+
+    /// Interpret programs with `args` as the arguments to `main`.
+    pub fn with_args(mut self, args: &[Literal]) -> Self {
+        self.prog_with_args.args = args.iter().map(Literal::to_string).collect();
+        self
+    }
+
+    /// Also saturate with `rules`, each a sequence of egglog commands such as
+    /// `(ruleset my-rules)` followed by `(rewrite ... :ruleset my-rules)`.
+    /// Rules can be added to new rulesets or to the built-in ones. Fails if
+    /// the rules don't parse or don't make sense for the RVSDG schema.
+    pub fn with_custom_rules(mut self, rules: Vec<String>) -> Result<Self, EggCCError> {
+        let mut egraph = new_rvsdg_egraph();
+        add_custom_rules(&mut egraph, &rules)?;
+        self.custom_rules = rules;
+        Ok(self)
+    }
+
+    /// Treat calls to the functions named in `pure` as pure, keeping them off
+    /// the state edge so that they can be reordered and deduplicated. Nothing
+    /// checks that the functions really are pure.
+    pub fn with_pure_functions(mut self, pure: Vec<String>) -> Self {
+        self.pure_functions = pure;
+        self
+    }
+
+    /// The program converted to an RVSDG, with calls to the pure functions
+    /// marked as such.
+    fn rvsdg(&self) -> Result<RvsdgProgram, EggCCError> {
+        let mut rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
+        rvsdg.mark_pure_calls(&self.pure_functions);
+        Ok(rvsdg)
+    }
+
+    /// The rules to optimize RVSDGs with.
+    fn rules(&self) -> Rules<'_> {
+        Rules {
+            rulesets: self.opt_level.rulesets(),
+            custom: &self.custom_rules,
+        }
+    }
+
+    /// Interpret `program` with this run's arguments, within the step budget
+    /// if there is one.
+    fn interpret(&self, program: &Program) -> Result<InterpResult, EggCCError> {
+        let args = self.prog_with_args.args.clone();
+        match self.interp_budget {
+            Some(steps) => Optimizer::interp_with_budget(program, args, steps),
+            None => Ok(InterpResult::Terminated(Optimizer::interp(
+                program, args, None,
+            ))),
+        }
+    }
+
     // give a unique name for this run configuration
     pub fn name(&self) -> String {
         let mut name = format!("{}-{}", self.prog_with_args.name, self.test_type);
+        // Runs at the default level keep the names their snapshots have.
+        if self.opt_level != OptLevel::default() {
+            name = format!("{}-{}", name, self.opt_level);
+        }
         if self.interp {
             name = format!("{}-interp", name);
         }
         name
     }
 
-    pub fn run(&self) -> RunOutput {
-        let original_interpreted = Optimizer::interp(
-            &self.prog_with_args.program,
-            self.prog_with_args.args.clone(),
-            None,
-        );
+    /// Run this configuration. Fails if the program can't be converted or
+    /// optimized, or if `interp` is set for a run type that doesn't produce a
+    /// Bril program (see [RunType::produces_bril]); the result that is
+    /// interpreted is the program the run produced, not the original.
+    pub fn run(&self) -> Result<RunOutput, EggCCError> {
+        Ok(self.run_with_timing()?.0)
+    }
+
+    /// Like [Run::run], also recording how long lowering, saturation, and
+    /// extraction took. Interpreting the programs is not timed.
+    pub fn run_with_timing(&self) -> Result<(RunOutput, RunTimings), EggCCError> {
+        if self.interp && !self.test_type.produces_bril() {
+            return Err(EggCCError::Uninterpretable(self.test_type));
+        }
+        let mut timings = RunTimings::default();
+        let mut captures = Captures {
+            iterations: 0,
+            egglog_program: self.capture_egglog.then(String::new),
+            egraph_json: self.dump_egraph.is_some().then(String::new),
+        };
+        let mut diverged = false;
+        let mut interp = |program: &Program| {
+            Ok(match self.interpret(program)? {
+                InterpResult::Terminated(output) => output,
+                InterpResult::Diverged => {
+                    diverged = true;
+                    String::new()
+                }
+            })
+        };
+        let original_interpreted = interp(&self.prog_with_args.program)?;
+        // The resulting program, for run types that produce bril
+        let mut result_program = None;
         let (visualization, visualization_file_extension) = match self.test_type {
             RunType::StructuredConversion => {
-                let structured =
-                    Optimizer::program_to_structured(&self.prog_with_args.program).unwrap();
+                let start = Instant::now();
+                let structured = Optimizer::program_to_structured(&self.prog_with_args.program)?;
+                timings.lowering = start.elapsed();
                 (structured.to_string(), ".txt")
             }
             RunType::RvsdgConversion => {
-                let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program).unwrap();
+                let start = Instant::now();
+                let rvsdg = self.rvsdg()?;
+                timings.lowering = start.elapsed();
                 let svg = rvsdg.to_svg();
                 (svg, ".svg")
             }
+            RunType::RvsdgDebugConversion => {
+                let start = Instant::now();
+                let rvsdg = self.rvsdg()?;
+                timings.lowering = start.elapsed();
+                (rvsdg.to_debug_svg(), ".svg")
+            }
+            RunType::RvsdgOptimize => {
+                let start = Instant::now();
+                let rvsdg = self.rvsdg()?;
+                timings.lowering = start.elapsed();
+                let cost_model = self.cost_model.as_deref().unwrap_or(&DefaultCostModel);
+                let optimized = rvsdg.optimize_egglog_timed(
+                    Optimizer::default().num_iters,
+                    self.rules(),
+                    cost_model,
+                    self.limit,
+                    &mut timings,
+                    &mut captures,
+                )?;
+                let mut res = String::new();
+                for (function, exprs) in rvsdg.functions.iter().zip(optimized) {
+                    res.push_str(&format!("@{}\n", function.name));
+                    for expr in exprs {
+                        res.push_str(&format!("  {expr}\n"));
+                    }
+                }
+                (res, ".txt")
+            }
             RunType::NaiiveOptimization => {
-                let mut optimizer = Optimizer::default();
-                let res = optimizer.optimize(&self.prog_with_args.program).unwrap();
+                let mut optimizer = Optimizer::default().with_limit(self.limit);
+                if self.opt_level == OptLevel::None {
+                    optimizer = optimizer.with_num_iters(0);
+                }
+                let res = optimizer
+                    .optimized_structured_timed(
+                        &self.prog_with_args.program,
+                        &mut timings,
+                        &mut captures,
+                    )?
+                    .to_program();
+                let visualization = format!("{}", res);
+                result_program = Some(res);
+
+                (visualization, ".bril")
+            }
+            RunType::OptimizedBril => {
+                let start = Instant::now();
+                let rvsdg = self.rvsdg()?;
+                timings.lowering = start.elapsed();
+                let cost_model = self.cost_model.as_deref().unwrap_or(&DefaultCostModel);
+                let res = rvsdg.optimize_to_bril_timed(
+                    Optimizer::default().num_iters,
+                    self.rules(),
+                    cost_model,
+                    self.limit,
+                    &mut timings,
+                    &mut captures,
+                )?;
+                let visualization = format!("{}", res);
+                result_program = Some(res);
 
-                (format!("{}", res), ".bril")
+                (visualization, ".bril")
+            }
+            RunType::Passthrough => {
+                let start = Instant::now();
+                let rvsdg = self.rvsdg()?;
+                timings.lowering = start.elapsed();
+                let start = Instant::now();
+                let res = rvsdg.to_bril().map_err(EggCCError::RvsdgError)?;
+                timings.extraction = start.elapsed();
+                let visualization = format!("{}", res);
+                result_program = Some(res);
+
+                (visualization, ".bril")
             }
         };
 
+        if let (Some(path), Some(json)) = (&self.dump_egraph, &captures.egraph_json) {
+            std::fs::write(path, json).map_err(|err| EggCCError::Write(path.clone(), err))?;
+        }
+
         let result_interpreted = if self.interp {
-            Some(Optimizer::interp(
-                &self.prog_with_args.program,
-                self.prog_with_args.args.clone(),
-                None,
-            ))
+            result_program.as_ref().map(interp).transpose()?
         } else {
             None
         };
 
-        RunOutput {
+        let output = RunOutput {
             visualization,
             visualization_file_extension: visualization_file_extension.to_string(),
             result_interpreted,
             original_interpreted,
+            egglog_program: captures.egglog_program,
+            diverged,
+            iterations: captures.iterations,
+        };
+        Ok((output, timings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use bril_rs::{Code, ConstOps, EffectOps, Function, Instruction, Literal, Program, Type};
+
+    use crate::{EggCCError, InterpResult, Optimizer};
+
+    use super::{OptLevel, Run, RunType, SaturationLimit, TestProgram};
+
+    #[test]
+    fn run_in_memory_program() {
+        // @main { v: int = const 1; print v; }
+        let program = Program {
+            functions: vec![Function {
+                args: vec![],
+                instrs: vec![
+                    Code::Instruction(Instruction::Constant {
+                        dest: "v".to_string(),
+                        op: ConstOps::Const,
+                        pos: None,
+                        const_type: Type::Int,
+                        value: Literal::Int(1),
+                    }),
+                    Code::Instruction(Instruction::Effect {
+                        args: vec!["v".to_string()],
+                        funcs: vec![],
+                        labels: vec![],
+                        op: EffectOps::Print,
+                        pos: None,
+                    }),
+                ],
+                name: "main".to_string(),
+                pos: None,
+                return_type: None,
+            }],
+            imports: vec![],
+        };
+        let runs = Run::all_configurations_for(TestProgram::Program(program));
+        assert!(!runs.is_empty());
+        for run in runs {
+            assert!(run.name().starts_with("program-"), "{}", run.name());
+            let output = run.run().unwrap();
+            assert_eq!(output.original_interpreted, "1\n");
+            if run.interp {
+                assert_eq!(output.result_interpreted.as_deref(), Some("1\n"));
+            }
+        }
+    }
+
+    #[test]
+    fn run_program_from_reader() {
+        // The `# ARGS:` line has to come first.
+        const PROGRAM: &str = r#"# ARGS: 4
+        @main(n: int) {
+            one: int = const 1;
+            v: int = add n one;
+            print v;
+        }
+        "#;
+        let prog = TestProgram::read_text(PROGRAM.as_bytes(), "piped".to_string());
+        assert_eq!(prog.args, vec!["4".to_string()]);
+        for run in Run::all_configurations_for(TestProgram::Prog(prog)) {
+            let output = run.run().unwrap();
+            assert_eq!(output.original_interpreted, "5\n");
+            if run.interp {
+                assert_eq!(output.result_interpreted.as_deref(), Some("5\n"));
+            }
+        }
+    }
+
+    #[test]
+    fn opt_levels_preserve_behavior() {
+        // The naiive optimizer has a single set of rules, so the RVSDG
+        // optimizer is what tells the levels apart.
+        let prog = TestProgram::File("tests/small/fib_shape.bril".into()).read_program();
+        let mut names = HashSet::new();
+        for opt_level in [OptLevel::None, OptLevel::Basic, OptLevel::Aggressive] {
+            let run = Run {
+                interp: true,
+                ..Run::new(prog.clone(), RunType::OptimizedBril)
+            }
+            .with_opt_level(opt_level);
+            assert!(names.insert(run.name()), "{}", run.name());
+            let output = run.run().unwrap();
+            assert_eq!(
+                output.result_interpreted.as_deref(),
+                Some(output.original_interpreted.as_str()),
+                "{opt_level}"
+            );
+        }
+    }
+
+    #[test]
+    fn interpreting_requires_bril() {
+        let prog = TestProgram::File("tests/small/add.bril".into()).read_program();
+        let run = Run {
+            interp: true,
+            ..Run::new(prog, RunType::RvsdgConversion)
+        };
+        let err = run.run().err().unwrap();
+        assert!(matches!(err, EggCCError::Uninterpretable(_)), "{err}");
+    }
+
+    #[test]
+    fn passthrough_preserves_behavior() {
+        for file in [
+            "tests/small/add.bril",
+            "tests/small/diamond.bril",
+            "tests/small/fib_shape.bril",
+            "tests/small/two_fns.bril",
+        ] {
+            let prog = TestProgram::File(file.into()).read_program();
+            let output = Run {
+                interp: true,
+                ..Run::new(prog, RunType::Passthrough)
+            }
+            .run()
+            .unwrap();
+            assert_eq!(
+                output.result_interpreted.as_deref(),
+                Some(output.original_interpreted.as_str()),
+                "{file}"
+            );
+            // Nothing was optimized, so egglog never ran.
+            assert_eq!(output.iterations, 0, "{file}");
+        }
+    }
+
+    #[test]
+    fn run_with_timing_records_phases() {
+        let prog = TestProgram::File("tests/small/fib_shape.bril".into()).read_program();
+        for test_type in [RunType::NaiiveOptimization, RunType::RvsdgOptimize] {
+            let run = Run::new(prog.clone(), test_type);
+            let (_output, timings) = run.run_with_timing().unwrap();
+            assert!(timings.lowering > Duration::ZERO, "{test_type}");
+            assert!(timings.saturation > Duration::ZERO, "{test_type}");
+            assert!(timings.extraction > Duration::ZERO, "{test_type}");
+        }
+    }
+
+    #[test]
+    fn saturation_limit_preserves_behavior() {
+        let prog = TestProgram::File("tests/small/fib_shape.bril".into()).read_program();
+        let limits = [
+            SaturationLimit {
+                max_iters: Some(1),
+                max_nodes: None,
+            },
+            SaturationLimit {
+                max_iters: None,
+                max_nodes: Some(1),
+            },
+        ];
+        for limit in limits {
+            let run = Run {
+                interp: true,
+                ..Run::new(prog.clone(), RunType::NaiiveOptimization)
+            }
+            .with_limit(limit);
+            let output = run.run().unwrap();
+            assert_eq!(
+                output.result_interpreted.as_deref(),
+                Some(output.original_interpreted.as_str()),
+                "{limit:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn optimized_bril_preserves_behavior() {
+        let prog = TestProgram::File("tests/small/fib_shape.bril".into()).read_program();
+        let run = Run {
+            interp: true,
+            ..Run::new(prog.clone(), RunType::OptimizedBril)
+        };
+        let output = run.run().unwrap();
+        assert_eq!(
+            output.result_interpreted.as_deref(),
+            Some(output.original_interpreted.as_str())
+        );
+
+        // The text that was output is a Bril program in its own right.
+        assert_eq!(output.visualization_file_extension, ".bril");
+        let emitted = Optimizer::parse_bril(&output.visualization).unwrap();
+        assert_eq!(
+            Optimizer::interp(&emitted, prog.args, None),
+            output.original_interpreted
+        );
+    }
+
+    #[test]
+    fn strength_reduced_mul_has_no_loop() {
+        // Strength reduction turns the multiplication into a shift, which
+        // has to come back out as a multiplication rather than a loop.
+        const PROGRAM: &str = r#"
+        @main(x: int) {
+            eight: int = const 8;
+            y: int = mul x eight;
+            print y;
+        }
+        "#;
+        let program = Optimizer::parse_bril(PROGRAM).unwrap();
+        let prog = TestProgram::Program(program).read_program();
+        let run = Run {
+            interp: true,
+            opt_level: OptLevel::Aggressive,
+            ..Run::new(prog, RunType::OptimizedBril)
+        }
+        .with_args(&[Literal::Int(3)]);
+        let output = run.run().unwrap();
+        assert_eq!(output.result_interpreted.as_deref(), Some("24\n"));
+
+        let emitted = Optimizer::parse_bril(&output.visualization).unwrap();
+        for instr in &emitted.functions[0].instrs {
+            assert!(
+                !matches!(instr, Code::Label { .. }),
+                "{}",
+                output.visualization
+            );
+        }
+    }
+
+    #[test]
+    fn capture_egglog_program() {
+        let prog = TestProgram::File("tests/small/fib_shape.bril".into()).read_program();
+        let run = |test_type| {
+            Run {
+                capture_egglog: true,
+                ..Run::new(prog.clone(), test_type)
+            }
+            .run()
+            .unwrap()
+            .egglog_program
+            .unwrap()
+        };
+
+        let rvsdg = run(RunType::RvsdgOptimize);
+        assert!(rvsdg.contains("(let main-0 "), "{rvsdg}");
+        assert!(rvsdg.contains("(run const-fold 1)"), "{rvsdg}");
+        assert!(rvsdg.contains("(extract main-0)"), "{rvsdg}");
+
+        let naiive = run(RunType::NaiiveOptimization);
+        assert!(naiive.contains("(run 3)"), "{naiive}");
+    }
+
+    #[test]
+    fn custom_rules() {
+        let prog = TestProgram::File("tests/small/add.bril".into()).read_program();
+        // Turn every addition into a subtraction of the negation, which keeps
+        // behavior the same but shows up in the captured program.
+        let rules = r#"
+        (ruleset sub-neg)
+        (rewrite (add ty x y)
+                 (sub ty x (Node (PureOp (sub ty (Node (PureOp (Const ty (const) (Num 0)))) y))))
+                 :ruleset sub-neg)
+        "#;
+        let run = Run {
+            interp: true,
+            opt_level: OptLevel::None,
+            capture_egglog: true,
+            ..Run::new(prog, RunType::OptimizedBril)
+        }
+        .with_custom_rules(vec![rules.to_string()])
+        .unwrap();
+        let output = run.run().unwrap();
+        assert_eq!(
+            output.result_interpreted.as_deref(),
+            Some(output.original_interpreted.as_str())
+        );
+        let program = output.egglog_program.unwrap();
+        assert!(program.contains("(ruleset sub-neg)"), "{program}");
+        assert!(program.contains("(run sub-neg 1)"), "{program}");
+
+        let err = run
+            .with_custom_rules(vec!["(rewrite (add ty x".to_string()])
+            .err()
+            .expect("rules that don't parse should be rejected");
+        assert!(matches!(err, EggCCError::InvalidRules(_)), "{err}");
+    }
+
+    #[test]
+    fn report_iterations() {
+        // Constant folding rewrites `1 + 2`.
+        let prog = TestProgram::File("tests/small/add.bril".into()).read_program();
+        for test_type in [
+            RunType::RvsdgOptimize,
+            RunType::OptimizedBril,
+            RunType::NaiiveOptimization,
+        ] {
+            let output = Run::new(prog.clone(), test_type).run().unwrap();
+            assert!(output.iterations >= 1, "{test_type}");
+            assert!(
+                output.iterations <= Optimizer::default().num_iters,
+                "{test_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn report_iterations_to_fixpoint() {
+        // The naiive optimizer only folds additions and subtractions, so the
+        // first iteration changes nothing and saturation stops there.
+        let prog = TestProgram::File("tests/small/diamond.bril".into()).read_program();
+        assert!(Optimizer::default().num_iters > 1);
+        let output = Run::new(prog, RunType::NaiiveOptimization).run().unwrap();
+        assert_eq!(output.iterations, 1);
+    }
+
+    #[test]
+    fn dump_egraph_json() {
+        let path = std::env::temp_dir().join("eggcc-dump-egraph-test.json");
+        let prog = TestProgram::File("tests/small/add.bril".into()).read_program();
+        Run {
+            dump_egraph: Some(path.clone()),
+            ..Run::new(prog, RunType::RvsdgOptimize)
+        }
+        .run()
+        .unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(!json["nodes"].as_object().unwrap().is_empty());
+        // `main` only outputs its state edge, whose e-class is in the egraph.
+        let roots = json["root_eclasses"].as_array().unwrap();
+        assert_eq!(roots.len(), 1);
+        for root in roots {
+            let size = &json["eclass_sizes"][root.as_str().unwrap()];
+            assert!(size.as_u64().unwrap() > 0, "{root}");
         }
+
+        // Failing to write the dump is reported rather than aborting.
+        let path = std::env::temp_dir().join("eggcc-missing-dir/egraph.json");
+        let prog = TestProgram::File("tests/small/add.bril".into()).read_program();
+        let err = Run {
+            dump_egraph: Some(path.clone()),
+            ..Run::new(prog, RunType::RvsdgOptimize)
+        }
+        .run()
+        .err()
+        .unwrap();
+        assert!(matches!(err, EggCCError::Write(p, _) if p == path));
+    }
+
+    #[test]
+    fn interp_budget_reports_divergence() {
+        const PROGRAM: &str = r#"
+        @main {
+            t: bool = const true;
+        .loop:
+            br t .body .exit;
+        .body:
+            jmp .loop;
+        .exit:
+        }
+        "#;
+        let program = Optimizer::parse_bril(PROGRAM).unwrap();
+        assert_eq!(
+            Optimizer::interp_with_budget(&program, vec![], 100).unwrap(),
+            InterpResult::Diverged
+        );
+
+        let prog = TestProgram::Program(program).read_program();
+        let run = Run::new(prog, RunType::StructuredConversion).with_interp_budget(100);
+        assert!(run.run().unwrap().diverged);
+
+        // Programs that finish are unaffected.
+        let prog = TestProgram::File("tests/small/add.bril".into()).read_program();
+        let output = Run {
+            prog_with_args: prog,
+            ..run
+        }
+        .run()
+        .unwrap();
+        assert!(!output.diverged);
+        assert!(!output.original_interpreted.is_empty());
+    }
+
+    #[test]
+    fn interp_budget_counts_steps() {
+        // Prints 0 through 4, calling a function each time around the loop.
+        // Entering main and leaving the loop are a step each, and each
+        // iteration takes three, plus one more for the final check.
+        const PROGRAM: &str = r#"
+        @print_it(x: int) {
+            print x;
+        }
+        @main(n: int) {
+            i: int = const 0;
+            one: int = const 1;
+        .loop:
+            more: bool = lt i n;
+            br more .body .exit;
+        .body:
+            call @print_it i;
+            i: int = add i one;
+            jmp .loop;
+        .exit:
+        }
+        "#;
+        let program = Optimizer::parse_bril(PROGRAM).unwrap();
+        let interp = |steps| Optimizer::interp_with_budget(&program, vec!["5".into()], steps);
+        let expected = InterpResult::Terminated("0\n1\n2\n3\n4\n".into());
+        assert_eq!(interp(100).unwrap(), expected);
+        assert_eq!(interp(18).unwrap(), expected);
+        assert_eq!(interp(17).unwrap(), InterpResult::Diverged);
+        assert_eq!(interp(3).unwrap(), InterpResult::Diverged);
+    }
+
+    #[test]
+    fn interp_budget_returns_errors() {
+        // Leaks the memory it allocates.
+        const PROGRAM: &str = r#"
+        @main {
+            one: int = const 1;
+            p: ptr<int> = alloc one;
+            print one;
+        }
+        "#;
+        let program = Optimizer::parse_bril(PROGRAM).unwrap();
+        let res = Optimizer::interp_with_budget(&program, vec![], 100);
+        assert!(matches!(res, Err(EggCCError::Interp(_))), "{res:?}");
+
+        let prog = TestProgram::Program(program).read_program();
+        let run = Run::new(prog, RunType::StructuredConversion).with_interp_budget(100);
+        assert!(matches!(run.run(), Err(EggCCError::Interp(_))));
     }
 }
@@ -6,7 +6,10 @@ fn generate_tests(glob: &str) -> Vec<Trial> {
     let mut trials = vec![];
     let mut mk_trial = |run: Run, snapshot: bool| {
         trials.push(Trial::test(run.name(), move || {
-            let result = run.run();
+            let result = run.run()?;
+            if result.diverged {
+                return Err("interpreting the program timed out".into());
+            }
 
             if let Some(interpreted) = result.result_interpreted {
                 assert_eq!(result.original_interpreted, interpreted);